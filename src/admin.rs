@@ -0,0 +1,287 @@
+//! Offline inspection and repair of a `KvStore` data directory.
+//!
+//! These functions read the `<gen>.log` files directly, so the store must not
+//! be open while they run.
+
+use crate::crypto::Keyring;
+use crate::kv::{gen_list, log_path};
+use crate::{Command, KvStoreOptions, KvsError, Result};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::ops::Range;
+use std::path::Path;
+
+/// A record read back from a log generation, decrypted if it was sealed.
+#[derive(Debug)]
+pub struct Record {
+    pub gen: u64,
+    pub offset: u64,
+    pub len: u64,
+    /// Whether the record is stored encrypted.
+    pub sealed: bool,
+    /// The change sequence number of a `set` or `remove`.
+    pub seq: Option<u64>,
+    /// The command, without its encryption or sequence number.
+    pub command: Command,
+}
+
+/// A region of a log generation that does not hold a readable record.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BadRecord {
+    pub gen: u64,
+    pub offset: u64,
+    pub len: u64,
+    pub error: String,
+}
+
+/// The result of `verify`.
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub generations: u64,
+    /// Number of readable records.
+    pub records: u64,
+    pub bad_records: Vec<BadRecord>,
+    /// Keys that have a value once every readable record is replayed.
+    pub live_keys: u64,
+    /// Readable records that are overwritten, removed or are removals.
+    pub stale_records: u64,
+}
+
+impl VerifyReport {
+    pub fn is_clean(&self) -> bool {
+        self.bad_records.is_empty()
+    }
+}
+
+/// The result of `repair`.
+#[derive(Debug, Default)]
+pub struct RepairReport {
+    /// Live records written to the clean generation.
+    pub salvaged: u64,
+    /// Unreadable regions that were dropped.
+    pub dropped: Vec<BadRecord>,
+    /// The generation holding every salvaged record.
+    pub generation: u64,
+}
+
+/// Selects the records printed by `dump`.
+#[derive(Debug, Default, Clone)]
+pub struct DumpFilter {
+    /// Only records whose key starts with this prefix.
+    pub prefix: Option<String>,
+    /// Only records of this generation.
+    pub gen: Option<u64>,
+    /// Only the records the index points to once the log is loaded.
+    pub live: bool,
+}
+
+/// The records and unreadable regions of one generation, in file order.
+pub(crate) struct Generation {
+    pub(crate) gen: u64,
+    pub(crate) data: Vec<u8>,
+    pub(crate) records: Vec<Record>,
+    pub(crate) bad_records: Vec<BadRecord>,
+}
+
+impl Generation {
+    /// Returns the bytes of a record as stored in the log.
+    pub(crate) fn raw(&self, record: &Record) -> &[u8] {
+        &self.data[record.offset as usize..(record.offset + record.len) as usize]
+    }
+}
+
+/// Reads every generation of a data directory, skipping over corrupt regions.
+///
+/// # Errors
+///
+/// It returns `KvsError::KeyRequired` if the log holds encrypted records and
+/// no key is given, and `KvsError::Decryption` if none of them can be opened
+/// with the given keys, rather than reporting every record as bad.
+pub(crate) fn scan(dir: &Path, options: &KvStoreOptions) -> Result<Vec<Generation>> {
+    let keyring = Keyring::new(options.encryption_key.as_ref(), &options.previous_keys);
+    let mut opened = 0;
+    let mut undecryptable = 0;
+    let mut generations = Vec::new();
+    for gen in gen_list(dir)? {
+        let data = fs::read(log_path(dir, gen))?;
+        let mut records = Vec::new();
+        let mut bad_records: Vec<BadRecord> = Vec::new();
+        let mut pos = 0;
+        while let Some((range, result)) = read_record(&data, pos) {
+            let sealed = matches!(result, Ok(Command::Sealed(..)));
+            let result = match result {
+                Ok(cmd @ Command::Sealed(..)) => match keyring.open(cmd) {
+                    Ok(cmd) => {
+                        opened += 1;
+                        Ok(cmd)
+                    }
+                    Err(KvsError::KeyRequired) => return Err(KvsError::KeyRequired),
+                    Err(e) => {
+                        undecryptable += 1;
+                        Err(e)
+                    }
+                },
+                result => result,
+            };
+            match result.map(Command::unsequence) {
+                Ok((seq, command)) => records.push(Record {
+                    gen,
+                    offset: range.start as u64,
+                    len: (range.end - range.start) as u64,
+                    sealed,
+                    seq,
+                    command,
+                }),
+                Err(e) => match bad_records.last_mut() {
+                    // Resynchronizing after a corrupt record can take several
+                    // attempts, report them as one region.
+                    Some(bad) if bad.offset + bad.len == range.start as u64 => {
+                        bad.len = range.end as u64 - bad.offset;
+                    }
+                    _ => bad_records.push(BadRecord {
+                        gen,
+                        offset: range.start as u64,
+                        len: (range.end - range.start) as u64,
+                        error: e.to_string(),
+                    }),
+                },
+            }
+            pos = range.end;
+        }
+        generations.push(Generation {
+            gen,
+            data,
+            records,
+            bad_records,
+        });
+    }
+    if opened == 0 && undecryptable > 0 {
+        return Err(KvsError::Decryption);
+    }
+    Ok(generations)
+}
+
+/// Reads the record starting at `pos`, or returns `None` if only whitespace
+/// is left.
+///
+/// On failure, the returned range ends where the next record may start: the
+/// next `{` right after a `}`, as records are written back to back, or the end
+/// of the data.
+fn read_record(data: &[u8], pos: usize) -> Option<(Range<usize>, Result<Command>)> {
+    let mut stream = serde_json::Deserializer::from_slice(&data[pos..]).into_iter::<Command>();
+    match stream.next()? {
+        Ok(cmd) => Some((pos..pos + stream.byte_offset(), Ok(cmd))),
+        Err(e) => {
+            let next = data[pos..]
+                .windows(2)
+                .position(|bytes| bytes == b"}{")
+                .map_or(data.len(), |skip| pos + skip + 1);
+            Some((pos..next, Err(e.into())))
+        }
+    }
+}
+
+/// Replays the readable records in order and returns the live record of each
+/// key, as `(generation index, record index)` into `generations`.
+pub(crate) fn live_records(generations: &[Generation]) -> BTreeMap<&str, (usize, usize)> {
+    let mut live = BTreeMap::new();
+    for (i, generation) in generations.iter().enumerate() {
+        for (j, record) in generation.records.iter().enumerate() {
+            match &record.command {
+                Command::Set(key, ..)
+                | Command::SetCompressed(key, ..)
+                | Command::SetBlob(key, ..) => {
+                    live.insert(key.as_str(), (i, j));
+                }
+                Command::Remove(key) => {
+                    live.remove(key.as_str());
+                }
+                Command::Sealed(..) | Command::Sequenced(..) => {}
+            }
+        }
+    }
+    live
+}
+
+/// Reads every record of a data directory and reports the unreadable ones.
+pub fn verify(dir: impl AsRef<Path>, options: &KvStoreOptions) -> Result<VerifyReport> {
+    let generations = scan(dir.as_ref(), options)?;
+    let records: usize = generations.iter().map(|g| g.records.len()).sum();
+    let live_keys = live_records(&generations).len();
+    Ok(VerifyReport {
+        generations: generations.len() as u64,
+        records: records as u64,
+        live_keys: live_keys as u64,
+        stale_records: (records - live_keys) as u64,
+        bad_records: generations
+            .into_iter()
+            .flat_map(|generation| generation.bad_records)
+            .collect(),
+    })
+}
+
+/// Rewrites the live records that can still be read into a new generation,
+/// and deletes every older one, dropping corrupt records and tails.
+///
+/// Records are copied as they are stored, so sealed, compressed and blob
+/// records stay valid.
+pub fn repair(dir: impl AsRef<Path>, options: &KvStoreOptions) -> Result<RepairReport> {
+    let dir = dir.as_ref();
+    let generations = scan(dir, options)?;
+    let gen = generations
+        .last()
+        .map_or(1, |generation| generation.gen + 1);
+
+    let mut live: Vec<(usize, usize)> = live_records(&generations).into_values().collect();
+    live.sort_unstable();
+    let mut file = File::create(log_path(dir, gen))?;
+    for &(i, j) in &live {
+        let generation = &generations[i];
+        file.write_all(generation.raw(&generation.records[j]))?;
+    }
+    file.sync_all()?;
+
+    for generation in &generations {
+        fs::remove_file(log_path(dir, generation.gen))?;
+    }
+    Ok(RepairReport {
+        salvaged: live.len() as u64,
+        dropped: generations
+            .into_iter()
+            .flat_map(|generation| generation.bad_records)
+            .collect(),
+        generation: gen,
+    })
+}
+
+/// Returns the readable records of a data directory in log order, keeping
+/// those selected by the filter.
+pub fn dump(
+    dir: impl AsRef<Path>,
+    options: &KvStoreOptions,
+    filter: &DumpFilter,
+) -> Result<Vec<Record>> {
+    let generations = scan(dir.as_ref(), options)?;
+    let mut live: Vec<(usize, usize)> = live_records(&generations).into_values().collect();
+    live.sort_unstable();
+
+    let mut records = Vec::new();
+    for (i, generation) in generations.into_iter().enumerate() {
+        for (j, record) in generation.records.into_iter().enumerate() {
+            let selected = filter.gen.is_none_or(|gen| gen == record.gen)
+                && filter.prefix.as_ref().is_none_or(|prefix| {
+                    record
+                        .command
+                        .key()
+                        .unwrap_or("")
+                        .starts_with(prefix.as_str())
+                })
+                && (!filter.live || live.binary_search(&(i, j)).is_ok());
+            if selected {
+                records.push(record);
+            }
+        }
+    }
+    Ok(records)
+}
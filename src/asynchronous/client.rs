@@ -0,0 +1,119 @@
+use super::{handshake, MessageReader, MessageWriter};
+use crate::common::{Request, Response};
+use crate::protocol::RequestId;
+use crate::{KvsError, Result, Stats};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+
+/// How many times a request is retried while a cluster has no leader or
+/// redirects it.
+const MAX_ATTEMPTS: usize = 20;
+const ELECTION_WAIT: Duration = Duration::from_millis(100);
+
+/// A client of a server, like `Client` but on tokio.
+pub struct AsyncClient {
+    addr: SocketAddr,
+    reader: MessageReader<OwnedReadHalf, Response>,
+    writer: MessageWriter<OwnedWriteHalf>,
+    next_id: RequestId,
+}
+
+impl AsyncClient {
+    pub async fn connect(addr: SocketAddr) -> Result<Self> {
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        let wire = handshake(&mut stream).await?;
+        let (reader, writer) = stream.into_split();
+        Ok(AsyncClient {
+            addr,
+            reader: MessageReader::new(reader, wire),
+            writer: MessageWriter::new(writer, wire),
+            next_id: 0,
+        })
+    }
+
+    pub async fn set(&mut self, key: String, value: String) -> Result<()> {
+        match self.call(&Request::Set { key, value }).await? {
+            Response::Ok(_) => Ok(()),
+            Response::Err(e) => Err(e.into()),
+            _ => Err(KvsError::UnexpectedResponseType),
+        }
+    }
+
+    pub async fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.call(&Request::Get { key }).await? {
+            Response::Ok(value) => Ok(value),
+            Response::Err(e) => Err(e.into()),
+            _ => Err(KvsError::UnexpectedResponseType),
+        }
+    }
+
+    pub async fn remove(&mut self, key: String) -> Result<()> {
+        match self.call(&Request::Remove { key }).await? {
+            Response::Ok(_) => Ok(()),
+            Response::Err(e) => Err(e.into()),
+            _ => Err(KvsError::UnexpectedResponseType),
+        }
+    }
+
+    /// Returns the keys starting with `prefix` with their values, in key
+    /// order.
+    pub async fn scan(&mut self, prefix: String) -> Result<Vec<(String, String)>> {
+        let request = Request::Scan {
+            prefix,
+            after: None,
+            limit: None,
+        };
+        match self.call(&request).await? {
+            Response::Entries(entries) => Ok(entries),
+            Response::Err(e) => Err(e.into()),
+            _ => Err(KvsError::UnexpectedResponseType),
+        }
+    }
+
+    pub async fn stats(&mut self) -> Result<Stats> {
+        match self.send(&Request::Stats).await? {
+            Response::Stats(stats) => Ok(stats),
+            Response::Err(e) => Err(e.into()),
+            _ => Err(KvsError::UnexpectedResponseType),
+        }
+    }
+
+    /// Sends a request and reads its response, following the redirects of a
+    /// cluster follower like `Client` does.
+    pub async fn call(&mut self, request: &Request) -> Result<Response> {
+        for _ in 0..MAX_ATTEMPTS {
+            match self.send(request).await? {
+                Response::NotLeader(Some(leader)) => {
+                    let addr = leader
+                        .parse()
+                        .map_err(|_| KvsError::NotLeader(Some(leader)))?;
+                    if addr == self.addr {
+                        tokio::time::sleep(ELECTION_WAIT).await;
+                    } else {
+                        *self = AsyncClient::connect(addr).await?;
+                    }
+                }
+                Response::NotLeader(None) => tokio::time::sleep(ELECTION_WAIT).await,
+                response => return Ok(response),
+            }
+        }
+        Err(KvsError::NotLeader(None))
+    }
+
+    /// Sends a request to this server and reads its response.
+    async fn send(&mut self, request: &Request) -> Result<Response> {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.writer.send(id, request).await?;
+        match self.reader.receive().await? {
+            (response_id, response) if response_id == id => Ok(response),
+            (response_id, _) => Err(KvsError::Protocol(format!(
+                "response to request {} while waiting for {}",
+                response_id, id
+            ))),
+        }
+    }
+}
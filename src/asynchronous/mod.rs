@@ -0,0 +1,180 @@
+//! A client and a server on tokio, speaking the protocol of `Client` and
+//! `Server`. Built with the `async` feature.
+
+mod client;
+mod server;
+
+pub use self::client::AsyncClient;
+pub use self::server::AsyncServer;
+
+use crate::protocol::{self, RequestId, Wire, MAGIC, VERSION};
+use crate::{KvsError, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io;
+use std::marker::PhantomData;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Opens a framed session on a new connection to a server.
+async fn handshake(stream: &mut TcpStream) -> Result<Wire> {
+    let mut hello = MAGIC.to_vec();
+    hello.push(VERSION);
+    stream.write_all(&hello).await?;
+    let mut reply = [0; 5];
+    stream.read_exact(&mut reply).await?;
+    if reply[..4] != MAGIC {
+        return Err(KvsError::Protocol("not a kvs server".to_owned()));
+    }
+    match reply[4] {
+        version @ 1..=VERSION => Ok(if version >= 2 {
+            Wire::Pipelined
+        } else {
+            Wire::Framed
+        }),
+        version => Err(KvsError::Protocol(format!(
+            "server answered with version {}",
+            version
+        ))),
+    }
+}
+
+/// The messages of type `T` coming from the other side, like
+/// `protocol::Reader`.
+struct MessageReader<R, T> {
+    reader: R,
+    buf: Vec<u8>,
+    wire: Wire,
+    /// The id given to the next message of a session without ids.
+    next_id: RequestId,
+    last_id: RequestId,
+    messages: PhantomData<T>,
+}
+
+impl<R: AsyncRead + Unpin, T: DeserializeOwned> MessageReader<R, T> {
+    fn new(reader: R, wire: Wire) -> Self {
+        MessageReader {
+            reader,
+            buf: Vec::new(),
+            wire,
+            next_id: 0,
+            last_id: 0,
+            messages: PhantomData,
+        }
+    }
+
+    /// Detects the wire a client speaks from its first byte, answering the
+    /// handshake of a framed session.
+    async fn accept<W: AsyncWrite + Unpin>(&mut self, writer: &mut W) -> Result<Wire> {
+        if !self.fill(1).await? || self.buf[0] != MAGIC[0] {
+            self.wire = Wire::Json;
+            return Ok(self.wire);
+        }
+        if !self.fill(5).await? || self.buf[..4] != MAGIC {
+            return Err(KvsError::Protocol("bad handshake".to_owned()));
+        }
+        let version = self.buf[4].min(VERSION);
+        self.buf.drain(..5);
+        writer.write_all(&MAGIC).await?;
+        writer.write_all(&[version]).await?;
+        writer.flush().await?;
+        self.wire = match version {
+            0 => {
+                return Err(KvsError::Protocol(
+                    "client speaks no known version".to_owned(),
+                ))
+            }
+            1 => Wire::Framed,
+            _ => Wire::Pipelined,
+        };
+        Ok(self.wire)
+    }
+
+    /// Reads until `len` bytes are buffered, returning false if the
+    /// connection closes first.
+    async fn fill(&mut self, len: usize) -> Result<bool> {
+        while self.buf.len() < len {
+            if self.reader.read_buf(&mut self.buf).await? == 0 {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Returns the next message, or `None` once the other side closed the
+    /// connection. A `KvsError::MalformedMessage` leaves the reader at the
+    /// start of the following message.
+    async fn next(&mut self) -> Result<Option<(RequestId, T)>> {
+        loop {
+            if let Some(decoded) = protocol::decode::<T>(self.wire, &self.buf)? {
+                self.buf.drain(..decoded.len);
+                self.last_id = match decoded.id {
+                    Some(id) => id,
+                    None => {
+                        self.next_id += 1;
+                        self.next_id - 1
+                    }
+                };
+                return decoded.message.map(|message| Some((self.last_id, message)));
+            }
+            if self.reader.read_buf(&mut self.buf).await? == 0 {
+                if self.buf.iter().all(u8::is_ascii_whitespace) {
+                    return Ok(None);
+                }
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+        }
+    }
+
+    /// Reads the next message, failing if the other side closed the
+    /// connection.
+    async fn receive(&mut self) -> Result<(RequestId, T)> {
+        match self.next().await? {
+            Some(message) => Ok(message),
+            None => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+        }
+    }
+
+    fn last_id(&self) -> RequestId {
+        self.last_id
+    }
+
+    /// Returns whether more input is already buffered.
+    fn has_buffered(&self) -> bool {
+        !self.buf.is_empty()
+    }
+}
+
+/// Sends messages to the other side, like `protocol::Writer`.
+struct MessageWriter<W> {
+    writer: W,
+    buf: Vec<u8>,
+    wire: Wire,
+}
+
+impl<W: AsyncWrite + Unpin> MessageWriter<W> {
+    fn new(writer: W, wire: Wire) -> Self {
+        MessageWriter {
+            writer,
+            buf: Vec::new(),
+            wire,
+        }
+    }
+
+    /// Buffers a message belonging to request `id` until the next flush.
+    fn write<T: Serialize>(&mut self, id: RequestId, message: &T) -> Result<()> {
+        protocol::encode(self.wire, id, message, &mut self.buf)
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        self.writer.write_all(&self.buf).await?;
+        self.buf.clear();
+        self.writer.flush().await?;
+        Ok(())
+    }
+
+    async fn send<T: Serialize>(&mut self, id: RequestId, message: &T) -> Result<()> {
+        self.write(id, message)?;
+        self.flush().await
+    }
+}
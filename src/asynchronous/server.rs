@@ -0,0 +1,178 @@
+use super::{MessageReader, MessageWriter};
+use crate::common::{ErrorCode, Request, Response};
+use crate::connection::Handler;
+use crate::expiry::{self, Deadlines};
+use crate::http;
+use crate::metrics::{self, Metrics};
+use crate::protocol::{RequestId, Wire};
+use crate::resp;
+use crate::server::open_engine;
+use crate::watch::Watchers;
+use crate::{Config, KvsError, Result};
+use slog::Logger;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task;
+
+/// A server running every connection as a tokio task rather than a thread,
+/// so that it holds many idle connections cheaply.
+///
+/// Requests run on tokio's blocking pool, since the engines block. Followers
+/// and cluster nodes need the threaded `Server`.
+pub struct AsyncServer {
+    config: Config,
+    handler: Handler,
+    log: Logger,
+}
+
+impl AsyncServer {
+    pub fn new(config: Config) -> Result<Self> {
+        if config.replica_of.is_some() || config.cluster.is_some() {
+            return Err(KvsError::StringErr(
+                "Replication and clustering need the threaded server".to_owned(),
+            ));
+        }
+        let log = config.log.new(o!("server-address"=>config.addr));
+        let handler = Handler {
+            db: Arc::new(Mutex::new(open_engine(&config)?)),
+            metrics: Arc::new(Metrics::new()),
+            watchers: Arc::new(Watchers::open(&config.path)?),
+            deadlines: Arc::new(Deadlines::open(&config.path)?),
+            replica: None,
+            raft: None,
+            admin: config.admin_requests,
+        };
+        Ok(AsyncServer {
+            config,
+            handler,
+            log,
+        })
+    }
+
+    /// Serves clients until the listener fails.
+    pub async fn run(self) -> Result<()> {
+        let listener = TcpListener::bind(self.config.address()).await?;
+
+        if let Some(addr) = self.config.metrics_addr {
+            let metrics_listener = std::net::TcpListener::bind(addr)?;
+            let db = self.handler.db.clone();
+            let metrics = self.handler.metrics.clone();
+            let log = self.log.new(o!("metrics-address"=>addr));
+            thread::spawn(move || metrics::serve_http(metrics_listener, metrics, db, log));
+        }
+
+        if let Some(addr) = self.config.resp_addr {
+            let resp_listener = std::net::TcpListener::bind(addr)?;
+            let handler = self.handler.clone();
+            let log = self.log.new(o!("resp-address"=>addr));
+            thread::spawn(move || resp::serve(resp_listener, handler, log));
+        }
+
+        if let Some(addr) = self.config.http_addr {
+            let http_listener = std::net::TcpListener::bind(addr)?;
+            let handler = self.handler.clone();
+            let log = self.log.new(o!("http-address"=>addr));
+            thread::spawn(move || http::serve(http_listener, handler, log));
+        }
+
+        let handler = self.handler.clone();
+        thread::spawn(move || expiry::sweep(handler));
+
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            let handler = self.handler.clone();
+            let log = self.log.new(o!("peer-address"=>peer));
+            task::spawn(async move {
+                handler.metrics.connection_opened();
+                if let Err(e) = serve(&handler, stream, &log).await {
+                    error!(log, "Error on serving client: {}", e);
+                }
+                handler.metrics.connection_closed();
+            });
+        }
+    }
+}
+
+async fn serve(handler: &Handler, stream: TcpStream, log: &Logger) -> Result<()> {
+    stream.set_nodelay(true)?;
+    let (reader, mut writer) = stream.into_split();
+    let mut requests = MessageReader::<_, Request>::new(reader, Wire::Json);
+    let wire = requests.accept(&mut writer).await?;
+    debug!(log, "Speaking {:?}", wire);
+    let mut writer = MessageWriter::new(writer, wire);
+
+    loop {
+        // Responses to pipelined requests go out together, once every
+        // request received so far is answered.
+        if !requests.has_buffered() {
+            writer.flush().await?;
+        }
+        let (id, request) = match requests.next().await {
+            Ok(Some(request)) => request,
+            Ok(None) => break,
+            Err(e @ KvsError::MalformedMessage(_)) => {
+                warn!(log, "{}", e);
+                writer.write(requests.last_id(), &Response::Err(e.into()))?;
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        debug!(log, "Receive request: {:?}", request);
+        let response = match request {
+            Request::Watch { prefix, since } => {
+                return watch(handler, prefix, since, id, writer).await;
+            }
+            other @ (Request::Replicate { .. } | Request::Raft { .. }) => {
+                Response::Err(ErrorCode::Other(format!(
+                    "Request {} is not supported by the async server",
+                    other.name()
+                )))
+            }
+            request => {
+                let handler = handler.clone();
+                task::spawn_blocking(move || handler.respond(request))
+                    .await
+                    .map_err(|e| KvsError::StringErr(e.to_string()))?
+            }
+        };
+        writer.write(id, &response)?;
+    }
+    writer.flush().await
+}
+
+/// Streams the changes of keys starting with `prefix` until the client goes
+/// away.
+async fn watch(
+    handler: &Handler,
+    prefix: String,
+    since: Option<u64>,
+    id: RequestId,
+    mut writer: MessageWriter<OwnedWriteHalf>,
+) -> Result<()> {
+    // Subscribing may read the change feed from disk.
+    let subscriber = handler.clone();
+    let subscription = task::spawn_blocking(move || {
+        let mut db = subscriber.db.lock().unwrap();
+        subscriber
+            .watchers
+            .subscribe_async(&mut **db, prefix, since)
+    })
+    .await
+    .map_err(|e| KvsError::StringErr(e.to_string()))?;
+    let (backlog, mut events) = match subscription {
+        Ok(subscription) => subscription,
+        Err(e) => return writer.send(id, &Response::Err(e.into())).await,
+    };
+    writer.write(id, &Response::Ok(None))?;
+    for event in backlog {
+        writer.write(id, &Response::Event(event))?;
+    }
+    writer.flush().await?;
+
+    while let Some(event) = events.recv().await {
+        writer.send(id, &Response::Event(event)).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,142 @@
+use kvs::admin::{self, BadRecord, DumpFilter};
+use kvs::crypto::KEY_ENV_VAR;
+use kvs::{EncryptionKey, KvStoreOptions, Result};
+use std::path::PathBuf;
+use std::process;
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+#[structopt(name = "kvs-admin", about = "Offline tools for kvs data directories")]
+struct Opt {
+    #[structopt(subcommand)]
+    command: Command,
+    #[structopt(
+        long,
+        global = true,
+        help = "Decrypts the log with the key in this file (defaults to $KVS_ENCRYPTION_KEY)",
+        value_name = "FILE",
+        parse(from_os_str)
+    )]
+    key_file: Option<PathBuf>,
+    #[structopt(
+        long,
+        global = true,
+        help = "Also tries this retired key when decrypting records",
+        value_name = "FILE",
+        parse(from_os_str)
+    )]
+    previous_key_file: Vec<PathBuf>,
+}
+
+#[derive(StructOpt)]
+enum Command {
+    #[structopt(
+        name = "verify",
+        about = "Reads every record and reports the corrupt ones"
+    )]
+    Verify {
+        #[structopt(name = "DIR", help = "The data directory", parse(from_os_str))]
+        dir: PathBuf,
+    },
+    #[structopt(
+        name = "repair",
+        about = "Rewrites the readable live records into a clean generation"
+    )]
+    Repair {
+        #[structopt(name = "DIR", help = "The data directory", parse(from_os_str))]
+        dir: PathBuf,
+    },
+    #[structopt(name = "dump", about = "Prints the records of the log in order")]
+    Dump {
+        #[structopt(name = "DIR", help = "The data directory", parse(from_os_str))]
+        dir: PathBuf,
+        #[structopt(
+            long,
+            help = "Only prints keys starting with PREFIX",
+            value_name = "PREFIX"
+        )]
+        prefix: Option<String>,
+        #[structopt(
+            long,
+            help = "Only prints records of generation GEN",
+            value_name = "GEN"
+        )]
+        gen: Option<u64>,
+        #[structopt(long, help = "Only prints the records the index points to")]
+        live: bool,
+    },
+}
+
+fn main() -> Result<()> {
+    let opt = Opt::from_args();
+    let options = KvStoreOptions {
+        encryption_key: match opt.key_file {
+            Some(path) => Some(EncryptionKey::from_file(path)?),
+            None => EncryptionKey::from_env(KEY_ENV_VAR)?,
+        },
+        previous_keys: opt
+            .previous_key_file
+            .into_iter()
+            .map(EncryptionKey::from_file)
+            .collect::<Result<_>>()?,
+        ..KvStoreOptions::default()
+    };
+
+    match opt.command {
+        Command::Verify { dir } => {
+            let report = admin::verify(dir, &options)?;
+            print_bad_records(&report.bad_records);
+            println!(
+                "{} generations, {} records: {} live keys, {} stale records, {} bad records",
+                report.generations,
+                report.records,
+                report.live_keys,
+                report.stale_records,
+                report.bad_records.len()
+            );
+            if !report.is_clean() {
+                process::exit(1);
+            }
+        }
+        Command::Repair { dir } => {
+            let report = admin::repair(dir, &options)?;
+            print_bad_records(&report.dropped);
+            println!(
+                "Salvaged {} live records into generation {}, dropped {} bad records",
+                report.salvaged,
+                report.generation,
+                report.dropped.len()
+            );
+        }
+        Command::Dump {
+            dir,
+            prefix,
+            gen,
+            live,
+        } => {
+            let filter = DumpFilter { prefix, gen, live };
+            for record in admin::dump(dir, &options, &filter)? {
+                let sealed = if record.sealed { " (sealed)" } else { "" };
+                println!(
+                    "{}\t{}\t{}\t{}{}\t{}",
+                    record.gen,
+                    record.offset,
+                    record.len,
+                    record.command.name(),
+                    sealed,
+                    record.command.key().unwrap_or("")
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+fn print_bad_records(bad_records: &[BadRecord]) {
+    for bad in bad_records {
+        println!(
+            "generation {}, offset {}, {} bytes: {}",
+            bad.gen, bad.offset, bad.len, bad.error
+        );
+    }
+}
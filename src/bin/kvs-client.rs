@@ -1,6 +1,9 @@
 use kvs::client::Client;
-use kvs::{KvStore, KvsError, Result};
+use kvs::raft::ClusterStatus;
+use kvs::sharding::ShardedClient;
+use kvs::{KvStore, KvsError, Result, Stats};
 use std::env::current_dir;
+use std::io::{self, Write};
 use std::net::SocketAddr;
 use std::process;
 use structopt::StructOpt;
@@ -12,12 +15,22 @@ struct Opt {
     command: Command,
     #[structopt(
         long,
+        global = true,
         help = "Sets the server address",
         value_name = "IP:PORT",
         default_value = "127.0.0.1:4000",
         parse(try_from_str)
     )]
     addr: SocketAddr,
+    #[structopt(
+        long,
+        global = true,
+        help = "Spreads the keys of get, set, rm and scan over these servers instead",
+        value_name = "IP:PORT,...",
+        use_delimiter = true,
+        parse(try_from_str)
+    )]
+    servers: Vec<SocketAddr>,
 }
 
 #[derive(StructOpt)]
@@ -39,20 +52,94 @@ enum Command {
         #[structopt(name = "KEY", help = "A string key")]
         key: String,
     },
+    #[structopt(name = "scan", about = "Prints the keys starting with a prefix")]
+    Scan {
+        #[structopt(name = "PREFIX", help = "A key prefix", default_value = "")]
+        prefix: String,
+    },
+    #[structopt(
+        name = "rebalance",
+        about = "Moves keys to the server that owns them among --servers"
+    )]
+    Rebalance,
+    #[structopt(name = "watch", about = "Prints the changes of keys as they happen")]
+    Watch {
+        #[structopt(
+            name = "PREFIX",
+            help = "Only watches keys starting with PREFIX",
+            default_value = ""
+        )]
+        prefix: String,
+        #[structopt(
+            long,
+            help = "Starts with the recent changes after sequence number SEQ",
+            value_name = "SEQ"
+        )]
+        since: Option<u64>,
+    },
+    #[structopt(
+        name = "replication",
+        about = "Prints the replication state of a follower"
+    )]
+    Replication,
+    #[structopt(name = "cluster", about = "Inspects and changes a Raft cluster")]
+    Cluster(ClusterCommand),
+    #[structopt(name = "compact", about = "Compacts the server's store now")]
+    Compact,
+    #[structopt(
+        name = "ack",
+        about = "Lets the server's store drop the changes up to SEQ from its change feed"
+    )]
+    Ack {
+        #[structopt(
+            name = "SEQ",
+            help = "The last sequence number every consumer processed"
+        )]
+        seq: u64,
+    },
+    #[structopt(name = "stats", about = "Prints statistics about the server's store")]
+    Stats {
+        #[structopt(long, help = "Prints the statistics as JSON")]
+        json: bool,
+    },
 }
 
-fn main() -> Result<()> {
-    let opt = Opt::from_args();
+#[derive(StructOpt)]
+enum ClusterCommand {
+    #[structopt(name = "status", about = "Prints the Raft state of the node")]
+    Status,
+    #[structopt(name = "add", about = "Adds a node to the cluster")]
+    Add {
+        #[structopt(name = "ID", help = "The node id")]
+        id: u64,
+        #[structopt(name = "ADDR", help = "The address of the node, as IP:PORT")]
+        addr: SocketAddr,
+    },
+    #[structopt(name = "remove", about = "Removes a node from the cluster")]
+    Remove {
+        #[structopt(name = "ID", help = "The node id")]
+        id: u64,
+    },
+}
+
+fn main() {
+    if let Err(e) = run(Opt::from_args()) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+fn run(opt: Opt) -> Result<()> {
     match opt.command {
         Command::Set { key, value } => {
-            let mut client = Client::connect(opt.addr)?;
+            let mut client = Store::connect(opt.addr, &opt.servers)?;
             client.set(key, value)?;
         }
         Command::Get { key } => {
-            let mut client = Client::connect(opt.addr)?;
+            let mut client = Store::connect(opt.addr, &opt.servers)?;
             match client.get(key)? {
                 Some(value) => {
-                    println!("{:?}", value);
+                    println!("{}", value);
                 }
                 None => {
                     println!("Key not found");
@@ -60,10 +147,174 @@ fn main() -> Result<()> {
             }
         }
         Command::Remove { key } => {
-            let mut client = Client::connect(opt.addr)?;
+            let mut client = Store::connect(opt.addr, &opt.servers)?;
             client.remove(key)?;
         }
+        Command::Scan { prefix } => {
+            let mut client = Store::connect(opt.addr, &opt.servers)?;
+            for (key, value) in client.scan(prefix)? {
+                println!("{}\t{}", key, value);
+            }
+        }
+        Command::Rebalance => {
+            if opt.servers.is_empty() {
+                return Err(KvsError::StringErr(
+                    "rebalance needs the servers to shard over in --servers".to_owned(),
+                ));
+            }
+            let mut client = ShardedClient::connect(&opt.servers)?;
+            let report = client.rebalance()?;
+            println!(
+                "Moved {} keys, skipped {} the owner already had",
+                report.moved, report.skipped
+            );
+        }
+        Command::Watch { prefix, since } => {
+            let client = Client::connect(opt.addr)?;
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            for event in client.watch(prefix, since)? {
+                let event = event?;
+                match event.value {
+                    Some(value) => {
+                        writeln!(stdout, "{}\tset\t{}\t{}", event.seq, event.key, value)?
+                    }
+                    None => writeln!(stdout, "{}\tremove\t{}", event.seq, event.key)?,
+                }
+                stdout.flush()?;
+            }
+        }
+        Command::Replication => {
+            let mut client = Client::connect(opt.addr)?;
+            let status = client.replication_status()?;
+            println!("leader:       {}", status.leader);
+            println!("connected:    {}", status.connected);
+            println!("applied seq:  {}", status.applied_seq);
+            println!("leader seq:   {}", status.leader_seq);
+            println!("lag:          {}", status.lag);
+            if let Some(ms) = status.last_contact_ms {
+                println!("last contact: {} ms ago", ms);
+            }
+        }
+        Command::Cluster(command) => {
+            let mut client = Client::connect(opt.addr)?;
+            match command {
+                ClusterCommand::Status => print_cluster_status(&client.cluster_status()?),
+                ClusterCommand::Add { id, addr } => client.add_member(id, addr.to_string())?,
+                ClusterCommand::Remove { id } => client.remove_member(id)?,
+            }
+        }
+        Command::Compact => {
+            let mut client = Client::connect(opt.addr)?;
+            let report = client.compact()?;
+            println!(
+                "Reclaimed {} bytes in {} ms",
+                report.reclaimed_bytes, report.duration_ms
+            );
+        }
+        Command::Ack { seq } => {
+            let mut client = Client::connect(opt.addr)?;
+            client.acknowledge_changes(seq)?;
+        }
+        Command::Stats { json } => {
+            let mut client = Client::connect(opt.addr)?;
+            let stats = client.stats()?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&stats)?);
+            } else {
+                print_stats(&stats);
+            }
+        }
     }
 
     Ok(())
 }
+
+/// The server, or the servers sharing the keys, that requests go to.
+enum Store {
+    Single(Box<Client>),
+    Sharded(ShardedClient),
+}
+
+impl Store {
+    fn connect(addr: SocketAddr, servers: &[SocketAddr]) -> Result<Store> {
+        if servers.is_empty() {
+            Ok(Store::Single(Box::new(Client::connect(addr)?)))
+        } else {
+            Ok(Store::Sharded(ShardedClient::connect(servers)?))
+        }
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        match self {
+            Store::Single(client) => client.set(key, value),
+            Store::Sharded(client) => client.set(key, value),
+        }
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self {
+            Store::Single(client) => client.get(key),
+            Store::Sharded(client) => client.get(key),
+        }
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        match self {
+            Store::Single(client) => client.remove(key),
+            Store::Sharded(client) => client.remove(key),
+        }
+    }
+
+    fn scan(&mut self, prefix: String) -> Result<Vec<(String, String)>> {
+        match self {
+            Store::Single(client) => client.scan(prefix),
+            Store::Sharded(client) => client.scan(prefix),
+        }
+    }
+}
+
+fn print_cluster_status(status: &ClusterStatus) {
+    println!("id:       {}", status.id);
+    println!("role:     {:?}", status.role);
+    println!("term:     {}", status.term);
+    match (status.leader, &status.leader_addr) {
+        (Some(id), Some(addr)) => println!("leader:   {} ({})", id, addr),
+        (Some(id), None) => println!("leader:   {}", id),
+        _ => println!("leader:   none"),
+    }
+    println!("commit:   {}", status.commit_index);
+    println!("applied:  {}", status.applied_index);
+    println!("snapshot: {}", status.snapshot_index);
+    let members: Vec<String> = status
+        .members
+        .iter()
+        .map(|(id, addr)| format!("{}={}", id, addr))
+        .collect();
+    println!("members:  {}", members.join(" "));
+}
+
+fn print_stats(stats: &Stats) {
+    println!("keys:               {}", stats.keys);
+    println!("generations:        {}", stats.generations);
+    println!("total bytes:        {}", stats.total_bytes);
+    println!("stale bytes:        {}", stats.stale_bytes);
+    println!("sets:               {}", stats.sets);
+    println!("gets:               {}", stats.gets);
+    println!("removes:            {}", stats.removes);
+    println!("compactions:        {}", stats.compactions);
+    if let (Some(at), Some(ms)) = (stats.last_compaction_at, stats.last_compaction_ms) {
+        println!("last compaction:    {} ({} ms)", at, ms);
+    }
+    println!(
+        "compression ratio:  {:.2} ({} values)",
+        stats.compression.ratio(),
+        stats.compression.compressed_values
+    );
+    println!(
+        "cache hit ratio:    {:.2} ({} entries, {} bytes)",
+        stats.cache.hit_ratio(),
+        stats.cache.entries,
+        stats.cache.size
+    );
+}
@@ -0,0 +1,77 @@
+#[macro_use]
+extern crate slog;
+extern crate slog_async;
+extern crate slog_term;
+
+use kvs::proxy::{Proxy, ProxyConfig, Shard};
+use kvs::Result;
+use slog::Drain;
+use std::net::SocketAddr;
+use std::time::Duration;
+use structopt::StructOpt;
+
+const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4100";
+
+#[derive(StructOpt)]
+#[structopt(
+    name = "kvs-proxy",
+    about = "Routes kvs requests to sharded back-end servers by key"
+)]
+struct Opt {
+    #[structopt(
+        long,
+        help = "Sets the proxy address",
+        value_name = "IP:PORT",
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+    )]
+    addr: SocketAddr,
+    #[structopt(
+        long,
+        required = true,
+        help = "Adds a shard: its primary server, then the replicas following it",
+        value_name = "PRIMARY[,REPLICA...]",
+        parse(try_from_str = parse_shard)
+    )]
+    backend: Vec<Shard>,
+    #[structopt(long, help = "Sends reads to the replicas of a shard first")]
+    read_from_replicas: bool,
+    #[structopt(
+        long,
+        help = "Checks the back ends every MS milliseconds",
+        value_name = "MS",
+        default_value = "1000"
+    )]
+    health_check_ms: u64,
+}
+
+fn parse_shard(s: &str) -> std::result::Result<Shard, String> {
+    let mut addrs = s.split(',').map(|addr| {
+        addr.parse::<SocketAddr>()
+            .map_err(|e| format!("invalid address {}: {}", addr, e))
+    });
+    let primary = addrs.next().expect("split yields one item")?;
+    let replicas = addrs.collect::<std::result::Result<_, _>>()?;
+    Ok(Shard { primary, replicas })
+}
+
+fn main() -> Result<()> {
+    let decorator = slog_term::TermDecorator::new().build();
+    let drain = slog_term::FullFormat::new(decorator).build().fuse();
+    let drain = slog_async::Async::new(drain).build().fuse();
+    let root = slog::Logger::root(drain, o!());
+
+    let opt = Opt::from_args();
+    info!(root, "kvs-proxy {}", env!("CARGO_PKG_VERSION"));
+    info!(root, "Listening on {}", opt.addr);
+    for shard in &opt.backend {
+        info!(
+            root,
+            "Shard {} with replicas {:?}", shard.primary, shard.replicas
+        );
+    }
+    let mut config = ProxyConfig::new(opt.addr, opt.backend, root);
+    config.read_from_replicas = opt.read_from_replicas;
+    config.health_check_interval = Duration::from_millis(opt.health_check_ms);
+    Proxy::new(config).run()
+}
@@ -5,11 +5,14 @@ extern crate slog;
 extern crate slog_async;
 extern crate slog_term;
 
+use kvs::crypto::KEY_ENV_VAR;
+use kvs::raft::RaftConfig;
 use kvs::server::Server;
-use kvs::{Config, Engine, Result};
+use kvs::{Config, EncryptionKey, Engine, Result};
 use slog::{Drain, Logger};
 use std::env::current_dir;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use structopt::StructOpt;
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
@@ -28,6 +31,125 @@ struct Opt {
         parse(try_from_str)
     )]
     addr: SocketAddr,
+    #[structopt(
+        long,
+        help = "Compresses values longer than BYTES with LZ4",
+        value_name = "BYTES"
+    )]
+    compression_threshold: Option<usize>,
+    #[structopt(
+        long,
+        help = "Stores values longer than BYTES in separate blob files",
+        value_name = "BYTES"
+    )]
+    blob_threshold: Option<usize>,
+    #[structopt(
+        long,
+        help = "Caches recently read values, up to BYTES of keys and values",
+        value_name = "BYTES"
+    )]
+    value_cache_size: Option<usize>,
+    #[structopt(
+        long,
+        help = "Keeps the key index on disk, caching ENTRIES of it in memory",
+        value_name = "ENTRIES"
+    )]
+    disk_index_cache: Option<usize>,
+    #[structopt(
+        long,
+        help = "Memory-maps the log generations that are no longer written to"
+    )]
+    mmap: bool,
+    #[structopt(
+        long,
+        help = "Keeps the changes not acknowledged with `kvs-client ack` through compaction"
+    )]
+    retain_changes: bool,
+    #[structopt(
+        long,
+        help = "Refuses admin requests, such as compact and ack, from clients"
+    )]
+    deny_admin_requests: bool,
+    #[structopt(
+        long,
+        help = "Encrypts the log with the key in this file (defaults to $KVS_ENCRYPTION_KEY)",
+        value_name = "FILE",
+        parse(from_os_str)
+    )]
+    key_file: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Decrypts records written with a retired key; compaction re-encrypts them",
+        value_name = "FILE",
+        parse(from_os_str)
+    )]
+    previous_key_file: Vec<PathBuf>,
+    #[structopt(
+        long,
+        help = "Serves Prometheus metrics at http://IP:PORT/metrics",
+        value_name = "IP:PORT",
+        parse(try_from_str)
+    )]
+    metrics_addr: Option<SocketAddr>,
+    #[structopt(
+        long,
+        help = "Serves the Redis RESP2 protocol on IP:PORT",
+        value_name = "IP:PORT",
+        parse(try_from_str)
+    )]
+    resp_addr: Option<SocketAddr>,
+    #[structopt(
+        long,
+        help = "Serves a REST API over HTTP on IP:PORT",
+        value_name = "IP:PORT",
+        parse(try_from_str)
+    )]
+    http_addr: Option<SocketAddr>,
+    #[structopt(
+        long,
+        help = "Runs as a read-only follower of the leader at IP:PORT",
+        value_name = "IP:PORT",
+        parse(try_from_str),
+        conflicts_with = "node-id"
+    )]
+    replica_of: Option<SocketAddr>,
+    #[structopt(long, help = "Runs as node ID of a Raft cluster", value_name = "ID")]
+    node_id: Option<u64>,
+    #[structopt(
+        long,
+        help = "Adds another initial member of the cluster",
+        value_name = "ID=IP:PORT",
+        parse(try_from_str = parse_peer),
+        requires = "node-id"
+    )]
+    peer: Vec<(u64, SocketAddr)>,
+    #[structopt(
+        long,
+        help = "Starts without members and waits to be added to a running cluster",
+        requires = "node-id",
+        conflicts_with = "peer"
+    )]
+    join: bool,
+    #[cfg(feature = "async")]
+    #[structopt(
+        long = "async",
+        help = "Serves clients from tokio tasks instead of threads",
+        conflicts_with_all = &["replica-of", "node-id"]
+    )]
+    async_io: bool,
+}
+
+fn parse_peer(s: &str) -> std::result::Result<(u64, SocketAddr), String> {
+    let (id, addr) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected ID=IP:PORT, got {}", s))?;
+    let id = id
+        .parse()
+        .map_err(|e| format!("invalid node id {}: {}", id, e))?;
+    let addr = addr
+        .parse()
+        .map_err(|e| format!("invalid address {}: {}", addr, e))?;
+    Ok((id, addr))
 }
 
 pub fn main() -> Result<()> {
@@ -38,7 +160,44 @@ pub fn main() -> Result<()> {
 
     let opt = Opt::from_args();
     let engine = opt.engine.unwrap_or(DEFAULT_ENGINE);
-    let config = Config::new(opt.addr, current_dir()?, engine, root);
+    let mut config = Config::new(opt.addr, current_dir()?, engine, root);
+    config.metrics_addr = opt.metrics_addr;
+    config.resp_addr = opt.resp_addr;
+    config.http_addr = opt.http_addr;
+    config.replica_of = opt.replica_of;
+    config.admin_requests = !opt.deny_admin_requests;
+    if let Some(id) = opt.node_id {
+        let mut cluster = RaftConfig::new(id, opt.addr.to_string());
+        for (peer, addr) in opt.peer {
+            cluster.members.insert(peer, addr.to_string());
+        }
+        if opt.join {
+            cluster.members.clear();
+        }
+        config.cluster = Some(cluster);
+    }
+    config.store.compression_threshold = opt.compression_threshold;
+    config.store.blob_threshold = opt.blob_threshold;
+    config.store.value_cache_size = opt.value_cache_size;
+    config.store.disk_index_cache = opt.disk_index_cache;
+    config.store.mmap = opt.mmap;
+    config.store.retain_changes = opt.retain_changes;
+    config.store.encryption_key = match opt.key_file {
+        Some(path) => Some(EncryptionKey::from_file(path)?),
+        None => EncryptionKey::from_env(KEY_ENV_VAR)?,
+    };
+    for path in opt.previous_key_file {
+        config
+            .store
+            .previous_keys
+            .push(EncryptionKey::from_file(path)?);
+    }
+    #[cfg(feature = "async")]
+    {
+        if opt.async_io {
+            return run_async(config);
+        }
+    }
     run(config)?;
     Ok(())
 }
@@ -47,7 +206,16 @@ fn run(cfg: Config) -> Result<()> {
     info!(cfg.log, "kvs-server {}", env!("CARGO_PKG_VERSION"));
     info!(cfg.log, "Storage engine: {}", cfg.engine);
     info!(cfg.log, "Listening on {}", cfg.addr);
-    let mut server = Server::new(cfg);
+    let mut server = Server::new(cfg)?;
     server.run()?;
     Ok(())
 }
+
+#[cfg(feature = "async")]
+fn run_async(cfg: Config) -> Result<()> {
+    info!(cfg.log, "kvs-server {} (async)", env!("CARGO_PKG_VERSION"));
+    info!(cfg.log, "Storage engine: {}", cfg.engine);
+    info!(cfg.log, "Listening on {}", cfg.addr);
+    let server = kvs::AsyncServer::new(cfg)?;
+    tokio::runtime::Runtime::new()?.block_on(server.run())
+}
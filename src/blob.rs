@@ -0,0 +1,192 @@
+//! Separate storage of large values, as in WiscKey.
+//!
+//! Values above `KvStoreOptions::blob_threshold` are appended to `<n>.blob`
+//! files while the main log only keeps a `Command::SetBlob` pointer to them.
+//! Blob files are logs of `Command::Set` or `Command::SetCompressed` records,
+//! sealed like the main log when encryption is enabled, so each value still
+//! carries its key. They are garbage-collected independently of log compaction.
+
+use crate::crypto::Keyring;
+use crate::kv::{files_with_extension, read_command, write_command};
+use crate::kv::{BufReaderWithOps, BufWriterWithOps};
+use crate::{Command, KvsError, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// A new blob file is started once the active one grows past this size.
+const BLOB_FILE_SIZE: u64 = 64 * 1024 * 1024;
+/// Blob files with at least this fraction of stale bytes are collected.
+const BLOB_GC_RATIO: f64 = 0.5;
+
+/// Location of a value in a blob file.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct BlobRef {
+    pub file: u64,
+    pub offset: u64,
+    pub len: u64,
+}
+
+struct BlobFile {
+    reader: BufReaderWithOps<File>,
+    size: u64,
+    live: u64,
+}
+
+pub(crate) struct BlobStore {
+    path: PathBuf,
+    files: BTreeMap<u64, BlobFile>,
+    writer: Option<BufWriterWithOps<File>>,
+    current: u64,
+    /// Blob files numbered below this one existed when the store was opened.
+    first_session_file: u64,
+}
+
+impl BlobStore {
+    /// Opens the blob files of a store. Live bytes start at zero and are
+    /// accounted with `retain` while the index is rebuilt.
+    pub(crate) fn open(path: &Path) -> Result<BlobStore> {
+        let mut files = BTreeMap::new();
+        let list = files_with_extension(path, "blob")?;
+        for &file in &list {
+            let path = blob_path(path, file);
+            let size = fs::metadata(&path)?.len();
+            let reader = BufReaderWithOps::new(File::open(&path)?)?;
+            files.insert(
+                file,
+                BlobFile {
+                    reader,
+                    size,
+                    live: 0,
+                },
+            );
+        }
+        let current = list.last().unwrap_or(&0) + 1;
+
+        Ok(BlobStore {
+            path: path.to_owned(),
+            files,
+            writer: None,
+            current,
+            first_session_file: current,
+        })
+    }
+
+    /// Marks a value as referenced by the index.
+    pub(crate) fn retain(&mut self, blob: &BlobRef) {
+        if let Some(file) = self.files.get_mut(&blob.file) {
+            file.live += blob.len;
+        }
+    }
+
+    /// Marks a value as no longer referenced by the index.
+    pub(crate) fn release(&mut self, blob: &BlobRef) {
+        if let Some(file) = self.files.get_mut(&blob.file) {
+            file.live -= blob.len;
+        }
+    }
+
+    /// Appends a value record to the active blob file.
+    pub(crate) fn append(&mut self, cmd: &Command, keyring: &Keyring) -> Result<BlobRef> {
+        if self.files.get(&self.current).map(|file| file.size) > Some(BLOB_FILE_SIZE) {
+            self.roll();
+        }
+        if self.writer.is_none() {
+            let path = blob_path(&self.path, self.current);
+            let writer =
+                BufWriterWithOps::new(OpenOptions::new().create(true).append(true).open(&path)?)?;
+            let reader = BufReaderWithOps::new(File::open(&path)?)?;
+            self.files.insert(
+                self.current,
+                BlobFile {
+                    reader,
+                    size: 0,
+                    live: 0,
+                },
+            );
+            self.writer = Some(writer);
+        }
+
+        let writer = self.writer.as_mut().expect("Blob writer is open");
+        let offset = writer.offset;
+        write_command(writer, cmd, keyring)?;
+        writer.flush()?;
+        let blob = BlobRef {
+            file: self.current,
+            offset,
+            len: writer.offset - offset,
+        };
+
+        let file = self
+            .files
+            .get_mut(&self.current)
+            .expect("Blob file is open");
+        file.size = writer.offset;
+        file.live += blob.len;
+        Ok(blob)
+    }
+
+    /// Reads the value record a pointer refers to.
+    pub(crate) fn read(&mut self, blob: &BlobRef, keyring: &Keyring) -> Result<Command> {
+        let file = self
+            .files
+            .get_mut(&blob.file)
+            .ok_or_else(|| KvsError::Corruption(format!("blob file {} is missing", blob.file)))?;
+        read_command(&mut file.reader, blob.offset, blob.len, keyring)
+    }
+
+    /// Whether a blob file is still there to read from.
+    pub(crate) fn contains(&self, file: u64) -> bool {
+        self.files.contains_key(&file)
+    }
+
+    /// Stale bytes held by blob files that are no longer written to.
+    pub(crate) fn garbage(&self) -> u64 {
+        self.files
+            .iter()
+            .filter(|(&id, _)| !self.is_active(id))
+            .map(|(_, file)| file.size - file.live)
+            .sum()
+    }
+
+    /// Returns the blob files worth collecting.
+    ///
+    /// With `rekey`, every file that existed when the store was opened is
+    /// returned so that its values get sealed again with the current key.
+    pub(crate) fn gc_candidates(&self, rekey: bool) -> Vec<u64> {
+        self.files
+            .iter()
+            .filter(|(&id, _)| !self.is_active(id))
+            .filter(|(&id, file)| {
+                (rekey && id < self.first_session_file)
+                    || file.size - file.live >= (file.size as f64 * BLOB_GC_RATIO) as u64
+            })
+            .map(|(&id, _)| id)
+            .collect()
+    }
+
+    /// Deletes a blob file whose values have all been moved or released.
+    pub(crate) fn remove_file(&mut self, file: u64) -> Result<()> {
+        self.files.remove(&file);
+        fs::remove_file(blob_path(&self.path, file))?;
+        Ok(())
+    }
+
+    fn is_active(&self, file: u64) -> bool {
+        self.writer.is_some() && file == self.current
+    }
+
+    /// Closes the active blob file; the next append starts a new one.
+    fn roll(&mut self) {
+        if self.writer.take().is_some() {
+            self.current += 1;
+        }
+    }
+}
+
+/// Return path of the blob file
+fn blob_path(dir: &Path, file: u64) -> PathBuf {
+    dir.join(format!("{}.blob", file))
+}
@@ -0,0 +1,93 @@
+//! A size-bounded LRU cache of decoded values, in front of the log.
+
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+
+/// Hit and miss counters of a `KvStore` value cache.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Number of cached values.
+    pub entries: usize,
+    /// Bytes of keys and values currently cached.
+    pub size: usize,
+}
+
+impl CacheStats {
+    /// Returns the fraction of lookups served from the cache.
+    pub fn hit_ratio(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            0.0
+        } else {
+            self.hits as f64 / lookups as f64
+        }
+    }
+}
+
+pub(crate) struct ValueCache {
+    entries: LruCache<String, String>,
+    capacity: usize,
+    stats: CacheStats,
+}
+
+impl ValueCache {
+    /// Creates a cache holding at most `capacity` bytes of keys and values.
+    pub(crate) fn new(capacity: usize) -> Self {
+        ValueCache {
+            entries: LruCache::unbounded(),
+            capacity,
+            stats: CacheStats::default(),
+        }
+    }
+
+    pub(crate) fn get(&mut self, key: &str) -> Option<String> {
+        match self.entries.get(key) {
+            Some(value) => {
+                self.stats.hits += 1;
+                Some(value.clone())
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Caches a value read from the log, evicting the least recently used
+    /// ones to stay within capacity. Values larger than the cache are skipped.
+    pub(crate) fn insert(&mut self, key: String, value: String) {
+        let size = key.len() + value.len();
+        if size > self.capacity {
+            return;
+        }
+        self.invalidate(&key);
+        while self.stats.size + size > self.capacity {
+            match self.entries.pop_lru() {
+                Some((key, value)) => self.stats.size -= key.len() + value.len(),
+                None => break,
+            }
+        }
+        self.stats.size += size;
+        self.entries.put(key, value);
+    }
+
+    pub(crate) fn invalidate(&mut self, key: &str) {
+        if let Some(value) = self.entries.pop(key) {
+            self.stats.size -= key.len() + value.len();
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.stats.size = 0;
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.entries.len(),
+            ..self.stats
+        }
+    }
+}
@@ -0,0 +1,51 @@
+//! The change feed of a `KvStore`.
+//!
+//! Every `set` and `remove` is written to the log with a sequence number that
+//! grows by one for each change. The feed reads the changes still in the log,
+//! and the state file remembers the last sequence number and how far
+//! consumers have acknowledged the feed.
+
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+
+const STATE_FILE: &str = "changes";
+
+/// A change read back from the log.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Change {
+    pub seq: u64,
+    pub key: String,
+    /// The new value, or `None` if the key was removed.
+    pub value: Option<String>,
+}
+
+/// Sequence numbers that must survive compaction.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy)]
+pub(crate) struct ChangeState {
+    /// The last sequence number handed out when the state was saved.
+    pub(crate) last_seq: u64,
+    /// Changes up to this sequence number may be dropped by compaction.
+    pub(crate) acknowledged: u64,
+}
+
+impl ChangeState {
+    pub(crate) fn load(dir: &Path) -> Result<ChangeState> {
+        match File::open(dir.join(STATE_FILE)) {
+            Ok(file) => Ok(serde_json::from_reader(file)?),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(ChangeState::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub(crate) fn save(&self, dir: &Path) -> Result<()> {
+        let tmp_path = dir.join(format!("{}.tmp", STATE_FILE));
+        let mut file = File::create(&tmp_path)?;
+        serde_json::to_writer(&mut file, self)?;
+        file.sync_all()?;
+        fs::rename(tmp_path, dir.join(STATE_FILE))?;
+        Ok(())
+    }
+}
@@ -1,63 +1,362 @@
 use crate::common::{Request, Response};
-use crate::{Command, KvsError, Result};
-use serde::Deserialize;
-use serde_json;
-use serde_json::de::{Deserializer, IoRead};
-use std::io::{BufReader, BufWriter, Write};
+use crate::protocol::{self, Reader, RequestId, Writer};
+use crate::raft::ClusterStatus;
+use crate::replication::ReplicationStatus;
+use crate::watch::Event;
+use crate::{Command, CompactionReport, KvsError, Result, Stats};
+use std::io::{self, BufReader, BufWriter};
 use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+/// How many times a request is retried while a cluster has no leader or
+/// redirects it.
+const MAX_ATTEMPTS: usize = 20;
+const ELECTION_WAIT: Duration = Duration::from_millis(100);
+/// Requests a pipeline sends ahead of their responses. Bounding them keeps
+/// both sides from blocking on writes while the other does too.
+const PIPELINE_WINDOW: usize = 128;
+
+/// Timeouts of the connection of a `Client`. `None` waits forever.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Timeouts {
+    pub connect: Option<Duration>,
+    pub read: Option<Duration>,
+    pub write: Option<Duration>,
+}
 
 pub struct Client {
     addr: SocketAddr,
-    reader: Deserializer<IoRead<BufReader<TcpStream>>>,
-    writer: BufWriter<TcpStream>,
+    timeouts: Timeouts,
+    stream: TcpStream,
+    reader: Reader<BufReader<TcpStream>, Response>,
+    writer: Writer<BufWriter<TcpStream>>,
+    next_id: RequestId,
 }
 
 impl Client {
     pub fn connect(addr: SocketAddr) -> Result<Self> {
-        let stream = TcpStream::connect(&addr)?;
-        let writer = BufWriter::new(stream.try_clone()?);
-        let reader = Deserializer::from_reader(BufReader::new(stream));
+        Client::connect_with(addr, Timeouts::default())
+    }
+
+    /// Connects to `addr`, failing requests that wait longer than
+    /// `timeouts` on the connection with `KvsError::Io`.
+    pub fn connect_with(addr: SocketAddr, timeouts: Timeouts) -> Result<Self> {
+        let mut stream = match timeouts.connect {
+            Some(timeout) => TcpStream::connect_timeout(&addr, timeout)?,
+            None => TcpStream::connect(addr)?,
+        };
+        stream.set_read_timeout(timeouts.read)?;
+        stream.set_write_timeout(timeouts.write)?;
+        let wire = protocol::handshake(&mut stream)?;
+        let writer = Writer::new(BufWriter::new(stream.try_clone()?), wire);
+        let reader = Reader::new(BufReader::new(stream.try_clone()?), wire);
 
         Ok(Client {
             addr,
+            timeouts,
+            stream,
             reader,
             writer,
+            next_id: 0,
         })
     }
 
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
         let request = Request::Set { key, value };
 
-        serde_json::to_writer(&mut self.writer, &request)?;
-        self.writer.flush()?;
-        let resp = Response::deserialize(&mut self.reader)?;
+        let resp = self.call(&request)?;
         match resp {
             Response::Ok(_) => Ok(()),
-            Response::Err(e) => Err(KvsError::StringErr(e)),
+            Response::Err(e) => Err(e.into()),
+            _ => Err(KvsError::UnexpectedResponseType),
         }
     }
 
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
         let request = Request::Get { key };
-        serde_json::to_writer(&mut self.writer, &request)?;
-        self.writer.flush()?;
-
-        let response = Response::deserialize(&mut self.reader)?;
+        let response = self.call(&request)?;
         match response {
             Response::Ok(res) => Ok(res),
             Response::Err(e) => Err(e.into()),
+            _ => Err(KvsError::UnexpectedResponseType),
         }
     }
 
     pub fn remove(&mut self, key: String) -> Result<()> {
         let request = Request::Remove { key };
-        serde_json::to_writer(&mut self.writer, &request)?;
-        self.writer.flush()?;
+        let response = self.call(&request)?;
+        match response {
+            Response::Ok(_) => Ok(()),
+            Response::Err(e) => Err(e.into()),
+            _ => Err(KvsError::UnexpectedResponseType),
+        }
+    }
 
-        let response = Response::deserialize(&mut self.reader)?;
+    /// Returns the keys starting with `prefix` with their values, in key
+    /// order.
+    pub fn scan(&mut self, prefix: String) -> Result<Vec<(String, String)>> {
+        self.scan_request(Request::Scan {
+            prefix,
+            after: None,
+            limit: None,
+        })
+    }
+
+    /// Returns up to `limit` of the keys starting with `prefix` that sort
+    /// after `after`, with their values, in key order.
+    pub fn scan_page(
+        &mut self,
+        prefix: String,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        self.scan_request(Request::Scan {
+            prefix,
+            after,
+            limit: Some(limit),
+        })
+    }
+
+    fn scan_request(&mut self, request: Request) -> Result<Vec<(String, String)>> {
+        match self.call(&request)? {
+            Response::Entries(entries) => Ok(entries),
+            Response::Err(e) => Err(e.into()),
+            _ => Err(KvsError::UnexpectedResponseType),
+        }
+    }
+
+    pub fn stats(&mut self) -> Result<Stats> {
+        let response = self.send(&Request::Stats)?;
+        match response {
+            Response::Stats(stats) => Ok(stats),
+            Response::Err(e) => Err(e.into()),
+            _ => Err(KvsError::UnexpectedResponseType),
+        }
+    }
+
+    /// Asks the server to compact its store now.
+    pub fn compact(&mut self) -> Result<CompactionReport> {
+        let response = self.send(&Request::Compact)?;
+        match response {
+            Response::Compacted(report) => Ok(report),
+            Response::Err(e) => Err(e.into()),
+            _ => Err(KvsError::UnexpectedResponseType),
+        }
+    }
+
+    /// Lets the server's store drop the changes up to `seq` from its change
+    /// feed.
+    pub fn acknowledge_changes(&mut self, seq: u64) -> Result<()> {
+        let response = self.send(&Request::AcknowledgeChanges { seq })?;
         match response {
             Response::Ok(_) => Ok(()),
             Response::Err(e) => Err(e.into()),
+            _ => Err(KvsError::UnexpectedResponseType),
+        }
+    }
+
+    /// Returns the replication state of a follower.
+    pub fn replication_status(&mut self) -> Result<ReplicationStatus> {
+        let response = self.send(&Request::ReplicationStatus)?;
+        match response {
+            Response::Replication(status) => Ok(status),
+            Response::Err(e) => Err(e.into()),
+            _ => Err(KvsError::UnexpectedResponseType),
+        }
+    }
+
+    /// Returns the Raft state of a cluster node.
+    pub fn cluster_status(&mut self) -> Result<ClusterStatus> {
+        let response = self.send(&Request::ClusterStatus)?;
+        match response {
+            Response::Cluster(status) => Ok(status),
+            Response::Err(e) => Err(e.into()),
+            _ => Err(KvsError::UnexpectedResponseType),
+        }
+    }
+
+    /// Adds node `id`, reachable at `addr`, to the cluster.
+    pub fn add_member(&mut self, id: u64, addr: String) -> Result<()> {
+        match self.call(&Request::AddMember { id, addr })? {
+            Response::Ok(_) => Ok(()),
+            Response::Err(e) => Err(e.into()),
+            _ => Err(KvsError::UnexpectedResponseType),
+        }
+    }
+
+    /// Removes node `id` from the cluster.
+    pub fn remove_member(&mut self, id: u64) -> Result<()> {
+        match self.call(&Request::RemoveMember { id })? {
+            Response::Ok(_) => Ok(()),
+            Response::Err(e) => Err(e.into()),
+            _ => Err(KvsError::UnexpectedResponseType),
+        }
+    }
+
+    /// Sends a request and reads its response. A cluster follower redirects
+    /// the client to its leader, and a cluster without a leader is given
+    /// time to elect one.
+    pub(crate) fn call(&mut self, request: &Request) -> Result<Response> {
+        self.try_call(request)?.into_result()
+    }
+
+    /// Sends a request like `call`, telling apart the failures that leave it
+    /// unsent. Other failures may come after the server ran the request.
+    pub(crate) fn try_call(&mut self, request: &Request) -> Result<Sent> {
+        for _ in 0..MAX_ATTEMPTS {
+            let response = match self.try_send(request)? {
+                Sent::Response(response) => response,
+                unsent => return Ok(unsent),
+            };
+            match response {
+                Response::NotLeader(Some(leader)) => {
+                    let addr = leader
+                        .parse()
+                        .map_err(|_| KvsError::NotLeader(Some(leader)))?;
+                    if addr == self.addr {
+                        thread::sleep(ELECTION_WAIT);
+                    } else {
+                        *self = Client::connect_with(addr, self.timeouts)?;
+                    }
+                }
+                Response::NotLeader(None) => thread::sleep(ELECTION_WAIT),
+                response => return Ok(Sent::Response(response)),
+            }
+        }
+        Err(KvsError::NotLeader(None))
+    }
+
+    /// Returns whether the connection is still open and idle, without
+    /// waiting on the server.
+    pub fn is_alive(&self) -> bool {
+        if self.reader.has_buffered() || self.stream.set_nonblocking(true).is_err() {
+            return false;
+        }
+        // An idle connection has nothing to read until the server closes it.
+        let idle = matches!(
+            self.stream.peek(&mut [0]),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock
+        );
+        self.stream.set_nonblocking(false).is_ok() && idle
+    }
+
+    /// Sends every request before waiting for their responses, which are
+    /// returned in the order of the requests.
+    ///
+    /// Unlike the other methods, a pipeline does not follow the redirects of
+    /// a cluster follower: they are returned as `Response::NotLeader`.
+    pub fn pipeline(&mut self, requests: &[Request]) -> Result<Vec<Response>> {
+        let first = self.next_id;
+        let mut responses: Vec<Option<Response>> = requests.iter().map(|_| None).collect();
+        let (mut sent, mut received) = (0, 0);
+        while received < requests.len() {
+            if sent < requests.len() && sent - received < PIPELINE_WINDOW {
+                while sent < requests.len() && sent - received < PIPELINE_WINDOW {
+                    self.write(&requests[sent])?;
+                    sent += 1;
+                }
+                self.writer.flush()?;
+            }
+            let (id, response) = self.reader.receive()?;
+            let slot = id
+                .checked_sub(first)
+                .and_then(|i| responses.get_mut(i as usize))
+                .filter(|slot| slot.is_none())
+                .ok_or_else(|| {
+                    KvsError::Protocol(format!("unexpected response to request {}", id))
+                })?;
+            *slot = Some(response);
+            received += 1;
+        }
+        Ok(responses.into_iter().flatten().collect())
+    }
+
+    /// Sends a request to this server and reads its response.
+    fn send(&mut self, request: &Request) -> Result<Response> {
+        self.try_send(request)?.into_result()
+    }
+
+    /// Sends a request like `send`, telling apart the failures that leave it
+    /// unsent.
+    fn try_send(&mut self, request: &Request) -> Result<Sent> {
+        let id = match self.write(request) {
+            Ok(id) => id,
+            Err(e @ KvsError::Io(_)) => return Ok(Sent::Unsent(e)),
+            Err(e) => return Err(e),
+        };
+        if let Err(e) = self.writer.flush() {
+            return Ok(Sent::Unsent(e));
+        }
+        match self.reader.next().transpose()? {
+            Some((response_id, response)) if response_id == id => Ok(Sent::Response(response)),
+            Some((response_id, _)) => Err(KvsError::Protocol(format!(
+                "response to request {} while waiting for {}",
+                response_id, id
+            ))),
+            None => Ok(Sent::Unsent(
+                io::Error::from(io::ErrorKind::UnexpectedEof).into(),
+            )),
+        }
+    }
+
+    /// Buffers a request, returning its id. Sessions without ids number
+    /// requests the same way the server does.
+    fn write(&mut self, request: &Request) -> Result<RequestId> {
+        let id = self.next_id;
+        self.writer.write(id, request)?;
+        self.next_id += 1;
+        Ok(id)
+    }
+
+    /// Watches the changes of keys starting with `prefix`, after the event
+    /// numbered `since` if given.
+    ///
+    /// The connection is dedicated to the watch from then on.
+    pub fn watch(mut self, prefix: String, since: Option<u64>) -> Result<Watch> {
+        let response = self.send(&Request::Watch { prefix, since })?;
+        match response {
+            Response::Ok(_) => Ok(Watch {
+                reader: self.reader,
+            }),
+            Response::Err(e) => Err(e.into()),
+            _ => Err(KvsError::UnexpectedResponseType),
+        }
+    }
+}
+
+/// The events of a watch, in the order the server applied them.
+pub struct Watch {
+    reader: Reader<BufReader<TcpStream>, Response>,
+}
+
+impl Iterator for Watch {
+    type Item = Result<Event>;
+
+    fn next(&mut self) -> Option<Result<Event>> {
+        match self.reader.next()?.map(|(_, response)| response) {
+            Ok(Response::Event(event)) => Some(Ok(event)),
+            Ok(Response::Err(e)) => Some(Err(e.into())),
+            Ok(_) => Some(Err(KvsError::UnexpectedResponseType)),
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// How a request went, for the callers that may send it again.
+pub(crate) enum Sent {
+    Response(Response),
+    /// The request most likely did not reach the server: the connection
+    /// failed while it was written, or was closed before any response, as
+    /// when the server closed an idle connection.
+    Unsent(KvsError),
+}
+
+impl Sent {
+    fn into_result(self) -> Result<Response> {
+        match self {
+            Sent::Response(response) => Ok(response),
+            Sent::Unsent(e) => Err(e),
         }
     }
 }
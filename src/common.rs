@@ -1,15 +1,258 @@
-use crate::KvsError;
+use crate::raft::{ClusterStatus, Message};
+use crate::replication::ReplicationStatus;
+use crate::watch::Event;
+use crate::{CompactionReport, KvsError, Stats};
 use serde::{Deserialize, Serialize};
+use std::io;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Request {
-    Set { key: String, value: String },
-    Get { key: String },
-    Remove { key: String },
+    Set {
+        key: String,
+        value: String,
+    },
+    Get {
+        key: String,
+    },
+    Remove {
+        key: String,
+    },
+    /// Lists the keys starting with `prefix` with their values.
+    ///
+    /// With `limit`, the list holds at most that many keys, from the first
+    /// key after `after` on.
+    Scan {
+        prefix: String,
+        #[serde(default)]
+        after: Option<String>,
+        #[serde(default)]
+        limit: Option<usize>,
+    },
+    Stats,
+    Compact,
+    /// Lets compaction drop the changes up to `seq` from the change feed of
+    /// a store that retains them, once every consumer has processed them.
+    AcknowledgeChanges {
+        seq: u64,
+    },
+    /// Turns the connection into a stream of `Response::Event`, after a first
+    /// `Response::Ok(None)`.
+    Watch {
+        prefix: String,
+        since: Option<u64>,
+    },
+    /// Turns the connection into a replication stream to a follower.
+    ///
+    /// A follower that applied the leader's changes up to `since` resumes
+    /// from there, unless the leader no longer has every change after it.
+    Replicate {
+        #[serde(default)]
+        since: Option<u64>,
+    },
+    ReplicationStatus,
+    /// A message between the nodes of a cluster, answered by none.
+    Raft {
+        from: u64,
+        addr: String,
+        message: Message,
+    },
+    ClusterStatus,
+    AddMember {
+        id: u64,
+        addr: String,
+    },
+    RemoveMember {
+        id: u64,
+    },
+}
+
+impl Request {
+    /// Returns the name of the request type, used to label metrics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Request::Set { .. } => "set",
+            Request::Get { .. } => "get",
+            Request::Remove { .. } => "remove",
+            Request::Scan { .. } => "scan",
+            Request::Stats => "stats",
+            Request::Compact => "compact",
+            Request::AcknowledgeChanges { .. } => "acknowledge_changes",
+            Request::Watch { .. } => "watch",
+            Request::Replicate { .. } => "replicate",
+            Request::ReplicationStatus => "replication_status",
+            Request::Raft { .. } => "raft",
+            Request::ClusterStatus => "cluster_status",
+            Request::AddMember { .. } => "add_member",
+            Request::RemoveMember { .. } => "remove_member",
+        }
+    }
+
+    /// Returns whether the request is meant for operators rather than
+    /// clients of the store.
+    pub fn is_admin(&self) -> bool {
+        matches!(self, Request::Compact | Request::AcknowledgeChanges { .. })
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Response {
     Ok(Option<String>),
-    Err(String),
+    Err(ErrorCode),
+    Stats(Stats),
+    Entries(Vec<(String, String)>),
+    Compacted(CompactionReport),
+    Event(Event),
+    SnapshotChunk(Vec<(String, String)>),
+    /// Ends a snapshot taken when the leader was at sequence number `seq`.
+    SnapshotDone {
+        seq: u64,
+    },
+    Heartbeat {
+        seq: u64,
+    },
+    Replication(ReplicationStatus),
+    Cluster(ClusterStatus),
+    /// Sent by a cluster follower, with the address of the leader to retry
+    /// with if one is known.
+    NotLeader(Option<String>),
+}
+
+/// Why a request failed, as sent to the client.
+///
+/// Each code converts to and from the `KvsError` variant of the same name, so
+/// that clients tell failures apart as the server did. `StringErr` travels as
+/// `Other`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ErrorCode {
+    KeyNotFound,
+    /// Carries the name of the `io::ErrorKind`, such as `"NotFound"`.
+    Io {
+        kind: String,
+        message: String,
+    },
+    Serde(String),
+    Sled(String),
+    Utf8Error(usize),
+    UnexpectedCommandType,
+    UnexpectedResponseType,
+    Corruption(String),
+    Decompression(String),
+    KeyRequired,
+    Decryption,
+    InvalidKey(String),
+    WrongEngine(String),
+    /// Carries the address of the leader of the replica.
+    ReadOnly(String),
+    Unauthorized(String),
+    Busy(String),
+    NotLeader(Option<String>),
+    MembershipChangePending,
+    Timeout,
+    Unavailable(String),
+    Unsupported(String),
+    HistoryUnavailable(u64),
+    Protocol(String),
+    MalformedMessage(String),
+    Other(String),
+}
+
+/// The kinds of I/O errors a code names. Others arrive as `Other`.
+const IO_ERROR_KINDS: [io::ErrorKind; 20] = [
+    io::ErrorKind::NotFound,
+    io::ErrorKind::PermissionDenied,
+    io::ErrorKind::ConnectionRefused,
+    io::ErrorKind::ConnectionReset,
+    io::ErrorKind::ConnectionAborted,
+    io::ErrorKind::NotConnected,
+    io::ErrorKind::AddrInUse,
+    io::ErrorKind::AddrNotAvailable,
+    io::ErrorKind::BrokenPipe,
+    io::ErrorKind::AlreadyExists,
+    io::ErrorKind::WouldBlock,
+    io::ErrorKind::InvalidInput,
+    io::ErrorKind::InvalidData,
+    io::ErrorKind::TimedOut,
+    io::ErrorKind::WriteZero,
+    io::ErrorKind::Interrupted,
+    io::ErrorKind::Unsupported,
+    io::ErrorKind::UnexpectedEof,
+    io::ErrorKind::OutOfMemory,
+    io::ErrorKind::Other,
+];
+
+impl From<KvsError> for ErrorCode {
+    fn from(e: KvsError) -> Self {
+        match e {
+            KvsError::KeyNotFound => ErrorCode::KeyNotFound,
+            KvsError::Io(e) => ErrorCode::Io {
+                kind: format!("{:?}", e.kind()),
+                message: e.to_string(),
+            },
+            KvsError::Serde(e) => ErrorCode::Serde(e.to_string()),
+            // Sled errors come back as `Unsupported`, so keep only the
+            // message of one.
+            KvsError::Sled(sled::Error::Unsupported(message)) => ErrorCode::Sled(message),
+            KvsError::Sled(e) => ErrorCode::Sled(e.to_string()),
+            KvsError::Utf8Error(index) => ErrorCode::Utf8Error(index),
+            KvsError::UnexpectedCommandType => ErrorCode::UnexpectedCommandType,
+            KvsError::UnexpectedResponseType => ErrorCode::UnexpectedResponseType,
+            KvsError::Corruption(message) => ErrorCode::Corruption(message),
+            KvsError::Decompression(message) => ErrorCode::Decompression(message),
+            KvsError::KeyRequired => ErrorCode::KeyRequired,
+            KvsError::Decryption => ErrorCode::Decryption,
+            KvsError::InvalidKey(message) => ErrorCode::InvalidKey(message),
+            KvsError::WrongEngine(engine) => ErrorCode::WrongEngine(engine),
+            KvsError::ReadOnly(leader) => ErrorCode::ReadOnly(leader),
+            KvsError::Unauthorized(message) => ErrorCode::Unauthorized(message),
+            KvsError::Busy(message) => ErrorCode::Busy(message),
+            KvsError::NotLeader(leader) => ErrorCode::NotLeader(leader),
+            KvsError::MembershipChangePending => ErrorCode::MembershipChangePending,
+            KvsError::Timeout => ErrorCode::Timeout,
+            KvsError::Unavailable(backend) => ErrorCode::Unavailable(backend),
+            KvsError::Unsupported(operation) => ErrorCode::Unsupported(operation),
+            KvsError::HistoryUnavailable(seq) => ErrorCode::HistoryUnavailable(seq),
+            KvsError::Protocol(message) => ErrorCode::Protocol(message),
+            KvsError::MalformedMessage(message) => ErrorCode::MalformedMessage(message),
+            KvsError::StringErr(message) => ErrorCode::Other(message),
+        }
+    }
+}
+
+impl From<ErrorCode> for KvsError {
+    fn from(code: ErrorCode) -> Self {
+        match code {
+            ErrorCode::KeyNotFound => KvsError::KeyNotFound,
+            ErrorCode::Io { kind, message } => {
+                let kind = IO_ERROR_KINDS
+                    .iter()
+                    .copied()
+                    .find(|known| format!("{:?}", known) == kind)
+                    .unwrap_or(io::ErrorKind::Other);
+                KvsError::Io(io::Error::new(kind, message))
+            }
+            ErrorCode::Serde(message) => KvsError::Serde(serde::de::Error::custom(message)),
+            ErrorCode::Sled(message) => KvsError::Sled(sled::Error::Unsupported(message)),
+            ErrorCode::Utf8Error(index) => KvsError::Utf8Error(index),
+            ErrorCode::UnexpectedCommandType => KvsError::UnexpectedCommandType,
+            ErrorCode::UnexpectedResponseType => KvsError::UnexpectedResponseType,
+            ErrorCode::Corruption(message) => KvsError::Corruption(message),
+            ErrorCode::Decompression(message) => KvsError::Decompression(message),
+            ErrorCode::KeyRequired => KvsError::KeyRequired,
+            ErrorCode::Decryption => KvsError::Decryption,
+            ErrorCode::InvalidKey(message) => KvsError::InvalidKey(message),
+            ErrorCode::WrongEngine(engine) => KvsError::WrongEngine(engine),
+            ErrorCode::ReadOnly(leader) => KvsError::ReadOnly(leader),
+            ErrorCode::Unauthorized(message) => KvsError::Unauthorized(message),
+            ErrorCode::Busy(message) => KvsError::Busy(message),
+            ErrorCode::NotLeader(leader) => KvsError::NotLeader(leader),
+            ErrorCode::MembershipChangePending => KvsError::MembershipChangePending,
+            ErrorCode::Timeout => KvsError::Timeout,
+            ErrorCode::Unavailable(backend) => KvsError::Unavailable(backend),
+            ErrorCode::Unsupported(operation) => KvsError::Unsupported(operation),
+            ErrorCode::HistoryUnavailable(seq) => KvsError::HistoryUnavailable(seq),
+            ErrorCode::Protocol(message) => KvsError::Protocol(message),
+            ErrorCode::MalformedMessage(message) => KvsError::MalformedMessage(message),
+            ErrorCode::Other(message) => KvsError::StringErr(message),
+        }
+    }
 }
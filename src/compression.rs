@@ -0,0 +1,53 @@
+//! Transparent LZ4 compression of large values in the log.
+
+use crate::{KvsError, Result};
+use serde::{Deserialize, Serialize};
+
+/// Compresses a value into the textual form stored in `Command::SetCompressed`.
+///
+/// The uncompressed size is prepended to the LZ4 block, and the result is
+/// base64 encoded so that it can live inside a JSON record.
+pub fn compress(value: &str) -> String {
+    base64::encode(lz4_flex::compress_prepend_size(value.as_bytes()))
+}
+
+/// Restores a value produced by `compress`.
+///
+/// # Errors
+///
+/// It returns `KvsError::Decompression` if the data is not valid base64, not a
+/// valid LZ4 block, or does not decode to UTF-8.
+pub fn decompress(data: &str) -> Result<String> {
+    let block = base64::decode(data).map_err(|e| KvsError::Decompression(e.to_string()))?;
+    let raw = lz4_flex::decompress_size_prepended(&block)
+        .map_err(|e| KvsError::Decompression(e.to_string()))?;
+    String::from_utf8(raw).map_err(|e| KvsError::Utf8Error(e.utf8_error().valid_up_to()))
+}
+
+/// Counters about values compressed by a `KvStore` since it was opened.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct CompressionStats {
+    /// Number of values written in compressed form.
+    pub compressed_values: u64,
+    /// Total size of those values before compression.
+    pub raw_bytes: u64,
+    /// Total size of those values as stored in the log.
+    pub compressed_bytes: u64,
+}
+
+impl CompressionStats {
+    pub(crate) fn record(&mut self, raw: usize, compressed: usize) {
+        self.compressed_values += 1;
+        self.raw_bytes += raw as u64;
+        self.compressed_bytes += compressed as u64;
+    }
+
+    /// Returns `raw_bytes / compressed_bytes`, or `1.0` if nothing was compressed.
+    pub fn ratio(&self) -> f64 {
+        if self.compressed_bytes == 0 {
+            1.0
+        } else {
+            self.raw_bytes as f64 / self.compressed_bytes as f64
+        }
+    }
+}
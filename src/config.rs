@@ -1,3 +1,5 @@
+use crate::raft::RaftConfig;
+use crate::KvStoreOptions;
 use slog::Logger;
 use std::net::SocketAddr;
 use std::path::PathBuf;
@@ -7,7 +9,8 @@ arg_enum! {
     #[derive(Debug, Copy, Clone, PartialEq, Eq)]
     pub enum Engine {
         kvs,
-        sled
+        sled,
+        lsm
     }
 }
 
@@ -16,6 +19,20 @@ pub struct Config {
     pub path: PathBuf,
     pub engine: Engine,
     pub log: Logger,
+    pub store: KvStoreOptions,
+    /// Serves Prometheus metrics over HTTP on this address, if set.
+    pub metrics_addr: Option<SocketAddr>,
+    /// Serves the Redis RESP2 protocol on this address, if set.
+    pub resp_addr: Option<SocketAddr>,
+    /// Serves a REST API over HTTP on this address, if set.
+    pub http_addr: Option<SocketAddr>,
+    /// Runs as a read-only follower of the leader at this address, if set.
+    pub replica_of: Option<SocketAddr>,
+    /// Runs as a node of a Raft cluster, if set. The Raft log is kept in
+    /// `path` unless the cluster config names another directory.
+    pub cluster: Option<RaftConfig>,
+    /// Answers admin requests, such as compaction. On by default.
+    pub admin_requests: bool,
 }
 
 impl Config {
@@ -25,6 +42,13 @@ impl Config {
             path,
             engine,
             log,
+            store: KvStoreOptions::default(),
+            metrics_addr: None,
+            resp_addr: None,
+            http_addr: None,
+            replica_of: None,
+            cluster: None,
+            admin_requests: true,
         }
     }
 
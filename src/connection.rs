@@ -1,80 +1,356 @@
-use crate::common::{Request, Response};
-use crate::{Command, KvStore, KvsError, Result};
-use serde_json;
+use crate::common::{ErrorCode, Request, Response};
+use crate::expiry::Deadlines;
+use crate::metrics::Metrics;
+use crate::protocol::{self, Reader, Writer};
+use crate::raft::Raft;
+use crate::replication::{self, Replica};
+use crate::watch::Watchers;
+use crate::{Command, KvsEngine, KvsError, Lookup, Result};
 use slog::Logger;
-use std::io::{BufReader, BufWriter, Write};
+use std::io::{BufReader, BufWriter};
 use std::net::TcpStream;
 use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+
+/// Answers the requests that take a single response, on behalf of every
+/// connection of a server.
+#[derive(Clone)]
+pub(crate) struct Handler {
+    pub(crate) db: Arc<Mutex<Box<dyn KvsEngine + Send>>>,
+    pub(crate) metrics: Arc<Metrics>,
+    pub(crate) watchers: Arc<Watchers>,
+    /// When keys set to expire do. Every write of a key clears its deadline.
+    pub(crate) deadlines: Arc<Deadlines>,
+    /// Set on a follower, which rejects writes.
+    pub(crate) replica: Option<Arc<Replica>>,
+    /// Set on a cluster node, which goes through the Raft log.
+    pub(crate) raft: Option<Arc<Raft>>,
+    /// Whether admin requests are answered, as configured.
+    pub(crate) admin: bool,
+}
+
+impl Handler {
+    /// Answers a request, recording how long it took. Watches, replication
+    /// streams and Raft messages are left to the connection.
+    pub(crate) fn respond(&self, req: Request) -> Response {
+        let start = Instant::now();
+        let name = req.name();
+        let resp = self.dispatch(req);
+        self.metrics.observe(name, start.elapsed());
+        resp
+    }
+
+    /// Answers a request like `respond`, turning error responses into errors.
+    pub(crate) fn call(&self, req: Request) -> Result<Response> {
+        match self.respond(req) {
+            Response::Err(e) => Err(e.into()),
+            Response::NotLeader(leader) => Err(KvsError::NotLeader(leader)),
+            resp => Ok(resp),
+        }
+    }
+
+    /// Sets a key that expires at `deadline`, recording it like a `Set`.
+    pub(crate) fn set_expiring(
+        &self,
+        key: String,
+        value: String,
+        deadline: SystemTime,
+    ) -> Result<()> {
+        let start = Instant::now();
+        let result = match &self.replica {
+            Some(replica) => Err(KvsError::ReadOnly(replica.leader().to_string())),
+            None => self.set(key, value, Some(deadline)),
+        };
+        self.metrics.observe("set", start.elapsed());
+        result
+    }
+
+    /// Removes `key` if it is past its deadline.
+    pub(crate) fn expire(&self, key: &str) -> Result<()> {
+        // A follower's keys go away once its leader removes them. Most keys
+        // have no deadline, and are let through without locking the store.
+        if self.replica.is_some() || !self.deadlines.is_past(key) {
+            return Ok(());
+        }
+        match &self.raft {
+            Some(raft) => match raft.remove(key.to_owned()) {
+                Ok(_) | Err(KvsError::KeyNotFound) => self.deadlines.set(key, None),
+                Err(e) => Err(e),
+            },
+            None => {
+                // The store stays locked so that no write slips in between.
+                let mut lock = self.db.lock().unwrap();
+                if !self.deadlines.is_past(key) {
+                    return Ok(());
+                }
+                match lock.remove(key.to_owned()) {
+                    Ok(_) => self.watchers.publish(&mut **lock, key.to_owned(), None)?,
+                    Err(KvsError::KeyNotFound) => {}
+                    Err(e) => return Err(e),
+                }
+                self.deadlines.set(key, None)
+            }
+        }
+    }
+
+    /// Removes every key starting with `prefix` that is past its deadline.
+    pub(crate) fn expire_all(&self, prefix: &str) -> Result<()> {
+        for key in self.deadlines.past() {
+            if key.starts_with(prefix) {
+                self.expire(&key)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn set(&self, key: String, value: String, deadline: Option<SystemTime>) -> Result<()> {
+        match &self.raft {
+            Some(raft) => {
+                raft.set(key.clone(), value)?;
+                self.deadlines.set(&key, deadline)
+            }
+            None => {
+                let mut lock = self.db.lock().unwrap();
+                lock.set(key.clone(), value.clone())?;
+                self.deadlines.set(&key, deadline)?;
+                self.watchers.publish(&mut **lock, key, Some(value))
+            }
+        }
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        match &self.raft {
+            Some(raft) => {
+                raft.remove(key.clone())?;
+                self.deadlines.set(&key, None)
+            }
+            None => {
+                let mut lock = self.db.lock().unwrap();
+                lock.remove(key.clone())?;
+                self.deadlines.set(&key, None)?;
+                self.watchers.publish(&mut **lock, key, None)
+            }
+        }
+    }
+
+    fn dispatch(&self, req: Request) -> Response {
+        if req.is_admin() && !self.admin {
+            return Response::Err(ErrorCode::Unauthorized(format!(
+                "{} is disabled on this server",
+                req.name()
+            )));
+        }
+        // Followers only take writes from their leader. Compaction does not
+        // change what the store holds, so each server compacts on its own.
+        if let (Request::Set { .. } | Request::Remove { .. }, Some(replica)) = (&req, &self.replica)
+        {
+            return Response::Err(ErrorCode::ReadOnly(replica.leader().to_string()));
+        }
+        match req {
+            Request::Set { key, value } => match self.set(key, value, None) {
+                Ok(_) => Response::Ok(None),
+                Err(e) => error_response(e),
+            },
+            Request::Get { key } => {
+                // Keys past their deadline are gone, whether or not the
+                // sweep has removed them yet.
+                if let Err(e) = self.expire(&key) {
+                    return error_response(e);
+                }
+                let result = match &self.raft {
+                    Some(raft) => raft.get(key),
+                    None => {
+                        // The value is read once the store is unlocked.
+                        let lookup = self.db.lock().unwrap().lookup(key);
+                        lookup.and_then(Lookup::into_value)
+                    }
+                };
+                match result {
+                    Ok(value) => Response::Ok(value),
+                    Err(e) => error_response(e),
+                }
+            }
+            Request::Remove { key } => match self.expire(&key).and_then(|_| self.remove(key)) {
+                Ok(_) => Response::Ok(None),
+                Err(e) => error_response(e),
+            },
+            Request::Scan {
+                prefix,
+                after,
+                limit,
+            } => {
+                if let Err(e) = self.expire_all(&prefix) {
+                    return error_response(e);
+                }
+                let (after, limit) = (after.as_deref(), limit.unwrap_or(usize::MAX));
+                let result = match &self.raft {
+                    Some(raft) => raft.scan_page(&prefix, after, limit),
+                    None => self.db.lock().unwrap().scan_page(&prefix, after, limit),
+                };
+                match result {
+                    Ok(entries) => Response::Entries(entries),
+                    Err(e) => error_response(e),
+                }
+            }
+            Request::Stats => match self.db.lock().unwrap().stats() {
+                Ok(stats) => Response::Stats(stats),
+                Err(e) => Response::Err(e.into()),
+            },
+            Request::ReplicationStatus => match &self.replica {
+                Some(replica) => Response::Replication(replica.status()),
+                None => Response::Err(ErrorCode::Other("Not a replica".to_owned())),
+            },
+            Request::ClusterStatus => match &self.raft {
+                Some(raft) => Response::Cluster(raft.status()),
+                None => Response::Err(ErrorCode::Other(NOT_CLUSTERED.to_owned())),
+            },
+            Request::AddMember { id, addr } => {
+                let result = match &self.raft {
+                    Some(raft) => raft.add_member(id, addr),
+                    None => Err(KvsError::StringErr(NOT_CLUSTERED.to_owned())),
+                };
+                match result {
+                    Ok(_) => Response::Ok(None),
+                    Err(e) => error_response(e),
+                }
+            }
+            Request::RemoveMember { id } => {
+                let result = match &self.raft {
+                    Some(raft) => raft.remove_member(id),
+                    None => Err(KvsError::StringErr(NOT_CLUSTERED.to_owned())),
+                };
+                match result {
+                    Ok(_) => Response::Ok(None),
+                    Err(e) => error_response(e),
+                }
+            }
+            Request::Compact => match self.db.lock().unwrap().compact() {
+                Ok(report) => Response::Compacted(report),
+                Err(e) => Response::Err(e.into()),
+            },
+            Request::AcknowledgeChanges { seq } => {
+                match self.db.lock().unwrap().acknowledge_changes(seq) {
+                    Ok(()) => Response::Ok(None),
+                    Err(e) => Response::Err(e.into()),
+                }
+            }
+            other @ (Request::Watch { .. } | Request::Replicate { .. } | Request::Raft { .. }) => {
+                Response::Err(ErrorCode::Other(format!(
+                    "Request {} does not take a single response",
+                    other.name()
+                )))
+            }
+        }
+    }
+}
 
 pub struct Connection {
-    db: Arc<Mutex<KvStore>>,
+    handler: Handler,
     stream: TcpStream,
     log: Logger,
 }
 
 impl Connection {
-    pub fn new(stream: TcpStream, db: Arc<Mutex<KvStore>>, log: Logger) -> Self {
+    pub(crate) fn new(stream: TcpStream, handler: Handler, log: Logger) -> Self {
         let log = log.new(o!("peer-address"=>stream.peer_addr().unwrap()));
-        Connection { db, stream, log }
+        Connection {
+            handler,
+            stream,
+            log,
+        }
     }
 
     pub fn run(&mut self) {
+        self.handler.metrics.connection_opened();
         match self.serve() {
             Ok(_) => {}
             Err(e) => {
                 error!(self.log, "Error on serving client: {}", e);
             }
         }
+        self.handler.metrics.connection_closed();
     }
 
     pub fn serve(&mut self) -> Result<()> {
-        let reader = BufReader::new(&self.stream);
-        let mut writer = BufWriter::new(&self.stream);
-        let req_reader = serde_json::Deserializer::from_reader(reader).into_iter::<Request>();
+        let metrics = &self.handler.metrics;
+        let mut reader = BufReader::new(metrics.count_reads(&self.stream));
+        let mut writer = BufWriter::new(metrics.count_writes(&self.stream));
+        let wire = protocol::accept(&mut reader, &mut writer)?;
+        debug!(self.log, "Speaking {:?}", wire);
+        let mut req_reader = Reader::<_, Request>::new(reader, wire);
+        let mut writer = Writer::new(writer, wire);
 
         macro_rules! send_resp {
-            ($resp:expr, $writer:expr) => {{
+            ($resp:expr, $writer:expr, $id:expr) => {{
                 let resp = $resp;
-                serde_json::to_writer(&mut $writer, &resp)?;
-                $writer.flush()?;
+                $writer.write($id, &resp)?;
                 debug!(self.log, "Sent response: {:?}", resp);
             }};
         }
 
-        for req in req_reader {
-            let req = req?;
+        loop {
+            // Responses to pipelined requests go out together, once every
+            // request received so far is answered.
+            if !req_reader.has_buffered() {
+                writer.flush()?;
+            }
+            let (req_id, req) = match req_reader.next() {
+                Some(Ok(req)) => req,
+                Some(Err(e @ KvsError::MalformedMessage(_))) => {
+                    warn!(self.log, "{}", e);
+                    send_resp!(Response::Err(e.into()), writer, req_reader.last_id());
+                    continue;
+                }
+                Some(Err(e)) => return Err(e),
+                None => break,
+            };
             debug!(self.log, "Receive request: {:?}", req);
             match req {
-                Request::Set { key, value } => {
-                    let mut lock = self.db.lock().unwrap();
-                    send_resp!(
-                        match lock.set(key, value) {
-                            Ok(_) => Response::Ok(None),
-                            Err(e) => Response::Err(format!("{}", e)),
-                        },
-                        writer
-                    );
+                Request::Watch { prefix, since } => {
+                    let subscription = {
+                        let mut db = self.handler.db.lock().unwrap();
+                        self.handler.watchers.subscribe(&mut **db, prefix, since)
+                    };
+                    let (backlog, events) = match subscription {
+                        Ok(subscription) => subscription,
+                        Err(e) => {
+                            send_resp!(Response::Err(e.into()), writer, req_id);
+                            continue;
+                        }
+                    };
+                    send_resp!(Response::Ok(None), writer, req_id);
+                    writer.flush()?;
+                    for event in backlog.into_iter().chain(events) {
+                        // The watcher going away ends the stream.
+                        if writer.send(req_id, &Response::Event(event)).is_err() {
+                            break;
+                        }
+                    }
+                    return Ok(());
                 }
-                Request::Get { key } => {
-                    let mut lock = self.db.lock().unwrap();
-                    send_resp!(
-                        match lock.get(key) {
-                            Ok(value) => Response::Ok(value),
-                            Err(e) => Response::Err(format!("{}", e)),
-                        },
-                        writer
-                    );
-                }
-                Request::Remove { key } => {
-                    let mut lock = self.db.lock().unwrap();
-                    send_resp!(
-                        match lock.remove(key) {
-                            Ok(_) => Response::Ok(None),
-                            Err(e) => Response::Err(format!("{}", e)),
-                        },
-                        writer
-                    );
+                Request::Replicate { since } => {
+                    info!(self.log, "Follower connected");
+                    let handler = &self.handler;
+                    if let Err(e) = replication::serve_follower(
+                        &handler.db,
+                        &handler.watchers,
+                        since,
+                        req_id,
+                        &mut writer,
+                    ) {
+                        info!(self.log, "Follower disconnected: {}", e);
+                    }
+                    return Ok(());
                 }
+                Request::Raft {
+                    from,
+                    addr,
+                    message,
+                } => match &self.handler.raft {
+                    Some(raft) => raft.receive(from, addr, message),
+                    None => warn!(self.log, "Ignoring a Raft message outside a cluster"),
+                },
+                req => send_resp!(self.handler.respond(req), writer, req_id),
             }
         }
 
@@ -82,6 +358,17 @@ impl Connection {
     }
 }
 
+const NOT_CLUSTERED: &str = "Not a cluster node";
+
+/// Turns an error into a response, redirecting clients of a cluster follower
+/// to the leader.
+fn error_response(e: KvsError) -> Response {
+    match e {
+        KvsError::NotLeader(leader) => Response::NotLeader(leader),
+        e => Response::Err(e.into()),
+    }
+}
+
 mod tests {
     #[test]
     pub fn test_connection() {
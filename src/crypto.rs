@@ -0,0 +1,189 @@
+//! Encryption at rest of log records with ChaCha20-Poly1305.
+
+use crate::{Command, KvsError, Result};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use std::env;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::Path;
+
+/// Name of the environment variable `EncryptionKey::from_env` reads by default.
+pub const KEY_ENV_VAR: &str = "KVS_ENCRYPTION_KEY";
+
+/// The file of an encrypted store holding its key verifier: a known record
+/// sealed with the key of the store.
+const VERIFIER_FILE: &str = "key-check";
+const VERIFIER_KEY: &str = "kvs-key-check";
+
+/// A 256-bit key used to seal log records.
+#[derive(Clone)]
+pub struct EncryptionKey([u8; 32]);
+
+impl EncryptionKey {
+    /// Creates a key from raw bytes.
+    pub fn new(bytes: [u8; 32]) -> Self {
+        EncryptionKey(bytes)
+    }
+
+    /// Parses a key written as 64 hexadecimal characters.
+    pub fn from_hex(hex_key: &str) -> Result<Self> {
+        let mut bytes = [0u8; 32];
+        hex::decode_to_slice(hex_key.trim(), &mut bytes)
+            .map_err(|e| KvsError::InvalidKey(e.to_string()))?;
+        Ok(EncryptionKey(bytes))
+    }
+
+    /// Reads a key file holding either 32 raw bytes or 64 hexadecimal characters.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let content = fs::read(path)?;
+        if content.len() == 32 {
+            let mut bytes = [0u8; 32];
+            bytes.copy_from_slice(&content);
+            return Ok(EncryptionKey(bytes));
+        }
+        match String::from_utf8(content) {
+            Ok(hex_key) => EncryptionKey::from_hex(&hex_key),
+            Err(_) => Err(KvsError::InvalidKey(
+                "key file must hold 32 bytes or 64 hex characters".to_owned(),
+            )),
+        }
+    }
+
+    /// Reads a hexadecimal key from the given environment variable.
+    ///
+    /// Returns `None` if the variable is not set.
+    pub fn from_env(var: &str) -> Result<Option<Self>> {
+        match env::var(var) {
+            Ok(hex_key) => EncryptionKey::from_hex(&hex_key).map(Some),
+            Err(env::VarError::NotPresent) => Ok(None),
+            Err(e) => Err(KvsError::InvalidKey(e.to_string())),
+        }
+    }
+
+    fn cipher(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new((&self.0).into())
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+/// The keys a `KvStore` seals new records with and opens existing ones with.
+pub(crate) struct Keyring {
+    current: Option<ChaCha20Poly1305>,
+    previous: Vec<ChaCha20Poly1305>,
+}
+
+impl Keyring {
+    pub(crate) fn new(current: Option<&EncryptionKey>, previous: &[EncryptionKey]) -> Self {
+        Keyring {
+            current: current.map(EncryptionKey::cipher),
+            previous: previous.iter().map(EncryptionKey::cipher).collect(),
+        }
+    }
+
+    /// Returns true if records must be rewritten rather than copied during compaction.
+    pub(crate) fn is_active(&self) -> bool {
+        self.current.is_some() || !self.previous.is_empty()
+    }
+
+    /// Wraps a command into a `Command::Sealed` record.
+    ///
+    /// Returns `None` when no current key is configured and the command should
+    /// be written as is.
+    pub(crate) fn seal(&self, cmd: &Command) -> Result<Option<Command>> {
+        let cipher = match &self.current {
+            Some(cipher) => cipher,
+            None => return Ok(None),
+        };
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let plaintext = serde_json::to_vec(cmd)?;
+        let data = cipher
+            .encrypt(&nonce, plaintext.as_ref())
+            .map_err(|_| KvsError::Decryption)?;
+        Ok(Some(Command::Sealed(
+            base64::encode(nonce),
+            base64::encode(data),
+        )))
+    }
+
+    /// Unwraps a `Command::Sealed` record with the current or a previous key.
+    /// Plain records are returned unchanged.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyRequired` if the record is sealed and no key is
+    /// configured, and `KvsError::Decryption` if no configured key can
+    /// authenticate it.
+    pub(crate) fn open(&self, cmd: Command) -> Result<Command> {
+        let (nonce, data) = match cmd {
+            Command::Sealed(nonce, data) => (nonce, data),
+            cmd => return Ok(cmd),
+        };
+        if !self.is_active() {
+            return Err(KvsError::KeyRequired);
+        }
+        let nonce = base64::decode(nonce).map_err(|_| KvsError::Decryption)?;
+        let data = base64::decode(data).map_err(|_| KvsError::Decryption)?;
+        if nonce.len() != 12 {
+            return Err(KvsError::Decryption);
+        }
+        let nonce = Nonce::from_slice(&nonce);
+        let plaintext = self
+            .current
+            .iter()
+            .chain(self.previous.iter())
+            .find_map(|cipher| cipher.decrypt(nonce, data.as_ref()).ok())
+            .ok_or(KvsError::Decryption)?;
+        match serde_json::from_slice(&plaintext)? {
+            Command::Sealed(..) => Err(KvsError::UnexpectedCommandType),
+            cmd => Ok(cmd),
+        }
+    }
+
+    /// Checks the keys against the key verifier of the store in `dir`, so
+    /// that a wrong key is rejected even before any record is read. Returns
+    /// whether the store has a verifier.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyRequired` if the store is encrypted and no key
+    /// is configured, and `KvsError::Decryption` if no configured key opens
+    /// the verifier.
+    pub(crate) fn check_verifier(&self, dir: &Path) -> Result<bool> {
+        let sealed: Command = match File::open(dir.join(VERIFIER_FILE)) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file))?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        match self.open(sealed)? {
+            Command::Set(key, _) if key == VERIFIER_KEY => Ok(true),
+            _ => Err(KvsError::Decryption),
+        }
+    }
+
+    /// Seals the key verifier of the store in `dir` with the current key, or
+    /// removes it when records are no longer sealed.
+    pub(crate) fn save_verifier(&self, dir: &Path) -> Result<()> {
+        let path = dir.join(VERIFIER_FILE);
+        match self.seal(&Command::Set(VERIFIER_KEY.to_owned(), String::new()))? {
+            Some(sealed) => {
+                let tmp_path = dir.join(format!("{}.tmp", VERIFIER_FILE));
+                let mut file = File::create(&tmp_path)?;
+                serde_json::to_writer(&mut file, &sealed)?;
+                file.sync_all()?;
+                fs::rename(tmp_path, path)?;
+            }
+            None => match fs::remove_file(path) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                result => result?,
+            },
+        }
+        Ok(())
+    }
+}
@@ -10,6 +10,8 @@ pub enum KvsError {
     Io(io::Error),
     #[fail(display = "{}", _0)]
     Serde(serde_json::Error),
+    #[fail(display = "{}", _0)]
+    Sled(sled::Error),
     #[fail(display = "Key not found")]
     KeyNotFound,
     /// UnexpectedCommandType indicated a corrupted log or a program bug.
@@ -20,6 +22,64 @@ pub enum KvsError {
     UnexpectedResponseType,
     #[fail(display = "{}", _0)]
     StringErr(String),
+    /// Corruption indicates stored data a server could not read back.
+    #[fail(display = "Corrupted data: {}", _0)]
+    Corruption(String),
+    /// Decompression indicated a corrupted compressed record.
+    #[fail(display = "Decompression failed: {}", _0)]
+    Decompression(String),
+    /// The log holds encrypted records but no encryption key was supplied.
+    #[fail(display = "Encryption key required")]
+    KeyRequired,
+    /// Decryption indicated a wrong encryption key or a corrupted record.
+    #[fail(display = "Unable to decrypt record: wrong key or corrupted data")]
+    Decryption,
+    #[fail(display = "Invalid encryption key: {}", _0)]
+    InvalidKey(String),
+    /// WrongEngine indicates a data directory written by another engine.
+    #[fail(display = "Data directory was created by the {} engine", _0)]
+    WrongEngine(String),
+    #[fail(display = "Operation not supported by this engine: {}", _0)]
+    Unsupported(String),
+    /// HistoryUnavailable indicates a watch resuming from an event that is no
+    /// longer kept.
+    #[fail(
+        display = "Changes after sequence number {} are no longer available",
+        _0
+    )]
+    HistoryUnavailable(u64),
+    /// ReadOnly indicates a write sent to a follower instead of its leader.
+    #[fail(display = "Read-only replica of {}", _0)]
+    ReadOnly(String),
+    /// Unauthorized indicates a request the client is not allowed to make.
+    #[fail(display = "Unauthorized: {}", _0)]
+    Unauthorized(String),
+    /// Busy indicates a server refusing work for now. The request may be
+    /// retried later.
+    #[fail(display = "Server busy: {}", _0)]
+    Busy(String),
+    /// NotLeader indicates a request a cluster follower cannot serve, with
+    /// the address of the leader if one is known.
+    #[fail(display = "Not the cluster leader")]
+    NotLeader(Option<String>),
+    #[fail(display = "A membership change is already in progress")]
+    MembershipChangePending,
+    /// Timeout indicates a write the cluster did not commit in time. It may
+    /// still be committed later.
+    #[fail(display = "Timed out waiting for the cluster")]
+    Timeout,
+    /// Unavailable indicates a back end of `kvs-proxy` that failed its
+    /// health check.
+    #[fail(display = "Back end {} is unavailable", _0)]
+    Unavailable(String),
+    /// Protocol indicates a peer breaking the wire protocol, which ends the
+    /// session.
+    #[fail(display = "Protocol error: {}", _0)]
+    Protocol(String),
+    /// MalformedMessage indicates a frame that could not be decoded. The
+    /// session goes on with the next frame.
+    #[fail(display = "Malformed message: {}", _0)]
+    MalformedMessage(String),
 }
 
 impl From<io::Error> for KvsError {
@@ -34,6 +94,12 @@ impl From<serde_json::Error> for KvsError {
     }
 }
 
+impl From<sled::Error> for KvsError {
+    fn from(f: sled::Error) -> Self {
+        KvsError::Sled(f)
+    }
+}
+
 impl From<String> for KvsError {
     fn from(f: String) -> Self {
         KvsError::StringErr(f)
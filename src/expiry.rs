@@ -0,0 +1,210 @@
+//! Deadlines of keys set to expire.
+//!
+//! The store has no expiry of its own, so the deadlines set with the `EX`
+//! option of RESP are kept beside it, in the `expiry` file, and outlive a
+//! restart. A later write of a key through any protocol clears its deadline.
+//! Reads through any protocol remove a key past its deadline first, and a
+//! sweep removes the keys no client reads.
+
+use crate::connection::Handler;
+use crate::{KvsError, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const EXPIRY_FILE: &str = "expiry";
+/// How often keys past their deadline are removed when no client reads them.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+/// Records of cleared or replaced deadlines the file may hold before it is
+/// rewritten.
+const COMPACTION_THRESHOLD: usize = 1024;
+
+/// Removes the keys past their deadline through `handler`, every
+/// `SWEEP_INTERVAL`, for as long as the server runs.
+pub(crate) fn sweep(handler: Handler) {
+    loop {
+        thread::sleep(SWEEP_INTERVAL);
+        // A failure leaves the keys for the next sweep.
+        let _ = handler.expire_all("");
+    }
+}
+
+/// A change of deadline, as appended to the `expiry` file.
+#[derive(Serialize, Deserialize)]
+struct Record {
+    key: String,
+    deadline: Option<u64>,
+}
+
+/// When the keys set to expire do, in milliseconds since the Unix epoch.
+///
+/// Every change is appended to the `expiry` file, which is rewritten with
+/// only the current deadlines once it holds enough stale records.
+pub struct Deadlines {
+    path: PathBuf,
+    state: Mutex<State>,
+}
+
+struct State {
+    deadlines: HashMap<String, u64>,
+    writer: BufWriter<File>,
+    /// Records in the file.
+    records: usize,
+}
+
+impl Deadlines {
+    /// Loads the deadlines recorded in `dir`.
+    pub fn open(dir: &Path) -> Result<Self> {
+        let path = dir.join(EXPIRY_FILE);
+        let content = match fs::read(&path) {
+            Ok(content) => content,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let mut deadlines = HashMap::new();
+        let mut records = 0;
+        // A record cut short by a crash is dropped, and the file rewritten
+        // so that no record is appended to it.
+        let mut torn = false;
+        for record in serde_json::Deserializer::from_slice(&content).into_iter::<Record>() {
+            match record {
+                Ok(Record {
+                    key,
+                    deadline: Some(deadline),
+                }) => {
+                    deadlines.insert(key, deadline);
+                }
+                Ok(Record {
+                    key,
+                    deadline: None,
+                }) => {
+                    deadlines.remove(&key);
+                }
+                Err(e) if e.is_eof() => {
+                    torn = true;
+                    break;
+                }
+                Err(_) => {
+                    return Err(KvsError::Corruption(format!("bad {} file", EXPIRY_FILE)));
+                }
+            }
+            records += 1;
+        }
+        let writer = BufWriter::new(OpenOptions::new().create(true).append(true).open(&path)?);
+        let mut state = State {
+            deadlines,
+            writer,
+            records,
+        };
+        if torn || state.stale() > COMPACTION_THRESHOLD {
+            state.compact(&path)?;
+        }
+        Ok(Deadlines {
+            path,
+            state: Mutex::new(state),
+        })
+    }
+
+    /// Makes `key` expire at `deadline`, or never if it is `None`.
+    pub fn set(&self, key: &str, deadline: Option<SystemTime>) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let deadline = deadline.map(millis);
+        match deadline {
+            Some(deadline) => {
+                state.deadlines.insert(key.to_owned(), deadline);
+            }
+            // Most keys never expire, and clearing them writes nothing.
+            None if state.deadlines.remove(key).is_none() => return Ok(()),
+            None => {}
+        }
+        state.append(&Record {
+            key: key.to_owned(),
+            deadline,
+        })?;
+        if state.stale() > COMPACTION_THRESHOLD {
+            state.compact(&self.path)?;
+        }
+        Ok(())
+    }
+
+    /// Returns whether `key` is past its deadline.
+    pub fn is_past(&self, key: &str) -> bool {
+        let now = millis(SystemTime::now());
+        self.state
+            .lock()
+            .unwrap()
+            .deadlines
+            .get(key)
+            .is_some_and(|deadline| *deadline <= now)
+    }
+
+    /// Returns the keys past their deadline.
+    pub fn past(&self) -> Vec<String> {
+        let now = millis(SystemTime::now());
+        self.state
+            .lock()
+            .unwrap()
+            .deadlines
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// Returns the number of keys set to expire.
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().deadlines.len()
+    }
+
+    /// Returns whether no key is set to expire.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl State {
+    /// Returns the number of records no longer holding a deadline.
+    fn stale(&self) -> usize {
+        self.records - self.deadlines.len()
+    }
+
+    fn append(&mut self, record: &Record) -> Result<()> {
+        serde_json::to_writer(&mut self.writer, record)?;
+        self.writer.write_all(b"\n")?;
+        self.writer.flush()?;
+        self.records += 1;
+        Ok(())
+    }
+
+    /// Rewrites the file with only the current deadlines.
+    fn compact(&mut self, path: &Path) -> Result<()> {
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        for (key, deadline) in &self.deadlines {
+            serde_json::to_writer(
+                &mut writer,
+                &Record {
+                    key: key.clone(),
+                    deadline: Some(*deadline),
+                },
+            )?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(tmp_path, path)?;
+        self.writer = BufWriter::new(OpenOptions::new().append(true).open(path)?);
+        self.records = self.deadlines.len();
+        Ok(())
+    }
+}
+
+fn millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
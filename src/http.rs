@@ -0,0 +1,316 @@
+//! A REST gateway over HTTP/1.1, for scripts, browsers and health checks.
+//!
+//! - `GET /keys/{key}` returns the value of a key as text, or 404.
+//! - `PUT /keys/{key}` sets a key to the request body.
+//! - `DELETE /keys/{key}` removes a key, or answers 404.
+//! - `GET /keys?prefix={prefix}` returns the keys starting with `prefix` as a
+//!   JSON array of `{"key": ..., "value": ...}` objects, in key order.
+//! - `GET /health` answers 200 while the server is up.
+//!
+//! Keys and prefixes are percent-decoded. Errors come as JSON objects with an
+//! `error` message. Requests go through the same `Handler` as the native
+//! protocol.
+
+use crate::common::{Request, Response};
+use crate::connection::Handler;
+use crate::protocol::MAX_FRAME;
+use crate::{KvsError, Result};
+use serde_json::json;
+use slog::Logger;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+
+/// Longest request line or header line.
+const MAX_LINE: u64 = 16 * 1024;
+const MAX_HEADERS: usize = 100;
+
+const TEXT: &str = "text/plain; charset=utf-8";
+const JSON: &str = "application/json";
+
+/// Serves HTTP clients until the listener fails, a thread per connection.
+pub(crate) fn serve(listener: TcpListener, handler: Handler, log: Logger) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let handler = handler.clone();
+                let log = log.new(o!("peer-address"=>stream.peer_addr().ok()));
+                thread::spawn(move || {
+                    handler.metrics.connection_opened();
+                    if let Err(e) = serve_client(&handler, stream) {
+                        error!(log, "Error on serving HTTP client: {}", e);
+                    }
+                    handler.metrics.connection_closed();
+                });
+            }
+            Err(e) => error!(log, "Error on new HTTP connection: {}", e),
+        }
+    }
+}
+
+fn serve_client(handler: &Handler, stream: TcpStream) -> Result<()> {
+    let metrics = &handler.metrics;
+    let mut reader = BufReader::new(metrics.count_reads(&stream));
+    let mut writer = BufWriter::new(metrics.count_writes(&stream));
+    loop {
+        let (response, close) = match read_request(&mut reader) {
+            Ok(Some(request)) => (route(handler, &request), request.close),
+            Ok(None) => return Ok(()),
+            Err(KvsError::Protocol(e)) => (HttpResponse::error("400 Bad Request", e), true),
+            Err(e) => return Err(e),
+        };
+        response.write_to(&mut writer, close)?;
+        writer.flush()?;
+        if close {
+            return Ok(());
+        }
+    }
+}
+
+struct HttpRequest {
+    method: String,
+    path: String,
+    query: Option<String>,
+    body: Vec<u8>,
+    /// Whether the client asked to close the connection after the response.
+    close: bool,
+}
+
+/// Reads a request, or returns `None` once the client closed the connection.
+fn read_request<R: BufRead>(reader: &mut R) -> Result<Option<HttpRequest>> {
+    // Clients may send empty lines between requests.
+    let request_line = loop {
+        match read_line(reader)? {
+            Some(line) if line.is_empty() => continue,
+            Some(line) => break line,
+            None => return Ok(None),
+        }
+    };
+    let mut parts = request_line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) if version.starts_with("HTTP/1.") => {
+            (method, target, version)
+        }
+        _ => return Err(KvsError::Protocol("malformed request line".to_owned())),
+    };
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query.to_owned())),
+        None => (target, None),
+    };
+
+    let mut close = version == "HTTP/1.0";
+    let mut content_length = 0;
+    for _ in 0..=MAX_HEADERS {
+        let line = read_line(reader)?
+            .ok_or_else(|| KvsError::Protocol("incomplete headers".to_owned()))?;
+        if line.is_empty() {
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body)?;
+            return Ok(Some(HttpRequest {
+                method: method.to_owned(),
+                path: path.to_owned(),
+                query,
+                body,
+                close,
+            }));
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| KvsError::Protocol("malformed header".to_owned()))?;
+        let value = value.trim();
+        match name.to_ascii_lowercase().as_str() {
+            "content-length" => {
+                content_length = value
+                    .parse()
+                    .ok()
+                    .filter(|len| *len <= MAX_FRAME)
+                    .ok_or_else(|| KvsError::Protocol("invalid Content-Length".to_owned()))?
+            }
+            "transfer-encoding" => {
+                return Err(KvsError::Protocol(
+                    "bodies must come with a Content-Length".to_owned(),
+                ))
+            }
+            "connection" if value.eq_ignore_ascii_case("close") => close = true,
+            "connection" if value.eq_ignore_ascii_case("keep-alive") => close = false,
+            _ => {}
+        }
+    }
+    Err(KvsError::Protocol("too many headers".to_owned()))
+}
+
+/// Reads a line without its line ending.
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<String>> {
+    let mut line = String::new();
+    if (&mut *reader).take(MAX_LINE).read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with('\n') {
+        return Err(KvsError::Protocol("line too long".to_owned()));
+    }
+    line.truncate(line.trim_end_matches(['\r', '\n']).len());
+    Ok(Some(line))
+}
+
+fn route(handler: &Handler, request: &HttpRequest) -> HttpResponse {
+    let method = request.method.as_str();
+    if request.path == "/health" {
+        return match method {
+            "GET" => HttpResponse::ok(TEXT, "OK\n".to_owned()),
+            _ => HttpResponse::method_not_allowed("GET"),
+        };
+    }
+    if request.path == "/keys" {
+        if method != "GET" {
+            return HttpResponse::method_not_allowed("GET");
+        }
+        let prefix = request
+            .query
+            .as_deref()
+            .unwrap_or_default()
+            .split('&')
+            .find_map(|param| param.strip_prefix("prefix="))
+            .unwrap_or_default();
+        let prefix = match percent_decode(prefix, true) {
+            Some(prefix) => prefix,
+            None => return HttpResponse::error("400 Bad Request", "invalid prefix"),
+        };
+        let request = Request::Scan {
+            prefix,
+            after: None,
+            limit: None,
+        };
+        return match handler.call(request) {
+            Ok(Response::Entries(entries)) => {
+                let entries: Vec<_> = entries
+                    .into_iter()
+                    .map(|(key, value)| json!({ "key": key, "value": value }))
+                    .collect();
+                HttpResponse::ok(JSON, serde_json::Value::from(entries).to_string())
+            }
+            Ok(_) => KvsError::UnexpectedResponseType.into(),
+            Err(e) => e.into(),
+        };
+    }
+    let key = match request.path.strip_prefix("/keys/") {
+        Some(key) if !key.is_empty() => key,
+        _ => return HttpResponse::error("404 Not Found", "no such resource"),
+    };
+    let key = match percent_decode(key, false) {
+        Some(key) => key,
+        None => return HttpResponse::error("400 Bad Request", "invalid key"),
+    };
+    let result = match method {
+        "GET" => handler.call(Request::Get { key }),
+        "PUT" => match String::from_utf8(request.body.clone()) {
+            Ok(value) => handler.call(Request::Set { key, value }),
+            Err(_) => return HttpResponse::error("400 Bad Request", "value must be UTF-8"),
+        },
+        "DELETE" => handler.call(Request::Remove { key }),
+        _ => return HttpResponse::method_not_allowed("GET, PUT, DELETE"),
+    };
+    match (method, result) {
+        ("GET", Ok(Response::Ok(Some(value)))) => HttpResponse::ok(TEXT, value),
+        ("GET", Ok(Response::Ok(None))) => KvsError::KeyNotFound.into(),
+        (_, Ok(Response::Ok(_))) => HttpResponse {
+            status: "204 No Content",
+            content_type: None,
+            body: String::new(),
+            allow: None,
+        },
+        (_, Ok(_)) => KvsError::UnexpectedResponseType.into(),
+        (_, Err(e)) => e.into(),
+    }
+}
+
+/// Decodes `%XX` escapes, and `+` as a space in query strings.
+fn percent_decode(s: &str, query: bool) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        bytes.push(match byte {
+            b'%' => {
+                let hex = rest.get(..2)?;
+                rest = &rest[2..];
+                u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?
+            }
+            b'+' if query => b' ',
+            byte => byte,
+        });
+    }
+    String::from_utf8(bytes).ok()
+}
+
+struct HttpResponse {
+    status: &'static str,
+    content_type: Option<&'static str>,
+    body: String,
+    /// The methods a resource allows, for a 405.
+    allow: Option<&'static str>,
+}
+
+impl HttpResponse {
+    fn ok(content_type: &'static str, body: String) -> Self {
+        HttpResponse {
+            status: "200 OK",
+            content_type: Some(content_type),
+            body,
+            allow: None,
+        }
+    }
+
+    fn error(status: &'static str, message: impl ToString) -> Self {
+        HttpResponse {
+            status,
+            content_type: Some(JSON),
+            body: json!({ "error": message.to_string() }).to_string(),
+            allow: None,
+        }
+    }
+
+    fn method_not_allowed(allow: &'static str) -> Self {
+        HttpResponse {
+            allow: Some(allow),
+            ..HttpResponse::error("405 Method Not Allowed", "method not allowed")
+        }
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W, close: bool) -> Result<()> {
+        write!(writer, "HTTP/1.1 {}\r\n", self.status)?;
+        if let Some(content_type) = self.content_type {
+            write!(writer, "Content-Type: {}\r\n", content_type)?;
+        }
+        if let Some(allow) = self.allow {
+            write!(writer, "Allow: {}\r\n", allow)?;
+        }
+        if close {
+            write!(writer, "Connection: close\r\n")?;
+        }
+        write!(
+            writer,
+            "Content-Length: {}\r\n\r\n{}",
+            self.body.len(),
+            self.body
+        )?;
+        Ok(())
+    }
+}
+
+impl From<KvsError> for HttpResponse {
+    fn from(e: KvsError) -> Self {
+        let status = match e {
+            KvsError::KeyNotFound => "404 Not Found",
+            // Writes belong to the leader.
+            KvsError::ReadOnly(_) | KvsError::NotLeader(_) => "421 Misdirected Request",
+            KvsError::Unauthorized(_) => "403 Forbidden",
+            KvsError::Busy(_) | KvsError::Unavailable(_) | KvsError::MembershipChangePending => {
+                "503 Service Unavailable"
+            }
+            KvsError::Timeout => "504 Gateway Timeout",
+            KvsError::Unsupported(_) => "501 Not Implemented",
+            _ => "500 Internal Server Error",
+        };
+        HttpResponse::error(status, e)
+    }
+}
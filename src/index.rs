@@ -0,0 +1,319 @@
+//! The map from keys to the position of their latest record.
+//!
+//! By default it is a `BTreeMap` held in memory. For stores whose keyspace does
+//! not fit in RAM, it can instead live in an on-disk sorted tree under the
+//! `index` directory of the store, with an LRU cache of recently used entries.
+//! The on-disk index keeps a `Checkpoint` of how far into the log it is, so
+//! that opening the store only replays the log from there.
+
+use crate::blob::BlobRef;
+use crate::Result;
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::num::NonZeroUsize;
+use std::ops::{Bound, Range};
+use std::path::Path;
+
+/// Position of a record in the log.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub(crate) struct CommandOps {
+    pub(crate) gen: u64,
+    pub(crate) offset: u64,
+    pub(crate) len: u64,
+    pub(crate) blob: Option<BlobRef>,
+}
+
+impl From<(u64, Range<u64>)> for CommandOps {
+    fn from((gen, range): (u64, Range<u64>)) -> Self {
+        CommandOps {
+            gen,
+            offset: range.start,
+            len: range.end - range.start,
+            blob: None,
+        }
+    }
+}
+
+/// How far into the log an index is, with the state of the store up to there.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct Checkpoint {
+    /// The records before `offset` in generation `gen`, and every record of
+    /// older generations, are indexed.
+    pub(crate) gen: u64,
+    pub(crate) offset: u64,
+    /// Stale bytes in the indexed records.
+    pub(crate) uncompacted: u64,
+    /// The highest sequence number in the indexed records.
+    pub(crate) last_seq: u64,
+}
+
+/// Iterator over the entries of an `Index`, in key order.
+pub(crate) type IndexIter<'a> = Box<dyn Iterator<Item = Result<(String, CommandOps)>> + 'a>;
+
+pub(crate) enum Index {
+    Memory(BTreeMap<String, CommandOps>),
+    Disk(DiskIndex),
+}
+
+impl Index {
+    /// Opens an index, on disk under `dir` if a cache capacity is given.
+    ///
+    /// The in-memory index starts empty. The on-disk index holds what it had
+    /// indexed up to its `checkpoint`, and may hold changes past it.
+    pub(crate) fn open(dir: &Path, cache_capacity: Option<usize>) -> Result<Index> {
+        match cache_capacity {
+            None => Ok(Index::Memory(BTreeMap::new())),
+            Some(capacity) => Ok(Index::Disk(DiskIndex::open(dir, capacity)?)),
+        }
+    }
+
+    /// Returns how far into the log the index is, if it was kept from a
+    /// previous open.
+    pub(crate) fn checkpoint(&self) -> Option<Checkpoint> {
+        match self {
+            Index::Memory(_) => None,
+            Index::Disk(index) => index.checkpoint,
+        }
+    }
+
+    /// Records how far into the log the index is. Only an on-disk index keeps
+    /// it.
+    pub(crate) fn save_checkpoint(&mut self, checkpoint: Option<Checkpoint>) -> Result<()> {
+        match self {
+            Index::Memory(_) => Ok(()),
+            Index::Disk(index) => index.save_checkpoint(checkpoint),
+        }
+    }
+
+    /// Removes every entry, for the index to be rebuilt from the whole log.
+    pub(crate) fn clear(&mut self) -> Result<()> {
+        match self {
+            Index::Memory(map) => map.clear(),
+            Index::Disk(index) => {
+                index.save_checkpoint(None)?;
+                index.tree.clear()?;
+                index.cache.clear();
+                index.len = 0;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn get(&mut self, key: &str) -> Result<Option<CommandOps>> {
+        match self {
+            Index::Memory(map) => Ok(map.get(key).copied()),
+            Index::Disk(index) => index.get(key),
+        }
+    }
+
+    pub(crate) fn contains_key(&mut self, key: &str) -> Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    /// Points a key to a new record and returns the previous position.
+    pub(crate) fn insert(&mut self, key: String, ops: CommandOps) -> Result<Option<CommandOps>> {
+        match self {
+            Index::Memory(map) => Ok(map.insert(key, ops)),
+            Index::Disk(index) => index.insert(key, ops),
+        }
+    }
+
+    pub(crate) fn remove(&mut self, key: &str) -> Result<Option<CommandOps>> {
+        match self {
+            Index::Memory(map) => Ok(map.remove(key)),
+            Index::Disk(index) => index.remove(key),
+        }
+    }
+
+    /// Returns the number of keys.
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Index::Memory(map) => map.len(),
+            Index::Disk(index) => index.len,
+        }
+    }
+
+    pub(crate) fn iter(&self) -> IndexIter<'_> {
+        match self {
+            Index::Memory(map) => Box::new(map.iter().map(|(key, ops)| Ok((key.clone(), *ops)))),
+            Index::Disk(index) => Box::new(index.tree.iter().map(|res| {
+                let (key, value) = res?;
+                Ok((decode_key(&key), serde_json::from_slice(&value)?))
+            })),
+        }
+    }
+
+    /// Iterates over the entries from `start` on, in key order.
+    pub(crate) fn range(&self, start: Bound<&str>) -> IndexIter<'_> {
+        match self {
+            Index::Memory(map) => Box::new(
+                map.range::<str, _>((start, Bound::Unbounded))
+                    .map(|(key, ops)| Ok((key.clone(), *ops))),
+            ),
+            Index::Disk(index) => {
+                let start = match start {
+                    Bound::Included(key) => Bound::Included(key.as_bytes()),
+                    Bound::Excluded(key) => Bound::Excluded(key.as_bytes()),
+                    Bound::Unbounded => Bound::Unbounded,
+                };
+                Box::new(
+                    index
+                        .tree
+                        .range::<&[u8], _>((start, Bound::Unbounded))
+                        .map(|res| {
+                            let (key, value) = res?;
+                            Ok((decode_key(&key), serde_json::from_slice(&value)?))
+                        }),
+                )
+            }
+        }
+    }
+
+    /// Calls `f` on every entry in key order, storing back any change it makes.
+    pub(crate) fn update_all<F>(&mut self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&str, &mut CommandOps) -> Result<()>,
+    {
+        match self {
+            Index::Memory(map) => {
+                for (key, ops) in map.iter_mut() {
+                    f(key, ops)?;
+                }
+            }
+            Index::Disk(index) => {
+                index.cache.clear();
+                for res in index.tree.iter() {
+                    let (key, value) = res?;
+                    let mut ops = serde_json::from_slice(&value)?;
+                    f(&decode_key(&key), &mut ops)?;
+                    index.tree.insert(key, serde_json::to_vec(&ops)?)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The key of the checkpoint in the `meta` tree of an on-disk index.
+const CHECKPOINT_KEY: &str = "checkpoint";
+/// The key of the number of entries in the `meta` tree, kept only while the
+/// tree has not changed since the checkpoint.
+const LEN_KEY: &str = "len";
+
+/// An index kept in a sled tree, with the hottest entries cached in memory.
+pub(crate) struct DiskIndex {
+    tree: sled::Db,
+    /// Holds the checkpoint, apart from the keys of the store.
+    meta: sled::Tree,
+    checkpoint: Option<Checkpoint>,
+    cache: LruCache<String, CommandOps>,
+    len: usize,
+    /// Whether `len` is saved in `meta`, and must be removed before the tree
+    /// changes.
+    len_saved: bool,
+}
+
+impl DiskIndex {
+    fn open(dir: &Path, capacity: usize) -> Result<DiskIndex> {
+        let tree = sled::Config::new()
+            .path(dir.join("index"))
+            .cache_capacity(64 * 1024 * 1024)
+            .open()?;
+        let meta = tree.open_tree("meta")?;
+        let checkpoint = match meta.get(CHECKPOINT_KEY)? {
+            Some(value) => Some(serde_json::from_slice(&value)?),
+            None => None,
+        };
+        // Counting the entries walks the whole tree, which is only needed when
+        // the count was not saved, or the tree changed after it was.
+        let len = match meta.get(LEN_KEY)? {
+            Some(value) => serde_json::from_slice(&value)?,
+            None => tree.len(),
+        };
+        let capacity = NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN);
+        Ok(DiskIndex {
+            tree,
+            meta,
+            checkpoint,
+            cache: LruCache::new(capacity),
+            len,
+            len_saved: false,
+        })
+    }
+
+    /// Saves or removes the checkpoint, with the number of entries. Sled
+    /// persists writes in order, so a checkpoint found after a crash never
+    /// runs ahead of the entries, and a count found is that of the tree.
+    fn save_checkpoint(&mut self, checkpoint: Option<Checkpoint>) -> Result<()> {
+        match checkpoint {
+            Some(checkpoint) => {
+                self.meta
+                    .insert(CHECKPOINT_KEY, serde_json::to_vec(&checkpoint)?)?;
+                self.meta.insert(LEN_KEY, serde_json::to_vec(&self.len)?)?;
+                self.len_saved = true;
+            }
+            None => {
+                self.meta.remove(CHECKPOINT_KEY)?;
+                self.forget_len()?;
+            }
+        }
+        self.tree.flush()?;
+        self.checkpoint = checkpoint;
+        Ok(())
+    }
+
+    /// Removes the saved number of entries, before the tree changes.
+    fn forget_len(&mut self) -> Result<()> {
+        if self.len_saved {
+            self.meta.remove(LEN_KEY)?;
+            self.len_saved = false;
+        }
+        Ok(())
+    }
+
+    fn get(&mut self, key: &str) -> Result<Option<CommandOps>> {
+        if let Some(ops) = self.cache.get(key) {
+            return Ok(Some(*ops));
+        }
+        match self.tree.get(key)? {
+            Some(value) => {
+                let ops: CommandOps = serde_json::from_slice(&value)?;
+                self.cache.put(key.to_owned(), ops);
+                Ok(Some(ops))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn insert(&mut self, key: String, ops: CommandOps) -> Result<Option<CommandOps>> {
+        self.forget_len()?;
+        let old = self
+            .tree
+            .insert(key.as_bytes(), serde_json::to_vec(&ops)?)?;
+        self.cache.put(key, ops);
+        match old {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => {
+                self.len += 1;
+                Ok(None)
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &str) -> Result<Option<CommandOps>> {
+        self.forget_len()?;
+        self.cache.pop(key);
+        match self.tree.remove(key)? {
+            Some(value) => {
+                self.len -= 1;
+                Ok(Some(serde_json::from_slice(&value)?))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+fn decode_key(key: &[u8]) -> String {
+    String::from_utf8_lossy(key).into_owned()
+}
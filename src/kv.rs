@@ -1,48 +1,217 @@
 // #![deny(missing_docs)]
 //! A simple key/value store.
 
+use crate::blob::{BlobRef, BlobStore};
+use crate::cache::{CacheStats, ValueCache};
+use crate::changes::{Change, ChangeState};
+use crate::compression::{self, CompressionStats};
+use crate::crypto::{EncryptionKey, Keyring};
+use crate::index::{Checkpoint, CommandOps, Index};
+use crate::stats::{CompactionReport, Stats};
 use crate::{KvsError, Result};
+use memmap2::Mmap;
 use serde::{Deserialize, Serialize};
 use serde_json;
-use std::collections::{BTreeMap, HashMap};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsStr;
 use std::fs;
 use std::fs::{File, OpenOptions};
 use std::io;
 use std::io::prelude::*;
 use std::io::{BufReader, BufWriter, SeekFrom};
-use std::ops::Range;
+use std::ops::{Bound, Range};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+const BLOB_GC_THRESHOLD: u64 = 16 * 1024 * 1024;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Command {
     Set(String, String),
     Remove(String),
+    /// A `Set` whose value was compressed with `compression::compress`.
+    SetCompressed(String, String),
+    /// Another command encrypted with the store key, as base64 nonce and ciphertext.
+    Sealed(String, String),
+    /// A `Set` whose value record lives in a blob file.
+    SetBlob(String, BlobRef),
+    /// A `Set`, `SetCompressed`, `SetBlob` or `Remove` with its change
+    /// sequence number.
+    Sequenced(u64, Box<Command>),
 }
 
-#[derive(Debug)]
-struct CommandOps {
-    gen: u64,
-    offset: u64,
-    len: u64,
-}
+impl Command {
+    /// Returns the name of the command type, as written in the log.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Set(..) => "Set",
+            Command::Remove(..) => "Remove",
+            Command::SetCompressed(..) => "SetCompressed",
+            Command::Sealed(..) => "Sealed",
+            Command::SetBlob(..) => "SetBlob",
+            Command::Sequenced(..) => "Sequenced",
+        }
+    }
+
+    /// Returns the key the command applies to, unless it is sealed.
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            Command::Set(key, ..)
+            | Command::Remove(key)
+            | Command::SetCompressed(key, ..)
+            | Command::SetBlob(key, ..) => Some(key),
+            Command::Sequenced(_, cmd) => cmd.key(),
+            Command::Sealed(..) => None,
+        }
+    }
 
-impl From<(u64, Range<u64>)> for CommandOps {
-    fn from((gen, range): (u64, Range<u64>)) -> Self {
-        CommandOps {
-            gen,
-            offset: range.start,
-            len: range.end - range.start,
+    /// Splits a sequenced command into its sequence number and the command.
+    pub fn unsequence(self) -> (Option<u64>, Command) {
+        match self {
+            Command::Sequenced(seq, cmd) => (Some(seq), *cmd),
+            cmd => (None, cmd),
+        }
+    }
+
+    fn into_key(self) -> Option<String> {
+        match self {
+            Command::Set(key, ..)
+            | Command::Remove(key)
+            | Command::SetCompressed(key, ..)
+            | Command::SetBlob(key, ..) => Some(key),
+            Command::Sequenced(_, cmd) => cmd.into_key(),
+            Command::Sealed(..) => None,
         }
     }
 }
 
+/// Options controlling how a `KvStore` writes its log.
+#[derive(Debug, Default, Clone)]
+pub struct KvStoreOptions {
+    /// Values longer than this many bytes are stored LZ4 compressed.
+    ///
+    /// `None` disables compression. Compressed records are always readable,
+    /// whatever this option is set to.
+    pub compression_threshold: Option<usize>,
+    /// Key used to encrypt every record written to the log.
+    ///
+    /// `None` writes plain records. Opening a store holding encrypted records
+    /// without a matching key fails with `KvsError::KeyRequired` or
+    /// `KvsError::Decryption`. The store keeps a record sealed with its key,
+    /// so that a wrong key fails to open it even while it holds no records.
+    pub encryption_key: Option<EncryptionKey>,
+    /// Retired keys that can still decrypt existing records.
+    ///
+    /// To rotate keys, open the store with the new `encryption_key` and the old
+    /// one here, then call `KvStore::compact`, which rewrites every live record
+    /// with the new key. The old key is no longer needed afterwards.
+    pub previous_keys: Vec<EncryptionKey>,
+    /// Values longer than this many bytes are stored in separate blob files.
+    ///
+    /// `None` keeps every value in the log. Blob files are garbage-collected on
+    /// their own, so compaction only rewrites small pointer records for them.
+    pub blob_threshold: Option<usize>,
+    /// Keeps the index in an on-disk sorted tree instead of memory, caching
+    /// this many recently used entries.
+    ///
+    /// `None` keeps the whole index in memory, which is faster but needs RAM
+    /// proportional to the number and length of keys. The on-disk index is
+    /// kept between opens, which then only read the log written since.
+    pub disk_index_cache: Option<usize>,
+    /// Memory-maps the generations that are no longer written to, so that
+    /// reading them is a slice lookup instead of a seek and a read.
+    ///
+    /// The active generation is always read through a buffered reader.
+    pub mmap: bool,
+    /// Caches recently read values, up to this many bytes of keys and values.
+    ///
+    /// `None` disables the cache. Entries are invalidated by `set`, `remove`
+    /// and compaction.
+    pub value_cache_size: Option<usize>,
+    /// Keeps the changes that consumers have not acknowledged with
+    /// `KvStore::acknowledge_changes` through compaction.
+    ///
+    /// Without it, `KvStore::changes_since` only returns the changes that
+    /// compaction has not dropped yet. With it, the log grows until changes
+    /// are acknowledged, and blob files are only collected by explicit calls
+    /// to `KvStore::gc_blobs`.
+    pub retain_changes: bool,
+}
+
 pub trait KvsEngine {
     fn set(&mut self, key: String, value: String) -> Result<()>;
     fn get(&mut self, key: String) -> Result<Option<String>>;
     fn remove(&mut self, key: String) -> Result<()>;
+
+    /// Returns statistics about the engine, if it keeps any.
+    fn stats(&mut self) -> Result<Stats> {
+        Err(KvsError::Unsupported("stats".to_owned()))
+    }
+
+    /// Reclaims the space of stale data now, if the engine supports it.
+    fn compact(&mut self) -> Result<CompactionReport> {
+        Err(KvsError::Unsupported("compact".to_owned()))
+    }
+
+    /// Returns every key with its value, if the engine supports it.
+    fn snapshot(&mut self) -> Result<Vec<(String, String)>> {
+        Err(KvsError::Unsupported("snapshot".to_owned()))
+    }
+
+    /// Returns the keys starting with `prefix` with their values, in key
+    /// order, if the engine supports it.
+    fn scan(&mut self, prefix: &str) -> Result<Vec<(String, String)>> {
+        self.scan_page(prefix, None, usize::MAX)
+    }
+
+    /// Gets the value of a key, possibly leaving the read of the value to
+    /// `Lookup::into_value` so that it happens without access to the engine.
+    fn lookup(&mut self, key: String) -> Result<Lookup> {
+        self.get(key).map(Lookup::Value)
+    }
+
+    /// Returns up to `limit` of the keys starting with `prefix` that sort
+    /// after `after`, with their values, in key order.
+    ///
+    /// Callers page through a range by passing the last key of a page as
+    /// `after` for the next one.
+    fn scan_page(
+        &mut self,
+        _prefix: &str,
+        _after: Option<&str>,
+        _limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        Err(KvsError::Unsupported("scan".to_owned()))
+    }
+
+    /// Returns the sequence number of the last change, if the engine keeps a
+    /// change feed.
+    fn last_seq(&mut self) -> Result<u64> {
+        Err(KvsError::Unsupported("change feed".to_owned()))
+    }
+
+    /// Returns the changes still kept with a sequence number above `seq`, in
+    /// sequence order, if the engine keeps a change feed.
+    fn changes_since(&mut self, _seq: u64) -> Result<Vec<Change>> {
+        Err(KvsError::Unsupported("change feed".to_owned()))
+    }
+
+    /// Lets the engine drop the changes up to `seq` from its feed.
+    fn acknowledge_changes(&mut self, _seq: u64) -> Result<()> {
+        Err(KvsError::Unsupported("change feed".to_owned()))
+    }
+}
+
+/// Returns where a page of a scan starts: at `prefix`, or right after
+/// `after` if that comes later.
+pub(crate) fn scan_start<'a>(prefix: &'a str, after: Option<&'a str>) -> Bound<&'a str> {
+    match after {
+        Some(after) if after >= prefix => Bound::Excluded(after),
+        _ => Bound::Included(prefix),
+    }
 }
 
 /// `KvStore` stores key/value pairs in Memory, not in disk.
@@ -65,66 +234,208 @@ pub struct KvStore {
     path: PathBuf,
     writer: BufWriterWithOps<File>,
     readers: HashMap<u64, BufReaderWithOps<File>>,
-    index: BTreeMap<String, CommandOps>,
+    maps: HashMap<u64, Arc<Mmap>>,
+    index: Index,
     load: bool,
     current_gen: u64,
     uncompacted: u64,
+    options: KvStoreOptions,
+    compression: CompressionStats,
+    keyring: Arc<Keyring>,
+    blobs: BlobStore,
+    cache: Option<ValueCache>,
+    /// Operation and compaction counters, completed by `stats`.
+    stats: Stats,
+    changes: ChangeState,
+    /// The highest sequence number in each generation no longer written to,
+    /// once read, so the feed can skip generations it has nothing from.
+    gen_seqs: HashMap<u64, u64>,
 }
 
 impl KvStore {
-    /// Open the KvStore at a given path. Return the KvStore.
+    /// Open the KvStore at a given path with default options. Return the KvStore.
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with_options(path, KvStoreOptions::default())
+    }
+
+    /// Open the KvStore at a given path with the given options. Return the KvStore.
+    pub fn open_with_options(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
         let path = path.into();
         fs::create_dir_all(&path)?;
 
         let mut readers = HashMap::new();
-        let mut index = BTreeMap::new();
+        let mut index = Index::open(&path, options.disk_index_cache)?;
 
         let gen_list = gen_list(&path)?;
-        let mut uncompacted: u64 = 0;
+        let keyring = Keyring::new(options.encryption_key.as_ref(), &options.previous_keys);
+        let has_verifier = keyring.check_verifier(&path)?;
+        let mut changes = ChangeState::load(&path)?;
 
+        // An index kept from a previous open only needs the log past its
+        // checkpoint, unless that part of the log is gone.
+        let checkpoint = match index.checkpoint() {
+            Some(checkpoint)
+                if gen_list.contains(&checkpoint.gen)
+                    && fs::metadata(log_path(&path, checkpoint.gen))?.len()
+                        >= checkpoint.offset =>
+            {
+                checkpoint
+            }
+            _ => {
+                index.clear()?;
+                Checkpoint::default()
+            }
+        };
+        let mut uncompacted = checkpoint.uncompacted;
+        changes.last_seq = changes.last_seq.max(checkpoint.last_seq);
         for &gen in &gen_list {
             let mut reader = BufReaderWithOps::new(File::open(log_path(&path, gen))?)?;
-            uncompacted += load(gen, &mut index, &mut reader)?;
+            if gen >= checkpoint.gen {
+                let start = if gen == checkpoint.gen {
+                    checkpoint.offset
+                } else {
+                    0
+                };
+                uncompacted += load(
+                    gen,
+                    start,
+                    &mut index,
+                    &mut reader,
+                    &keyring,
+                    &mut changes.last_seq,
+                )?;
+            }
             readers.insert(gen, reader);
         }
+        // The log was readable with the key, so it becomes the key of the
+        // store.
+        if !has_verifier && options.encryption_key.is_some() {
+            keyring.save_verifier(&path)?;
+        }
         let current_gen = gen_list.last().unwrap_or(&0) + 1;
         let writer = new_log_file(&path, current_gen, &mut readers)?;
+        index.save_checkpoint(Some(Checkpoint {
+            gen: current_gen,
+            offset: 0,
+            uncompacted,
+            last_seq: changes.last_seq,
+        }))?;
+
+        let mut maps = HashMap::new();
+        if options.mmap {
+            for &gen in &gen_list {
+                if let Some(map) = map_log_file(&path, gen)? {
+                    maps.insert(gen, Arc::new(map));
+                }
+            }
+        }
+
+        let mut blobs = BlobStore::open(&path)?;
+        for entry in index.iter() {
+            if let Some(blob) = entry?.1.blob {
+                blobs.retain(&blob);
+            }
+        }
 
         Ok(KvStore {
             path,
             readers,
+            maps,
             writer,
             index,
             current_gen,
             uncompacted,
             load: true,
+            compression: CompressionStats::default(),
+            keyring: Arc::new(keyring),
+            blobs,
+            cache: options.value_cache_size.map(ValueCache::new),
+            options,
+            stats: Stats::default(),
+            changes,
+            gen_seqs: HashMap::new(),
+        })
+    }
+
+    /// Returns counters about the values compressed since the store was opened.
+    pub fn compression_stats(&self) -> CompressionStats {
+        self.compression
+    }
+
+    /// Returns the key count, the size of the log and counters about the
+    /// operations served since the store was opened.
+    pub fn stats(&self) -> Result<Stats> {
+        Ok(Stats {
+            keys: self.index.len() as u64,
+            generations: self.readers.len() as u64,
+            total_bytes: self.log_size()?,
+            stale_bytes: self.uncompacted,
+            compression: self.compression,
+            cache: self.cache_stats(),
+            ..self.stats.clone()
         })
     }
 
+    /// Returns the size of all log generations.
+    fn log_size(&self) -> Result<u64> {
+        let mut size = 0;
+        for gen in self.readers.keys() {
+            size += fs::metadata(log_path(&self.path, *gen))?.len();
+        }
+        Ok(size)
+    }
+
+    /// Returns the hit and miss counters of the value cache, all zero if the
+    /// cache is disabled.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache
+            .as_ref()
+            .map(ValueCache::stats)
+            .unwrap_or_default()
+    }
+
     /// Sets a value from a string key to a string.
     ///
     /// If the key already exists, the value will be overwritten.
     pub fn set(&mut self, key: String, val: String) -> Result<()> {
         assert!(self.load);
+        self.stats.sets += 1;
+        if let Some(cache) = &mut self.cache {
+            cache.invalidate(&key);
+        }
 
-        let ops = self.writer.offset;
-        let cmd = Command::Set(key, val);
-        serde_json::to_writer(&mut self.writer, &cmd)?;
-        self.writer.flush()?;
+        let separate =
+            matches!(self.options.blob_threshold, Some(threshold) if val.len() > threshold);
+        let mut cmd = self.set_command(key, val);
+        let mut blob = None;
+        if separate {
+            let blob_ref = self.blobs.append(&cmd, &self.keyring)?;
+            let key = cmd.into_key().expect("Set command has a key");
+            cmd = Command::SetBlob(key, blob_ref);
+            blob = Some(blob_ref);
+        }
+        let key = cmd.key().expect("Set command has a key").to_owned();
+        let ops = self.append_change(cmd)?;
 
-        if let Command::Set(key, ..) = cmd {
-            if let Some(old_ops) = self
-                .index
-                .insert(key, (self.current_gen, (ops..self.writer.offset)).into())
-            {
-                self.uncompacted += old_ops.len;
-            }
+        let new_ops = CommandOps {
+            blob,
+            ..(self.current_gen, ops).into()
+        };
+        if let Some(old_ops) = self.index.insert(key, new_ops)? {
+            self.release(&old_ops);
         }
 
+        self.maintain()
+    }
+
+    /// Runs log compaction and blob garbage collection when they are due.
+    fn maintain(&mut self) -> Result<()> {
         if self.uncompacted > COMPACTION_THRESHOLD {
             self.compact()?;
         }
+        if !self.options.retain_changes && self.blobs.garbage() > BLOB_GC_THRESHOLD {
+            self.gc_blobs()?;
+        }
 
         Ok(())
     }
@@ -134,20 +445,67 @@ impl KvStore {
     /// Return None if the given key does not exist.
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
         assert!(self.load);
-        match self.index.get(&key) {
+        self.stats.gets += 1;
+        if let Some(value) = self.cache.as_mut().and_then(|cache| cache.get(&key)) {
+            return Ok(Some(value));
+        }
+
+        let value = self.read_value(&key)?;
+        if let (Some(cache), Some(value)) = (&mut self.cache, &value) {
+            cache.insert(key, value.clone());
+        }
+        Ok(value)
+    }
+
+    /// Gets the value of a key, or where to read it in a memory-mapped
+    /// generation.
+    ///
+    /// Reading a `Lookup::Mapped` value needs no access to the store, so a
+    /// server can decode it after releasing its lock on the store. Such
+    /// values bypass the value cache.
+    pub fn lookup(&mut self, key: String) -> Result<Lookup> {
+        let mapped = match self.index.get(&key)? {
+            Some(ops) if ops.blob.is_none() => self.maps.get(&ops.gen).map(|map| {
+                let start = ops.offset as usize;
+                MappedValue {
+                    map: map.clone(),
+                    range: start..start + ops.len as usize,
+                    keyring: self.keyring.clone(),
+                }
+            }),
+            _ => None,
+        };
+        match mapped {
+            Some(value) => {
+                self.stats.gets += 1;
+                Ok(Lookup::Mapped(value))
+            }
+            None => self.get(key).map(Lookup::Value),
+        }
+    }
+
+    /// Reads the latest value of a key from the log or its blob file.
+    fn read_value(&mut self, key: &str) -> Result<Option<String>> {
+        match self.index.get(key)? {
             None => Ok(None),
             Some(ops) => {
-                let reader = self
-                    .readers
-                    .get_mut(&ops.gen)
-                    .expect("Cannot find log reader");
-                reader.seek(SeekFrom::Start(ops.offset))?;
-                let cmd_reader = reader.take(ops.len);
-                if let Command::Set(.., value) = serde_json::from_reader(cmd_reader)? {
-                    Ok(Some(value))
-                } else {
-                    Err(KvsError::UnexpectedCommandType)
-                }
+                let cmd = match (&ops.blob, self.maps.get(&ops.gen)) {
+                    (Some(blob), _) => self.blobs.read(blob, &self.keyring)?,
+                    (None, Some(map)) => {
+                        let start = ops.offset as usize;
+                        let end = start + ops.len as usize;
+                        self.keyring
+                            .open(serde_json::from_slice(&map[start..end])?)?
+                    }
+                    (None, None) => {
+                        let reader = self
+                            .readers
+                            .get_mut(&ops.gen)
+                            .expect("Cannot find log reader");
+                        read_command(reader, ops.offset, ops.len, &self.keyring)?
+                    }
+                };
+                decode_value(cmd.unsequence().1).map(Some)
             }
         }
     }
@@ -161,50 +519,81 @@ impl KvStore {
     /// It propagates I/O or serialization errors during writing the log.
     pub fn remove(&mut self, key: String) -> Result<()> {
         assert!(self.load);
+        self.stats.removes += 1;
+        if let Some(cache) = &mut self.cache {
+            cache.invalidate(&key);
+        }
 
-        if self.index.contains_key(&key) {
-            let ops = self.writer.offset;
-            let cmd = Command::Remove(key);
-            serde_json::to_writer(&mut self.writer, &cmd)?;
-            self.writer.flush()?;
-            self.uncompacted += self.writer.offset - ops;
+        if self.index.contains_key(&key)? {
+            let ops = self.append_change(Command::Remove(key.clone()))?;
+            self.uncompacted += ops.end - ops.start;
 
-            if let Command::Remove(key) = cmd {
-                let old_ops = self.index.remove(&key).expect("Key not found");
-                self.uncompacted += old_ops.len;
-            }
+            let old_ops = self.index.remove(&key)?.expect("Key not found");
+            self.release(&old_ops);
 
-            Ok(())
+            self.maintain()
         } else {
             Err(KvsError::KeyNotFound)
         }
     }
 
     /// Clears stale entries in the log.
-    pub fn compact(&mut self) -> Result<()> {
+    ///
+    /// When encryption is configured, live records are decrypted and sealed
+    /// again with the current key instead of being copied byte for byte.
+    /// While rotating keys, blob files written before the store was opened
+    /// are rewritten too.
+    ///
+    /// Returns how much smaller the log got and how long it took.
+    pub fn compact(&mut self) -> Result<CompactionReport> {
+        let start = Instant::now();
+        self.writer.flush()?;
+        let size_before = self.log_size()?;
+        if !self.options.previous_keys.is_empty() {
+            self.collect_blobs(true)?;
+        }
+
         let compaction_gen = self.current_gen + 1;
         self.current_gen += 2;
         self.writer = self.new_log_file(self.current_gen)?;
 
         let mut compaction_writer = self.new_log_file(compaction_gen)?;
+        // The index is rewritten to point into the compaction generation, so
+        // a crash midway needs it rebuilt.
+        self.index.save_checkpoint(None)?;
+        if self.options.retain_changes {
+            self.copy_retained_changes(compaction_gen, &mut compaction_writer)?;
+        }
 
-        let mut ops: u64 = 0;
-        for (.., cmd_ops) in &mut self.index {
-            let reader = self
-                .readers
+        let mut ops = compaction_writer.offset;
+        let readers = &mut self.readers;
+        let keyring = &self.keyring;
+        self.index.update_all(|_, cmd_ops| {
+            let reader = readers
                 .get_mut(&cmd_ops.gen)
                 .expect("Cannot find log reader");
-            if reader.offset != cmd_ops.offset {
-                reader.seek(SeekFrom::Start(cmd_ops.offset))?;
-            }
-
-            let mut cmd_reader = reader.take(cmd_ops.len);
-            io::copy(&mut cmd_reader, &mut compaction_writer)?;
-            *cmd_ops = (compaction_gen, (ops..compaction_writer.offset)).into();
+            copy_record(
+                reader,
+                cmd_ops.offset,
+                cmd_ops.len,
+                &mut compaction_writer,
+                keyring,
+            )?;
+            *cmd_ops = CommandOps {
+                blob: cmd_ops.blob,
+                ..(compaction_gen, (ops..compaction_writer.offset)).into()
+            };
 
             ops = compaction_writer.offset;
-        }
+            Ok(())
+        })?;
         compaction_writer.flush()?;
+        self.changes.save(&self.path)?;
+        if self.options.mmap {
+            if let Some(map) = map_log_file(&self.path, compaction_gen)? {
+                self.maps.insert(compaction_gen, Arc::new(map));
+            }
+        }
 
         let stale_gens: Vec<u64> = self
             .readers
@@ -214,44 +603,476 @@ impl KvStore {
             .collect();
         for stale_gen in stale_gens {
             self.readers.remove(&stale_gen);
+            self.maps.remove(&stale_gen);
+            self.gen_seqs.remove(&stale_gen);
             fs::remove_file(log_path(&self.path, stale_gen))?;
         }
+        // Records left are all sealed with the current key, if any.
+        if self.keyring.is_active() {
+            self.keyring.save_verifier(&self.path)?;
+        }
 
         self.uncompacted = 0;
+        if let Some(cache) = &mut self.cache {
+            cache.clear();
+        }
+        self.save_checkpoint()?;
+
+        let report = CompactionReport {
+            reclaimed_bytes: size_before.saturating_sub(self.log_size()?),
+            duration_ms: start.elapsed().as_millis() as u64,
+        };
+        self.stats.compactions += 1;
+        self.stats.last_compaction_ms = Some(report.duration_ms);
+        self.stats.last_compaction_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|since_epoch| since_epoch.as_secs());
+        Ok(report)
+    }
+
+    /// Copies the stale changes that consumers have not acknowledged to the
+    /// start of a compaction generation, in sequence order.
+    ///
+    /// Loading the log replays them before the live records, so they do not
+    /// change the value any key ends up with.
+    fn copy_retained_changes(
+        &mut self,
+        compaction_gen: u64,
+        writer: &mut BufWriterWithOps<File>,
+    ) -> Result<()> {
+        let acknowledged = self.changes.acknowledged;
+        let mut retained = self.sequenced_records(compaction_gen, acknowledged)?;
+        retained.sort_by_key(|record| record.seq);
+        for record in retained {
+            let key = record.cmd.key().ok_or(KvsError::UnexpectedCommandType)?;
+            let live = self
+                .index
+                .get(key)?
+                .is_some_and(|ops| ops.gen == record.gen && ops.offset == record.offset);
+            if !live {
+                let reader = self
+                    .readers
+                    .get_mut(&record.gen)
+                    .expect("Cannot find log reader");
+                copy_record(reader, record.offset, record.len, writer, &self.keyring)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the changes still in the log with a sequence number above
+    /// `seq`, in sequence order.
+    ///
+    /// Unless `KvStoreOptions::retain_changes` is set, compaction drops the
+    /// changes that were overwritten or removed, so the feed may skip some.
+    /// Blob garbage collection does the same to the stale values it deletes.
+    pub fn changes_since(&mut self, seq: u64) -> Result<Vec<Change>> {
+        let retained_after = if self.options.retain_changes {
+            self.changes.acknowledged
+        } else {
+            u64::MAX
+        };
+        let mut records = self.sequenced_records(u64::MAX, seq)?;
+        // A value moved by blob garbage collection, or an interrupted
+        // compaction, leaves a change in two records. The newest one is kept.
+        records.sort_by_key(|record| (record.seq, Reverse((record.gen, record.offset))));
+        records.dedup_by_key(|record| record.seq);
+
+        let mut changes = Vec::with_capacity(records.len());
+        for record in records {
+            let (key, value) = match record.cmd {
+                Command::Set(key, value) => (key, Some(value)),
+                Command::SetCompressed(key, data) => (key, Some(compression::decompress(&data)?)),
+                Command::SetBlob(_, blob)
+                    if record.seq <= retained_after && !self.blobs.contains(blob.file) =>
+                {
+                    continue
+                }
+                Command::SetBlob(key, blob) => {
+                    let value = decode_value(self.blobs.read(&blob, &self.keyring)?)?;
+                    (key, Some(value))
+                }
+                Command::Remove(key) => (key, None),
+                _ => return Err(KvsError::UnexpectedCommandType),
+            };
+            changes.push(Change {
+                seq: record.seq,
+                key,
+                value,
+            });
+        }
+        Ok(changes)
+    }
+
+    /// Lets compaction drop the changes up to `seq`, once every consumer of
+    /// the feed has processed them.
+    pub fn acknowledge_changes(&mut self, seq: u64) -> Result<()> {
+        let seq = seq.min(self.changes.last_seq);
+        self.changes.acknowledged = self.changes.acknowledged.max(seq);
+        self.changes.save(&self.path)
+    }
+
+    /// Returns every key with its value, in key order.
+    pub fn snapshot(&mut self) -> Result<Vec<(String, String)>> {
+        let keys = self
+            .index
+            .iter()
+            .map(|entry| entry.map(|(key, _)| key))
+            .collect::<Result<Vec<String>>>()?;
+        let mut entries = Vec::with_capacity(keys.len());
+        for key in keys {
+            let value = self.read_value(&key)?.expect("Indexed key has a value");
+            entries.push((key, value));
+        }
+        Ok(entries)
+    }
+
+    /// Returns up to `limit` of the keys starting with `prefix` that sort
+    /// after `after`, with their values, in key order.
+    ///
+    /// Only the keys of the page are read from the index and the log.
+    pub fn scan_page(
+        &mut self,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        let keys = self
+            .index
+            .range(scan_start(prefix, after))
+            .take_while(|entry| match entry {
+                Ok((key, _)) => key.starts_with(prefix),
+                Err(_) => true,
+            })
+            .take(limit)
+            .map(|entry| entry.map(|(key, _)| key))
+            .collect::<Result<Vec<String>>>()?;
+        let mut entries = Vec::with_capacity(keys.len());
+        for key in keys {
+            let value = self.read_value(&key)?.expect("Indexed key has a value");
+            entries.push((key, value));
+        }
+        Ok(entries)
+    }
+
+    /// Returns the sequence number of the latest change.
+    pub fn last_seq(&self) -> u64 {
+        self.changes.last_seq
+    }
+
+    /// Reads the sequenced records above `after` of the generations below
+    /// `end_gen`.
+    fn sequenced_records(&mut self, end_gen: u64, after: u64) -> Result<Vec<SequencedRecord>> {
+        let gen_seqs = &self.gen_seqs;
+        let mut gens: Vec<u64> = self
+            .readers
+            .keys()
+            .cloned()
+            .filter(|&gen| gen < end_gen)
+            .filter(|gen| gen_seqs.get(gen).is_none_or(|&max| max > after))
+            .collect();
+        gens.sort_unstable();
+
+        let mut records = Vec::new();
+        for gen in gens {
+            let mut max_seq = 0;
+            let reader = self.readers.get_mut(&gen).expect("Cannot find log reader");
+            let mut offset = reader.seek(SeekFrom::Start(0))?;
+            let mut stream =
+                serde_json::Deserializer::from_reader(&mut *reader).into_iter::<Command>();
+            while let Some(cmd) = stream.next() {
+                let end = stream.byte_offset() as u64;
+                match self.keyring.open(cmd?)?.unsequence() {
+                    (Some(seq), cmd) if seq > after => {
+                        max_seq = max_seq.max(seq);
+                        records.push(SequencedRecord {
+                            seq,
+                            cmd,
+                            gen,
+                            offset,
+                            len: end - offset,
+                        });
+                    }
+                    (Some(seq), _) => max_seq = max_seq.max(seq),
+                    _ => {}
+                }
+                offset = end;
+            }
+            // Keep the reader position in line with its recorded offset.
+            reader.seek(SeekFrom::Start(0))?;
+            if gen != self.current_gen {
+                self.gen_seqs.insert(gen, max_seq);
+            }
+        }
+        Ok(records)
+    }
+
+    /// Moves the live values out of mostly stale blob files and deletes them.
+    ///
+    /// This runs on its own when stale blob bytes exceed a threshold, and does
+    /// not rewrite the main log beyond appending one pointer per moved value.
+    /// A pointer keeps the sequence number of the change that set the value,
+    /// so moves do not show up in the change feed.
+    pub fn gc_blobs(&mut self) -> Result<()> {
+        self.collect_blobs(false)
+    }
+
+    /// Blob files that changes not yet acknowledged still point to are kept,
+    /// even while rotating keys.
+    fn collect_blobs(&mut self, rekey: bool) -> Result<()> {
+        let mut files = self.blobs.gc_candidates(rekey);
+        if self.options.retain_changes && !files.is_empty() {
+            let referenced: HashSet<u64> = self
+                .sequenced_records(u64::MAX, self.changes.acknowledged)?
+                .into_iter()
+                .filter_map(|record| match record.cmd {
+                    Command::SetBlob(_, blob) => Some(blob.file),
+                    _ => None,
+                })
+                .collect();
+            files.retain(|file| !referenced.contains(file));
+        }
+        if files.is_empty() {
+            return Ok(());
+        }
+
+        let mut moves: Vec<(String, CommandOps, BlobRef)> = Vec::new();
+        for entry in self.index.iter() {
+            let (key, ops) = entry?;
+            if let Some(blob) = ops.blob.filter(|blob| files.contains(&blob.file)) {
+                moves.push((key, ops, blob));
+            }
+        }
+        for (key, old_ops, blob) in moves {
+            let reader = self
+                .readers
+                .get_mut(&old_ops.gen)
+                .expect("Cannot find log reader");
+            let (seq, _) =
+                read_command(reader, old_ops.offset, old_ops.len, &self.keyring)?.unsequence();
+            let value = self.blobs.read(&blob, &self.keyring)?;
+            let new_blob = self.blobs.append(&value, &self.keyring)?;
+            let cmd = Command::SetBlob(key.clone(), new_blob);
+            let ops = match seq {
+                Some(seq) => self.append(&Command::Sequenced(seq, Box::new(cmd)))?,
+                None => self.append(&cmd)?,
+            };
+            let new_ops = CommandOps {
+                blob: Some(new_blob),
+                ..(self.current_gen, ops).into()
+            };
+            if let Some(old_ops) = self.index.insert(key, new_ops)? {
+                self.release(&old_ops);
+            }
+        }
+
+        for file in files {
+            self.blobs.remove_file(file)?;
+        }
         Ok(())
     }
 
+    /// Accounts for a record that the index no longer points to.
+    fn release(&mut self, old_ops: &CommandOps) {
+        self.uncompacted += old_ops.len;
+        if let Some(blob) = &old_ops.blob {
+            self.blobs.release(blob);
+        }
+    }
+
     fn new_log_file(&mut self, gen: u64) -> Result<BufWriterWithOps<File>> {
         new_log_file(&self.path, gen, &mut self.readers)
     }
+
+    /// Appends a `set` or `remove` with the next sequence number.
+    fn append_change(&mut self, cmd: Command) -> Result<Range<u64>> {
+        self.changes.last_seq += 1;
+        self.append(&Command::Sequenced(self.changes.last_seq, Box::new(cmd)))
+    }
+
+    /// Appends a command to the current log and returns its position.
+    fn append(&mut self, cmd: &Command) -> Result<Range<u64>> {
+        let ops = self.writer.offset;
+        write_command(&mut self.writer, cmd, &self.keyring)?;
+        self.writer.flush()?;
+        Ok(ops..self.writer.offset)
+    }
+
+    /// Records that the index covers the whole log written so far.
+    fn save_checkpoint(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.index.save_checkpoint(Some(Checkpoint {
+            gen: self.current_gen,
+            offset: self.writer.offset,
+            uncompacted: self.uncompacted,
+            last_seq: self.changes.last_seq,
+        }))
+    }
+
+    /// Builds the record for a `set`, compressing the value when it is large
+    /// enough and compression actually saves space.
+    fn set_command(&mut self, key: String, val: String) -> Command {
+        match self.options.compression_threshold {
+            Some(threshold) if val.len() > threshold => {
+                let data = compression::compress(&val);
+                if data.len() < val.len() {
+                    self.compression.record(val.len(), data.len());
+                    Command::SetCompressed(key, data)
+                } else {
+                    Command::Set(key, val)
+                }
+            }
+            _ => Command::Set(key, val),
+        }
+    }
+}
+
+impl Drop for KvStore {
+    fn drop(&mut self) {
+        // Spares the next open replaying the log written since the last
+        // checkpoint.
+        let _ = self.save_checkpoint();
+    }
+}
+
+impl KvsEngine for KvStore {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        KvStore::set(self, key, value)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        KvStore::get(self, key)
+    }
+
+    fn lookup(&mut self, key: String) -> Result<Lookup> {
+        KvStore::lookup(self, key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        KvStore::remove(self, key)
+    }
+
+    fn stats(&mut self) -> Result<Stats> {
+        KvStore::stats(self)
+    }
+
+    fn compact(&mut self) -> Result<CompactionReport> {
+        KvStore::compact(self)
+    }
+
+    fn snapshot(&mut self) -> Result<Vec<(String, String)>> {
+        KvStore::snapshot(self)
+    }
+
+    fn scan_page(
+        &mut self,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        KvStore::scan_page(self, prefix, after, limit)
+    }
+
+    fn last_seq(&mut self) -> Result<u64> {
+        Ok(KvStore::last_seq(self))
+    }
+
+    fn changes_since(&mut self, seq: u64) -> Result<Vec<Change>> {
+        KvStore::changes_since(self, seq)
+    }
+
+    fn acknowledge_changes(&mut self, seq: u64) -> Result<()> {
+        KvStore::acknowledge_changes(self, seq)
+    }
+}
+
+/// The result of `KvsEngine::lookup`.
+pub enum Lookup {
+    Value(Option<String>),
+    /// A value still to be read from a memory-mapped generation.
+    Mapped(MappedValue),
+}
+
+impl Lookup {
+    /// Returns the value, reading it from its mapping if needed.
+    pub fn into_value(self) -> Result<Option<String>> {
+        match self {
+            Lookup::Value(value) => Ok(value),
+            Lookup::Mapped(value) => value.read().map(Some),
+        }
+    }
 }
 
-/// load the whole log file and store value location in the index map.
+/// A record of a memory-mapped generation, readable through `&self` from any
+/// thread.
+pub struct MappedValue {
+    map: Arc<Mmap>,
+    range: Range<usize>,
+    keyring: Arc<Keyring>,
+}
+
+impl MappedValue {
+    /// Decodes the value of the record, opening and decompressing it as
+    /// needed.
+    pub fn read(&self) -> Result<String> {
+        let cmd = self
+            .keyring
+            .open(serde_json::from_slice(&self.map[self.range.clone()])?)?;
+        decode_value(cmd.unsequence().1)
+    }
+}
+
+/// load the log file from `start` on and store value location in the index map.
 ///
+/// Raises `last_seq` to the highest sequence number found.
 /// Return how many bytes can be saved after a compaction.
+///
+/// A record the index already points to, replayed after a crash, is live
+/// and not counted as stale.
 fn load(
     gen: u64,
-    index: &mut BTreeMap<String, CommandOps>,
+    start: u64,
+    index: &mut Index,
     reader: &mut BufReaderWithOps<File>,
+    keyring: &Keyring,
+    last_seq: &mut u64,
 ) -> Result<u64> {
     let mut uncompacted: u64 = 0;
-    let mut offset: u64 = reader.seek(SeekFrom::Start(0))?;
+    let mut offset: u64 = reader.seek(SeekFrom::Start(start))?;
     let mut stream = serde_json::Deserializer::from_reader(reader).into_iter::<Command>();
     while let Some(cmd) = stream.next() {
-        let new_ops = stream.byte_offset() as u64;
-        match cmd? {
-            Command::Set(key, ..) => {
-                if let Some(ops) = index.insert(key, (gen, (offset..new_ops)).into()) {
-                    uncompacted += ops.len;
+        let new_ops = start + stream.byte_offset() as u64;
+        let (seq, cmd) = keyring.open(cmd?)?.unsequence();
+        *last_seq = (*last_seq).max(seq.unwrap_or(0));
+        match cmd {
+            Command::Set(key, ..) | Command::SetCompressed(key, ..) => {
+                let new_ops = (gen, (offset..new_ops)).into();
+                match index.insert(key, new_ops)? {
+                    Some(ops) if ops != new_ops => uncompacted += ops.len,
+                    _ => {}
+                }
+            }
+            Command::SetBlob(key, blob) => {
+                let new_ops = CommandOps {
+                    blob: Some(blob),
+                    ..(gen, (offset..new_ops)).into()
+                };
+                match index.insert(key, new_ops)? {
+                    Some(ops) if ops != new_ops => uncompacted += ops.len,
+                    _ => {}
                 }
             }
             Command::Remove(key) => {
-                if let Some(ops) = index.remove(&key) {
+                if let Some(ops) = index.remove(&key)? {
                     uncompacted += ops.len;
                 }
 
                 uncompacted += new_ops - offset;
             }
+            Command::Sealed(..) | Command::Sequenced(..) => {
+                return Err(KvsError::UnexpectedCommandType)
+            }
         }
         offset = new_ops;
     }
@@ -259,10 +1080,76 @@ fn load(
     Ok(uncompacted)
 }
 
+/// A `set` or `remove` found in the log, with the position of its record.
+struct SequencedRecord {
+    seq: u64,
+    cmd: Command,
+    gen: u64,
+    offset: u64,
+    len: u64,
+}
+
+/// Returns the value of a `Set` or `SetCompressed` command.
+fn decode_value(cmd: Command) -> Result<String> {
+    match cmd {
+        Command::Set(.., value) => Ok(value),
+        Command::SetCompressed(.., data) => compression::decompress(&data),
+        _ => Err(KvsError::UnexpectedCommandType),
+    }
+}
+
+/// Copies a record into a compaction generation, sealing it again with the
+/// current key when encryption is configured.
+fn copy_record(
+    reader: &mut BufReaderWithOps<File>,
+    offset: u64,
+    len: u64,
+    writer: &mut BufWriterWithOps<File>,
+    keyring: &Keyring,
+) -> Result<()> {
+    if keyring.is_active() {
+        let cmd = read_command(reader, offset, len, keyring)?;
+        write_command(writer, &cmd, keyring)?;
+    } else {
+        if reader.offset != offset {
+            reader.seek(SeekFrom::Start(offset))?;
+        }
+
+        let mut cmd_reader = reader.take(len);
+        io::copy(&mut cmd_reader, writer)?;
+    }
+    Ok(())
+}
+
+/// Read the command stored at the given position, decrypting it if it is sealed.
+pub(crate) fn read_command(
+    reader: &mut BufReaderWithOps<File>,
+    offset: u64,
+    len: u64,
+    keyring: &Keyring,
+) -> Result<Command> {
+    reader.seek(SeekFrom::Start(offset))?;
+    let cmd_reader = reader.take(len);
+    keyring.open(serde_json::from_reader(cmd_reader)?)
+}
+
+/// Write a command, sealing it first if a current key is configured.
+pub(crate) fn write_command<W: Write + Seek>(
+    writer: &mut BufWriterWithOps<W>,
+    cmd: &Command,
+    keyring: &Keyring,
+) -> Result<()> {
+    match keyring.seal(cmd)? {
+        Some(sealed) => serde_json::to_writer(writer, &sealed)?,
+        None => serde_json::to_writer(writer, cmd)?,
+    }
+    Ok(())
+}
+
 /// Wrapper BufWriter with offset
 pub struct BufWriterWithOps<W: Write + Seek> {
     writer: BufWriter<W>,
-    offset: u64,
+    pub(crate) offset: u64,
 }
 
 impl<W: Write + Seek> BufWriterWithOps<W> {
@@ -333,26 +1220,45 @@ impl<R: Read + Seek> Seek for BufReaderWithOps<R> {
 /// Log file with a lock file indicates a compaction failure and is invalid.
 /// This function returns valid generation numbers.
 pub fn gen_list(path: impl AsRef<Path>) -> Result<Vec<u64>> {
-    let mut gen_list: Vec<u64> = fs::read_dir(path)?
+    files_with_extension(path.as_ref(), "log")
+}
+
+/// Returns the sorted numbers of the `<n>.<extension>` files in a directory.
+pub(crate) fn files_with_extension(path: &Path, extension: &str) -> Result<Vec<u64>> {
+    let mut list: Vec<u64> = fs::read_dir(path)?
         .flat_map(|res| -> Result<_> { Ok(res?.path()) })
-        .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
+        .filter(|path| path.is_file() && path.extension() == Some(extension.as_ref()))
         .flat_map(|path| {
-            path.file_name()
+            path.file_stem()
                 .and_then(OsStr::to_str)
-                .map(|res| res.trim_end_matches(".log"))
                 .map(str::parse::<u64>)
         })
         .flatten()
         .collect();
-    gen_list.sort_unstable();
-    Ok(gen_list)
+    list.sort_unstable();
+    Ok(list)
 }
 
 /// Return path of the log file
-fn log_path(dir: &Path, gen: u64) -> PathBuf {
+pub(crate) fn log_path(dir: &Path, gen: u64) -> PathBuf {
     dir.join(format!("{}.log", gen))
 }
 
+/// Memory-maps a generation that will not be written to anymore.
+///
+/// Returns `None` for an empty file, which cannot be mapped on every platform.
+fn map_log_file(dir: &Path, gen: u64) -> Result<Option<Mmap>> {
+    let file = File::open(log_path(dir, gen))?;
+    if file.metadata()?.len() == 0 {
+        return Ok(None);
+    }
+    // Safety: generations other than the active one are never modified. A
+    // mapping outlives the deletion of its file by compaction while a
+    // `MappedValue` still shares it, which keeps the pages readable.
+    let map = unsafe { Mmap::map(&file)? };
+    Ok(Some(map))
+}
+
 fn new_log_file(
     dir: &Path,
     gen: u64,
@@ -3,15 +3,43 @@ extern crate clap;
 #[macro_use]
 extern crate slog;
 
+pub mod admin;
+#[cfg(feature = "async")]
+pub mod asynchronous;
+pub mod blob;
+pub mod cache;
+pub mod changes;
 pub mod client;
 pub mod common;
+pub mod compression;
 pub mod config;
 pub mod connection;
+pub mod crypto;
 pub mod error;
+pub mod expiry;
+mod http;
+mod index;
 pub mod kv;
+pub mod lsm;
+pub mod metrics;
+pub mod pool;
+pub mod protocol;
+pub mod proxy;
+pub mod raft;
+pub mod replication;
+mod resp;
 pub mod server;
+pub mod sharding;
+pub mod stats;
+pub mod watch;
 
+#[cfg(feature = "async")]
+pub use asynchronous::{AsyncClient, AsyncServer};
+pub use changes::Change;
 pub use config::{Config, Engine};
 pub use connection::Connection;
+pub use crypto::EncryptionKey;
 pub use error::{KvsError, Result};
-pub use kv::{Command, KvStore, KvsEngine};
+pub use kv::{Command, KvStore, KvStoreOptions, KvsEngine, Lookup, MappedValue};
+pub use lsm::{LsmKvsEngine, LsmOptions};
+pub use stats::{CompactionReport, Stats};
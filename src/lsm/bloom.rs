@@ -0,0 +1,61 @@
+//! Bloom filters letting reads skip tables that cannot hold a key.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+const BITS_PER_KEY: usize = 10;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct BloomFilter {
+    #[serde(serialize_with = "to_base64", deserialize_with = "from_base64")]
+    bits: Vec<u8>,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    /// Builds a filter sized for the given key hashes, about 1% false positives.
+    pub(crate) fn new(key_hashes: &[u64]) -> Self {
+        let bits = (key_hashes.len() * BITS_PER_KEY).max(64);
+        // k = bits per key * ln 2
+        let hashes = ((BITS_PER_KEY as f64) * 0.69) as u32;
+        let mut filter = BloomFilter {
+            bits: vec![0; bits.div_ceil(8)],
+            hashes,
+        };
+        for &hash in key_hashes {
+            for bit in filter.bit_positions(hash) {
+                filter.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        filter
+    }
+
+    /// Returns false only if the key was certainly not added.
+    pub(crate) fn may_contain(&self, key: &str) -> bool {
+        self.bit_positions(hash(key))
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    /// Double hashing: the i-th probe is `h1 + i * h2`.
+    fn bit_positions(&self, hash: u64) -> impl Iterator<Item = usize> {
+        let len = (self.bits.len() * 8) as u64;
+        let delta = hash.rotate_right(17) | 1;
+        (0..u64::from(self.hashes))
+            .map(move |i| (hash.wrapping_add(i.wrapping_mul(delta)) % len) as usize)
+    }
+}
+
+/// A 64-bit FNV-1a hash, stable across builds so filters can be persisted.
+pub(crate) fn hash(key: &str) -> u64 {
+    key.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn to_base64<S: Serializer>(bits: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&base64::encode(bits))
+}
+
+fn from_base64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let encoded = String::deserialize(deserializer)?;
+    base64::decode(encoded).map_err(serde::de::Error::custom)
+}
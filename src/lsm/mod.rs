@@ -0,0 +1,445 @@
+//! A log-structured merge-tree storage engine.
+//!
+//! Writes go to a write-ahead log (`<id>.wal`) and to a sorted in-memory
+//! memtable. Full memtables are flushed to level 0 as immutable SSTables
+//! (`<id>.sst`), which leveled compaction merges down into non-overlapping
+//! levels of growing size. The `MANIFEST` file records which table belongs to
+//! which level.
+
+mod bloom;
+mod sstable;
+
+use self::sstable::{Entry, SsTable, TableBuilder};
+use crate::kv::{files_with_extension, scan_start};
+use crate::{Command, KvsEngine, KvsError, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+
+const MANIFEST: &str = "MANIFEST";
+
+/// Tuning knobs of an `LsmKvsEngine`.
+#[derive(Debug, Clone)]
+pub struct LsmOptions {
+    /// The memtable is flushed to an SSTable once it holds this many bytes.
+    pub memtable_size: usize,
+    /// Target size of a data block inside an SSTable.
+    pub block_size: usize,
+    /// Compaction output is split into SSTables of about this size.
+    pub table_size: u64,
+    /// Level 0 is compacted once it holds this many SSTables.
+    pub level0_tables: usize,
+    /// Maximum size of level 1; every further level is ten times larger.
+    pub level1_size: u64,
+}
+
+impl Default for LsmOptions {
+    fn default() -> Self {
+        LsmOptions {
+            memtable_size: 4 * 1024 * 1024,
+            block_size: 4 * 1024,
+            table_size: 2 * 1024 * 1024,
+            level0_tables: 4,
+            level1_size: 10 * 1024 * 1024,
+        }
+    }
+}
+
+/// The set of live SSTables, persisted in the `MANIFEST` file.
+#[derive(Serialize, Deserialize, Default)]
+struct Manifest {
+    next_id: u64,
+    levels: Vec<Vec<u64>>,
+}
+
+/// `LsmKvsEngine` stores key/value pairs in a log-structured merge-tree.
+///
+///  Example:
+///
+/// ```rust
+/// # use kvs::{KvsEngine, LsmKvsEngine, Result};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// let mut engine = LsmKvsEngine::open(current_dir()?)?;
+/// engine.set("hello".to_owned(), "world".to_owned())?;
+/// assert_eq!(engine.get("hello".to_owned())?, Some("world".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+pub struct LsmKvsEngine {
+    path: PathBuf,
+    options: LsmOptions,
+    memtable: BTreeMap<String, Option<String>>,
+    memtable_size: usize,
+    wal: BufWriter<File>,
+    wal_id: u64,
+    /// Level 0 is ordered from oldest to newest, other levels by key.
+    levels: Vec<Vec<SsTable>>,
+    next_id: u64,
+}
+
+impl LsmKvsEngine {
+    /// Open the engine at a given path with default options.
+    pub fn open(path: impl Into<PathBuf>) -> Result<LsmKvsEngine> {
+        LsmKvsEngine::open_with_options(path, LsmOptions::default())
+    }
+
+    /// Open the engine at a given path with the given options.
+    ///
+    /// Write-ahead logs left by a previous run are replayed and flushed to
+    /// level 0, and tables missing from the manifest are deleted.
+    pub fn open_with_options(
+        path: impl Into<PathBuf>,
+        options: LsmOptions,
+    ) -> Result<LsmKvsEngine> {
+        let path = path.into();
+        fs::create_dir_all(&path)?;
+
+        let manifest: Manifest = match File::open(path.join(MANIFEST)) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file))?,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Manifest::default(),
+            Err(e) => return Err(e.into()),
+        };
+        let mut levels = Vec::new();
+        for ids in &manifest.levels {
+            let mut level = Vec::new();
+            for &id in ids {
+                level.push(SsTable::open(&path, id)?);
+            }
+            levels.push(level);
+        }
+        let live: Vec<u64> = manifest.levels.iter().flatten().cloned().collect();
+        for id in files_with_extension(&path, "sst")? {
+            if !live.contains(&id) {
+                fs::remove_file(sstable::table_path(&path, id))?;
+            }
+        }
+
+        let wals = files_with_extension(&path, "wal")?;
+        let next_id = wals
+            .last()
+            .map_or(manifest.next_id, |&id| manifest.next_id.max(id + 1));
+        let wal_id = next_id;
+        let wal = new_wal(&path, wal_id)?;
+
+        let mut engine = LsmKvsEngine {
+            path,
+            options,
+            memtable: BTreeMap::new(),
+            memtable_size: 0,
+            wal,
+            wal_id,
+            levels,
+            next_id: next_id + 1,
+        };
+
+        for &id in &wals {
+            let reader = BufReader::new(File::open(wal_path(&engine.path, id))?);
+            let stream = serde_json::Deserializer::from_reader(reader).into_iter::<Command>();
+            for cmd in stream {
+                match cmd? {
+                    Command::Set(key, value) => engine.insert(key, Some(value)),
+                    Command::Remove(key) => engine.insert(key, None),
+                    _ => return Err(KvsError::UnexpectedCommandType),
+                }
+            }
+        }
+        if !wals.is_empty() {
+            engine.flush_memtable()?;
+            for id in wals {
+                fs::remove_file(wal_path(&engine.path, id))?;
+            }
+        }
+
+        Ok(engine)
+    }
+
+    /// Looks a key up from the newest to the oldest data.
+    fn lookup(&mut self, key: &str) -> Result<Option<Option<String>>> {
+        if let Some(value) = self.memtable.get(key) {
+            return Ok(Some(value.clone()));
+        }
+        for (level, tables) in self.levels.iter_mut().enumerate() {
+            if level == 0 {
+                for table in tables.iter_mut().rev() {
+                    if let Some(value) = table.get(key)? {
+                        return Ok(Some(value));
+                    }
+                }
+            } else {
+                let pos = tables.partition_point(|table| table.last_key() < key);
+                if let Some(table) = tables.get_mut(pos) {
+                    if let Some(value) = table.get(key)? {
+                        return Ok(Some(value));
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns up to `limit` of the keys starting with `prefix` that sort
+    /// after `after`, with their values, in key order.
+    ///
+    /// Every table is read from the start of the page on, a page at a time.
+    /// A table that filled its page only tells which keys come next up to
+    /// its last entry, so the merged page ends there and the scan goes on
+    /// after it.
+    fn scan_range(
+        &mut self,
+        prefix: &str,
+        mut after: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        let mut entries = Vec::new();
+        while entries.len() < limit {
+            let start = scan_start(prefix, after.as_deref());
+            let wanted = limit - entries.len();
+            // Older data first, so newer values overwrite it.
+            let mut sources: Vec<Vec<Entry>> = Vec::new();
+            for level in self.levels.iter_mut().rev() {
+                for table in level.iter_mut() {
+                    sources.push(table.scan(prefix, start, wanted)?);
+                }
+            }
+            sources.push(
+                self.memtable
+                    .range::<str, _>((start, Bound::Unbounded))
+                    .take_while(|(key, _)| key.starts_with(prefix))
+                    .take(wanted)
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect(),
+            );
+
+            // Keys past the end of a full page may be missing from the merge.
+            let end = sources
+                .iter()
+                .filter(|source| source.len() == wanted)
+                .filter_map(|source| source.last())
+                .map(|(key, _)| key.clone())
+                .min();
+            let mut merged: BTreeMap<String, Option<String>> = BTreeMap::new();
+            for (key, value) in sources.into_iter().flatten() {
+                if end.as_ref().is_none_or(|end| key <= *end) {
+                    merged.insert(key, value);
+                }
+            }
+            entries.extend(
+                merged
+                    .into_iter()
+                    .filter_map(|(key, value)| value.map(|value| (key, value)))
+                    .take(wanted),
+            );
+            match end {
+                Some(end) => after = Some(end),
+                None => break,
+            }
+        }
+        Ok(entries)
+    }
+
+    fn write(&mut self, cmd: Command) -> Result<()> {
+        serde_json::to_writer(&mut self.wal, &cmd)?;
+        self.wal.flush()?;
+        match cmd {
+            Command::Set(key, value) => self.insert(key, Some(value)),
+            Command::Remove(key) => self.insert(key, None),
+            _ => return Err(KvsError::UnexpectedCommandType),
+        }
+
+        if self.memtable_size >= self.options.memtable_size {
+            self.flush_memtable()?;
+            let old_wal = self.wal_id;
+            self.wal_id = self.take_id();
+            self.wal = new_wal(&self.path, self.wal_id)?;
+            fs::remove_file(wal_path(&self.path, old_wal))?;
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, key: String, value: Option<String>) {
+        self.memtable_size += key.len() + value.as_ref().map_or(0, String::len);
+        self.memtable.insert(key, value);
+    }
+
+    /// Writes the memtable to a new level 0 table.
+    fn flush_memtable(&mut self) -> Result<()> {
+        if self.memtable.is_empty() {
+            return Ok(());
+        }
+        let mut builder = self.new_table()?;
+        for (key, value) in &self.memtable {
+            builder.add(key, value)?;
+        }
+        let table = builder.finish()?;
+        if self.levels.is_empty() {
+            self.levels.push(Vec::new());
+        }
+        self.levels[0].push(table);
+        self.save_manifest()?;
+
+        self.memtable.clear();
+        self.memtable_size = 0;
+        Ok(())
+    }
+
+    /// Runs leveled compaction until every level is within its limits.
+    fn compact(&mut self) -> Result<()> {
+        loop {
+            if self.levels.first().map_or(0, Vec::len) >= self.options.level0_tables {
+                self.compact_level(0)?;
+                continue;
+            }
+            let oversized = (1..self.levels.len()).find(|&level| {
+                let size: u64 = self.levels[level].iter().map(|table| table.size).sum();
+                size > self.options.level1_size * 10u64.pow(level as u32 - 1)
+            });
+            match oversized {
+                Some(level) => self.compact_level(level)?,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Merges all of level 0, or the first table of a deeper level, with the
+    /// overlapping tables of the next level.
+    fn compact_level(&mut self, level: usize) -> Result<()> {
+        if self.levels.len() == level + 1 {
+            self.levels.push(Vec::new());
+        }
+        let inputs: Vec<SsTable> = if level == 0 {
+            self.levels[0].drain(..).collect()
+        } else {
+            vec![self.levels[level].remove(0)]
+        };
+        let first = inputs
+            .iter()
+            .map(SsTable::first_key)
+            .min()
+            .unwrap_or("")
+            .to_owned();
+        let last = inputs
+            .iter()
+            .map(SsTable::last_key)
+            .max()
+            .unwrap_or("")
+            .to_owned();
+        let (overlapping, rest): (Vec<SsTable>, Vec<SsTable>) = self.levels[level + 1]
+            .drain(..)
+            .partition(|table| table.overlaps(&first, &last));
+        self.levels[level + 1] = rest;
+
+        // Older data first, so newer values overwrite it.
+        let mut merged: BTreeMap<String, Option<String>> = BTreeMap::new();
+        let mut old_tables = overlapping;
+        old_tables.extend(inputs);
+        for table in &mut old_tables {
+            merged.extend(table.entries()?);
+        }
+        let bottom = self.levels[level + 2..].iter().all(Vec::is_empty);
+        let outputs = self.write_tables(
+            merged
+                .into_iter()
+                .filter(|(.., value)| !bottom || value.is_some()),
+        )?;
+
+        let next = &mut self.levels[level + 1];
+        next.extend(outputs);
+        next.sort_by(|a, b| a.first_key().cmp(b.first_key()));
+        self.save_manifest()?;
+        for table in old_tables {
+            table.remove_file()?;
+        }
+        Ok(())
+    }
+
+    /// Writes sorted entries into tables of about `table_size` bytes.
+    fn write_tables(&mut self, entries: impl Iterator<Item = Entry>) -> Result<Vec<SsTable>> {
+        let mut tables = Vec::new();
+        let mut builder: Option<TableBuilder> = None;
+        for (key, value) in entries {
+            if builder.is_none() {
+                builder = Some(self.new_table()?);
+            }
+            let current = builder.as_mut().expect("Table builder is open");
+            current.add(&key, &value)?;
+            if current.size() >= self.options.table_size {
+                tables.push(builder.take().expect("Table builder is open").finish()?);
+            }
+        }
+        if let Some(builder) = builder.filter(|builder| !builder.is_empty()) {
+            tables.push(builder.finish()?);
+        }
+        Ok(tables)
+    }
+
+    fn save_manifest(&self) -> Result<()> {
+        let manifest = Manifest {
+            next_id: self.next_id,
+            levels: self
+                .levels
+                .iter()
+                .map(|level| level.iter().map(|table| table.id).collect())
+                .collect(),
+        };
+        let tmp_path = self.path.join(format!("{}.tmp", MANIFEST));
+        let mut file = File::create(&tmp_path)?;
+        serde_json::to_writer(&mut file, &manifest)?;
+        file.sync_all()?;
+        fs::rename(tmp_path, self.path.join(MANIFEST))?;
+        Ok(())
+    }
+
+    fn new_table(&mut self) -> Result<TableBuilder> {
+        let id = self.take_id();
+        TableBuilder::new(&self.path, id, self.options.block_size)
+    }
+
+    fn take_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id - 1
+    }
+}
+
+impl KvsEngine for LsmKvsEngine {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.write(Command::Set(key, value))
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        Ok(self.lookup(&key)?.and_then(|value| value))
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        match self.lookup(&key)? {
+            Some(Some(_)) => self.write(Command::Remove(key)),
+            _ => Err(KvsError::KeyNotFound),
+        }
+    }
+
+    fn scan_page(
+        &mut self,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        self.scan_range(prefix, after.map(str::to_owned), limit)
+    }
+}
+
+/// Return path of the write-ahead log
+fn wal_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.wal", id))
+}
+
+fn new_wal(dir: &Path, id: u64) -> Result<BufWriter<File>> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(wal_path(dir, id))?;
+    Ok(BufWriter::new(file))
+}
@@ -0,0 +1,247 @@
+//! Immutable sorted string tables.
+//!
+//! A table file is a sequence of data blocks holding JSON `(key, value)`
+//! entries in key order, where a `None` value is a tombstone. They are followed
+//! by a JSON `TableMeta` with the block index and the bloom filter, and by the
+//! offset of that metadata as 8 little-endian bytes.
+
+use super::bloom::{self, BloomFilter};
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::io::{BufWriter, SeekFrom};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+
+/// A key with its value, or `None` if the key was removed.
+pub(crate) type Entry = (String, Option<String>);
+
+#[derive(Serialize, Deserialize, Debug)]
+struct BlockHandle {
+    last_key: String,
+    offset: u64,
+    len: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct TableMeta {
+    first_key: String,
+    last_key: String,
+    index: Vec<BlockHandle>,
+    bloom: BloomFilter,
+}
+
+pub(crate) struct SsTable {
+    pub(crate) id: u64,
+    pub(crate) size: u64,
+    path: PathBuf,
+    file: File,
+    meta: TableMeta,
+}
+
+impl SsTable {
+    pub(crate) fn open(dir: &Path, id: u64) -> Result<SsTable> {
+        let path = table_path(dir, id);
+        let mut file = File::open(&path)?;
+        let size = file.seek(SeekFrom::End(-8))?;
+        let mut footer = [0u8; 8];
+        file.read_exact(&mut footer)?;
+        let meta_offset = u64::from_le_bytes(footer);
+
+        file.seek(SeekFrom::Start(meta_offset))?;
+        let meta = serde_json::from_reader((&mut file).take(size - meta_offset))?;
+        Ok(SsTable {
+            id,
+            size: size + 8,
+            path,
+            file,
+            meta,
+        })
+    }
+
+    pub(crate) fn first_key(&self) -> &str {
+        &self.meta.first_key
+    }
+
+    pub(crate) fn last_key(&self) -> &str {
+        &self.meta.last_key
+    }
+
+    /// Returns true if the key range of this table intersects `[first, last]`.
+    pub(crate) fn overlaps(&self, first: &str, last: &str) -> bool {
+        self.first_key() <= last && first <= self.last_key()
+    }
+
+    /// Looks a key up. Returns `Some(None)` if the table holds a tombstone for it.
+    pub(crate) fn get(&mut self, key: &str) -> Result<Option<Option<String>>> {
+        if key < self.first_key() || key > self.last_key() || !self.meta.bloom.may_contain(key) {
+            return Ok(None);
+        }
+        let block = self
+            .meta
+            .index
+            .binary_search_by(|handle| handle.last_key.as_str().cmp(key))
+            .unwrap_or_else(|block| block);
+        if block == self.meta.index.len() {
+            return Ok(None);
+        }
+        for entry in self.read_block(block)? {
+            if entry.0 == key {
+                return Ok(Some(entry.1));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Reads every entry of the table, in key order.
+    pub(crate) fn entries(&mut self) -> Result<Vec<Entry>> {
+        let mut entries = Vec::new();
+        for block in 0..self.meta.index.len() {
+            entries.extend(self.read_block(block)?);
+        }
+        Ok(entries)
+    }
+
+    /// Reads up to `limit` entries from `start` on whose keys start with
+    /// `prefix`, tombstones included, in key order.
+    pub(crate) fn scan(
+        &mut self,
+        prefix: &str,
+        start: Bound<&str>,
+        limit: usize,
+    ) -> Result<Vec<Entry>> {
+        let first_block = self
+            .meta
+            .index
+            .partition_point(|handle| before(&handle.last_key, start));
+        let mut entries = Vec::new();
+        for block in first_block..self.meta.index.len() {
+            for entry in self.read_block(block)? {
+                if before(&entry.0, start) {
+                    continue;
+                }
+                if !entry.0.starts_with(prefix) || entries.len() == limit {
+                    return Ok(entries);
+                }
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+
+    pub(crate) fn remove_file(self) -> Result<()> {
+        fs::remove_file(self.path)?;
+        Ok(())
+    }
+
+    fn read_block(&mut self, block: usize) -> Result<Vec<Entry>> {
+        let handle = &self.meta.index[block];
+        self.file.seek(SeekFrom::Start(handle.offset))?;
+        let mut data = vec![0u8; handle.len as usize];
+        self.file.read_exact(&mut data)?;
+        let entries = serde_json::Deserializer::from_slice(&data)
+            .into_iter::<Entry>()
+            .collect::<serde_json::Result<_>>()?;
+        Ok(entries)
+    }
+}
+
+/// Writes the entries of a new table, which must be added in key order.
+pub(crate) struct TableBuilder {
+    dir: PathBuf,
+    id: u64,
+    writer: BufWriter<File>,
+    block_size: usize,
+    block: Vec<u8>,
+    last_key: String,
+    first_key: Option<String>,
+    offset: u64,
+    index: Vec<BlockHandle>,
+    key_hashes: Vec<u64>,
+}
+
+impl TableBuilder {
+    pub(crate) fn new(dir: &Path, id: u64, block_size: usize) -> Result<TableBuilder> {
+        let file = File::create(table_path(dir, id))?;
+        Ok(TableBuilder {
+            dir: dir.to_owned(),
+            id,
+            writer: BufWriter::new(file),
+            block_size,
+            block: Vec::new(),
+            last_key: String::new(),
+            first_key: None,
+            offset: 0,
+            index: Vec::new(),
+            key_hashes: Vec::new(),
+        })
+    }
+
+    pub(crate) fn add(&mut self, key: &str, value: &Option<String>) -> Result<()> {
+        if self.first_key.is_none() {
+            self.first_key = Some(key.to_owned());
+        }
+        serde_json::to_writer(&mut self.block, &(key, value))?;
+        self.key_hashes.push(bloom::hash(key));
+        self.last_key.clear();
+        self.last_key.push_str(key);
+        if self.block.len() >= self.block_size {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    /// Bytes written so far, including the pending block.
+    pub(crate) fn size(&self) -> u64 {
+        self.offset + self.block.len() as u64
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.first_key.is_none()
+    }
+
+    pub(crate) fn finish(mut self) -> Result<SsTable> {
+        self.finish_block()?;
+        let meta = TableMeta {
+            first_key: self.first_key.take().unwrap_or_default(),
+            last_key: self.last_key.clone(),
+            index: self.index,
+            bloom: BloomFilter::new(&self.key_hashes),
+        };
+        serde_json::to_writer(&mut self.writer, &meta)?;
+        self.writer.write_all(&self.offset.to_le_bytes())?;
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        SsTable::open(&self.dir, self.id)
+    }
+
+    fn finish_block(&mut self) -> Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        self.writer.write_all(&self.block)?;
+        self.index.push(BlockHandle {
+            last_key: self.last_key.clone(),
+            offset: self.offset,
+            len: self.block.len() as u64,
+        });
+        self.offset += self.block.len() as u64;
+        self.block.clear();
+        Ok(())
+    }
+}
+
+/// Return path of the table file
+/// Returns whether `key` sorts before `start`.
+fn before(key: &str, start: Bound<&str>) -> bool {
+    match start {
+        Bound::Included(start) => key < start,
+        Bound::Excluded(start) => key <= start,
+        Bound::Unbounded => false,
+    }
+}
+
+pub(crate) fn table_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.sst", id))
+}
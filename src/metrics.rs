@@ -0,0 +1,274 @@
+//! Server metrics, served in the Prometheus text format.
+
+use crate::{KvsEngine, Result, Stats};
+use slog::Logger;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 10] = [
+    0.000_1, 0.000_5, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0,
+];
+
+/// How long a scrape may take to arrive or to be read, so that a stalled
+/// client cannot hold up the scrapes behind it.
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS.iter()) {
+            if seconds <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+}
+
+/// Counters shared by the connections of a `Server`.
+#[derive(Default)]
+pub struct Metrics {
+    /// Latency of served requests, by request type.
+    requests: Mutex<BTreeMap<&'static str, Histogram>>,
+    active_connections: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    /// Records a served request and the time it took.
+    pub fn observe(&self, request: &'static str, latency: Duration) {
+        let mut requests = self.requests.lock().unwrap();
+        requests
+            .entry(request)
+            .or_default()
+            .observe(latency.as_secs_f64());
+    }
+
+    pub fn connection_opened(&self) {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn active_connections(&self) -> u64 {
+        self.active_connections.load(Ordering::Relaxed)
+    }
+
+    /// Wraps a reader so that the bytes read from it are counted.
+    pub fn count_reads<R: Read>(&self, inner: R) -> Counted<'_, R> {
+        Counted {
+            inner,
+            counter: &self.bytes_read,
+        }
+    }
+
+    /// Wraps a writer so that the bytes written to it are counted.
+    pub fn count_writes<W: Write>(&self, inner: W) -> Counted<'_, W> {
+        Counted {
+            inner,
+            counter: &self.bytes_written,
+        }
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format, with the
+    /// store statistics if the engine keeps any.
+    pub fn render(&self, stats: Option<&Stats>) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP kvs_requests_total Requests served, by request type.\n");
+        out.push_str("# TYPE kvs_requests_total counter\n");
+        let requests = self.requests.lock().unwrap();
+        for (request, histogram) in requests.iter() {
+            let _ = writeln!(
+                out,
+                "kvs_requests_total{{request=\"{}\"}} {}",
+                request, histogram.count
+            );
+        }
+
+        out.push_str("# HELP kvs_request_duration_seconds Time taken to serve requests.\n");
+        out.push_str("# TYPE kvs_request_duration_seconds histogram\n");
+        for (request, histogram) in requests.iter() {
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets.iter()) {
+                let _ = writeln!(
+                    out,
+                    "kvs_request_duration_seconds_bucket{{request=\"{}\",le=\"{}\"}} {}",
+                    request, bound, count
+                );
+            }
+            let _ = writeln!(
+                out,
+                "kvs_request_duration_seconds_bucket{{request=\"{}\",le=\"+Inf\"}} {}",
+                request, histogram.count
+            );
+            let _ = writeln!(
+                out,
+                "kvs_request_duration_seconds_sum{{request=\"{}\"}} {}",
+                request, histogram.sum
+            );
+            let _ = writeln!(
+                out,
+                "kvs_request_duration_seconds_count{{request=\"{}\"}} {}",
+                request, histogram.count
+            );
+        }
+        drop(requests);
+
+        gauge(
+            &mut out,
+            "kvs_active_connections",
+            "Client connections currently open.",
+            self.active_connections.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "kvs_read_bytes_total",
+            "Bytes read from clients.",
+            self.bytes_read.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "kvs_written_bytes_total",
+            "Bytes written to clients.",
+            self.bytes_written.load(Ordering::Relaxed),
+        );
+
+        if let Some(stats) = stats {
+            counter(
+                &mut out,
+                "kvs_compactions_total",
+                "Compactions run since the store was opened.",
+                stats.compactions,
+            );
+            gauge(&mut out, "kvs_store_keys", "Live keys.", stats.keys);
+            gauge(
+                &mut out,
+                "kvs_store_bytes",
+                "Size of all log generations.",
+                stats.total_bytes,
+            );
+            gauge(
+                &mut out,
+                "kvs_store_stale_bytes",
+                "Bytes that the next compaction can reclaim.",
+                stats.stale_bytes,
+            );
+        }
+        out
+    }
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = write!(
+        out,
+        "# HELP {0} {1}\n# TYPE {0} counter\n{0} {2}\n",
+        name, help, value
+    );
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = write!(
+        out,
+        "# HELP {0} {1}\n# TYPE {0} gauge\n{0} {2}\n",
+        name, help, value
+    );
+}
+
+/// A reader or writer adding the bytes it transfers to a counter.
+pub struct Counted<'a, T> {
+    inner: T,
+    counter: &'a AtomicU64,
+}
+
+impl<T: Read> Read for Counted<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.counter.fetch_add(len as u64, Ordering::Relaxed);
+        Ok(len)
+    }
+}
+
+impl<T: Write> Write for Counted<'_, T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.counter.fetch_add(len as u64, Ordering::Relaxed);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Answers plain HTTP scrapes of `/metrics` on the listener until it fails.
+pub(crate) fn serve_http(
+    listener: TcpListener,
+    metrics: Arc<Metrics>,
+    db: Arc<Mutex<Box<dyn KvsEngine + Send>>>,
+    log: Logger,
+) {
+    for stream in listener.incoming() {
+        let result = stream
+            .map_err(Into::into)
+            .and_then(|stream| respond(stream, &metrics, &db));
+        if let Err(e) = result {
+            error!(log, "Error on serving metrics: {}", e);
+        }
+    }
+}
+
+fn respond(
+    stream: TcpStream,
+    metrics: &Metrics,
+    db: &Mutex<Box<dyn KvsEngine + Send>>,
+) -> Result<()> {
+    stream.set_read_timeout(Some(SCRAPE_TIMEOUT))?;
+    stream.set_write_timeout(Some(SCRAPE_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Skip the headers, the request has no body we care about.
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let stats = db.lock().unwrap().stats().ok();
+            ("200 OK", metrics.render(stats.as_ref()))
+        }
+        _ => ("404 Not Found", "Not found\n".to_owned()),
+    };
+    let mut writer = &stream;
+    write!(
+        writer,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    writer.flush()?;
+    Ok(())
+}
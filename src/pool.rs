@@ -0,0 +1,210 @@
+//! A pool of client connections to one server, shared between threads.
+//!
+//! Requests check a connection out of the pool, opening a new one while fewer
+//! than `max_connections` are open and waiting for one to be checked back in
+//! otherwise. Idle connections the server closed are dropped on checkout, and
+//! a connection that failed mid-request is never reused. Idempotent requests
+//! that fail on the connection, or because the server is overloaded, are
+//! retried on another one after a growing pause.
+
+use crate::client::{Client, Timeouts};
+use crate::{KvsError, Result};
+use std::net::SocketAddr;
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Options of a `ClientPool`.
+#[derive(Debug, Clone)]
+pub struct PoolOptions {
+    /// Connections kept open while idle.
+    pub min_connections: usize,
+    /// Connections open at once. Requests beyond them wait for one to free up.
+    pub max_connections: usize,
+    pub timeouts: Timeouts,
+    /// Attempts of an idempotent request after the first one failed.
+    pub retries: usize,
+    /// The pause before the first retry, doubling with every further one.
+    pub backoff: Duration,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        PoolOptions {
+            min_connections: 1,
+            max_connections: 16,
+            timeouts: Timeouts {
+                connect: Some(Duration::from_secs(5)),
+                read: Some(Duration::from_secs(30)),
+                write: Some(Duration::from_secs(30)),
+            },
+            retries: 3,
+            backoff: Duration::from_millis(50),
+        }
+    }
+}
+
+struct Connections {
+    idle: Vec<Client>,
+    /// Connections open, idle or checked out, or being opened.
+    open: usize,
+}
+
+/// A thread-safe pool of connections to a server.
+pub struct ClientPool {
+    addr: SocketAddr,
+    options: PoolOptions,
+    connections: Mutex<Connections>,
+    checked_in: Condvar,
+}
+
+impl ClientPool {
+    /// Opens `options.min_connections` connections to `addr`.
+    pub fn new(addr: SocketAddr, options: PoolOptions) -> Result<Self> {
+        let pool = ClientPool {
+            addr,
+            options,
+            connections: Mutex::new(Connections {
+                idle: Vec::new(),
+                open: 0,
+            }),
+            checked_in: Condvar::new(),
+        };
+        pool.health_check()?;
+        Ok(pool)
+    }
+
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.run(true, |client| client.get(key.clone()))
+    }
+
+    /// Sets `key` once: a failure leaves it unknown whether the server
+    /// applied the request. See `set_retryable`.
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.run(false, |client| client.set(key.clone(), value.clone()))
+    }
+
+    /// Sets `key`, retrying like `get` does. Only for callers that do not
+    /// mind the value being written twice, or over a later write of theirs.
+    pub fn set_retryable(&self, key: String, value: String) -> Result<()> {
+        self.run(true, |client| client.set(key.clone(), value.clone()))
+    }
+
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.run(false, |client| client.remove(key.clone()))
+    }
+
+    /// Returns the keys starting with `prefix` with their values, in key
+    /// order.
+    pub fn scan(&self, prefix: String) -> Result<Vec<(String, String)>> {
+        self.run(true, |client| client.scan(prefix.clone()))
+    }
+
+    /// Drops the idle connections the server closed and opens new ones up to
+    /// `min_connections`.
+    pub fn health_check(&self) -> Result<()> {
+        let missing = {
+            let mut connections = self.connections.lock().unwrap();
+            let before = connections.idle.len();
+            connections.idle.retain(Client::is_alive);
+            connections.open -= before - connections.idle.len();
+            let missing = self
+                .options
+                .min_connections
+                .saturating_sub(connections.open);
+            connections.open += missing;
+            missing
+        };
+        for opened in 0..missing {
+            match Client::connect_with(self.addr, self.options.timeouts) {
+                Ok(client) => self.check_in(client),
+                Err(e) => {
+                    self.connections.lock().unwrap().open -= missing - opened;
+                    self.checked_in.notify_all();
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the number of open connections, idle or in use.
+    pub fn open_connections(&self) -> usize {
+        self.connections.lock().unwrap().open
+    }
+
+    /// Runs `request` on a connection, retrying it if `retryable`.
+    fn run<T, F>(&self, retryable: bool, mut request: F) -> Result<T>
+    where
+        F: FnMut(&mut Client) -> Result<T>,
+    {
+        let mut backoff = self.options.backoff;
+        for _ in 0..self.options.retries {
+            match self.attempt(&mut request) {
+                Err(e) if retryable && is_transient(&e) => {
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                }
+                result => return result,
+            }
+        }
+        self.attempt(&mut request)
+    }
+
+    fn attempt<T, F>(&self, request: &mut F) -> Result<T>
+    where
+        F: FnMut(&mut Client) -> Result<T>,
+    {
+        let mut client = self.check_out()?;
+        let result = request(&mut client);
+        match &result {
+            Err(e) if is_broken(e) => self.close(),
+            _ => self.check_in(client),
+        }
+        result
+    }
+
+    fn check_out(&self) -> Result<Client> {
+        let mut connections = self.connections.lock().unwrap();
+        loop {
+            while let Some(client) = connections.idle.pop() {
+                if client.is_alive() {
+                    return Ok(client);
+                }
+                connections.open -= 1;
+            }
+            if connections.open < self.options.max_connections {
+                connections.open += 1;
+                drop(connections);
+                return Client::connect_with(self.addr, self.options.timeouts)
+                    .inspect_err(|_| self.close());
+            }
+            connections = self.checked_in.wait(connections).unwrap();
+        }
+    }
+
+    fn check_in(&self, client: Client) {
+        self.connections.lock().unwrap().idle.push(client);
+        self.checked_in.notify_one();
+    }
+
+    /// Forgets a checked out connection, letting a waiting request open
+    /// another.
+    fn close(&self) {
+        self.connections.lock().unwrap().open -= 1;
+        self.checked_in.notify_one();
+    }
+}
+
+/// Returns whether the connection a request failed on cannot be reused.
+fn is_broken(e: &KvsError) -> bool {
+    matches!(e, KvsError::Io(_) | KvsError::Protocol(_))
+}
+
+/// Returns whether a request may succeed if sent again.
+fn is_transient(e: &KvsError) -> bool {
+    matches!(
+        e,
+        KvsError::Io(_) | KvsError::Busy(_) | KvsError::Timeout | KvsError::Unavailable(_)
+    )
+}
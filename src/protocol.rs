@@ -0,0 +1,350 @@
+//! The wire protocol between clients and servers.
+//!
+//! A client opens a session with `MAGIC` and the highest protocol version it
+//! speaks. The server answers with `MAGIC` and the version both sides use,
+//! or version 0 if it speaks none of them. Every message then travels as a
+//! frame: a big-endian `u32` length followed by the bincode encoding of the
+//! message. From version 2 on, the length is followed by the big-endian `u64`
+//! id of the request the message belongs to, so a client can send many
+//! requests before reading their responses. A frame that fails to decode is
+//! skipped whole, so the session survives it.
+//!
+//! Older clients send a bare stream of JSON values instead. No JSON value
+//! starts with the first byte of `MAGIC`, so servers tell the two apart from
+//! the first byte of a connection. Sessions without ids are answered in
+//! order, and their requests are numbered from 0 as they arrive.
+
+use crate::{KvsError, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::de::{IoRead, StreamDeserializer};
+use std::convert::TryInto;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::marker::PhantomData;
+use std::net::TcpStream;
+
+/// Opens a framed session.
+pub const MAGIC: [u8; 4] = [0xfe, b'K', b'V', b'S'];
+/// The latest protocol version.
+pub const VERSION: u8 = 2;
+/// Frames longer than this end the session.
+pub const MAX_FRAME: usize = 64 * 1024 * 1024;
+
+/// Identifies a request, and the responses to it.
+pub type RequestId = u64;
+
+/// The encoding of the messages of a session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Wire {
+    /// The legacy stream of JSON values.
+    Json,
+    /// Length-prefixed bincode frames, as of version 1.
+    Framed,
+    /// Frames carrying request ids, as of version 2.
+    Pipelined,
+}
+
+impl Wire {
+    fn for_version(version: u8) -> Wire {
+        if version >= 2 {
+            Wire::Pipelined
+        } else {
+            Wire::Framed
+        }
+    }
+}
+
+/// Opens a framed session on a new connection to a server.
+pub fn handshake(stream: &mut TcpStream) -> Result<Wire> {
+    let mut hello = MAGIC.to_vec();
+    hello.push(VERSION);
+    stream.write_all(&hello)?;
+    let mut reply = [0; 5];
+    stream.read_exact(&mut reply)?;
+    if reply[..4] != MAGIC {
+        return Err(KvsError::Protocol("not a kvs server".to_owned()));
+    }
+    match reply[4] {
+        0 => Err(KvsError::Protocol(format!(
+            "server does not speak version {}",
+            VERSION
+        ))),
+        version if version > VERSION => Err(KvsError::Protocol(format!(
+            "server answered with unknown version {}",
+            version
+        ))),
+        version => Ok(Wire::for_version(version)),
+    }
+}
+
+/// Detects the wire a client speaks from its first byte, completing the
+/// handshake of a framed session.
+pub fn accept<R: BufRead, W: Write>(reader: &mut R, writer: &mut W) -> Result<Wire> {
+    if reader.fill_buf()?.first() != Some(&MAGIC[0]) {
+        return Ok(Wire::Json);
+    }
+    let mut hello = [0; 5];
+    reader.read_exact(&mut hello)?;
+    if hello[..4] != MAGIC {
+        return Err(KvsError::Protocol("bad handshake".to_owned()));
+    }
+    let version = hello[4].min(VERSION);
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&[version])?;
+    writer.flush()?;
+    if version == 0 {
+        return Err(KvsError::Protocol(
+            "client speaks no known version".to_owned(),
+        ));
+    }
+    Ok(Wire::for_version(version))
+}
+
+/// The messages of type `T` coming from the other side with the ids of their
+/// requests, until it closes the connection.
+pub struct Reader<R: Read, T> {
+    inner: ReaderInner<R, T>,
+    wire: Wire,
+    /// The id given to the next message of a session without ids.
+    next_id: RequestId,
+    last_id: RequestId,
+}
+
+enum ReaderInner<R: Read, T> {
+    Json(StreamDeserializer<'static, IoRead<R>, T>),
+    Framed(R, PhantomData<T>),
+}
+
+impl<R: Read, T: DeserializeOwned> Reader<R, T> {
+    pub fn new(reader: R, wire: Wire) -> Self {
+        let inner = match wire {
+            Wire::Json => {
+                ReaderInner::Json(serde_json::Deserializer::from_reader(reader).into_iter())
+            }
+            Wire::Framed | Wire::Pipelined => ReaderInner::Framed(reader, PhantomData),
+        };
+        Reader {
+            inner,
+            wire,
+            next_id: 0,
+            last_id: 0,
+        }
+    }
+
+    /// Reads the next message, failing if the other side closed the
+    /// connection.
+    pub fn receive(&mut self) -> Result<(RequestId, T)> {
+        self.next()
+            .unwrap_or_else(|| Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()))
+    }
+
+    /// Returns the id of the last message read, even one that failed to
+    /// decode.
+    pub fn last_id(&self) -> RequestId {
+        self.last_id
+    }
+
+    fn read(&mut self) -> Result<Option<T>> {
+        let reader = match &mut self.inner {
+            ReaderInner::Json(messages) => {
+                self.last_id = self.next_id;
+                self.next_id += 1;
+                return messages.next().transpose().map_err(KvsError::from);
+            }
+            ReaderInner::Framed(reader, _) => reader,
+        };
+        let frame = match read_frame(reader)? {
+            Some(frame) => frame,
+            None => return Ok(None),
+        };
+        let (id, message) = parse_frame(self.wire, &frame)?;
+        self.last_id = match id {
+            Some(id) => id,
+            None => {
+                self.next_id += 1;
+                self.next_id - 1
+            }
+        };
+        message.map(Some)
+    }
+}
+
+impl<R: Read, T> Reader<BufReader<R>, T> {
+    /// Returns whether more input is already buffered, in which case the
+    /// other side may be waiting for several responses at once.
+    pub fn has_buffered(&self) -> bool {
+        match &self.inner {
+            ReaderInner::Json(_) => false,
+            ReaderInner::Framed(reader, _) => !reader.buffer().is_empty(),
+        }
+    }
+}
+
+impl<R: Read, T: DeserializeOwned> Iterator for Reader<R, T> {
+    type Item = Result<(RequestId, T)>;
+
+    /// Returns the next message. A `KvsError::MalformedMessage` leaves the
+    /// reader at the start of the following message.
+    fn next(&mut self) -> Option<Result<(RequestId, T)>> {
+        self.read()
+            .map(|message| message.map(|message| (self.last_id, message)))
+            .transpose()
+    }
+}
+
+fn read_frame<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    // A session ends cleanly between frames only.
+    match reader.read(&mut len[..1])? {
+        0 => return Ok(None),
+        _ => reader.read_exact(&mut len[1..])?,
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME {
+        return Err(KvsError::Protocol(format!(
+            "frame of {} bytes is too long",
+            len
+        )));
+    }
+    let mut frame = vec![0; len];
+    reader.read_exact(&mut frame)?;
+    Ok(Some(frame))
+}
+
+/// Splits the body of a frame into the request id it carries, if the wire
+/// has them, and its message.
+fn parse_frame<T: DeserializeOwned>(
+    wire: Wire,
+    frame: &[u8],
+) -> Result<(Option<RequestId>, Result<T>)> {
+    let (id, message) = match wire {
+        Wire::Pipelined if frame.len() < 8 => {
+            return Err(KvsError::Protocol("frame without a request id".to_owned()))
+        }
+        Wire::Pipelined => {
+            let (id, message) = frame.split_at(8);
+            (
+                Some(RequestId::from_be_bytes(id.try_into().unwrap())),
+                message,
+            )
+        }
+        _ => (None, frame),
+    };
+    let message =
+        bincode::deserialize(message).map_err(|e| KvsError::MalformedMessage(e.to_string()));
+    Ok((id, message))
+}
+
+/// Appends a message belonging to request `id` to `buf`, encoded for `wire`.
+pub(crate) fn encode<T: Serialize>(
+    wire: Wire,
+    id: RequestId,
+    message: &T,
+    buf: &mut Vec<u8>,
+) -> Result<()> {
+    let start = buf.len();
+    if wire == Wire::Json {
+        serde_json::to_writer(&mut *buf, message)?;
+        return Ok(());
+    }
+    buf.extend_from_slice(&[0; 4]);
+    if wire == Wire::Pipelined {
+        buf.extend_from_slice(&id.to_be_bytes());
+    }
+    bincode::serialize_into(&mut *buf, message)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let len = buf.len() - start - 4;
+    if len > MAX_FRAME {
+        buf.truncate(start);
+        return Err(KvsError::Protocol(format!(
+            "message of {} bytes is too long",
+            len
+        )));
+    }
+    buf[start..start + 4].copy_from_slice(&(len as u32).to_be_bytes());
+    Ok(())
+}
+
+/// A message decoded from the front of a buffer.
+#[cfg(feature = "async")]
+pub(crate) struct Decoded<T> {
+    /// The bytes the message took up.
+    pub(crate) len: usize,
+    /// The request id the message carried, if the wire has them.
+    pub(crate) id: Option<RequestId>,
+    /// The message, or a `KvsError::MalformedMessage`.
+    pub(crate) message: Result<T>,
+}
+
+/// Decodes the message at the front of `buf`, unless it is incomplete.
+#[cfg(feature = "async")]
+pub(crate) fn decode<T: DeserializeOwned>(wire: Wire, buf: &[u8]) -> Result<Option<Decoded<T>>> {
+    if wire == Wire::Json {
+        let mut messages = serde_json::Deserializer::from_slice(buf).into_iter::<T>();
+        return match messages.next() {
+            Some(Ok(message)) => Ok(Some(Decoded {
+                len: messages.byte_offset(),
+                id: None,
+                message: Ok(message),
+            })),
+            Some(Err(ref e)) if e.is_eof() => Ok(None),
+            Some(Err(e)) => Err(e.into()),
+            None => Ok(None),
+        };
+    }
+    if buf.len() < 4 {
+        return Ok(None);
+    }
+    let len = u32::from_be_bytes(buf[..4].try_into().unwrap()) as usize;
+    if len > MAX_FRAME {
+        return Err(KvsError::Protocol(format!(
+            "frame of {} bytes is too long",
+            len
+        )));
+    }
+    if buf.len() < 4 + len {
+        return Ok(None);
+    }
+    let (id, message) = parse_frame(wire, &buf[4..4 + len])?;
+    Ok(Some(Decoded {
+        len: 4 + len,
+        id,
+        message,
+    }))
+}
+
+/// Sends messages to the other side.
+pub struct Writer<W: Write> {
+    writer: W,
+    wire: Wire,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(writer: W, wire: Wire) -> Self {
+        Writer { writer, wire }
+    }
+
+    /// Writes and flushes a message belonging to request `id`.
+    pub fn send<T: Serialize>(&mut self, id: RequestId, message: &T) -> Result<()> {
+        self.write(id, message)?;
+        self.flush()
+    }
+
+    /// Writes a message belonging to request `id`, leaving it buffered until
+    /// the next flush. Sessions without ids drop it.
+    pub fn write<T: Serialize>(&mut self, id: RequestId, message: &T) -> Result<()> {
+        if self.wire == Wire::Json {
+            serde_json::to_writer(&mut self.writer, message)?;
+            return Ok(());
+        }
+        let mut frame = Vec::new();
+        encode(self.wire, id, message, &mut frame)?;
+        self.writer.write_all(&frame)?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,311 @@
+//! A proxy speaking the server protocol in front of sharded back ends.
+//!
+//! Keys are spread over shards with the `sharding::HashRing` of their
+//! primaries. Writes go to the primary of the shard; reads go to the primary
+//! too, or to one of its replicas with `read_from_replicas`, falling back to
+//! the other healthy back ends of the shard. Connections to back ends are
+//! pooled and shared by every client of the proxy.
+
+use crate::client::{Client, Sent, Timeouts};
+use crate::common::{ErrorCode, Request, Response};
+use crate::protocol::{self, Reader, Writer};
+use crate::sharding::{HashRing, DEFAULT_VNODES};
+use crate::{KvsError, Result};
+use slog::Logger;
+use std::collections::HashMap;
+use std::io::{BufReader, BufWriter};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// A primary server and the followers replicating it.
+#[derive(Debug, Clone)]
+pub struct Shard {
+    pub primary: SocketAddr,
+    pub replicas: Vec<SocketAddr>,
+}
+
+pub struct ProxyConfig {
+    pub addr: SocketAddr,
+    pub shards: Vec<Shard>,
+    /// Sends reads to the replicas of a shard before its primary.
+    pub read_from_replicas: bool,
+    pub health_check_interval: Duration,
+    /// Idle connections kept open per back end.
+    pub max_idle: usize,
+    /// Timeouts of the connections to back ends, health checks included.
+    pub timeouts: Timeouts,
+    pub log: Logger,
+}
+
+impl ProxyConfig {
+    pub fn new(addr: SocketAddr, shards: Vec<Shard>, log: Logger) -> Self {
+        ProxyConfig {
+            addr,
+            shards,
+            read_from_replicas: false,
+            health_check_interval: Duration::from_secs(1),
+            max_idle: 16,
+            timeouts: Timeouts {
+                connect: Some(Duration::from_secs(5)),
+                read: Some(Duration::from_secs(30)),
+                write: Some(Duration::from_secs(30)),
+            },
+            log,
+        }
+    }
+}
+
+/// A back-end server with its pool of idle connections.
+struct Backend {
+    addr: SocketAddr,
+    idle: Mutex<Vec<Client>>,
+    max_idle: usize,
+    timeouts: Timeouts,
+    healthy: AtomicBool,
+}
+
+impl Backend {
+    fn new(addr: SocketAddr, max_idle: usize, timeouts: Timeouts) -> Self {
+        Backend {
+            addr,
+            idle: Mutex::new(Vec::new()),
+            max_idle,
+            timeouts,
+            healthy: AtomicBool::new(true),
+        }
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    /// Forwards a request over a pooled connection.
+    fn call(&self, request: &Request) -> Result<Response> {
+        if !self.is_healthy() {
+            return Err(KvsError::Unavailable(self.addr.to_string()));
+        }
+        let pooled = self.idle.lock().unwrap().pop();
+        let response = match pooled {
+            Some(mut client) => match client.try_call(request) {
+                Ok(Sent::Response(response)) => Ok((client, response)),
+                // The back end may have closed an idle connection. A request
+                // it may have run is not sent again.
+                Ok(Sent::Unsent(_)) => self.call_fresh(request),
+                Err(e) => Err(e),
+            },
+            None => self.call_fresh(request),
+        };
+        let (client, response) = response.inspect_err(|_| self.mark_down())?;
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.max_idle {
+            idle.push(client);
+        }
+        Ok(response)
+    }
+
+    fn call_fresh(&self, request: &Request) -> Result<(Client, Response)> {
+        let mut client = Client::connect_with(self.addr, self.timeouts)?;
+        let response = client.call(request)?;
+        Ok((client, response))
+    }
+
+    fn mark_down(&self) {
+        self.healthy.store(false, Ordering::Relaxed);
+        self.idle.lock().unwrap().clear();
+    }
+
+    /// Returns whether the back end answers a request on a new connection.
+    fn check(&self) -> bool {
+        let healthy = Client::connect_with(self.addr, self.timeouts)
+            .and_then(|mut client| client.call(&Request::Stats))
+            .is_ok();
+        if healthy {
+            self.healthy.store(true, Ordering::Relaxed);
+        } else {
+            self.mark_down();
+        }
+        healthy
+    }
+
+    /// Checks the back end every `interval`, logging when it goes up or down.
+    fn watch_health(&self, interval: Duration, log: &Logger) {
+        loop {
+            let was_healthy = self.is_healthy();
+            let healthy = self.check();
+            if healthy != was_healthy {
+                let state = if healthy { "up" } else { "down" };
+                warn!(log, "Back end {} is {}", self.addr, state);
+            }
+            thread::sleep(interval);
+        }
+    }
+}
+
+struct ShardBackends {
+    primary: Arc<Backend>,
+    replicas: Vec<Arc<Backend>>,
+    /// Round-robin position over the replicas.
+    next_replica: AtomicUsize,
+}
+
+impl ShardBackends {
+    /// Returns the back ends to try for a read, in order.
+    fn readers(&self, from_replicas: bool) -> Vec<&Arc<Backend>> {
+        let start = self.next_replica.fetch_add(1, Ordering::Relaxed);
+        let n = self.replicas.len();
+        let replicas = (0..n).map(|i| &self.replicas[(start + i) % n.max(1)]);
+        if from_replicas {
+            replicas.chain(Some(&self.primary)).collect()
+        } else {
+            Some(&self.primary).into_iter().chain(replicas).collect()
+        }
+    }
+
+    fn write(&self, request: &Request) -> Result<Response> {
+        self.primary.call(request)
+    }
+
+    fn read(&self, request: &Request, from_replicas: bool) -> Result<Response> {
+        let mut last_error = None;
+        for backend in self.readers(from_replicas) {
+            if !backend.is_healthy() {
+                continue;
+            }
+            match backend.call(request) {
+                Ok(response) => return Ok(response),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| KvsError::Unavailable(self.primary.addr.to_string())))
+    }
+}
+
+pub struct Proxy {
+    config: ProxyConfig,
+    ring: HashRing,
+    shards: HashMap<SocketAddr, ShardBackends>,
+}
+
+impl Proxy {
+    pub fn new(config: ProxyConfig) -> Self {
+        let mut ring = HashRing::new(DEFAULT_VNODES);
+        let mut shards = HashMap::new();
+        for shard in &config.shards {
+            ring.add(shard.primary);
+            let backend = |addr| Arc::new(Backend::new(addr, config.max_idle, config.timeouts));
+            shards.insert(
+                shard.primary,
+                ShardBackends {
+                    primary: backend(shard.primary),
+                    replicas: shard.replicas.iter().copied().map(backend).collect(),
+                    next_replica: AtomicUsize::new(0),
+                },
+            );
+        }
+        Proxy {
+            config,
+            ring,
+            shards,
+        }
+    }
+
+    /// Serves clients until the listener fails.
+    pub fn run(self) -> Result<()> {
+        let listener = TcpListener::bind(self.config.addr)?;
+        let proxy = Arc::new(self);
+
+        // Each back end is checked on its own thread, so that one that hangs
+        // does not hold up the checks of the others.
+        for shard in proxy.shards.values() {
+            for backend in Some(&shard.primary).into_iter().chain(&shard.replicas) {
+                let backend = backend.clone();
+                let interval = proxy.config.health_check_interval;
+                let log = proxy.config.log.clone();
+                thread::spawn(move || backend.watch_health(interval, &log));
+            }
+        }
+
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let proxy = proxy.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = proxy.serve(stream) {
+                            error!(proxy.config.log, "Error on serving client: {}", e);
+                        }
+                    });
+                }
+                Err(e) => error!(proxy.config.log, "Error on new connection: {}", e),
+            }
+        }
+        Ok(())
+    }
+
+    fn serve(&self, stream: TcpStream) -> Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+        let wire = protocol::accept(&mut reader, &mut writer)?;
+        let mut writer = Writer::new(writer, wire);
+        let mut requests = Reader::<_, Request>::new(reader, wire);
+        loop {
+            if !requests.has_buffered() {
+                writer.flush()?;
+            }
+            let response = match requests.next() {
+                Some(Ok((_, request))) => self.handle(&request),
+                Some(Err(e @ KvsError::MalformedMessage(_))) => Err(e),
+                Some(Err(e)) => return Err(e),
+                None => break,
+            };
+            let response = response.unwrap_or_else(|e| Response::Err(e.into()));
+            writer.write(requests.last_id(), &response)?;
+        }
+        Ok(())
+    }
+
+    fn handle(&self, request: &Request) -> Result<Response> {
+        let from_replicas = self.config.read_from_replicas;
+        match request {
+            Request::Set { key, .. } | Request::Remove { key } => self.shard(key)?.write(request),
+            Request::Get { key } => self.shard(key)?.read(request, from_replicas),
+            Request::Scan { limit, .. } => {
+                let responses = thread::scope(|scope| {
+                    let reads: Vec<_> = self
+                        .shards
+                        .values()
+                        .map(|shard| scope.spawn(move || shard.read(request, from_replicas)))
+                        .collect();
+                    reads
+                        .into_iter()
+                        .map(|read| read.join().expect("Scan panicked"))
+                        .collect::<Result<Vec<_>>>()
+                })?;
+                let mut entries = Vec::new();
+                for response in responses {
+                    match response {
+                        Response::Entries(shard_entries) => entries.extend(shard_entries),
+                        other => return Ok(other),
+                    }
+                }
+                entries.sort();
+                // Each shard sent a page of its own.
+                entries.truncate(limit.unwrap_or(usize::MAX));
+                Ok(Response::Entries(entries))
+            }
+            other => Ok(Response::Err(ErrorCode::Other(format!(
+                "Request {} is not supported by kvs-proxy",
+                other.name()
+            )))),
+        }
+    }
+
+    fn shard(&self, key: &str) -> Result<&ShardBackends> {
+        self.ring
+            .server_for(key)
+            .and_then(|primary| self.shards.get(&primary))
+            .ok_or_else(|| KvsError::StringErr("No back ends configured".to_owned()))
+    }
+}
@@ -0,0 +1,479 @@
+//! A cluster of servers replicating `Command`s through a Raft log.
+//!
+//! Writes go to the leader, which appends them to its log and applies them to
+//! its store once a majority of the members stored them. Followers apply the
+//! same entries in the same order. Applied entries are periodically replaced
+//! by a snapshot of the store, taken right before compacting it, and a
+//! follower that falls behind the snapshot is sent the snapshot instead.
+//!
+//! Membership changes add or remove one node at a time, as a log entry that
+//! takes effect as soon as it is appended.
+
+mod node;
+mod storage;
+mod transport;
+
+pub use self::node::{Entry, Members, Message, Payload, Role, Snapshot};
+pub use self::transport::{LocalNetwork, TcpTransport, Transport};
+
+use self::node::{HardState, Node, Ticks, Unstable};
+use self::storage::Storage;
+use crate::replication;
+use crate::watch::Watchers;
+use crate::{Command, KvsEngine, KvsError, Lookup, Result};
+use serde::{Deserialize, Serialize};
+use slog::Logger;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often a read polls for the leader to confirm it leads and catch up.
+const READ_WAIT: Duration = Duration::from_millis(5);
+
+/// Settings of a cluster node.
+#[derive(Debug, Clone)]
+pub struct RaftConfig {
+    pub id: u64,
+    /// The address clients and peers reach this node at.
+    pub addr: String,
+    /// The initial members, including this node. A node started with no
+    /// members waits for a leader to add it.
+    pub members: Members,
+    /// Where the Raft log and snapshot are kept; in memory only if `None`.
+    pub dir: Option<PathBuf>,
+    pub tick: Duration,
+    /// A follower campaigns after this many ticks or up to twice as many
+    /// without hearing from a leader.
+    pub election_ticks: u32,
+    pub heartbeat_ticks: u32,
+    /// Applied entries are replaced by a snapshot once there are this many.
+    pub snapshot_entries: u64,
+    /// How long a write waits to be committed.
+    pub proposal_timeout: Duration,
+}
+
+impl RaftConfig {
+    /// Returns the settings of a single-node cluster.
+    pub fn new(id: u64, addr: String) -> Self {
+        let mut members = Members::new();
+        members.insert(id, addr.clone());
+        RaftConfig {
+            id,
+            addr,
+            members,
+            dir: None,
+            tick: Duration::from_millis(50),
+            election_ticks: 10,
+            heartbeat_ticks: 2,
+            snapshot_entries: 1000,
+            proposal_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// The state of a cluster node.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ClusterStatus {
+    pub id: u64,
+    pub role: Role,
+    pub term: u64,
+    pub leader: Option<u64>,
+    pub leader_addr: Option<String>,
+    pub commit_index: u64,
+    pub applied_index: u64,
+    pub snapshot_index: u64,
+    pub members: Members,
+}
+
+enum Input {
+    Message(u64, String, Message),
+    /// New entries were proposed and should be sent out now.
+    Wake,
+    Stop,
+}
+
+/// A proposal waiting to be applied, by log index.
+type Waiters = HashMap<u64, (u64, Sender<Result<()>>)>;
+
+struct Shared {
+    node: Node,
+    waiters: Waiters,
+    /// The last index applied to the store.
+    applied: u64,
+}
+
+/// A running cluster node, applying the committed log to a store.
+pub struct Raft {
+    addr: String,
+    config: RaftConfig,
+    shared: Mutex<Shared>,
+    inbox: Sender<Input>,
+    db: Arc<Mutex<Box<dyn KvsEngine + Send>>>,
+    watchers: Arc<Watchers>,
+    transport: Arc<dyn Transport>,
+    log: Logger,
+}
+
+impl Raft {
+    /// Starts a node that applies its log to `db`, publishing the changes to
+    /// `watchers`.
+    pub fn start(
+        config: RaftConfig,
+        db: Arc<Mutex<Box<dyn KvsEngine + Send>>>,
+        watchers: Arc<Watchers>,
+        transport: Arc<dyn Transport>,
+        log: Logger,
+    ) -> Result<Arc<Raft>> {
+        let (mut storage, hard_state) = match &config.dir {
+            Some(dir) => {
+                let (storage, hard_state) = Storage::open(dir)?;
+                (Some(storage), hard_state)
+            }
+            None => (None, HardState::default()),
+        };
+        let hard_state = if hard_state.term == 0 && hard_state.snapshot.index == 0 {
+            let snapshot = Snapshot {
+                members: config.members.clone(),
+                ..Snapshot::default()
+            };
+            // No log entry holds the initial members, so they are kept as an
+            // empty snapshot for the node to find them after a restart.
+            if let Some(storage) = &mut storage {
+                storage.save(Unstable {
+                    hard_state: None,
+                    snapshot: Some(&snapshot),
+                    first: 1,
+                    entries: &[],
+                })?;
+            }
+            HardState {
+                snapshot,
+                ..hard_state
+            }
+        } else {
+            hard_state
+        };
+        let ticks = Ticks {
+            election: config.election_ticks,
+            heartbeat: config.heartbeat_ticks,
+        };
+        let applied = hard_state.applied;
+        let node = Node::new(config.id, config.addr.clone(), hard_state, ticks);
+        let (inbox, receiver) = channel();
+        let raft = Arc::new(Raft {
+            addr: config.addr.clone(),
+            shared: Mutex::new(Shared {
+                node,
+                waiters: HashMap::new(),
+                applied,
+            }),
+            inbox,
+            db,
+            watchers,
+            transport,
+            log: log.new(o!("node-id"=>config.id)),
+            config,
+        });
+        let driver = raft.clone();
+        thread::spawn(move || driver.run(storage, receiver));
+        Ok(raft)
+    }
+
+    pub fn address(&self) -> &str {
+        &self.addr
+    }
+
+    /// Hands a message from node `from`, reachable at `addr`, to this node.
+    pub fn receive(&self, from: u64, addr: String, message: Message) {
+        let _ = self.inbox.send(Input::Message(from, addr, message));
+    }
+
+    /// Stops the node. Its store is left as it is.
+    pub fn stop(&self) {
+        let _ = self.inbox.send(Input::Stop);
+    }
+
+    pub fn status(&self) -> ClusterStatus {
+        let shared = self.shared.lock().unwrap();
+        let node = &shared.node;
+        ClusterStatus {
+            id: node.id(),
+            role: node.role(),
+            term: node.term(),
+            leader: node.leader(),
+            leader_addr: node.leader_address(),
+            commit_index: node.commit_index(),
+            applied_index: shared.applied,
+            snapshot_index: node.snapshot_index(),
+            members: node.members().clone(),
+        }
+    }
+
+    /// Sets a key through the log.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::NotLeader` unless this node is the leader, and
+    /// `KvsError::Timeout` if the write was not committed in time.
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.propose(Payload::Command(Command::Set(key, value)))
+    }
+
+    /// Removes a key through the log.
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.propose(Payload::Command(Command::Remove(key)))
+    }
+
+    /// Reads a key from the store of the leader.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.wait_for_reads()?;
+        let lookup = self.db.lock().unwrap().lookup(key);
+        lookup.and_then(Lookup::into_value)
+    }
+
+    /// Reads a page of the keys starting with `prefix` from the store of
+    /// the leader, as `KvsEngine::scan_page` does.
+    pub fn scan_page(
+        &self,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        self.wait_for_reads()?;
+        self.db.lock().unwrap().scan_page(prefix, after, limit)
+    }
+
+    /// Waits until a quorum confirmed that this node still leads, and its
+    /// store applied every write committed before the read.
+    fn wait_for_reads(&self) -> Result<()> {
+        let deadline = Instant::now() + self.config.proposal_timeout;
+        let mut read = None;
+        loop {
+            let started = {
+                let mut shared = self.shared.lock().unwrap();
+                if shared.node.role() != Role::Leader {
+                    return Err(KvsError::NotLeader(shared.node.leader_address()));
+                }
+                match read {
+                    // A new leader knows what was committed only once an
+                    // entry of its own term is.
+                    None if shared.node.in_current_term(shared.node.commit_index()) => {
+                        read = Some(shared.node.read_index()?);
+                        true
+                    }
+                    Some((round, index))
+                        if shared.node.read_confirmed(round) && shared.applied >= index =>
+                    {
+                        break;
+                    }
+                    _ => false,
+                }
+            };
+            if started {
+                let _ = self.inbox.send(Input::Wake);
+            }
+            if Instant::now() >= deadline {
+                return Err(KvsError::Timeout);
+            }
+            thread::sleep(READ_WAIT);
+        }
+        Ok(())
+    }
+
+    /// Adds node `id`, reachable at `addr`, to the cluster.
+    pub fn add_member(&self, id: u64, addr: String) -> Result<()> {
+        let mut members = self.leader_members()?;
+        members.insert(id, addr);
+        self.propose(Payload::Members(members))
+    }
+
+    /// Removes node `id` from the cluster.
+    pub fn remove_member(&self, id: u64) -> Result<()> {
+        let mut members = self.leader_members()?;
+        if members.remove(&id).is_none() {
+            return Err(KvsError::StringErr(format!("Node {} is not a member", id)));
+        }
+        self.propose(Payload::Members(members))
+    }
+
+    fn check_leader(&self) -> Result<()> {
+        let shared = self.shared.lock().unwrap();
+        match shared.node.role() {
+            Role::Leader => Ok(()),
+            _ => Err(KvsError::NotLeader(shared.node.leader_address())),
+        }
+    }
+
+    fn leader_members(&self) -> Result<Members> {
+        self.check_leader()?;
+        Ok(self.shared.lock().unwrap().node.members().clone())
+    }
+
+    fn propose(&self, payload: Payload) -> Result<()> {
+        let (sender, receiver) = channel();
+        {
+            let mut shared = self.shared.lock().unwrap();
+            let (index, term) = shared.node.propose(payload)?;
+            shared.waiters.insert(index, (term, sender));
+        }
+        let _ = self.inbox.send(Input::Wake);
+        receiver
+            .recv_timeout(self.config.proposal_timeout)
+            .unwrap_or(Err(KvsError::Timeout))
+    }
+
+    fn run(&self, mut storage: Option<Storage>, inbox: Receiver<Input>) {
+        let mut next_tick = Instant::now() + self.config.tick;
+        loop {
+            let timeout = next_tick.saturating_duration_since(Instant::now());
+            match inbox.recv_timeout(timeout) {
+                Ok(Input::Message(from, addr, message)) => {
+                    let mut shared = self.shared.lock().unwrap();
+                    shared.node.learn_address(from, addr);
+                    shared.node.step(from, message);
+                }
+                Ok(Input::Wake) | Err(RecvTimeoutError::Timeout) => {}
+                Ok(Input::Stop) | Err(RecvTimeoutError::Disconnected) => return,
+            }
+            if Instant::now() >= next_tick {
+                self.shared.lock().unwrap().node.tick();
+                next_tick += self.config.tick;
+            }
+            if let Err(e) = self.process(storage.as_mut()) {
+                // Without its storage the node cannot keep its promises.
+                error!(self.log, "Raft node stopped: {}", e);
+                return;
+            }
+        }
+    }
+
+    /// Persists the node, sends its messages and applies its committed
+    /// entries.
+    fn process(&self, mut storage: Option<&mut Storage>) -> Result<()> {
+        let (install, committed, messages) = {
+            let mut shared = self.shared.lock().unwrap();
+            let shared = &mut *shared;
+            if let Some(storage) = &mut storage {
+                storage.save(shared.node.unstable())?;
+            }
+            let messages: Vec<_> = shared
+                .node
+                .take_messages()
+                .into_iter()
+                .filter_map(|(to, message)| {
+                    shared
+                        .node
+                        .address(to)
+                        .map(|addr| (to, addr.clone(), message))
+                })
+                .collect();
+            let committed: Vec<_> = shared
+                .node
+                .take_committed()
+                .into_iter()
+                .map(|(index, entry)| {
+                    let waiter = shared.waiters.remove(&index);
+                    (index, entry, waiter)
+                })
+                .collect();
+            (shared.node.take_install(), committed, messages)
+        };
+
+        let id = self.config.id;
+        for (to, addr, message) in messages {
+            self.transport.send(id, to, &addr, message);
+        }
+
+        if let Some(snapshot) = install {
+            info!(self.log, "Installing snapshot at {}", snapshot.index);
+            replication::apply_snapshot(&self.db, &self.watchers, snapshot.data)?;
+            self.shared.lock().unwrap().applied = snapshot.index;
+            if let Some(storage) = &mut storage {
+                storage.save_applied(snapshot.index)?;
+            }
+        }
+        let mut last_applied = None;
+        for (index, entry, waiter) in committed {
+            let result = self.apply(entry.payload);
+            if let Err(ref e) = result {
+                if waiter.is_none() && !matches!(e, KvsError::KeyNotFound) {
+                    error!(self.log, "Unable to apply entry {}: {}", index, e);
+                }
+            }
+            if let Some((term, sender)) = waiter {
+                // Another leader overwrote the proposal at this index.
+                let result = if term == entry.term {
+                    result
+                } else {
+                    Err(KvsError::NotLeader(None))
+                };
+                let _ = sender.send(result);
+            }
+            last_applied = Some(index);
+        }
+
+        if let Some(index) = last_applied {
+            self.shared.lock().unwrap().applied = index;
+            if let Some(storage) = storage {
+                storage.save_applied(index)?;
+            }
+            self.maybe_snapshot(index);
+        }
+        Ok(())
+    }
+
+    fn apply(&self, payload: Payload) -> Result<()> {
+        let mut db = self.db.lock().unwrap();
+        match payload {
+            Payload::Command(Command::Set(key, value)) => {
+                db.set(key.clone(), value.clone())?;
+                self.watchers.publish(&mut **db, key, Some(value))?;
+            }
+            Payload::Command(Command::Remove(key)) => {
+                db.remove(key.clone())?;
+                self.watchers.publish(&mut **db, key, None)?;
+            }
+            Payload::Command(command) => {
+                warn!(self.log, "Ignoring {} in the Raft log", command.name());
+            }
+            Payload::Members(members) => {
+                info!(self.log, "Cluster members: {:?}", members);
+            }
+            Payload::Noop => {}
+        }
+        Ok(())
+    }
+
+    /// Replaces the log up to `index` with a snapshot once it is long enough,
+    /// compacting the store at the same time.
+    fn maybe_snapshot(&self, index: u64) {
+        let snapshot_index = self.shared.lock().unwrap().node.snapshot_index();
+        if index - snapshot_index < self.config.snapshot_entries {
+            return;
+        }
+        let data = {
+            let mut db = self.db.lock().unwrap();
+            let data = match db.snapshot() {
+                Ok(data) => data,
+                Err(e) => {
+                    warn!(self.log, "Unable to snapshot the store: {}", e);
+                    return;
+                }
+            };
+            match db.compact() {
+                Ok(report) => debug!(
+                    self.log,
+                    "Compacted the store, reclaimed {} bytes", report.reclaimed_bytes
+                ),
+                Err(KvsError::Unsupported(_)) => {}
+                Err(e) => warn!(self.log, "Unable to compact the store: {}", e),
+            }
+            data
+        };
+        self.shared.lock().unwrap().node.compact(index, data);
+        info!(self.log, "Took a snapshot at {}", index);
+    }
+}
@@ -0,0 +1,843 @@
+//! The Raft algorithm as a state machine driven by ticks and messages.
+//!
+//! A `Node` does no I/O: the driver in `raft::Raft` persists what it marks
+//! unstable, delivers the messages it queues and applies the entries it
+//! commits.
+
+use crate::{Command, KvsError, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Most entries sent in one `AppendEntries`.
+const MAX_ENTRIES: usize = 256;
+
+/// Cluster members by node id, with the address clients reach them at.
+pub type Members = BTreeMap<u64, String>;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Payload {
+    /// Appended by a new leader to commit the entries of earlier terms.
+    Noop,
+    Command(Command),
+    /// Replaces the members of the cluster. It takes effect as soon as it is
+    /// appended, and only one change may be uncommitted at a time.
+    Members(Members),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Entry {
+    pub term: u64,
+    pub payload: Payload,
+}
+
+/// The state of the store after applying every entry up to `index`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Snapshot {
+    pub index: u64,
+    pub term: u64,
+    pub members: Members,
+    pub data: Vec<(String, String)>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Message {
+    RequestVote {
+        term: u64,
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    Vote {
+        term: u64,
+        granted: bool,
+    },
+    /// `round` numbers the broadcasts of the leader, and comes back in the
+    /// answer.
+    AppendEntries {
+        term: u64,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<Entry>,
+        leader_commit: u64,
+        round: u64,
+    },
+    /// On success, `match_index` is the last entry known to match the
+    /// leader's log; on failure, the index the leader should retry after.
+    AppendResponse {
+        term: u64,
+        success: bool,
+        match_index: u64,
+        round: u64,
+    },
+    InstallSnapshot {
+        term: u64,
+        snapshot: Snapshot,
+    },
+}
+
+impl Message {
+    fn term(&self) -> u64 {
+        match *self {
+            Message::RequestVote { term, .. }
+            | Message::Vote { term, .. }
+            | Message::AppendEntries { term, .. }
+            | Message::AppendResponse { term, .. }
+            | Message::InstallSnapshot { term, .. } => term,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// What a node must remember across restarts.
+#[derive(Debug, Default)]
+pub(crate) struct HardState {
+    pub(crate) term: u64,
+    pub(crate) voted_for: Option<u64>,
+    pub(crate) snapshot: Snapshot,
+    /// The entries after the snapshot.
+    pub(crate) log: Vec<Entry>,
+    /// The last index the store applied.
+    pub(crate) applied: u64,
+}
+
+/// Timing of a node, counted in ticks.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Ticks {
+    /// A follower campaigns after hearing nothing for a random number of
+    /// ticks between this and twice this.
+    pub(crate) election: u32,
+    pub(crate) heartbeat: u32,
+}
+
+pub(crate) struct Node {
+    id: u64,
+    ticks: Ticks,
+    term: u64,
+    voted_for: Option<u64>,
+    snapshot: Snapshot,
+    log: Vec<Entry>,
+    commit: u64,
+    /// The last index handed to the driver to apply.
+    applied: u64,
+    role: Role,
+    leader: Option<u64>,
+    /// Addresses of every node ever heard of, for delivering messages.
+    addresses: HashMap<u64, String>,
+    votes: HashSet<u64>,
+    next_index: HashMap<u64, u64>,
+    match_index: HashMap<u64, u64>,
+    /// Followers that answered since the leader last checked its quorum.
+    recent_active: HashSet<u64>,
+    /// The number of the latest broadcast of the leader.
+    round: u64,
+    /// The latest round each follower answered in the current term.
+    acked_rounds: HashMap<u64, u64>,
+    elapsed: u32,
+    election_timeout: u32,
+    rng: u64,
+    outbox: Vec<(u64, Message)>,
+    /// A snapshot received from the leader, to load into the store.
+    install: Option<Snapshot>,
+    /// The term or vote changed since the last `persisted` call.
+    hard_state_changed: bool,
+    /// The snapshot changed since the last `persisted` call.
+    snapshot_changed: bool,
+    /// The log up to this index is persisted.
+    stable_index: u64,
+}
+
+impl Node {
+    pub(crate) fn new(id: u64, addr: String, state: HardState, ticks: Ticks) -> Node {
+        let mut addresses: HashMap<u64, String> = state
+            .snapshot
+            .members
+            .iter()
+            .map(|(id, addr)| (*id, addr.clone()))
+            .collect();
+        for entry in &state.log {
+            if let Payload::Members(members) = &entry.payload {
+                addresses.extend(members.iter().map(|(id, addr)| (*id, addr.clone())));
+            }
+        }
+        addresses.insert(id, addr);
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        let stable_index = state.snapshot.index + state.log.len() as u64;
+        let snapshot_index = state.snapshot.index;
+        // Replay starts after what the store holds, or at the snapshot if
+        // the store is behind it.
+        let applied = if state.applied >= snapshot_index {
+            state.applied.min(stable_index)
+        } else {
+            snapshot_index
+        };
+        let mut node = Node {
+            id,
+            ticks,
+            term: state.term,
+            voted_for: state.voted_for,
+            commit: applied,
+            applied,
+            install: if snapshot_index > state.applied {
+                Some(state.snapshot.clone())
+            } else {
+                None
+            },
+            snapshot: state.snapshot,
+            log: state.log,
+            role: Role::Follower,
+            leader: None,
+            addresses,
+            votes: HashSet::new(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            recent_active: HashSet::new(),
+            round: 0,
+            acked_rounds: HashMap::new(),
+            elapsed: 0,
+            election_timeout: 0,
+            rng: seed ^ id.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1,
+            outbox: Vec::new(),
+            hard_state_changed: false,
+            snapshot_changed: false,
+            stable_index,
+        };
+        node.reset_election_timeout();
+        node
+    }
+
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub(crate) fn role(&self) -> Role {
+        self.role
+    }
+
+    pub(crate) fn term(&self) -> u64 {
+        self.term
+    }
+
+    pub(crate) fn leader(&self) -> Option<u64> {
+        self.leader
+    }
+
+    pub(crate) fn commit_index(&self) -> u64 {
+        self.commit
+    }
+
+    /// Returns whether the entry at `index` was appended in the current
+    /// term.
+    pub(crate) fn in_current_term(&self, index: u64) -> bool {
+        self.term_at(index) == Some(self.term)
+    }
+
+    pub(crate) fn snapshot_index(&self) -> u64 {
+        self.snapshot.index
+    }
+
+    pub(crate) fn address(&self, id: u64) -> Option<&String> {
+        self.addresses.get(&id)
+    }
+
+    pub(crate) fn leader_address(&self) -> Option<String> {
+        self.leader.and_then(|id| self.address(id).cloned())
+    }
+
+    /// Records the address a node sent a message from.
+    pub(crate) fn learn_address(&mut self, id: u64, addr: String) {
+        self.addresses.insert(id, addr);
+    }
+
+    pub(crate) fn last_index(&self) -> u64 {
+        self.snapshot.index + self.log.len() as u64
+    }
+
+    fn last_term(&self) -> u64 {
+        self.term_at(self.last_index()).unwrap()
+    }
+
+    /// Returns the term of the entry at `index`, unless it was compacted
+    /// away or is not there yet.
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot.index {
+            Some(self.snapshot.term)
+        } else if index < self.snapshot.index || index > self.last_index() {
+            None
+        } else {
+            Some(self.entry(index).term)
+        }
+    }
+
+    fn entry(&self, index: u64) -> &Entry {
+        &self.log[(index - self.snapshot.index - 1) as usize]
+    }
+
+    /// Returns the members of the latest configuration in the log, committed
+    /// or not.
+    pub(crate) fn members(&self) -> &Members {
+        self.members_at(self.last_index())
+    }
+
+    fn members_at(&self, index: u64) -> &Members {
+        self.log[..(index - self.snapshot.index) as usize]
+            .iter()
+            .rev()
+            .find_map(|entry| match &entry.payload {
+                Payload::Members(members) => Some(members),
+                _ => None,
+            })
+            .unwrap_or(&self.snapshot.members)
+    }
+
+    /// Returns the index of the latest configuration in the log.
+    fn members_index(&self) -> u64 {
+        self.log
+            .iter()
+            .rposition(|entry| matches!(entry.payload, Payload::Members(_)))
+            .map_or(self.snapshot.index, |i| self.snapshot.index + 1 + i as u64)
+    }
+
+    fn quorum(&self) -> usize {
+        self.members().len() / 2 + 1
+    }
+
+    fn peers(&self) -> Vec<u64> {
+        self.members()
+            .keys()
+            .copied()
+            .filter(|&id| id != self.id)
+            .collect()
+    }
+
+    fn send(&mut self, to: u64, message: Message) {
+        self.outbox.push((to, message));
+    }
+
+    fn reset_election_timeout(&mut self) {
+        // xorshift64
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        let election = self.ticks.election.max(1);
+        self.election_timeout = election + (self.rng % u64::from(election)) as u32;
+        self.elapsed = 0;
+    }
+
+    pub(crate) fn tick(&mut self) {
+        self.elapsed += 1;
+        match self.role {
+            Role::Leader => {
+                if self.elapsed.is_multiple_of(self.ticks.heartbeat.max(1)) {
+                    self.broadcast_append();
+                }
+                // A leader that has not heard from a quorum for an election
+                // timeout steps down, so that clients look for the new one.
+                if self.elapsed >= self.ticks.election {
+                    let mut active = self.recent_active.len();
+                    if self.members().contains_key(&self.id) {
+                        active += 1;
+                    }
+                    if active < self.quorum() {
+                        let term = self.term;
+                        self.become_follower(term, None);
+                        return;
+                    }
+                    self.recent_active.clear();
+                    self.elapsed = 0;
+                }
+            }
+            Role::Follower | Role::Candidate => {
+                if self.elapsed >= self.election_timeout {
+                    self.campaign();
+                }
+            }
+        }
+    }
+
+    fn campaign(&mut self) {
+        self.reset_election_timeout();
+        // A node outside the configuration, such as one waiting to join or
+        // one that was removed, never disrupts the cluster.
+        if !self.members().contains_key(&self.id) {
+            return;
+        }
+        self.term += 1;
+        self.voted_for = Some(self.id);
+        self.hard_state_changed = true;
+        self.role = Role::Candidate;
+        self.leader = None;
+        self.votes.clear();
+        self.votes.insert(self.id);
+        if self.votes.len() >= self.quorum() {
+            self.become_leader();
+            return;
+        }
+        let message = Message::RequestVote {
+            term: self.term,
+            last_log_index: self.last_index(),
+            last_log_term: self.last_term(),
+        };
+        for peer in self.peers() {
+            self.send(peer, message.clone());
+        }
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<u64>) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.hard_state_changed = true;
+        }
+        if self.role != Role::Follower || leader.is_some() {
+            self.reset_election_timeout();
+        }
+        self.role = Role::Follower;
+        self.leader = leader;
+        self.votes.clear();
+    }
+
+    fn become_leader(&mut self) {
+        self.role = Role::Leader;
+        self.leader = Some(self.id);
+        self.elapsed = 0;
+        self.recent_active.clear();
+        self.acked_rounds.clear();
+        self.next_index.clear();
+        self.match_index.clear();
+        self.append(Payload::Noop);
+        self.maybe_commit();
+        self.broadcast_append();
+    }
+
+    fn append(&mut self, payload: Payload) {
+        if let Payload::Members(members) = &payload {
+            self.addresses
+                .extend(members.iter().map(|(id, addr)| (*id, addr.clone())));
+        }
+        self.log.push(Entry {
+            term: self.term,
+            payload,
+        });
+    }
+
+    /// Appends `payload` to the log of the leader, returning its index and
+    /// term.
+    pub(crate) fn propose(&mut self, payload: Payload) -> Result<(u64, u64)> {
+        if self.role != Role::Leader {
+            return Err(KvsError::NotLeader(self.leader_address()));
+        }
+        if let Payload::Members(_) = payload {
+            if self.members_index() > self.commit {
+                return Err(KvsError::MembershipChangePending);
+            }
+        }
+        self.append(payload);
+        self.maybe_commit();
+        self.broadcast_append();
+        Ok((self.last_index(), self.term))
+    }
+
+    fn broadcast_append(&mut self) {
+        for peer in self.peers() {
+            self.send_append(peer);
+        }
+    }
+
+    fn send_append(&mut self, peer: u64) {
+        let last_index = self.last_index();
+        let next = *self.next_index.entry(peer).or_insert(last_index + 1);
+        let message = if next <= self.snapshot.index {
+            Message::InstallSnapshot {
+                term: self.term,
+                snapshot: self.snapshot.clone(),
+            }
+        } else {
+            let start = (next - self.snapshot.index - 1) as usize;
+            Message::AppendEntries {
+                term: self.term,
+                prev_log_index: next - 1,
+                prev_log_term: self.term_at(next - 1).unwrap(),
+                entries: self.log[start..]
+                    .iter()
+                    .take(MAX_ENTRIES)
+                    .cloned()
+                    .collect(),
+                leader_commit: self.commit,
+                round: self.round,
+            }
+        };
+        self.send(peer, message);
+    }
+
+    /// Commits the latest entry of the current term stored on a quorum.
+    fn maybe_commit(&mut self) {
+        let last_index = self.last_index();
+        let mut matched: Vec<u64> = self
+            .members()
+            .keys()
+            .map(|id| {
+                if *id == self.id {
+                    last_index
+                } else {
+                    self.match_index.get(id).copied().unwrap_or(0)
+                }
+            })
+            .collect();
+        if matched.is_empty() {
+            return;
+        }
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+        let index = matched[self.quorum() - 1];
+        if index > self.commit && self.term_at(index) == Some(self.term) {
+            self.commit = index;
+            // A leader removed from the cluster hands over once its removal
+            // is committed.
+            if !self.members().contains_key(&self.id) && self.members_index() <= self.commit {
+                let term = self.term;
+                self.become_follower(term, None);
+            }
+        }
+    }
+
+    pub(crate) fn step(&mut self, from: u64, message: Message) {
+        let term = message.term();
+        if term > self.term {
+            if let Message::RequestVote { .. } = message {
+                // Ignore candidates while a leader is known to be alive, so a
+                // node that was partitioned away or removed cannot force an
+                // election when it comes back.
+                let leader_alive = self.role == Role::Leader
+                    || (self.leader.is_some() && self.elapsed < self.ticks.election);
+                if leader_alive {
+                    return;
+                }
+            }
+            let leader = match message {
+                Message::AppendEntries { .. } | Message::InstallSnapshot { .. } => Some(from),
+                _ => None,
+            };
+            self.become_follower(term, leader);
+        } else if term < self.term {
+            let reply = match message {
+                Message::RequestVote { .. } => Message::Vote {
+                    term: self.term,
+                    granted: false,
+                },
+                Message::AppendEntries { .. } | Message::InstallSnapshot { .. } => {
+                    Message::AppendResponse {
+                        term: self.term,
+                        success: false,
+                        match_index: 0,
+                        round: 0,
+                    }
+                }
+                _ => return,
+            };
+            self.send(from, reply);
+            return;
+        }
+
+        match message {
+            Message::RequestVote {
+                last_log_index,
+                last_log_term,
+                ..
+            } => {
+                let up_to_date =
+                    (last_log_term, last_log_index) >= (self.last_term(), self.last_index());
+                let granted = up_to_date
+                    && self.role == Role::Follower
+                    && self.voted_for.is_none_or(|id| id == from);
+                if granted {
+                    self.voted_for = Some(from);
+                    self.hard_state_changed = true;
+                    self.reset_election_timeout();
+                }
+                self.send(
+                    from,
+                    Message::Vote {
+                        term: self.term,
+                        granted,
+                    },
+                );
+            }
+            Message::Vote { granted, .. } => {
+                if self.role == Role::Candidate && granted && self.members().contains_key(&from) {
+                    self.votes.insert(from);
+                    if self.votes.len() >= self.quorum() {
+                        self.become_leader();
+                    }
+                }
+            }
+            Message::AppendEntries {
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+                round,
+                ..
+            } => {
+                self.become_follower(term, Some(from));
+                let reply = self.append_entries(
+                    prev_log_index,
+                    prev_log_term,
+                    entries,
+                    leader_commit,
+                    round,
+                );
+                self.send(from, reply);
+            }
+            Message::AppendResponse {
+                success,
+                match_index,
+                round,
+                ..
+            } => {
+                if self.role != Role::Leader {
+                    return;
+                }
+                self.recent_active.insert(from);
+                let acked = self.acked_rounds.entry(from).or_insert(0);
+                *acked = (*acked).max(round);
+                let next = self.next_index.get(&from).copied().unwrap_or(1);
+                if success {
+                    let matched = self.match_index.entry(from).or_insert(0);
+                    *matched = (*matched).max(match_index);
+                    self.next_index.insert(from, next.max(match_index + 1));
+                    self.maybe_commit();
+                    if self.role == Role::Leader && match_index < self.last_index() {
+                        self.send_append(from);
+                    }
+                } else if match_index < next {
+                    self.next_index.insert(from, match_index + 1);
+                    self.send_append(from);
+                }
+            }
+            Message::InstallSnapshot { snapshot, .. } => {
+                self.become_follower(term, Some(from));
+                let match_index = snapshot.index;
+                self.install_snapshot(snapshot);
+                self.send(
+                    from,
+                    Message::AppendResponse {
+                        term: self.term,
+                        success: true,
+                        match_index,
+                        round: 0,
+                    },
+                );
+            }
+        }
+    }
+
+    fn append_entries(
+        &mut self,
+        mut prev_index: u64,
+        mut prev_term: u64,
+        mut entries: Vec<Entry>,
+        leader_commit: u64,
+        round: u64,
+    ) -> Message {
+        // Entries covered by the snapshot are committed, so they match.
+        if prev_index < self.snapshot.index {
+            let skip = (self.snapshot.index - prev_index) as usize;
+            if skip >= entries.len() {
+                return Message::AppendResponse {
+                    term: self.term,
+                    success: true,
+                    match_index: prev_index + entries.len() as u64,
+                    round,
+                };
+            }
+            entries.drain(..skip);
+            prev_index = self.snapshot.index;
+            prev_term = self.snapshot.term;
+        }
+
+        match self.term_at(prev_index) {
+            Some(term) if term == prev_term => {}
+            conflict => {
+                // Skip back over the whole conflicting term at once.
+                let mut hint = self.last_index().min(prev_index - 1);
+                if let Some(term) = conflict {
+                    while hint > self.snapshot.index && self.term_at(hint) == Some(term) {
+                        hint -= 1;
+                    }
+                }
+                return Message::AppendResponse {
+                    term: self.term,
+                    success: false,
+                    match_index: hint,
+                    round,
+                };
+            }
+        }
+
+        let matched = prev_index + entries.len() as u64;
+        for (i, entry) in entries.into_iter().enumerate() {
+            let index = prev_index + 1 + i as u64;
+            match self.term_at(index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => {
+                    self.log
+                        .truncate((index - self.snapshot.index - 1) as usize);
+                    self.stable_index = self.stable_index.min(index - 1);
+                }
+                None => {}
+            }
+            self.append_received(entry);
+        }
+        self.commit = self.commit.max(leader_commit.min(matched));
+        Message::AppendResponse {
+            term: self.term,
+            success: true,
+            match_index: matched,
+            round,
+        }
+    }
+
+    fn append_received(&mut self, entry: Entry) {
+        if let Payload::Members(members) = &entry.payload {
+            self.addresses
+                .extend(members.iter().map(|(id, addr)| (*id, addr.clone())));
+        }
+        self.log.push(entry);
+    }
+
+    fn install_snapshot(&mut self, snapshot: Snapshot) {
+        if snapshot.index <= self.commit {
+            return;
+        }
+        if self.term_at(snapshot.index) == Some(snapshot.term) {
+            let keep = (snapshot.index - self.snapshot.index) as usize;
+            self.log.drain(..keep);
+        } else {
+            self.log.clear();
+            self.stable_index = snapshot.index;
+        }
+        self.addresses.extend(
+            snapshot
+                .members
+                .iter()
+                .map(|(id, addr)| (*id, addr.clone())),
+        );
+        self.commit = snapshot.index;
+        self.applied = snapshot.index;
+        self.install = Some(snapshot.clone());
+        self.snapshot = snapshot;
+        self.snapshot_changed = true;
+    }
+
+    /// Replaces the log up to `index`, which must be applied, with a
+    /// snapshot of the store.
+    pub(crate) fn compact(&mut self, index: u64, data: Vec<(String, String)>) {
+        if index <= self.snapshot.index || index > self.applied {
+            return;
+        }
+        let members = self.members_at(index).clone();
+        let term = self.term_at(index).unwrap();
+        self.log.drain(..(index - self.snapshot.index) as usize);
+        self.snapshot = Snapshot {
+            index,
+            term,
+            members,
+            data,
+        };
+        self.snapshot_changed = true;
+    }
+
+    /// Starts a round of messages to confirm that this node still leads,
+    /// for a read of what was committed so far (ReadIndex).
+    ///
+    /// Returns the round to pass to `read_confirmed` and the index the store
+    /// must have applied before the read.
+    pub(crate) fn read_index(&mut self) -> Result<(u64, u64)> {
+        if self.role != Role::Leader {
+            return Err(KvsError::NotLeader(self.leader_address()));
+        }
+        self.round += 1;
+        self.broadcast_append();
+        Ok((self.round, self.commit))
+    }
+
+    /// Returns whether a quorum answered the leader in `round` or a later
+    /// one of the current term.
+    pub(crate) fn read_confirmed(&self, round: u64) -> bool {
+        let members = self.members();
+        let mut acked = self
+            .acked_rounds
+            .iter()
+            .filter(|&(id, &acked)| acked >= round && members.contains_key(id))
+            .count();
+        if members.contains_key(&self.id) {
+            acked += 1;
+        }
+        acked >= self.quorum()
+    }
+
+    pub(crate) fn take_messages(&mut self) -> Vec<(u64, Message)> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    pub(crate) fn take_install(&mut self) -> Option<Snapshot> {
+        self.install.take()
+    }
+
+    /// Returns the committed entries not yet handed out, with their indexes.
+    pub(crate) fn take_committed(&mut self) -> Vec<(u64, Entry)> {
+        let first = self.applied + 1;
+        let entries = (first..=self.commit)
+            .map(|index| (index, self.entry(index).clone()))
+            .collect();
+        self.applied = self.commit;
+        entries
+    }
+
+    /// Returns what changed since the last call, for the driver to persist.
+    pub(crate) fn unstable(&mut self) -> Unstable<'_> {
+        let hard_state = if self.hard_state_changed || self.snapshot_changed {
+            Some((self.term, self.voted_for))
+        } else {
+            None
+        };
+        let snapshot = if self.snapshot_changed {
+            Some(&self.snapshot)
+        } else {
+            None
+        };
+        let first = if self.snapshot_changed {
+            self.snapshot.index + 1
+        } else {
+            self.stable_index + 1
+        };
+        let entries = &self.log[(first - self.snapshot.index - 1) as usize..];
+        self.hard_state_changed = false;
+        self.snapshot_changed = false;
+        self.stable_index = self.last_index();
+        Unstable {
+            hard_state,
+            snapshot,
+            first,
+            entries,
+        }
+    }
+}
+
+/// State a node changed since it was last persisted.
+pub(crate) struct Unstable<'a> {
+    pub(crate) hard_state: Option<(u64, Option<u64>)>,
+    /// A new snapshot, which replaces the whole log with `entries`.
+    pub(crate) snapshot: Option<&'a Snapshot>,
+    /// Index of the first entry of `entries`, which replace the log from
+    /// there on.
+    pub(crate) first: u64,
+    pub(crate) entries: &'a [Entry],
+}
@@ -0,0 +1,159 @@
+//! Persistence of a Raft node in its data directory.
+//!
+//! `raft-state` holds the term and vote and `raft-snapshot` the latest
+//! snapshot, both replaced atomically through a temporary file. `raft-log` is
+//! a stream of `LogRecord`s, each replacing the log from its index on; it is
+//! rewritten whenever a new snapshot is saved. `raft-applied` holds the last
+//! index applied to the store, so that a restart does not apply it again.
+
+use super::node::{Entry, HardState, Snapshot, Unstable};
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+const STATE_FILE: &str = "raft-state";
+const SNAPSHOT_FILE: &str = "raft-snapshot";
+const LOG_FILE: &str = "raft-log";
+const APPLIED_FILE: &str = "raft-applied";
+
+#[derive(Serialize, Deserialize, Default)]
+struct State {
+    term: u64,
+    voted_for: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LogRecord {
+    first: u64,
+    entries: Vec<Entry>,
+}
+
+pub(crate) struct Storage {
+    dir: PathBuf,
+    log: BufWriter<File>,
+}
+
+impl Storage {
+    /// Opens the storage in `dir`, returning what it holds.
+    pub(crate) fn open(dir: &Path) -> Result<(Storage, HardState)> {
+        let state: State = read_json(&dir.join(STATE_FILE))?.unwrap_or_default();
+        let snapshot: Snapshot = read_json(&dir.join(SNAPSHOT_FILE))?.unwrap_or_default();
+        let applied: u64 = read_json(&dir.join(APPLIED_FILE))?.unwrap_or_default();
+
+        let log_path = dir.join(LOG_FILE);
+        let mut log = Vec::new();
+        // The length of the records read back whole.
+        let mut good_len = 0;
+        if log_path.exists() {
+            let reader = BufReader::new(File::open(&log_path)?);
+            let mut stream = serde_json::Deserializer::from_reader(reader).into_iter::<LogRecord>();
+            while let Some(record) = stream.next() {
+                // A torn last record was never acknowledged, so it is dropped.
+                let record = match record {
+                    Ok(record) => record,
+                    Err(ref e) if e.is_eof() || e.is_syntax() => break,
+                    Err(e) => return Err(e.into()),
+                };
+                good_len = stream.byte_offset() as u64;
+                let mut first = record.first;
+                let mut entries = record.entries;
+                if first <= snapshot.index {
+                    let skip = ((snapshot.index + 1 - first) as usize).min(entries.len());
+                    entries.drain(..skip);
+                    first = snapshot.index + 1;
+                }
+                log.truncate((first - snapshot.index - 1) as usize);
+                log.extend(entries);
+            }
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_path)?;
+        // New records must not follow the remains of a torn one.
+        if file.metadata()?.len() > good_len {
+            file.set_len(good_len)?;
+        }
+        let storage = Storage {
+            dir: dir.to_owned(),
+            log: BufWriter::new(file),
+        };
+        let hard_state = HardState {
+            term: state.term,
+            voted_for: state.voted_for,
+            snapshot,
+            log,
+            applied,
+        };
+        Ok((storage, hard_state))
+    }
+
+    /// Durably writes what changed.
+    pub(crate) fn save(&mut self, unstable: Unstable) -> Result<()> {
+        if let Some(snapshot) = unstable.snapshot {
+            write_json(&self.dir.join(SNAPSHOT_FILE), snapshot)?;
+            let tmp_path = self.dir.join(format!("{}.tmp", LOG_FILE));
+            let mut file = File::create(&tmp_path)?;
+            write_record(&mut file, unstable.first, unstable.entries)?;
+            file.sync_all()?;
+            fs::rename(&tmp_path, self.dir.join(LOG_FILE))?;
+            let file = OpenOptions::new()
+                .append(true)
+                .open(self.dir.join(LOG_FILE))?;
+            self.log = BufWriter::new(file);
+        } else if !unstable.entries.is_empty() {
+            write_record(&mut self.log, unstable.first, unstable.entries)?;
+            self.log.flush()?;
+            self.log.get_ref().sync_data()?;
+        }
+        if let Some((term, voted_for)) = unstable.hard_state {
+            write_json(&self.dir.join(STATE_FILE), &State { term, voted_for })?;
+        }
+        Ok(())
+    }
+
+    /// Records the last index applied to the store.
+    ///
+    /// Like the writes of the store, the file is left for the system to
+    /// write out, so that it is never more durable than what it describes.
+    pub(crate) fn save_applied(&self, index: u64) -> Result<()> {
+        let path = self.dir.join(APPLIED_FILE);
+        let tmp_path = self.dir.join(format!("{}.tmp", APPLIED_FILE));
+        fs::write(&tmp_path, serde_json::to_vec(&index)?)?;
+        fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+}
+
+fn write_record<W: Write>(writer: &mut W, first: u64, entries: &[Entry]) -> Result<()> {
+    serde_json::to_writer(
+        &mut *writer,
+        &LogRecord {
+            first,
+            entries: entries.to_vec(),
+        },
+    )?;
+    Ok(())
+}
+
+fn read_json<T: for<'de> Deserialize<'de>>(path: &Path) -> Result<Option<T>> {
+    match File::open(path) {
+        Ok(file) => Ok(Some(serde_json::from_reader(BufReader::new(file))?)),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let mut file = BufWriter::new(File::create(&tmp_path)?);
+    serde_json::to_writer(&mut file, value)?;
+    file.flush()?;
+    file.get_ref().sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
@@ -0,0 +1,170 @@
+//! Delivery of Raft messages between nodes.
+
+use super::node::Message;
+use super::Raft;
+use crate::common::Request;
+use crate::protocol::{self, Writer};
+use crate::Result;
+use std::collections::{HashMap, HashSet};
+use std::io::BufWriter;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Messages queued for a peer before newer ones are dropped.
+const QUEUE_SIZE: usize = 1024;
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(200);
+/// How long a peer that refused a connection is left alone.
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Carries messages from one node to another. Delivery may fail silently:
+/// Raft retries whatever is lost.
+pub trait Transport: Send + Sync {
+    /// Sends `message` from node `from` to node `to`, reachable at `addr`.
+    fn send(&self, from: u64, to: u64, addr: &str, message: Message);
+}
+
+/// An in-process network of nodes, which can be split into partitions.
+#[derive(Default)]
+pub struct LocalNetwork {
+    nodes: Mutex<HashMap<u64, (String, Weak<Raft>)>>,
+    /// Links that drop every message, as (from, to).
+    cut: Mutex<HashSet<(u64, u64)>>,
+}
+
+impl LocalNetwork {
+    pub fn new() -> Arc<Self> {
+        Arc::new(LocalNetwork::default())
+    }
+
+    /// Connects a node to the network.
+    pub fn add(&self, raft: &Arc<Raft>) {
+        let status = raft.status();
+        self.nodes
+            .lock()
+            .unwrap()
+            .insert(status.id, (raft.address().to_owned(), Arc::downgrade(raft)));
+    }
+
+    /// Cuts every link between the nodes of `group` and the other nodes.
+    pub fn partition(&self, group: &[u64]) {
+        let nodes = self.nodes.lock().unwrap();
+        let mut cut = self.cut.lock().unwrap();
+        for &inside in group {
+            for &outside in nodes.keys().filter(|id| !group.contains(id)) {
+                cut.insert((inside, outside));
+                cut.insert((outside, inside));
+            }
+        }
+    }
+
+    /// Restores every link.
+    pub fn heal(&self) {
+        self.cut.lock().unwrap().clear();
+    }
+}
+
+impl Transport for LocalNetwork {
+    fn send(&self, from: u64, to: u64, _addr: &str, message: Message) {
+        if self.cut.lock().unwrap().contains(&(from, to)) {
+            return;
+        }
+        let (from_addr, raft) = {
+            let nodes = self.nodes.lock().unwrap();
+            match (nodes.get(&from), nodes.get(&to)) {
+                (Some((from_addr, _)), Some((_, raft))) => (from_addr.clone(), raft.upgrade()),
+                _ => return,
+            }
+        };
+        if let Some(raft) = raft {
+            raft.receive(from, from_addr, message);
+        }
+    }
+}
+
+/// The queue of messages for a peer, with the node each is from.
+type Queue = SyncSender<(u64, Message)>;
+
+/// Sends messages over TCP as `Request::Raft`, with one connection and one
+/// thread per peer.
+pub struct TcpTransport {
+    /// The address this node serves clients and peers on.
+    addr: String,
+    /// The address and queue of each peer.
+    peers: Mutex<HashMap<u64, (String, Queue)>>,
+}
+
+impl TcpTransport {
+    pub fn new(addr: String) -> Self {
+        TcpTransport {
+            addr,
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Transport for TcpTransport {
+    fn send(&self, from: u64, to: u64, addr: &str, message: Message) {
+        let mut peers = self.peers.lock().unwrap();
+        let mut message = (from, message);
+        if let Some((peer_addr, sender)) = peers.get(&to) {
+            if peer_addr == addr {
+                match sender.try_send(message) {
+                    Ok(()) | Err(TrySendError::Full(_)) => return,
+                    Err(TrySendError::Disconnected(returned)) => message = returned,
+                }
+            }
+        }
+        let Ok(peer_addr) = addr.parse::<SocketAddr>() else {
+            return;
+        };
+        let (sender, receiver) = sync_channel(QUEUE_SIZE);
+        let own_addr = self.addr.clone();
+        thread::spawn(move || send_to_peer(peer_addr, own_addr, receiver));
+        let _ = sender.try_send(message);
+        peers.insert(to, (addr.to_owned(), sender));
+    }
+}
+
+fn send_to_peer(peer: SocketAddr, own_addr: String, messages: Receiver<(u64, Message)>) {
+    let mut connection: Option<Writer<BufWriter<TcpStream>>> = None;
+    let mut refused_at: Option<Instant> = None;
+    for (from, message) in messages {
+        if connection.is_none() {
+            if refused_at.is_some_and(|at| at.elapsed() < RETRY_INTERVAL) {
+                continue;
+            }
+            match connect(peer) {
+                Ok(writer) => {
+                    connection = Some(writer);
+                    refused_at = None;
+                }
+                Err(_) => {
+                    refused_at = Some(Instant::now());
+                    continue;
+                }
+            }
+        }
+        let writer = connection.as_mut().unwrap();
+        let request = Request::Raft {
+            from,
+            addr: own_addr.clone(),
+            message,
+        };
+        // Raft messages are never answered, so they need no id.
+        if writer.send(0, &request).is_err() {
+            connection = None;
+        }
+    }
+}
+
+fn connect(peer: SocketAddr) -> Result<Writer<BufWriter<TcpStream>>> {
+    let mut stream = TcpStream::connect_timeout(&peer, CONNECT_TIMEOUT)?;
+    stream.set_nodelay(true)?;
+    // A peer that accepts but never answers the handshake must not block us.
+    stream.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+    let wire = protocol::handshake(&mut stream)?;
+    Ok(Writer::new(BufWriter::new(stream), wire))
+}
@@ -0,0 +1,307 @@
+//! Leader–follower replication between servers.
+//!
+//! A follower sends `Request::Replicate` to its leader, which answers with a
+//! snapshot of its store in `Response::SnapshotChunk`s and a
+//! `Response::SnapshotDone`, then streams every later change as a
+//! `Response::Event`. While idle, the leader sends a `Response::Heartbeat`
+//! with its latest sequence number, from which the follower computes its lag.
+//!
+//! Changes carry the sequence numbers of the leader store's change feed,
+//! which survive restarts. The stream follows the leader's watchers, which
+//! keep the recent changes in memory, so a follower that keeps up costs the
+//! leader no reads of its store. A follower that falls behind them, or
+//! reconnects with the last change it applied, is sent the changes it missed
+//! from the feed, or a snapshot if the feed no longer has every one of them.
+
+use crate::common::{Request, Response};
+use crate::protocol::{self, Reader, RequestId, Writer};
+use crate::watch::{Event, Watchers};
+use crate::{KvsEngine, KvsError, Result};
+use serde::{Deserialize, Serialize};
+use slog::Logger;
+use std::collections::HashMap;
+use std::io::{BufReader, BufWriter, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const SNAPSHOT_CHUNK: usize = 1000;
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The replication state of a follower.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReplicationStatus {
+    pub leader: SocketAddr,
+    /// Whether the follower is streaming changes from the leader.
+    pub connected: bool,
+    /// Sequence number of the last change applied, in the leader's numbering.
+    pub applied_seq: u64,
+    /// Latest sequence number the leader reported.
+    pub leader_seq: u64,
+    /// Number of changes the leader applied that the follower did not.
+    pub lag: u64,
+    /// Milliseconds since the leader was last heard from.
+    pub last_contact_ms: Option<u64>,
+}
+
+/// The follower side of replication, shared with the connections that
+/// report it.
+pub struct Replica {
+    leader: SocketAddr,
+    state: Mutex<ReplicaState>,
+}
+
+#[derive(Default)]
+struct ReplicaState {
+    connected: bool,
+    /// Whether the store holds a snapshot of the leader from this run, which
+    /// the changes after `applied_seq` can be applied to.
+    synced: bool,
+    applied_seq: u64,
+    leader_seq: u64,
+    last_contact: Option<Instant>,
+}
+
+impl Replica {
+    pub fn new(leader: SocketAddr) -> Self {
+        Replica {
+            leader,
+            state: Mutex::new(ReplicaState::default()),
+        }
+    }
+
+    pub fn leader(&self) -> SocketAddr {
+        self.leader
+    }
+
+    pub fn status(&self) -> ReplicationStatus {
+        let state = self.state.lock().unwrap();
+        ReplicationStatus {
+            leader: self.leader,
+            connected: state.connected,
+            applied_seq: state.applied_seq,
+            leader_seq: state.leader_seq,
+            lag: state.leader_seq.saturating_sub(state.applied_seq),
+            last_contact_ms: state
+                .last_contact
+                .map(|contact| contact.elapsed().as_millis() as u64),
+        }
+    }
+
+    fn update(&self, f: impl FnOnce(&mut ReplicaState)) {
+        let mut state = self.state.lock().unwrap();
+        f(&mut state);
+        state.last_contact = Some(Instant::now());
+    }
+}
+
+/// Sends a snapshot, or the changes after `since`, and then the stream of
+/// changes to a follower, until it goes away.
+pub(crate) fn serve_follower<W: Write>(
+    db: &Mutex<Box<dyn KvsEngine + Send>>,
+    watchers: &Watchers,
+    since: Option<u64>,
+    id: RequestId,
+    writer: &mut Writer<W>,
+) -> Result<()> {
+    // Writing to the follower happens once the store is unlocked.
+    let first = start(&mut **db.lock().unwrap(), watchers, since);
+    let (mut sent, mut events) = match first {
+        Ok((Start::Resume(since, backlog), events)) => {
+            let mut sent = since;
+            send_events(backlog, &mut sent, id, writer)?;
+            (sent, events)
+        }
+        Ok((Start::Snapshot(seq, snapshot), events)) => {
+            for chunk in snapshot.chunks(SNAPSHOT_CHUNK) {
+                writer.send(id, &Response::SnapshotChunk(chunk.to_vec()))?;
+            }
+            writer.send(id, &Response::SnapshotDone { seq })?;
+            (seq, events)
+        }
+        Err(e) => return writer.send(id, &Response::Err(e.into())),
+    };
+
+    let mut last_sent = Instant::now();
+    loop {
+        match events.recv_timeout(HEARTBEAT_INTERVAL) {
+            Ok(event) => send_events(vec![event], &mut sent, id, writer)?,
+            Err(RecvTimeoutError::Timeout) => {}
+            // Dropped for falling behind; the feed still has the changes.
+            Err(RecvTimeoutError::Disconnected) => {
+                let (backlog, resubscribed) = {
+                    let mut lock = db.lock().unwrap();
+                    watchers.subscribe(&mut **lock, String::new(), Some(sent))?
+                };
+                events = resubscribed;
+                send_events(backlog, &mut sent, id, writer)?;
+            }
+        }
+        if last_sent.elapsed() >= HEARTBEAT_INTERVAL {
+            writer.send(id, &Response::Heartbeat { seq: sent })?;
+            last_sent = Instant::now();
+        }
+    }
+}
+
+/// Where the stream to a follower starts.
+enum Start {
+    /// The changes after the sequence number the follower applied.
+    Resume(u64, Vec<Event>),
+    /// Every key with its value, at a sequence number.
+    Snapshot(u64, Vec<(String, String)>),
+}
+
+/// Subscribes to the changes after `since` if the store still has every one
+/// of them. Otherwise takes a snapshot and subscribes to the changes after
+/// it.
+///
+/// Other consumers of the change feed may be further behind, so the changes
+/// the follower applied are left for an operator to acknowledge.
+fn start(
+    db: &mut dyn KvsEngine,
+    watchers: &Watchers,
+    since: Option<u64>,
+) -> Result<(Start, Receiver<Event>)> {
+    if let Some(since) = since {
+        match watchers.subscribe(db, String::new(), Some(since)) {
+            Ok((backlog, events)) => return Ok((Start::Resume(since, backlog), events)),
+            Err(KvsError::HistoryUnavailable(_)) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    let seq = db.last_seq()?;
+    let snapshot = db.snapshot()?;
+    let (_, events) = watchers.subscribe(db, String::new(), None)?;
+    Ok((Start::Snapshot(seq, snapshot), events))
+}
+
+/// Sends the events following `sent`.
+///
+/// # Errors
+///
+/// It returns `KvsError::HistoryUnavailable` if the events skip a change, so
+/// that the follower reconnects and takes a snapshot.
+fn send_events<W: Write>(
+    events: Vec<Event>,
+    sent: &mut u64,
+    id: RequestId,
+    writer: &mut Writer<W>,
+) -> Result<()> {
+    for event in events {
+        if event.seq != *sent + 1 {
+            return Err(KvsError::HistoryUnavailable(*sent));
+        }
+        *sent = event.seq;
+        writer.send(id, &Response::Event(event))?;
+    }
+    Ok(())
+}
+
+/// Replicates the leader into the local store, reconnecting whenever the
+/// stream breaks. This never returns.
+pub(crate) fn follow(
+    replica: Arc<Replica>,
+    db: Arc<Mutex<Box<dyn KvsEngine + Send>>>,
+    watchers: Arc<Watchers>,
+    log: Logger,
+) {
+    loop {
+        if let Err(e) = sync(&replica, &db, &watchers, &log) {
+            warn!(log, "Replication from {} stopped: {}", replica.leader, e);
+        }
+        replica.update(|state| state.connected = false);
+        thread::sleep(RETRY_INTERVAL);
+    }
+}
+
+fn sync(
+    replica: &Replica,
+    db: &Mutex<Box<dyn KvsEngine + Send>>,
+    watchers: &Watchers,
+    log: &Logger,
+) -> Result<()> {
+    let mut stream = TcpStream::connect(replica.leader)?;
+    let wire = protocol::handshake(&mut stream)?;
+    let mut writer = Writer::new(BufWriter::new(stream.try_clone()?), wire);
+    let mut reader = Reader::<_, Response>::new(BufReader::new(stream), wire);
+    let since = {
+        let state = replica.state.lock().unwrap();
+        Some(state.applied_seq).filter(|_| state.synced)
+    };
+    writer.send(0, &Request::Replicate { since })?;
+    if let Some(seq) = since {
+        replica.update(|state| state.connected = true);
+        info!(log, "Resuming from {} after {}", replica.leader, seq);
+    }
+
+    let mut snapshot = Vec::new();
+    loop {
+        match reader.receive()?.1 {
+            Response::SnapshotChunk(chunk) => snapshot.extend(chunk),
+            Response::SnapshotDone { seq } => {
+                apply_snapshot(db, watchers, std::mem::take(&mut snapshot))?;
+                replica.update(|state| {
+                    state.connected = true;
+                    state.synced = true;
+                    state.applied_seq = seq;
+                    state.leader_seq = seq;
+                });
+                info!(log, "Synced snapshot from {} at {}", replica.leader, seq);
+            }
+            Response::Event(event) => {
+                let seq = event.seq;
+                apply_event(db, watchers, event)?;
+                replica.update(|state| {
+                    state.applied_seq = seq;
+                    state.leader_seq = state.leader_seq.max(seq);
+                });
+            }
+            Response::Heartbeat { seq } => {
+                replica.update(|state| state.leader_seq = state.leader_seq.max(seq));
+            }
+            Response::Err(e) => return Err(e.into()),
+            _ => return Err(KvsError::UnexpectedResponseType),
+        }
+    }
+}
+
+/// Makes the local store hold exactly the snapshot.
+pub(crate) fn apply_snapshot(
+    db: &Mutex<Box<dyn KvsEngine + Send>>,
+    watchers: &Watchers,
+    snapshot: Vec<(String, String)>,
+) -> Result<()> {
+    let mut lock = db.lock().unwrap();
+    let mut local: HashMap<String, String> = lock.snapshot()?.into_iter().collect();
+    for (key, value) in snapshot {
+        if local.remove(&key).as_ref() != Some(&value) {
+            lock.set(key.clone(), value.clone())?;
+            watchers.publish(&mut **lock, key, Some(value))?;
+        }
+    }
+    for key in local.into_keys() {
+        lock.remove(key.clone())?;
+        watchers.publish(&mut **lock, key, None)?;
+    }
+    Ok(())
+}
+
+fn apply_event(
+    db: &Mutex<Box<dyn KvsEngine + Send>>,
+    watchers: &Watchers,
+    event: Event,
+) -> Result<()> {
+    let mut lock = db.lock().unwrap();
+    match &event.value {
+        Some(value) => lock.set(event.key.clone(), value.clone())?,
+        None => match lock.remove(event.key.clone()) {
+            Ok(()) | Err(KvsError::KeyNotFound) => {}
+            Err(e) => return Err(e),
+        },
+    }
+    watchers.publish(&mut **lock, event.key, event.value)
+}
@@ -0,0 +1,548 @@
+//! A listener speaking RESP2, the protocol of Redis, so that `redis-cli` and
+//! Redis client libraries can use a server.
+//!
+//! Commands go through the same `Handler` as the native protocol, so they
+//! see the same store and obey its replication and cluster rules. Only the
+//! strings Redis commands need are supported: `GET`, `SET` (with `EX` and
+//! `NX`), `DEL`, `EXISTS`, `INCR`, `KEYS`, `SCAN`, `PING`, `INFO` and `QUIT`.
+//!
+//! Keys set with `EX` are removed once their deadline passes, which any
+//! later write of the key clears. `SET NX` and `INCR` are atomic with respect
+//! to other RESP clients only.
+
+use crate::common::{Request, Response};
+use crate::connection::Handler;
+use crate::protocol::MAX_FRAME;
+use crate::{KvsError, Result};
+use slog::Logger;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// Arguments a command may have.
+const MAX_ARGS: usize = 1024 * 1024;
+/// Arguments, and bytes of a bulk string, that room is made for before they
+/// arrive. Larger ones grow their buffer as they are read, so that a header
+/// alone cannot make the server allocate up to the limits.
+const PREALLOC_ARGS: usize = 1024;
+const PREALLOC_BYTES: usize = 64 * 1024;
+/// Keys a `SCAN` returns unless given a `COUNT`.
+const SCAN_COUNT: usize = 10;
+/// Cursors of unfinished `SCAN`s kept at once; older ones become invalid.
+const MAX_CURSORS: usize = 1024;
+
+/// Serves RESP clients until the listener fails, a thread per connection.
+pub(crate) fn serve(listener: TcpListener, handler: Handler, log: Logger) {
+    let store = Arc::new(Store {
+        handler,
+        updates: Mutex::new(()),
+        cursors: Mutex::new(Cursors::default()),
+    });
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let store = store.clone();
+                let log = log.new(o!("peer-address"=>stream.peer_addr().ok()));
+                thread::spawn(move || {
+                    store.handler.metrics.connection_opened();
+                    if let Err(e) = serve_client(&store, stream) {
+                        error!(log, "Error on serving RESP client: {}", e);
+                    }
+                    store.handler.metrics.connection_closed();
+                });
+            }
+            Err(e) => error!(log, "Error on new RESP connection: {}", e),
+        }
+    }
+}
+
+fn serve_client(store: &Store, stream: TcpStream) -> Result<()> {
+    let metrics = &store.handler.metrics;
+    let mut reader = BufReader::new(metrics.count_reads(&stream));
+    let mut writer = BufWriter::new(metrics.count_writes(&stream));
+    loop {
+        // Replies to pipelined commands go out together.
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+        let args = match read_command(&mut reader) {
+            Ok(Some(args)) => args,
+            Ok(None) => break,
+            Err(KvsError::Protocol(e)) => {
+                // Redis gives up on a client that breaks the protocol too.
+                Reply::Error(format!("ERR Protocol error: {}", e)).write_to(&mut writer)?;
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+        if args.is_empty() {
+            continue;
+        }
+        let quit = args[0].eq_ignore_ascii_case(b"QUIT");
+        store.execute(&args).write_to(&mut writer)?;
+        if quit {
+            break;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads a command, either an array of bulk strings or an inline command.
+/// Returns `None` once the client closed the connection.
+fn read_command<R: BufRead>(reader: &mut R) -> Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if line.first() != Some(&b'*') {
+        let args = line
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|arg| !arg.is_empty())
+            .map(<[u8]>::to_vec)
+            .collect();
+        return Ok(Some(args));
+    }
+    let count = parse_length(&line[1..], MAX_ARGS, "multibulk length")?;
+    let mut args = Vec::with_capacity(count.min(PREALLOC_ARGS));
+    for _ in 0..count {
+        let header = read_line(reader)?.ok_or_else(unexpected_eof)?;
+        if header.first() != Some(&b'$') {
+            return Err(KvsError::Protocol(format!(
+                "expected '$', got '{}'",
+                String::from_utf8_lossy(&header[..header.len().min(1)])
+            )));
+        }
+        let len = parse_length(&header[1..], MAX_FRAME, "bulk length")?;
+        let mut arg = Vec::with_capacity(len.min(PREALLOC_BYTES) + 2);
+        reader.by_ref().take(len as u64 + 2).read_to_end(&mut arg)?;
+        if arg.len() < len + 2 {
+            return Err(unexpected_eof());
+        }
+        if !arg.ends_with(b"\r\n") {
+            return Err(KvsError::Protocol("bulk string without CRLF".to_owned()));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+/// Reads a line without its line ending.
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    if reader.read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(unexpected_eof());
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_length(digits: &[u8], max: usize, what: &str) -> Result<usize> {
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| digits.parse().ok())
+        .filter(|len| *len <= max)
+        .ok_or_else(|| KvsError::Protocol(format!("invalid {}", what)))
+}
+
+fn unexpected_eof() -> KvsError {
+    io::Error::from(io::ErrorKind::UnexpectedEof).into()
+}
+
+/// A RESP2 reply.
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    /// A bulk string, or the null bulk string.
+    Bulk(Option<String>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        match self {
+            Reply::Simple(s) => write!(writer, "+{}\r\n", s),
+            // Line breaks would end the error early.
+            Reply::Error(e) => write!(writer, "-{}\r\n", e.replace(['\r', '\n'], " ")),
+            Reply::Integer(n) => write!(writer, ":{}\r\n", n),
+            Reply::Bulk(None) => write!(writer, "$-1\r\n"),
+            Reply::Bulk(Some(s)) => write!(writer, "${}\r\n{}\r\n", s.len(), s),
+            Reply::Array(replies) => {
+                write!(writer, "*{}\r\n", replies.len())?;
+                replies.iter().try_for_each(|reply| reply.write_to(writer))
+            }
+        }
+    }
+}
+
+impl From<KvsError> for Reply {
+    fn from(e: KvsError) -> Reply {
+        match e {
+            KvsError::ReadOnly(_) => Reply::Error(format!("READONLY {}", e)),
+            KvsError::Busy(_) => Reply::Error(format!("BUSY {}", e)),
+            e => Reply::Error(format!("ERR {}", e)),
+        }
+    }
+}
+
+fn syntax_error() -> Reply {
+    Reply::Error("ERR syntax error".to_owned())
+}
+
+fn invalid_cursor() -> Reply {
+    Reply::Error("ERR invalid cursor".to_owned())
+}
+
+fn not_an_integer() -> Reply {
+    Reply::Error("ERR value is not an integer or out of range".to_owned())
+}
+
+/// The store as the RESP clients of a server see it.
+struct Store {
+    handler: Handler,
+    /// Held by the commands that read a key before writing it.
+    updates: Mutex<()>,
+    cursors: Mutex<Cursors>,
+}
+
+/// The last key each unfinished `SCAN` returned, by cursor.
+#[derive(Default)]
+struct Cursors {
+    last: u64,
+    keys: HashMap<u64, String>,
+    /// The cursors in the order they were handed out.
+    order: VecDeque<u64>,
+}
+
+impl Cursors {
+    /// Returns a new cursor continuing after `key`.
+    fn add(&mut self, key: String) -> u64 {
+        if self.order.len() == MAX_CURSORS {
+            let oldest = self.order.pop_front().unwrap();
+            self.keys.remove(&oldest);
+        }
+        self.last += 1;
+        self.keys.insert(self.last, key);
+        self.order.push_back(self.last);
+        self.last
+    }
+}
+
+impl Store {
+    fn execute(&self, args: &[Vec<u8>]) -> Reply {
+        let command = String::from_utf8_lossy(&args[0]).into_owned();
+        let name = command.to_ascii_uppercase();
+        let args = match args[1..]
+            .iter()
+            .map(|arg| String::from_utf8(arg.clone()))
+            .collect::<std::result::Result<Vec<String>, _>>()
+        {
+            Ok(args) => args,
+            Err(_) => return Reply::Error("ERR arguments must be valid UTF-8".to_owned()),
+        };
+        let result = match (name.as_str(), args.len()) {
+            ("PING", 0) => Ok(Reply::Simple("PONG")),
+            ("PING", 1) => Ok(Reply::Bulk(Some(args[0].clone()))),
+            ("QUIT", 0) => Ok(Reply::Simple("OK")),
+            ("GET", 1) => self.get(&args[0]).map(Reply::Bulk),
+            ("SET", n) if n >= 2 => self.set(&args),
+            ("DEL", n) if n >= 1 => self.count(&args, |key| self.remove(key)),
+            ("EXISTS", n) if n >= 1 => self.count(&args, |key| Ok(self.get(key)?.is_some())),
+            ("INCR", 1) => self.incr(&args[0]),
+            ("KEYS", 1) => self.keys(&args[0]).map(|keys| {
+                Reply::Array(keys.into_iter().map(|key| Reply::Bulk(Some(key))).collect())
+            }),
+            ("SCAN", n) if n >= 1 => self.scan(&args),
+            ("INFO", 0) => self.info(None),
+            ("INFO", 1) => self.info(Some(&args[0])),
+            (
+                "PING" | "QUIT" | "GET" | "SET" | "DEL" | "EXISTS" | "INCR" | "KEYS" | "SCAN"
+                | "INFO",
+                _,
+            ) => {
+                return Reply::Error(format!(
+                    "ERR wrong number of arguments for '{}' command",
+                    command.to_ascii_lowercase()
+                ))
+            }
+            _ => return Reply::Error(format!("ERR unknown command '{}'", command)),
+        };
+        result.unwrap_or_else(Reply::from)
+    }
+
+    /// `SET key value [EX seconds] [NX]`
+    fn set(&self, args: &[String]) -> Result<Reply> {
+        let (mut ttl, mut nx) = (None, false);
+        let mut options = args[2..].iter();
+        while let Some(option) = options.next() {
+            match option.to_ascii_uppercase().as_str() {
+                "NX" if !nx => nx = true,
+                "EX" if ttl.is_none() => match options.next().map(|secs| secs.parse::<u64>()) {
+                    Some(Ok(secs)) if secs > 0 => ttl = Some(Duration::from_secs(secs)),
+                    Some(_) => {
+                        return Ok(Reply::Error(
+                            "ERR invalid expire time in 'set' command".to_owned(),
+                        ))
+                    }
+                    None => return Ok(syntax_error()),
+                },
+                _ => return Ok(syntax_error()),
+            }
+        }
+        let (key, value) = (&args[0], &args[1]);
+        let _update = self.updates.lock().unwrap();
+        if nx && self.get(key)?.is_some() {
+            return Ok(Reply::Bulk(None));
+        }
+        match ttl {
+            Some(ttl) => {
+                self.handler
+                    .set_expiring(key.clone(), value.clone(), SystemTime::now() + ttl)?
+            }
+            None => {
+                self.handler.call(Request::Set {
+                    key: key.clone(),
+                    value: value.clone(),
+                })?;
+            }
+        }
+        Ok(Reply::Simple("OK"))
+    }
+
+    fn incr(&self, key: &str) -> Result<Reply> {
+        let _update = self.updates.lock().unwrap();
+        let value = match self.get(key)?.map(|value| value.parse::<i64>()) {
+            Some(Ok(value)) => value.checked_add(1),
+            Some(Err(_)) => None,
+            None => Some(1),
+        };
+        let value = match value {
+            Some(value) => value,
+            None => return Ok(not_an_integer()),
+        };
+        self.handler.call(Request::Set {
+            key: key.to_owned(),
+            value: value.to_string(),
+        })?;
+        Ok(Reply::Integer(value))
+    }
+
+    /// `SCAN cursor [MATCH pattern] [COUNT count]`
+    ///
+    /// A cursor stands for the last key returned, and the next page starts
+    /// after it in key order. Only the latest cursors are remembered, so a
+    /// scan left unfinished for long may have to start over.
+    fn scan(&self, args: &[String]) -> Result<Reply> {
+        let after = match args[0].parse::<u64>() {
+            Ok(0) => None,
+            Ok(cursor) => match self.cursors.lock().unwrap().keys.get(&cursor) {
+                Some(key) => Some(key.clone()),
+                None => return Ok(invalid_cursor()),
+            },
+            Err(_) => return Ok(invalid_cursor()),
+        };
+        let (mut pattern, mut count) = ("*", SCAN_COUNT);
+        let mut options = args[1..].iter();
+        while let Some(option) = options.next() {
+            match (option.to_ascii_uppercase().as_str(), options.next()) {
+                ("MATCH", Some(arg)) => pattern = arg,
+                ("COUNT", Some(arg)) => match arg.parse() {
+                    Ok(n) if n > 0 => count = n,
+                    _ => return Ok(not_an_integer()),
+                },
+                _ => return Ok(syntax_error()),
+            }
+        }
+        let request = Request::Scan {
+            prefix: literal_prefix(pattern).to_owned(),
+            after,
+            limit: Some(count),
+        };
+        let keys: Vec<String> = match self.handler.call(request)? {
+            Response::Entries(entries) => entries.into_iter().map(|(key, _)| key).collect(),
+            _ => return Err(KvsError::UnexpectedResponseType),
+        };
+        // A short page is the last one.
+        let next = match keys.last() {
+            Some(last) if keys.len() == count => self.cursors.lock().unwrap().add(last.clone()),
+            _ => 0,
+        };
+        let page = keys
+            .into_iter()
+            .filter(|key| glob_match(pattern.as_bytes(), key.as_bytes()))
+            .map(|key| Reply::Bulk(Some(key)))
+            .collect();
+        Ok(Reply::Array(vec![
+            Reply::Bulk(Some(next.to_string())),
+            Reply::Array(page),
+        ]))
+    }
+
+    /// Returns the keys matching a glob-style `pattern`, in key order.
+    fn keys(&self, pattern: &str) -> Result<Vec<String>> {
+        let request = Request::Scan {
+            prefix: literal_prefix(pattern).to_owned(),
+            after: None,
+            limit: None,
+        };
+        match self.handler.call(request)? {
+            Response::Entries(entries) => Ok(entries
+                .into_iter()
+                .map(|(key, _)| key)
+                .filter(|key| glob_match(pattern.as_bytes(), key.as_bytes()))
+                .collect()),
+            _ => Err(KvsError::UnexpectedResponseType),
+        }
+    }
+
+    fn info(&self, section: Option<&str>) -> Result<Reply> {
+        let section = section.map(str::to_ascii_lowercase);
+        let wanted = |name: &str| match section.as_deref() {
+            None | Some("all") | Some("default") | Some("everything") => true,
+            Some(section) => section == name,
+        };
+        let mut info = String::new();
+        if wanted("server") {
+            let _ = write!(
+                info,
+                "# Server\r\nkvs_version:{}\r\n\r\n",
+                env!("CARGO_PKG_VERSION")
+            );
+        }
+        if wanted("clients") {
+            let _ = write!(
+                info,
+                "# Clients\r\nconnected_clients:{}\r\n\r\n",
+                self.handler.metrics.active_connections()
+            );
+        }
+        if wanted("keyspace") {
+            let expiring = self.handler.deadlines.len();
+            match self.handler.call(Request::Stats)? {
+                Response::Stats(stats) => {
+                    let _ = write!(
+                        info,
+                        "# Keyspace\r\ndb0:keys={},expires={}\r\n\r\n",
+                        stats.keys, expiring
+                    );
+                }
+                _ => return Err(KvsError::UnexpectedResponseType),
+            }
+        }
+        Ok(Reply::Bulk(Some(info)))
+    }
+
+    /// Counts the keys `f` returns true for.
+    fn count<F: Fn(&str) -> Result<bool>>(&self, keys: &[String], f: F) -> Result<Reply> {
+        let mut count = 0;
+        for key in keys {
+            if f(key)? {
+                count += 1;
+            }
+        }
+        Ok(Reply::Integer(count))
+    }
+
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        match self.handler.call(Request::Get {
+            key: key.to_owned(),
+        })? {
+            Response::Ok(value) => Ok(value),
+            _ => Err(KvsError::UnexpectedResponseType),
+        }
+    }
+
+    /// Removes `key`, returning whether it existed.
+    fn remove(&self, key: &str) -> Result<bool> {
+        match self.handler.call(Request::Remove {
+            key: key.to_owned(),
+        }) {
+            Ok(_) => Ok(true),
+            Err(KvsError::KeyNotFound) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Returns the part of a glob-style `pattern` before its first special
+/// character, which every key matching it starts with.
+fn literal_prefix(pattern: &str) -> &str {
+    let len = pattern.find(['*', '?', '[', '\\']).unwrap_or(pattern.len());
+    &pattern[..len]
+}
+
+/// Returns whether `text` matches the glob-style `pattern` of `KEYS`: `*`,
+/// `?`, `[abc]`, `[^a-z]` and `\` escapes.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Where to resume after the last `*` if the rest fails to match.
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        let step = match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p + 1, t));
+                p += 1;
+                continue;
+            }
+            Some(b'?') => Some(1),
+            Some(b'[') => match_class(&pattern[p..], text[t]),
+            Some(b'\\') if p + 1 < pattern.len() => Some(2).filter(|_| pattern[p + 1] == text[t]),
+            Some(c) => Some(1).filter(|_| *c == text[t]),
+            None => None,
+        };
+        match (step, star) {
+            (Some(step), _) => {
+                p += step;
+                t += 1;
+            }
+            (None, Some((star_p, star_t))) => {
+                p = star_p;
+                t = star_t + 1;
+                star = Some((star_p, star_t + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p.min(pattern.len())..].iter().all(|c| *c == b'*')
+}
+
+/// Matches `c` against the class at the start of `pattern`, returning the
+/// length of the class if it matches.
+fn match_class(pattern: &[u8], c: u8) -> Option<usize> {
+    let mut i = 1;
+    let negated = pattern.get(i) == Some(&b'^');
+    if negated {
+        i += 1;
+    }
+    let mut matched = false;
+    while i < pattern.len() && pattern[i] != b']' {
+        if pattern[i] == b'\\' && i + 1 < pattern.len() {
+            matched |= pattern[i + 1] == c;
+            i += 2;
+        } else if i + 2 < pattern.len() && pattern[i + 1] == b'-' && pattern[i + 2] != b']' {
+            let (low, high) = (
+                pattern[i].min(pattern[i + 2]),
+                pattern[i].max(pattern[i + 2]),
+            );
+            matched |= (low..=high).contains(&c);
+            i += 3;
+        } else {
+            matched |= pattern[i] == c;
+            i += 1;
+        }
+    }
+    // An unterminated class matches like Redis does, up to the end.
+    Some(i.min(pattern.len() - 1) + 1).filter(|_| matched != negated)
+}
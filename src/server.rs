@@ -1,6 +1,17 @@
-use crate::{Config, Connection, KvStore, Result};
+use crate::connection::Handler;
+use crate::expiry::{self, Deadlines};
+use crate::http;
+use crate::metrics::{self, Metrics};
+use crate::raft::{Raft, TcpTransport};
+use crate::replication::{self, Replica};
+use crate::resp;
+use crate::watch::Watchers;
+use crate::{Config, Connection, Engine, KvStore, KvsEngine, KvsError, LsmKvsEngine, Result};
 use slog::{o, Logger};
+use std::fs;
+use std::io;
 use std::net::{TcpListener, TcpStream};
+use std::path::Path;
 use std::sync::mpsc::{channel, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread;
@@ -8,21 +19,33 @@ use std::thread;
 pub struct Server {
     log: Logger,
     config: Config,
-    db: Arc<Mutex<KvStore>>,
+    db: Arc<Mutex<Box<dyn KvsEngine + Send>>>,
+    metrics: Arc<Metrics>,
+    watchers: Arc<Watchers>,
+    deadlines: Arc<Deadlines>,
+    replica: Option<Arc<Replica>>,
+    raft: Option<Arc<Raft>>,
     listener_threads: Vec<thread::JoinHandle<()>>,
     listener_channels: Vec<Sender<()>>,
 }
 
 impl Server {
-    pub fn new(config: Config) -> Self {
+    pub fn new(config: Config) -> Result<Self> {
         let log = config.log.new(o!("server-address"=>config.addr));
-        Server {
-            db: Arc::new(Mutex::new(KvStore::open(config.path()).unwrap())),
+        Ok(Server {
+            db: Arc::new(Mutex::new(open_engine(&config)?)),
+            metrics: Arc::new(Metrics::new()),
+            watchers: Arc::new(Watchers::open(&config.path)?),
+            deadlines: Arc::new(Deadlines::open(&config.path)?),
+            replica: config
+                .replica_of
+                .map(|leader| Arc::new(Replica::new(leader))),
+            raft: None,
             listener_threads: Vec::new(),
             listener_channels: Vec::new(),
             log,
             config,
-        }
+        })
     }
 
     pub fn run(&mut self) -> Result<()> {
@@ -37,7 +60,53 @@ impl Server {
         let (tx, rx) = channel::<()>();
         self.listener_channels.push(tx);
 
-        let db = self.db.clone();
+        if let Some(addr) = self.config.metrics_addr {
+            let metrics_listener = TcpListener::bind(addr)?;
+            let db = self.db.clone();
+            let metrics = self.metrics.clone();
+            let log = self.log.new(o!("metrics-address"=>addr));
+            thread::spawn(move || metrics::serve_http(metrics_listener, metrics, db, log));
+        }
+
+        if let Some(replica) = &self.replica {
+            let replica = replica.clone();
+            let db = self.db.clone();
+            let watchers = self.watchers.clone();
+            let log = self.log.new(o!("leader-address"=>replica.leader()));
+            thread::spawn(move || replication::follow(replica, db, watchers, log));
+        }
+
+        if let Some(cluster) = &self.config.cluster {
+            let mut cluster = cluster.clone();
+            cluster.dir.get_or_insert_with(|| self.config.path.clone());
+            let transport = Arc::new(TcpTransport::new(cluster.addr.clone()));
+            self.raft = Some(Raft::start(
+                cluster,
+                self.db.clone(),
+                self.watchers.clone(),
+                transport,
+                self.log.clone(),
+            )?);
+        }
+
+        if let Some(addr) = self.config.resp_addr {
+            let resp_listener = TcpListener::bind(addr)?;
+            let handler = self.handler();
+            let log = self.log.new(o!("resp-address"=>addr));
+            thread::spawn(move || resp::serve(resp_listener, handler, log));
+        }
+
+        if let Some(addr) = self.config.http_addr {
+            let http_listener = TcpListener::bind(addr)?;
+            let handler = self.handler();
+            let log = self.log.new(o!("http-address"=>addr));
+            thread::spawn(move || http::serve(http_listener, handler, log));
+        }
+
+        let handler = self.handler();
+        thread::spawn(move || expiry::sweep(handler));
+
+        let handler = self.handler();
         let log = self.log.clone();
         let th = thread::spawn(move || {
             for stream in listener.incoming() {
@@ -47,10 +116,10 @@ impl Server {
                 }
                 match stream {
                     Ok(stream) => {
-                        let db1 = db.clone();
+                        let handler1 = handler.clone();
                         let log1 = log.clone();
                         thread::spawn(move || {
-                            let mut conn = Connection::new(stream, db1, log1);
+                            let mut conn = Connection::new(stream, handler1, log1);
                             conn.run();
                         });
                     }
@@ -66,6 +135,20 @@ impl Server {
         Ok(())
     }
 
+    /// Returns a handler answering requests on this server's store, for the
+    /// connections of every protocol.
+    fn handler(&self) -> Handler {
+        Handler {
+            db: self.db.clone(),
+            metrics: self.metrics.clone(),
+            watchers: self.watchers.clone(),
+            deadlines: self.deadlines.clone(),
+            replica: self.replica.clone(),
+            raft: self.raft.clone(),
+            admin: self.config.admin_requests,
+        }
+    }
+
     pub fn join(&mut self) {
         if self.listener_threads.len() > 0 {
             self.listener_threads.pop().unwrap().join();
@@ -74,3 +157,32 @@ impl Server {
 
     pub fn stop(&mut self) {}
 }
+
+/// Opens the store of the configured engine in the data directory.
+pub(crate) fn open_engine(config: &Config) -> Result<Box<dyn KvsEngine + Send>> {
+    check_engine(config.path(), config.engine)?;
+    Ok(match config.engine {
+        Engine::kvs | Engine::sled => Box::new(KvStore::open_with_options(
+            config.path(),
+            config.store.clone(),
+        )?),
+        Engine::lsm => Box::new(LsmKvsEngine::open(config.path())?),
+    })
+}
+
+/// Records the engine in the data directory, and refuses to open a directory
+/// written by another engine.
+fn check_engine(dir: &Path, engine: Engine) -> Result<()> {
+    let path = dir.join("engine");
+    match fs::read_to_string(&path) {
+        Ok(previous) if previous.trim() != engine.to_string() => {
+            Err(KvsError::WrongEngine(previous.trim().to_owned()))
+        }
+        Ok(_) => Ok(()),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            fs::write(&path, engine.to_string())?;
+            Ok(())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
@@ -0,0 +1,224 @@
+//! Client-side sharding of keys over several servers.
+//!
+//! Every server owns many points, its virtual nodes, on a ring of 64-bit
+//! hashes, and a key belongs to the server owning the first point at or after
+//! the hash of the key. Adding a server therefore only moves the keys that
+//! fall just before its points, about `1 / n` of them.
+
+use crate::client::Client;
+use crate::{KvsError, Result};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::thread;
+
+/// Virtual nodes per server unless configured otherwise.
+pub const DEFAULT_VNODES: usize = 128;
+
+/// Keys a rebalance reads from a server at once.
+const REBALANCE_PAGE: usize = 1000;
+
+/// A consistent-hash ring of servers.
+#[derive(Debug, Clone)]
+pub struct HashRing {
+    vnodes: usize,
+    ring: BTreeMap<u64, SocketAddr>,
+}
+
+impl HashRing {
+    /// Creates an empty ring placing `vnodes` points per server.
+    pub fn new(vnodes: usize) -> Self {
+        HashRing {
+            vnodes: vnodes.max(1),
+            ring: BTreeMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, server: SocketAddr) {
+        for i in 0..self.vnodes {
+            self.ring.insert(hash(&format!("{}#{}", server, i)), server);
+        }
+    }
+
+    pub fn remove(&mut self, server: SocketAddr) {
+        self.ring.retain(|_, owner| *owner != server);
+    }
+
+    /// Returns the server owning `key`, unless the ring is empty.
+    pub fn server_for(&self, key: &str) -> Option<SocketAddr> {
+        let point = hash(key);
+        self.ring
+            .range(point..)
+            .next()
+            .or_else(|| self.ring.iter().next())
+            .map(|(_, server)| *server)
+    }
+
+    /// Returns the servers on the ring, in address order.
+    pub fn servers(&self) -> Vec<SocketAddr> {
+        let mut servers: Vec<SocketAddr> = self.ring.values().copied().collect();
+        servers.sort();
+        servers.dedup();
+        servers
+    }
+}
+
+/// FNV-1a, finished with the splitmix64 mixer so that similar keys spread
+/// over the whole ring. It must stay stable: it decides where keys live.
+fn hash(key: &str) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in key.bytes() {
+        h ^= u64::from(byte);
+        h = h.wrapping_mul(0x0100_0000_01b3);
+    }
+    h = (h ^ (h >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    h ^ (h >> 31)
+}
+
+type Entries = Vec<(String, String)>;
+
+/// The keys a rebalance moved between servers.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RebalanceReport {
+    pub moved: usize,
+    /// Keys their owner already held, which were only removed from the old
+    /// server.
+    pub skipped: usize,
+}
+
+/// A client spreading keys over several servers.
+pub struct ShardedClient {
+    ring: HashRing,
+    clients: HashMap<SocketAddr, Client>,
+}
+
+impl ShardedClient {
+    /// Connects to every server, with `DEFAULT_VNODES` virtual nodes each.
+    pub fn connect(servers: &[SocketAddr]) -> Result<Self> {
+        ShardedClient::connect_with_vnodes(servers, DEFAULT_VNODES)
+    }
+
+    pub fn connect_with_vnodes(servers: &[SocketAddr], vnodes: usize) -> Result<Self> {
+        let mut sharded = ShardedClient {
+            ring: HashRing::new(vnodes),
+            clients: HashMap::new(),
+        };
+        for &server in servers {
+            sharded.clients.insert(server, Client::connect(server)?);
+            sharded.ring.add(server);
+        }
+        Ok(sharded)
+    }
+
+    pub fn ring(&self) -> &HashRing {
+        &self.ring
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.client_for(&key)?.set(key, value)
+    }
+
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.client_for(&key)?.get(key)
+    }
+
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.client_for(&key)?.remove(key)
+    }
+
+    /// Scans every server and merges the keys starting with `prefix`, in key
+    /// order.
+    pub fn scan(&mut self, prefix: String) -> Result<Vec<(String, String)>> {
+        let mut entries = Vec::new();
+        for (_, server_entries) in self.scan_servers(&prefix)? {
+            entries.extend(server_entries);
+        }
+        entries.sort();
+        Ok(entries)
+    }
+
+    /// Connects to a new server and moves the keys it now owns onto it.
+    pub fn add_server(&mut self, server: SocketAddr) -> Result<RebalanceReport> {
+        if let Entry::Vacant(entry) = self.clients.entry(server) {
+            entry.insert(Client::connect(server)?);
+            self.ring.add(server);
+        }
+        self.rebalance()
+    }
+
+    /// Moves every key held by a server other than its owner on the ring.
+    ///
+    /// Servers are read `REBALANCE_PAGE` keys at a time. A key is written to
+    /// its owner before it is removed from the old server, so it stays
+    /// readable through a client that still uses the old ring. A key the
+    /// owner already holds was written through the new ring, so the newer
+    /// value is kept and the old one only removed.
+    pub fn rebalance(&mut self) -> Result<RebalanceReport> {
+        let mut report = RebalanceReport::default();
+        let servers: Vec<SocketAddr> = self.clients.keys().copied().collect();
+        for server in servers {
+            let mut after = None;
+            loop {
+                let page =
+                    self.client(server)?
+                        .scan_page(String::new(), after.take(), REBALANCE_PAGE)?;
+                let last_page = page.len() < REBALANCE_PAGE;
+                after = page.last().map(|(key, _)| key.clone());
+                for (key, value) in page {
+                    let owner = self.ring.server_for(&key).expect("Ring has servers");
+                    if owner == server {
+                        continue;
+                    }
+                    let owner = self.client(owner)?;
+                    if owner.get(key.clone())?.is_some() {
+                        report.skipped += 1;
+                    } else {
+                        owner.set(key.clone(), value)?;
+                        report.moved += 1;
+                    }
+                    match self.client(server)?.remove(key) {
+                        Ok(()) | Err(KvsError::KeyNotFound) => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+                if last_page {
+                    break;
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// Scans every server in parallel.
+    fn scan_servers(&mut self, prefix: &str) -> Result<Vec<(SocketAddr, Entries)>> {
+        thread::scope(|scope| {
+            let scans: Vec<_> = self
+                .clients
+                .iter_mut()
+                .map(|(server, client)| {
+                    let prefix = prefix.to_owned();
+                    (*server, scope.spawn(move || client.scan(prefix)))
+                })
+                .collect();
+            scans
+                .into_iter()
+                .map(|(server, scan)| Ok((server, scan.join().expect("Scan panicked")?)))
+                .collect()
+        })
+    }
+
+    fn client_for(&mut self, key: &str) -> Result<&mut Client> {
+        let server = self
+            .ring
+            .server_for(key)
+            .ok_or_else(|| KvsError::StringErr("No servers to shard over".to_owned()))?;
+        self.client(server)
+    }
+
+    fn client(&mut self, server: SocketAddr) -> Result<&mut Client> {
+        self.clients
+            .get_mut(&server)
+            .ok_or_else(|| KvsError::StringErr(format!("Not connected to {}", server)))
+    }
+}
@@ -0,0 +1,39 @@
+//! Statistics about a running store.
+
+use crate::cache::CacheStats;
+use crate::compression::CompressionStats;
+use serde::{Deserialize, Serialize};
+
+/// A snapshot of the state and activity of a `KvStore`.
+///
+/// Operation counts cover the time since the store was opened.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct Stats {
+    /// Number of live keys.
+    pub keys: u64,
+    /// Number of log generations on disk.
+    pub generations: u64,
+    /// Size of all log generations.
+    pub total_bytes: u64,
+    /// Bytes that the next compaction can reclaim.
+    pub stale_bytes: u64,
+    pub sets: u64,
+    pub gets: u64,
+    pub removes: u64,
+    pub compactions: u64,
+    /// When the last compaction finished, in seconds since the Unix epoch.
+    pub last_compaction_at: Option<u64>,
+    /// How long the last compaction took, in milliseconds.
+    pub last_compaction_ms: Option<u64>,
+    pub compression: CompressionStats,
+    pub cache: CacheStats,
+}
+
+/// The outcome of a compaction.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct CompactionReport {
+    /// How much smaller the log is after the compaction.
+    pub reclaimed_bytes: u64,
+    /// How long the compaction took, in milliseconds.
+    pub duration_ms: u64,
+}
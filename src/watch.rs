@@ -0,0 +1,281 @@
+//! Notifications of key changes to watching connections.
+//!
+//! An event carries the sequence number of its change in the store's change
+//! feed. Recent events are kept in memory, and a watcher resuming from an
+//! older one, for example after a restart, reads the changes it missed from
+//! the feed.
+//!
+//! Engines without a change feed leave the numbering to the watchers, which
+//! hand the numbers out from blocks recorded in the `watch-seq` file before
+//! use, so that they keep growing across restarts. A watcher of such an
+//! engine can only resume from the recent events.
+
+use crate::{KvsEngine, KvsError, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::Mutex;
+
+/// Number of recent events kept so that watchers can resume.
+const HISTORY_SIZE: usize = 10_000;
+
+/// Number of events a watcher may fall behind before it is dropped.
+const SUBSCRIBER_BUFFER: usize = 1_000;
+
+/// Number of event numbers recorded at once, for engines without a change
+/// feed.
+const SEQ_BLOCK: u64 = 1_000;
+
+const SEQ_FILE: &str = "watch-seq";
+
+/// A change of a key, numbered in the order the server applied it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Event {
+    pub seq: u64,
+    pub key: String,
+    /// The new value, or `None` if the key was removed.
+    pub value: Option<String>,
+}
+
+/// The watchers of a server, and the recent events they can resume from.
+#[derive(Default)]
+pub struct Watchers {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Sequence number of the last published event.
+    last_seq: u64,
+    /// Where the numbers handed out for engines without a change feed are
+    /// recorded, if they outlive the server.
+    path: Option<PathBuf>,
+    /// The highest number recorded in the file.
+    reserved: u64,
+    history: VecDeque<Event>,
+    subscribers: Vec<(String, Subscriber)>,
+}
+
+/// Where the events of a watcher go.
+enum Subscriber {
+    Blocking(SyncSender<Event>),
+    #[cfg(feature = "async")]
+    Async(tokio::sync::mpsc::Sender<Event>),
+}
+
+impl Subscriber {
+    /// Hands an event over without waiting, returning whether it was taken.
+    fn try_send(&self, event: Event) -> bool {
+        match self {
+            Subscriber::Blocking(sender) => sender.try_send(event).is_ok(),
+            #[cfg(feature = "async")]
+            Subscriber::Async(sender) => sender.try_send(event).is_ok(),
+        }
+    }
+}
+
+impl Watchers {
+    /// Creates watchers whose own event numbers start at 1 each time.
+    pub fn new() -> Self {
+        Watchers::default()
+    }
+
+    /// Creates watchers whose own event numbers continue from the previous
+    /// run in `dir`.
+    pub fn open(dir: &Path) -> Result<Self> {
+        let path = dir.join(SEQ_FILE);
+        let reserved = match fs::read_to_string(&path) {
+            Ok(content) => content
+                .trim()
+                .parse()
+                .map_err(|_| KvsError::Corruption(format!("bad {} file", SEQ_FILE)))?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Watchers {
+            inner: Mutex::new(Inner {
+                last_seq: reserved,
+                path: Some(path),
+                reserved,
+                ..Inner::default()
+            }),
+        })
+    }
+
+    /// Returns the sequence number of the last published event.
+    pub fn last_seq(&self) -> u64 {
+        self.inner.lock().unwrap().last_seq
+    }
+
+    /// Sends a change that was just applied to `db` to the watchers of a
+    /// matching prefix, numbered with its sequence number in the change feed
+    /// of `db`.
+    ///
+    /// Callers must publish changes in the order they are applied, while
+    /// still holding `db`. Watchers that fell too far behind are dropped,
+    /// which ends their stream.
+    ///
+    /// # Errors
+    ///
+    /// It returns an error if the sequence number cannot be read, or for an
+    /// engine without a change feed, the next block of event numbers cannot
+    /// be recorded. The change is then not published.
+    pub fn publish(
+        &self,
+        db: &mut dyn KvsEngine,
+        key: String,
+        value: Option<String>,
+    ) -> Result<()> {
+        let feed_seq = feed_seq(db)?;
+        let mut inner = self.inner.lock().unwrap();
+        match feed_seq {
+            Some(seq) => inner.last_seq = seq,
+            None => {
+                if inner.last_seq == inner.reserved {
+                    if let Some(path) = &inner.path {
+                        save_seq(path, inner.reserved + SEQ_BLOCK)?;
+                    }
+                    inner.reserved += SEQ_BLOCK;
+                }
+                inner.last_seq += 1;
+            }
+        }
+        let event = Event {
+            seq: inner.last_seq,
+            key,
+            value,
+        };
+        // A full buffer drops the watcher as a closed one does.
+        inner.subscribers.retain(|(prefix, subscriber)| {
+            !event.key.starts_with(prefix.as_str()) || subscriber.try_send(event.clone())
+        });
+        if inner.history.len() == HISTORY_SIZE {
+            inner.history.pop_front();
+        }
+        inner.history.push_back(event);
+        Ok(())
+    }
+
+    /// Subscribes to the changes of keys starting with `prefix`.
+    ///
+    /// With `since`, the events numbered after it are returned first, from
+    /// the recent events or else from the change feed of `db`. Callers must
+    /// hold `db`, so that no change is published meanwhile.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::HistoryUnavailable` if some of the changes after
+    /// `since` are no longer kept, or `since` is past the last change.
+    pub fn subscribe(
+        &self,
+        db: &mut dyn KvsEngine,
+        prefix: String,
+        since: Option<u64>,
+    ) -> Result<(Vec<Event>, Receiver<Event>)> {
+        let (sender, receiver) = sync_channel(SUBSCRIBER_BUFFER);
+        let backlog = self.add(db, prefix, since, Subscriber::Blocking(sender))?;
+        Ok((backlog, receiver))
+    }
+
+    /// Subscribes like `subscribe`, with a receiver for async tasks.
+    #[cfg(feature = "async")]
+    pub fn subscribe_async(
+        &self,
+        db: &mut dyn KvsEngine,
+        prefix: String,
+        since: Option<u64>,
+    ) -> Result<(Vec<Event>, tokio::sync::mpsc::Receiver<Event>)> {
+        let (sender, receiver) = tokio::sync::mpsc::channel(SUBSCRIBER_BUFFER);
+        let backlog = self.add(db, prefix, since, Subscriber::Async(sender))?;
+        Ok((backlog, receiver))
+    }
+
+    /// Registers a subscriber, returning the events after `since` it missed.
+    fn add(
+        &self,
+        db: &mut dyn KvsEngine,
+        prefix: String,
+        since: Option<u64>,
+        subscriber: Subscriber,
+    ) -> Result<Vec<Event>> {
+        let feed_seq = feed_seq(db)?;
+        let mut inner = self.inner.lock().unwrap();
+        let backlog = match since {
+            Some(since) => {
+                let last_seq = feed_seq.unwrap_or(inner.last_seq);
+                let oldest = inner
+                    .history
+                    .front()
+                    .map_or(last_seq + 1, |event| event.seq);
+                if since > last_seq {
+                    return Err(KvsError::HistoryUnavailable(since));
+                }
+                if since + 1 >= oldest {
+                    inner
+                        .history
+                        .iter()
+                        .filter(|event| event.seq > since && event.key.starts_with(&prefix))
+                        .cloned()
+                        .collect()
+                } else if feed_seq.is_some() {
+                    feed_events(db, &prefix, since, last_seq)?
+                } else {
+                    return Err(KvsError::HistoryUnavailable(since));
+                }
+            }
+            None => Vec::new(),
+        };
+        inner.subscribers.push((prefix, subscriber));
+        Ok(backlog)
+    }
+}
+
+/// Returns the sequence number of the last change of `db`, or `None` if it
+/// has no change feed.
+fn feed_seq(db: &mut dyn KvsEngine) -> Result<Option<u64>> {
+    match db.last_seq() {
+        Ok(seq) => Ok(Some(seq)),
+        Err(KvsError::Unsupported(_)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Reads the changes after `since` of keys starting with `prefix` from the
+/// change feed, provided it still has every change up to `last_seq`.
+fn feed_events(
+    db: &mut dyn KvsEngine,
+    prefix: &str,
+    since: u64,
+    last_seq: u64,
+) -> Result<Vec<Event>> {
+    let changes = db.changes_since(since)?;
+    // Compaction may have dropped changes the watcher has not seen.
+    if !changes
+        .iter()
+        .map(|change| change.seq)
+        .eq(since + 1..=last_seq)
+    {
+        return Err(KvsError::HistoryUnavailable(since));
+    }
+    Ok(changes
+        .into_iter()
+        .filter(|change| change.key.starts_with(prefix))
+        .map(|change| Event {
+            seq: change.seq,
+            key: change.key,
+            value: change.value,
+        })
+        .collect())
+}
+
+fn save_seq(path: &Path, seq: u64) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(seq.to_string().as_bytes())?;
+    file.sync_all()?;
+    fs::rename(tmp_path, path)?;
+    Ok(())
+}
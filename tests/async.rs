@@ -0,0 +1,114 @@
+#![cfg(feature = "async")]
+
+use kvs::client::Client;
+use kvs::common::{Request, Response};
+use kvs::{AsyncClient, AsyncServer, Config, Engine, KvsError};
+use slog::{o, Discard, Logger};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::net::TcpStream;
+
+async fn start(port: u16, temp_dir: &TempDir) -> SocketAddr {
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let config = Config::new(
+        addr,
+        temp_dir.path().to_path_buf(),
+        Engine::kvs,
+        Logger::root(Discard, o!()),
+    );
+    tokio::spawn(AsyncServer::new(config).unwrap().run());
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    addr
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn async_client_and_server() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = start(4024, &temp_dir).await;
+
+    let mut client = AsyncClient::connect(addr).await.unwrap();
+    client
+        .set("key1".to_owned(), "value1".to_owned())
+        .await
+        .unwrap();
+    client
+        .set("key2".to_owned(), "value2".to_owned())
+        .await
+        .unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).await.unwrap(),
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        client.scan("key".to_owned()).await.unwrap(),
+        vec![
+            ("key1".to_owned(), "value1".to_owned()),
+            ("key2".to_owned(), "value2".to_owned()),
+        ]
+    );
+    client.remove("key1".to_owned()).await.unwrap();
+    assert_eq!(client.get("key1".to_owned()).await.unwrap(), None);
+    assert!(matches!(
+        client.remove("key1".to_owned()).await,
+        Err(KvsError::KeyNotFound)
+    ));
+
+    // The synchronous client speaks the same protocol.
+    let value = tokio::task::spawn_blocking(move || {
+        let mut client = Client::connect(addr).unwrap();
+        client.get("key2".to_owned()).unwrap()
+    })
+    .await
+    .unwrap();
+    assert_eq!(value, Some("value2".to_owned()));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn async_server_holds_idle_connections() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = start(4025, &temp_dir).await;
+
+    let mut idle = Vec::new();
+    for _ in 0..5000 {
+        idle.push(TcpStream::connect(addr).await.unwrap());
+    }
+    let mut client = AsyncClient::connect(addr).await.unwrap();
+    client
+        .set("key".to_owned(), "value".to_owned())
+        .await
+        .unwrap();
+    assert_eq!(
+        client.get("key".to_owned()).await.unwrap(),
+        Some("value".to_owned())
+    );
+    let stats = client.stats().await.unwrap();
+    assert_eq!(stats.keys, 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn async_server_holds_many_watches() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = start(4032, &temp_dir).await;
+
+    // More watches than the blocking thread pool has threads.
+    let mut watches = Vec::new();
+    for _ in 0..600 {
+        let mut watch = AsyncClient::connect(addr).await.unwrap();
+        let request = Request::Watch {
+            prefix: "key".to_owned(),
+            since: None,
+        };
+        assert!(matches!(
+            watch.call(&request).await.unwrap(),
+            Response::Ok(None)
+        ));
+        watches.push(watch);
+    }
+    let mut client = AsyncClient::connect(addr).await.unwrap();
+    let set = client.set("key".to_owned(), "value".to_owned());
+    tokio::time::timeout(Duration::from_secs(5), set)
+        .await
+        .expect("the server stopped answering")
+        .unwrap();
+}
@@ -291,6 +291,23 @@ fn cli_access_server(engine: &str, addr: &str) {
         .success()
         .stdout(is_empty());
 
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["stats", "--json", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("\"keys\": 1"))
+        .stdout(contains("\"sets\": 3"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["compact", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Reclaimed"));
+
     sender.send(()).unwrap();
     handle.join().unwrap();
 
@@ -335,3 +352,1312 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+fn scrape(addr: &str, path: &str) -> String {
+    use std::io::{Read, Write};
+    let mut stream = std::net::TcpStream::connect(addr).unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", path, addr).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn cli_metrics() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&[
+            "--addr",
+            "127.0.0.1:4006",
+            "--metrics-addr",
+            "127.0.0.1:4007",
+        ])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", "127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", "127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    // A client that never sends its request must not block the scrapes
+    // behind it for good.
+    let _stalled = std::net::TcpStream::connect("127.0.0.1:4007").unwrap();
+    let response = scrape("127.0.0.1:4007", "/metrics");
+    child.kill().expect("server exited before killed");
+
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains("kvs_requests_total{request=\"set\"} 1"));
+    assert!(response.contains("kvs_requests_total{request=\"get\"} 1"));
+    assert!(response.contains("kvs_request_duration_seconds_count{request=\"get\"} 1"));
+    assert!(response.contains("# TYPE kvs_active_connections gauge"));
+    assert!(response.contains("kvs_store_keys 1"));
+    assert!(!response.contains("kvs_read_bytes_total 0\n"));
+}
+
+// `kvs-admin verify` should fail on a corrupt log until it is repaired.
+#[test]
+fn admin_cli_verify_repair() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(
+        temp_dir.path().join("1.log"),
+        "{\"Set\":[\"key1\",\"value1\"]}{\"Set\":[\"key2\",",
+    )
+    .unwrap();
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["verify", "."])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("generation 1, offset 25"))
+        .stdout(contains("1 live keys"));
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["repair", "."])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Salvaged 1 live records into generation 2"));
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["verify", "."])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("0 bad records"));
+}
+
+#[test]
+fn admin_cli_dump() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(
+        temp_dir.path().join("1.log"),
+        "{\"Set\":[\"key1\",\"value1\"]}{\"Remove\":\"key1\"}{\"Set\":[\"key2\",\"value2\"]}",
+    )
+    .unwrap();
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["dump", "."])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("1\t0\t25\tSet\tkey1\n1\t25\t17\tRemove\tkey1\n1\t42\t25\tSet\tkey2\n");
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["dump", ".", "--live"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("1\t42\t25\tSet\tkey2\n");
+}
+
+// `kvs-client watch` should print matching changes, and resume from a sequence number,
+// even after a restart.
+#[test]
+fn cli_watch() {
+    use std::io::{BufRead, BufReader};
+    use std::process::Stdio;
+
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4008";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut watch = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["watch", "app/", "--addr", addr])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_millis(500));
+
+    for args in &[
+        &["set", "app/a", "1"][..],
+        &["set", "other", "2"][..],
+        &["rm", "app/a"][..],
+    ] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(*args)
+            .args(&["--addr", addr])
+            .assert()
+            .success();
+    }
+
+    let mut lines = BufReader::new(watch.stdout.take().unwrap()).lines();
+    assert_eq!(lines.next().unwrap().unwrap(), "1\tset\tapp/a\t1");
+    assert_eq!(lines.next().unwrap().unwrap(), "3\tremove\tapp/a");
+    watch.kill().unwrap();
+
+    let mut resumed = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["watch", "--since", "1", "--addr", addr])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut lines = BufReader::new(resumed.stdout.take().unwrap()).lines();
+    assert_eq!(lines.next().unwrap().unwrap(), "2\tset\tother\t2");
+    assert_eq!(lines.next().unwrap().unwrap(), "3\tremove\tapp/a");
+    resumed.kill().unwrap();
+
+    // After a restart, the missed changes are read from the store's feed.
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let mut resumed = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["watch", "--since", "1", "--addr", addr])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut lines = BufReader::new(resumed.stdout.take().unwrap()).lines();
+    assert_eq!(lines.next().unwrap().unwrap(), "2\tset\tother\t2");
+    assert_eq!(lines.next().unwrap().unwrap(), "3\tremove\tapp/a");
+    resumed.kill().unwrap();
+    resumed.wait().unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["watch", "--since", "4", "--addr", addr])
+        .assert()
+        .failure()
+        .stderr(contains("no longer available"));
+
+    server.kill().expect("server exited before killed");
+}
+
+#[test]
+fn cli_replication() {
+    let leader_dir = TempDir::new().unwrap();
+    let follower_dir = TempDir::new().unwrap();
+    let leader_addr = "127.0.0.1:4009";
+    let follower_addr = "127.0.0.1:4010";
+    let client = |args: &[&str], addr: &str| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).args(&["--addr", addr]);
+        cmd
+    };
+
+    let mut leader = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", leader_addr])
+        .current_dir(&leader_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    client(&["set", "key1", "value1"], leader_addr)
+        .assert()
+        .success();
+    client(&["set", "key2", "value2"], leader_addr)
+        .assert()
+        .success();
+
+    let mut follower = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", follower_addr, "--replica-of", leader_addr])
+        .current_dir(&follower_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    client(&["get", "key1"], follower_addr)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    client(&["set", "key3", "value3"], leader_addr)
+        .assert()
+        .success();
+    client(&["rm", "key2"], leader_addr).assert().success();
+    thread::sleep(Duration::from_millis(500));
+    client(&["get", "key3"], follower_addr)
+        .assert()
+        .success()
+        .stdout("value3\n");
+    client(&["get", "key2"], follower_addr)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+
+    client(&["set", "key4", "value4"], follower_addr)
+        .assert()
+        .failure()
+        .stderr(contains("Read-only"));
+    client(&["replication"], follower_addr)
+        .assert()
+        .success()
+        .stdout(contains("connected:    true"))
+        .stdout(contains("lag:          0"));
+    client(&["replication"], leader_addr)
+        .assert()
+        .failure()
+        .stderr(contains("Not a replica"));
+
+    // Followers compact their own store.
+    client(&["compact"], follower_addr).assert().success();
+
+    // The follower resumes from the changes it applied once the leader is back.
+    leader.kill().expect("server exited before killed");
+    leader.wait().unwrap();
+    let mut leader = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", leader_addr])
+        .current_dir(&leader_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    client(&["set", "key5", "value5"], leader_addr)
+        .assert()
+        .success();
+    thread::sleep(Duration::from_secs(2));
+    client(&["get", "key5"], follower_addr)
+        .assert()
+        .success()
+        .stdout("value5\n");
+    client(&["get", "key1"], follower_addr)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    // Other consumers of the leader's change feed may be further behind, so
+    // only an operator acknowledges changes.
+    let acknowledged = || match fs::read(leader_dir.path().join("changes")) {
+        Ok(state) => serde_json::from_slice::<serde_json::Value>(&state).unwrap()["acknowledged"]
+            .as_u64()
+            .unwrap(),
+        Err(_) => 0,
+    };
+    assert_eq!(acknowledged(), 0);
+    client(&["ack", "4"], leader_addr).assert().success();
+    assert_eq!(acknowledged(), 4);
+
+    follower.kill().expect("server exited before killed");
+    leader.kill().expect("server exited before killed");
+}
+
+#[test]
+fn cli_cluster() {
+    let addrs = [
+        "127.0.0.1:4011",
+        "127.0.0.1:4012",
+        "127.0.0.1:4013",
+        "127.0.0.1:4014",
+    ];
+    let dirs: Vec<TempDir> = addrs.iter().map(|_| TempDir::new().unwrap()).collect();
+    let mut servers = Vec::new();
+    for id in 1..=3 {
+        let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+        cmd.args(&["--addr", addrs[id - 1], "--node-id", &id.to_string()])
+            .current_dir(&dirs[id - 1]);
+        for peer in (1..=3).filter(|&peer| peer != id) {
+            cmd.args(&["--peer", &format!("{}={}", peer, addrs[peer - 1])]);
+        }
+        servers.push(cmd.spawn().unwrap());
+    }
+    thread::sleep(Duration::from_secs(2));
+
+    // Any node takes requests, followers redirect them to the leader.
+    for (i, addr) in addrs[..3].iter().enumerate() {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["set", &format!("key{}", i), "value", "--addr", addr])
+            .assert()
+            .success();
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key0", "--addr", addrs[2]])
+        .assert()
+        .success()
+        .stdout("value\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["cluster", "status", "--addr", addrs[1]])
+        .assert()
+        .success()
+        .stdout(contains(
+            "members:  1=127.0.0.1:4011 2=127.0.0.1:4012 3=127.0.0.1:4013",
+        ));
+
+    servers.push(
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--addr", addrs[3], "--node-id", "4", "--join"])
+            .current_dir(&dirs[3])
+            .spawn()
+            .unwrap(),
+    );
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["cluster", "add", "4", addrs[3], "--addr", addrs[0]])
+        .assert()
+        .success();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["cluster", "status", "--addr", addrs[3]])
+        .assert()
+        .success()
+        .stdout(contains("4=127.0.0.1:4014"));
+    for mut server in servers {
+        server.kill().expect("server exited before killed");
+    }
+}
+
+#[test]
+fn cli_sharding() {
+    let addrs = ["127.0.0.1:4015", "127.0.0.1:4016", "127.0.0.1:4017"];
+    let dirs: Vec<TempDir> = addrs.iter().map(|_| TempDir::new().unwrap()).collect();
+    let mut servers: Vec<_> = addrs
+        .iter()
+        .zip(&dirs)
+        .map(|(addr, dir)| {
+            Command::cargo_bin("kvs-server")
+                .unwrap()
+                .args(&["--addr", addr])
+                .current_dir(dir)
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+    thread::sleep(Duration::from_secs(1));
+    let two = addrs[..2].join(",");
+    let three = addrs.join(",");
+
+    for i in 0..20 {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["set", &format!("key{:02}", i), &i.to_string()])
+            .args(&["--servers", &two])
+            .assert()
+            .success();
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key07", "--servers", &two])
+        .assert()
+        .success()
+        .stdout("7\n");
+
+    // Even keys are written again through the new ring before the
+    // rebalance, which must keep those newer values.
+    for i in (0..20).step_by(2) {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", &format!("key{:02}", i), &format!("new{}", i)])
+            .args(["--servers", &three])
+            .assert()
+            .success();
+    }
+
+    // The third server owns some keys once they are moved to it.
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["rebalance", "--servers", &three])
+        .assert()
+        .success()
+        .stdout(contains("Moved"));
+    let held: Vec<usize> = addrs
+        .iter()
+        .map(|addr| {
+            let output = Command::cargo_bin("kvs-client")
+                .unwrap()
+                .args(&["scan", "--addr", addr])
+                .output()
+                .unwrap();
+            String::from_utf8(output.stdout).unwrap().lines().count()
+        })
+        .collect();
+    assert_eq!(held.iter().sum::<usize>(), 20);
+    assert!(held[2] > 0);
+
+    let value = |i: usize| {
+        if i % 2 == 1 {
+            i.to_string()
+        } else {
+            format!("new{}", i)
+        }
+    };
+    for i in 0..20 {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["get", &format!("key{:02}", i), "--servers", &three])
+            .assert()
+            .success()
+            .stdout(format!("{}\n", value(i)));
+    }
+    let expected: String = (0..20)
+        .filter(|i| i / 10 == 1)
+        .map(|i| format!("key{:02}\t{}\n", i, value(i)))
+        .collect();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "key1", "--servers", &three])
+        .assert()
+        .success()
+        .stdout(expected);
+
+    for server in &mut servers {
+        server.kill().expect("server exited before killed");
+    }
+}
+
+#[test]
+fn cli_proxy() {
+    let (leader, follower, other, proxy) = (
+        "127.0.0.1:4018",
+        "127.0.0.1:4019",
+        "127.0.0.1:4020",
+        "127.0.0.1:4021",
+    );
+    let dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+    let server = |args: &[&str], dir: &TempDir| {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(args)
+            .current_dir(dir)
+            .spawn()
+            .unwrap()
+    };
+    let client = |args: &[&str], addr: &str| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).args(&["--addr", addr]);
+        cmd
+    };
+    let mut leader_server = server(&["--addr", leader], &dirs[0]);
+    let mut follower_server = server(&["--addr", follower, "--replica-of", leader], &dirs[1]);
+    let mut other_server = server(&["--addr", other], &dirs[2]);
+    thread::sleep(Duration::from_secs(1));
+    let mut proxy_server = Command::cargo_bin("kvs-proxy")
+        .unwrap()
+        .args(&["--addr", proxy, "--read-from-replicas"])
+        .args(&["--backend", &format!("{},{}", leader, follower)])
+        .args(&["--backend", other, "--health-check-ms", "200"])
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    for i in 0..20 {
+        client(&["set", &format!("key{:02}", i), &i.to_string()], proxy)
+            .assert()
+            .success();
+    }
+    thread::sleep(Duration::from_millis(500));
+    for i in 0..20 {
+        client(&["get", &format!("key{:02}", i)], proxy)
+            .assert()
+            .success()
+            .stdout(format!("{}\n", i));
+    }
+    client(&["scan", "key"], proxy)
+        .assert()
+        .success()
+        .stdout(contains("key00\t0\n"))
+        .stdout(contains("key19\t19\n"));
+    client(&["stats"], proxy)
+        .assert()
+        .failure()
+        .stderr(contains("not supported by kvs-proxy"));
+
+    // Reads of the first shard were served by its replica.
+    let output = client(&["stats", "--json"], follower).output().unwrap();
+    let stats: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert!(stats["gets"].as_u64().unwrap() > 0);
+
+    // A shard that is down fails its keys only.
+    other_server.kill().unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let failures = (0..20)
+        .filter(|i| {
+            !client(&["get", &format!("key{:02}", i)], proxy)
+                .output()
+                .unwrap()
+                .status
+                .success()
+        })
+        .count();
+    assert!(failures > 0 && failures < 20);
+
+    proxy_server.kill().unwrap();
+    follower_server.kill().unwrap();
+    leader_server.kill().unwrap();
+}
+
+// Servers should speak the framed protocol, survive a malformed frame and
+// still accept clients sending bare JSON.
+#[test]
+fn cli_wire_protocols() {
+    use kvs::client::Client;
+    use kvs::common::{ErrorCode, Request, Response};
+    use kvs::protocol::{self, Reader, Wire, Writer};
+    use kvs::KvsError;
+    use std::io::{BufReader, Read, Write};
+    use std::net::TcpStream;
+
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--addr", "127.0.0.1:4022"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", "127.0.0.1:4022"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    let mut stream = TcpStream::connect("127.0.0.1:4022").unwrap();
+    assert_eq!(protocol::handshake(&mut stream).unwrap(), Wire::Pipelined);
+    stream
+        .write_all(&[0, 0, 0, 10, 0, 0, 0, 0, 0, 0, 0, 7, 0xff, 0xff])
+        .unwrap();
+    let mut reader =
+        Reader::<_, Response>::new(BufReader::new(stream.try_clone().unwrap()), Wire::Pipelined);
+    match reader.receive().unwrap() {
+        (7, Response::Err(ErrorCode::MalformedMessage(_))) => {}
+        other => panic!("expected an error, got {:?}", other),
+    }
+    let mut writer = Writer::new(stream, Wire::Pipelined);
+    let get = Request::Get {
+        key: "key1".to_owned(),
+    };
+    writer.send(8, &get).unwrap();
+    match reader.receive().unwrap() {
+        (8, Response::Ok(value)) => assert_eq!(value, Some("value1".to_owned())),
+        other => panic!("expected a value, got {:?}", other),
+    }
+
+    // A version 1 client sends frames without request ids.
+    let mut stream = TcpStream::connect("127.0.0.1:4022").unwrap();
+    stream.write_all(&protocol::MAGIC).unwrap();
+    stream.write_all(&[1]).unwrap();
+    let mut reply = [0; 5];
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(reply[4], 1);
+    let mut reader =
+        Reader::<_, Response>::new(BufReader::new(stream.try_clone().unwrap()), Wire::Framed);
+    Writer::new(stream, Wire::Framed).send(0, &get).unwrap();
+    match reader.receive().unwrap() {
+        (_, Response::Ok(value)) => assert_eq!(value, Some("value1".to_owned())),
+        other => panic!("expected a value, got {:?}", other),
+    }
+
+    let mut client = Client::connect("127.0.0.1:4022".parse().unwrap()).unwrap();
+    match client.remove("key2".to_owned()) {
+        Err(KvsError::KeyNotFound) => {}
+        other => panic!("expected KeyNotFound, got {:?}", other),
+    }
+
+    let mut stream = TcpStream::connect("127.0.0.1:4022").unwrap();
+    write!(stream, "{{\"Get\":{{\"key\":\"key1\"}}}}").unwrap();
+    let mut responses =
+        serde_json::Deserializer::from_reader(stream).into_iter::<serde_json::Value>();
+    let response = responses.next().unwrap().unwrap();
+    child.kill().expect("server exited before killed");
+    assert_eq!(response, serde_json::json!({ "Ok": "value1" }));
+}
+
+// Pipelined requests should be answered in order, each with its own response.
+#[test]
+fn cli_pipelining() {
+    use kvs::client::Client;
+    use kvs::common::{ErrorCode, Request, Response};
+
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--addr", "127.0.0.1:4023"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = Client::connect("127.0.0.1:4023".parse().unwrap()).unwrap();
+    let mut requests = Vec::new();
+    for i in 0..1000 {
+        requests.push(Request::Set {
+            key: format!("key{}", i),
+            value: format!("value{}", i),
+        });
+        requests.push(Request::Get {
+            key: format!("key{}", i),
+        });
+    }
+    requests.push(Request::Remove {
+        key: "missing".to_owned(),
+    });
+    let responses = client.pipeline(&requests).unwrap();
+    client
+        .set("after".to_owned(), "pipeline".to_owned())
+        .unwrap();
+    child.kill().expect("server exited before killed");
+
+    assert_eq!(responses.len(), requests.len());
+    for (i, pair) in responses[..2000].chunks(2).enumerate() {
+        match pair {
+            [Response::Ok(None), Response::Ok(Some(value))] => {
+                assert_eq!(value, &format!("value{}", i))
+            }
+            other => panic!("unexpected responses {:?}", other),
+        }
+    }
+    match &responses[2000] {
+        Response::Err(ErrorCode::KeyNotFound) => {}
+        other => panic!("expected KeyNotFound, got {:?}", other),
+    }
+}
+
+#[test]
+fn cli_client_pool() {
+    use kvs::pool::{ClientPool, PoolOptions};
+    use kvs::KvsError;
+    use std::sync::Arc;
+
+    let temp_dir = TempDir::new().unwrap();
+    let start = || {
+        let child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--addr", "127.0.0.1:4026"])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        child
+    };
+    let mut child = start();
+
+    let options = PoolOptions {
+        min_connections: 2,
+        max_connections: 4,
+        backoff: Duration::from_millis(10),
+        ..PoolOptions::default()
+    };
+    let pool = Arc::new(ClientPool::new("127.0.0.1:4026".parse().unwrap(), options).unwrap());
+    assert_eq!(pool.open_connections(), 2);
+
+    let workers: Vec<_> = (0..8)
+        .map(|t| {
+            let pool = pool.clone();
+            thread::spawn(move || {
+                for i in 0..50 {
+                    let key = format!("key{}-{}", t, i);
+                    pool.set(key.clone(), format!("value{}", i)).unwrap();
+                    assert_eq!(pool.get(key).unwrap(), Some(format!("value{}", i)));
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+    assert!(pool.open_connections() <= 4);
+    assert_eq!(pool.scan("key7-".to_owned()).unwrap().len(), 50);
+
+    // Requests fail once retries run out, and reconnect when the server is
+    // back.
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+    assert!(matches!(
+        pool.get("key0-0".to_owned()),
+        Err(KvsError::Io(_))
+    ));
+    let mut child = start();
+    assert_eq!(
+        pool.get("key0-0".to_owned()).unwrap(),
+        Some("value0".to_owned())
+    );
+    pool.set_retryable("key".to_owned(), "value".to_owned())
+        .unwrap();
+    pool.health_check().unwrap();
+    assert!(pool.open_connections() >= 2);
+    child.kill().expect("server exited before killed");
+}
+
+#[test]
+fn cli_resp() {
+    use kvs::client::Client;
+    use std::io::{Read, Write};
+    use std::net::{Shutdown, TcpStream};
+
+    fn command(args: &[&str]) -> String {
+        let mut command = format!("*{}\r\n", args.len());
+        for arg in args {
+            command.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        command
+    }
+
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--addr", "127.0.0.1:4027", "--resp-addr", "127.0.0.1:4028"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut stream = TcpStream::connect("127.0.0.1:4028").unwrap();
+    let mut commands = String::new();
+    for args in [
+        &["PING"][..],
+        &["SET", "key1", "value1"],
+        &["set", "key2", "value2"],
+        &["SET", "key1", "other", "NX"],
+        &["GET", "key1"],
+        &["GET", "missing"],
+        &["SET", "counter", "41"],
+        &["INCR", "counter"],
+        &["INCR", "key1"],
+        &["EXISTS", "key1", "key2", "missing"],
+        &["KEYS", "key*"],
+        &["SCAN", "0", "COUNT", "2"],
+        &["SCAN", "1", "MATCH", "k?y[0-9]"],
+        &["SCAN", "7"],
+        &["DEL", "key2", "missing"],
+        &["SET", "temp", "value", "EX", "1"],
+        &["SET", "key1", "value", "EX", "0"],
+        &["GET"],
+        &["FLUSHALL"],
+    ] {
+        commands.push_str(&command(args));
+    }
+    // Inline commands, as typed into telnet.
+    commands.push_str("ping hello\r\n");
+    stream.write_all(commands.as_bytes()).unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    let mut replies = String::new();
+    stream.read_to_string(&mut replies).unwrap();
+    assert_eq!(
+        replies,
+        [
+            "+PONG\r\n",
+            "+OK\r\n",
+            "+OK\r\n",
+            "$-1\r\n",
+            "$6\r\nvalue1\r\n",
+            "$-1\r\n",
+            "+OK\r\n",
+            ":42\r\n",
+            "-ERR value is not an integer or out of range\r\n",
+            ":2\r\n",
+            "*2\r\n$4\r\nkey1\r\n$4\r\nkey2\r\n",
+            "*2\r\n$1\r\n1\r\n*2\r\n$7\r\ncounter\r\n$4\r\nkey1\r\n",
+            "*2\r\n$1\r\n0\r\n*1\r\n$4\r\nkey2\r\n",
+            "-ERR invalid cursor\r\n",
+            ":1\r\n",
+            "+OK\r\n",
+            "-ERR invalid expire time in 'set' command\r\n",
+            "-ERR wrong number of arguments for 'get' command\r\n",
+            "-ERR unknown command 'FLUSHALL'\r\n",
+            "$5\r\nhello\r\n",
+        ]
+        .concat()
+    );
+
+    // Lengths announced by a client that never sends the data are not
+    // allocated up front.
+    for _ in 0..16 {
+        let mut stream = TcpStream::connect("127.0.0.1:4028").unwrap();
+        stream.write_all(b"*1048576\r\n$67108864\r\nvalue").unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        let mut replies = String::new();
+        stream.read_to_string(&mut replies).unwrap();
+        assert_eq!(replies, "");
+    }
+    let mut stream = TcpStream::connect("127.0.0.1:4028").unwrap();
+    stream.write_all(command(&["PING"]).as_bytes()).unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    let mut replies = String::new();
+    stream.read_to_string(&mut replies).unwrap();
+    assert_eq!(replies, "+PONG\r\n");
+
+    // The native protocol shares the store, and keys set with EX expire.
+    let mut client = Client::connect("127.0.0.1:4027".parse().unwrap()).unwrap();
+    assert_eq!(
+        client.get("counter".to_owned()).unwrap(),
+        Some("42".to_owned())
+    );
+    assert_eq!(client.get("key2".to_owned()).unwrap(), None);
+    assert_eq!(
+        client.get("temp".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+    thread::sleep(Duration::from_millis(2500));
+    assert_eq!(client.get("temp".to_owned()).unwrap(), None);
+
+    let mut stream = TcpStream::connect("127.0.0.1:4028").unwrap();
+    stream
+        .write_all(command(&["INFO", "keyspace"]).as_bytes())
+        .unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    let mut info = String::new();
+    stream.read_to_string(&mut info).unwrap();
+    assert!(info.contains("db0:keys=2,expires=0"), "{}", info);
+
+    // A write through another protocol clears the deadline, and deadlines
+    // outlive a restart.
+    let mut stream = TcpStream::connect("127.0.0.1:4028").unwrap();
+    let commands = [
+        command(&["SET", "kept", "value", "EX", "1"]),
+        command(&["SET", "temp", "value", "EX", "2"]),
+    ];
+    stream.write_all(commands.concat().as_bytes()).unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    let mut replies = String::new();
+    stream.read_to_string(&mut replies).unwrap();
+    assert_eq!(replies, "+OK\r\n+OK\r\n");
+    client.set("kept".to_owned(), "other".to_owned()).unwrap();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    // Keys expire without RESP being served too.
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--addr", "127.0.0.1:4027"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_millis(3500));
+    let mut client = Client::connect("127.0.0.1:4027".parse().unwrap()).unwrap();
+    assert_eq!(client.get("temp".to_owned()).unwrap(), None);
+    assert_eq!(
+        client.get("kept".to_owned()).unwrap(),
+        Some("other".to_owned())
+    );
+
+    child.kill().expect("server exited before killed");
+}
+
+#[test]
+fn cli_http() {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    /// Sends a request on its own connection, returning the response.
+    fn http(method: &str, target: &str, body: &str) -> String {
+        let mut stream = TcpStream::connect("127.0.0.1:4030").unwrap();
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+            method,
+            target,
+            body.len(),
+            body
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--addr", "127.0.0.1:4029", "--http-addr", "127.0.0.1:4030"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let response = http("GET", "/health", "");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.ends_with("\r\n\r\nOK\n"), "{}", response);
+
+    let response = http("PUT", "/keys/key%201", "value 1");
+    assert!(
+        response.starts_with("HTTP/1.1 204 No Content\r\n"),
+        "{}",
+        response
+    );
+    http("PUT", "/keys/key2", "value2");
+    http("PUT", "/keys/other", "value3");
+
+    let response = http("GET", "/keys/key%201", "");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.contains("Content-Type: text/plain; charset=utf-8\r\n"));
+    assert!(response.ends_with("\r\n\r\nvalue 1"), "{}", response);
+
+    let response = http("GET", "/keys?prefix=key", "");
+    assert!(response.contains("Content-Type: application/json\r\n"));
+    assert!(
+        response
+            .ends_with(r#"[{"key":"key 1","value":"value 1"},{"key":"key2","value":"value2"}]"#),
+        "{}",
+        response
+    );
+
+    let response = http("DELETE", "/keys/key2", "");
+    assert!(
+        response.starts_with("HTTP/1.1 204 No Content\r\n"),
+        "{}",
+        response
+    );
+    for (method, target) in [("GET", "/keys/key2"), ("DELETE", "/keys/key2")] {
+        let response = http(method, target, "");
+        assert!(
+            response.starts_with("HTTP/1.1 404 Not Found\r\n"),
+            "{}",
+            response
+        );
+        assert!(
+            response.ends_with(r#"{"error":"Key not found"}"#),
+            "{}",
+            response
+        );
+    }
+
+    let response = http("POST", "/keys/key2", "");
+    assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+    assert!(response.contains("Allow: GET, PUT, DELETE\r\n"));
+    assert!(http("GET", "/nothing", "").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    assert!(http("GET", "/keys/%zz", "").starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+    // Connections stay open between requests.
+    let mut stream = TcpStream::connect("127.0.0.1:4030").unwrap();
+    stream
+        .write_all(
+            b"GET /keys/other HTTP/1.1\r\n\r\nGET /health HTTP/1.1\r\nConnection: close\r\n\r\n",
+        )
+        .unwrap();
+    let mut responses = String::new();
+    stream.read_to_string(&mut responses).unwrap();
+    assert_eq!(responses.matches("HTTP/1.1 200 OK\r\n").count(), 2);
+    assert!(
+        responses.contains("\r\n\r\nvalue3HTTP/1.1"),
+        "{}",
+        responses
+    );
+
+    child.kill().expect("server exited before killed");
+}
+
+#[test]
+fn cli_compression_threshold() {
+    use kvs::client::Client;
+
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--addr", "127.0.0.1:4031", "--compression-threshold", "64"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = Client::connect("127.0.0.1:4031".parse().unwrap()).unwrap();
+    let value = "abcd".repeat(1000);
+    client.set("small".to_owned(), "value".to_owned()).unwrap();
+    client.set("large".to_owned(), value.clone()).unwrap();
+    assert_eq!(client.get("large".to_owned()).unwrap(), Some(value));
+    let stats = client.stats().unwrap();
+    child.kill().expect("server exited before killed");
+
+    assert_eq!(stats.compression.compressed_values, 1);
+    assert!(stats.compression.compressed_bytes < stats.compression.raw_bytes);
+}
+
+#[test]
+fn cli_value_cache_size() {
+    use kvs::client::Client;
+
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--addr", "127.0.0.1:4033", "--value-cache-size", "4096"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = Client::connect("127.0.0.1:4033".parse().unwrap()).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    for _ in 0..3 {
+        assert_eq!(
+            client.get("key1".to_owned()).unwrap(),
+            Some("value1".to_owned())
+        );
+    }
+    let stats = client.stats().unwrap();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    assert_eq!(stats.cache.entries, 1);
+    assert!(stats.cache.hits >= 2);
+}
+
+#[test]
+fn cli_disk_index_cache() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--addr", "127.0.0.1:4034", "--disk-index-cache", "16"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4034"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4034"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    assert!(temp_dir.path().join("index").is_dir());
+}
+
+#[test]
+fn cli_mmap() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4035";
+    let server = || {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--addr", addr, "--mmap"])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap()
+    };
+    let mut child = server();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .assert()
+        .success();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    // The value is now in a generation that is no longer written to.
+    let mut child = server();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_blob_threshold() {
+    use kvs::client::Client;
+
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--addr", "127.0.0.1:4036", "--blob-threshold", "64"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = Client::connect("127.0.0.1:4036".parse().unwrap()).unwrap();
+    let value = "abcd".repeat(1000);
+    client.set("small".to_owned(), "value".to_owned()).unwrap();
+    client.set("large".to_owned(), value.clone()).unwrap();
+    assert_eq!(client.get("large".to_owned()).unwrap(), Some(value.clone()));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    let blobs: Vec<_> = fs::read_dir(&temp_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "blob"))
+        .collect();
+    assert_eq!(blobs.len(), 1);
+    assert!(fs::read(&blobs[0]).unwrap().len() >= value.len());
+}
+
+#[test]
+fn cli_retain_changes() {
+    use std::io::{BufRead, BufReader};
+    use std::process::Stdio;
+
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4037";
+    let server = || {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--addr", addr, "--retain-changes"])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap()
+    };
+    let client = |args: &[&str]| {
+        let mut command = Command::cargo_bin("kvs-client").unwrap();
+        command.args(args).args(["--addr", addr]);
+        command
+    };
+    let mut child = server();
+    thread::sleep(Duration::from_secs(1));
+    client(&["set", "key1", "value1"]).assert().success();
+    client(&["set", "key1", "value2"]).assert().success();
+    client(&["compact"]).assert().success();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    // The overwritten change outlives compaction and the restart.
+    let mut child = server();
+    thread::sleep(Duration::from_secs(1));
+    let mut watch = client(&["watch", "--since", "0"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut lines = BufReader::new(watch.stdout.take().unwrap()).lines();
+    assert_eq!(lines.next().unwrap().unwrap(), "1\tset\tkey1\tvalue1");
+    assert_eq!(lines.next().unwrap().unwrap(), "2\tset\tkey1\tvalue2");
+    watch.kill().unwrap();
+    watch.wait().unwrap();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_deny_admin_requests() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4038";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--addr", addr, "--deny-admin-requests"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let client = |args: &[&str]| {
+        let mut command = Command::cargo_bin("kvs-client").unwrap();
+        command.args(args).args(["--addr", addr]);
+        command
+    };
+
+    client(&["set", "key1", "value1"]).assert().success();
+    client(&["compact"])
+        .assert()
+        .failure()
+        .stderr(contains("compact is disabled"));
+    client(&["ack", "1"])
+        .assert()
+        .failure()
+        .stderr(contains("acknowledge_changes is disabled"));
+    client(&["get", "key1"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_proxy_retry() {
+    use kvs::common::{Request, Response};
+    use kvs::protocol::{self, Reader, Writer};
+    use std::io::{BufReader, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+
+    let (backend, proxy) = ("127.0.0.1:4039", "127.0.0.1:4040");
+    // A back end that closes the connection after answering `idle`, and
+    // breaks off its response to `partial`.
+    fn serve(stream: TcpStream, received: &Mutex<Vec<String>>) -> kvs::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut raw = stream.try_clone()?;
+        let wire = protocol::accept(&mut reader, &mut raw)?;
+        let mut writer = Writer::new(stream.try_clone()?, wire);
+        for request in Reader::<_, Request>::new(reader, wire) {
+            let (id, request) = request?;
+            // Health checks are answered like writes.
+            let key = match request {
+                Request::Set { key, .. } => key,
+                _ => String::new(),
+            };
+            if !key.is_empty() {
+                received.lock().unwrap().push(key.clone());
+            }
+            if key == "partial" {
+                raw.write_all(&[0, 0])?;
+                break;
+            }
+            writer.send(id, &Response::Ok(None))?;
+            if key == "idle" {
+                break;
+            }
+        }
+        Ok(())
+    }
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let listener = TcpListener::bind(backend).unwrap();
+    let backend_received = received.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let received = backend_received.clone();
+            thread::spawn(move || serve(stream.unwrap(), &received));
+        }
+    });
+    let mut proxy_server = Command::cargo_bin("kvs-proxy")
+        .unwrap()
+        .args(["--addr", proxy, "--backend", backend])
+        .args(["--health-check-ms", "60000"])
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).args(["--addr", proxy]);
+        cmd
+    };
+
+    // The pooled connection was closed by the back end before the request,
+    // which goes out again on a new one. The broken-off response may come
+    // after the back end ran the request, which is not sent again.
+    client(&["set", "idle", "1"]).assert().success();
+    client(&["set", "key1", "1"]).assert().success();
+    client(&["set", "partial", "1"]).assert().failure();
+    proxy_server.kill().expect("proxy exited before killed");
+    proxy_server.wait().unwrap();
+
+    assert_eq!(*received.lock().unwrap(), ["idle", "key1", "partial"]);
+}
@@ -0,0 +1,60 @@
+use kvs::expiry::Deadlines;
+use kvs::Result;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::time::{Duration, SystemTime};
+use tempfile::TempDir;
+
+// Deadlines should outlive a reopen, and the file only grow with the keys set
+// to expire, however often they change.
+#[test]
+fn deadlines_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let past = SystemTime::now() - Duration::from_secs(1);
+    let future = SystemTime::now() + Duration::from_secs(3600);
+    let deadlines = Deadlines::open(temp_dir.path())?;
+    for i in 0..5_000 {
+        deadlines.set(&format!("key{}", i % 10), Some(future))?;
+        deadlines.set("temp", Some(future))?;
+        deadlines.set("temp", None)?;
+    }
+    deadlines.set("key0", Some(past))?;
+    deadlines.set("key1", None)?;
+    drop(deadlines);
+
+    let size = fs::metadata(temp_dir.path().join("expiry"))?.len();
+    assert!(size < 100 * 1024, "{}", size);
+    let deadlines = Deadlines::open(temp_dir.path())?;
+    assert_eq!(deadlines.len(), 9);
+    assert_eq!(deadlines.past(), vec!["key0".to_owned()]);
+    assert!(deadlines.is_past("key0"));
+    assert!(!deadlines.is_past("key1"));
+    assert!(!deadlines.is_past("key2"));
+    assert!(!deadlines.is_past("temp"));
+    Ok(())
+}
+
+// A record cut short by a crash should be dropped, and not break later ones.
+#[test]
+fn deadlines_torn_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let past = SystemTime::now() - Duration::from_secs(1);
+    let deadlines = Deadlines::open(temp_dir.path())?;
+    deadlines.set("key1", Some(past))?;
+    drop(deadlines);
+    OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join("expiry"))?
+        .write_all(b"{\"key\":\"key2\",\"dead")?;
+
+    let deadlines = Deadlines::open(temp_dir.path())?;
+    assert_eq!(deadlines.past(), vec!["key1".to_owned()]);
+    deadlines.set("key3", Some(past))?;
+    drop(deadlines);
+
+    let deadlines = Deadlines::open(temp_dir.path())?;
+    let mut past = deadlines.past();
+    past.sort();
+    assert_eq!(past, vec!["key1".to_owned(), "key3".to_owned()]);
+    Ok(())
+}
@@ -1,12 +1,17 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::admin;
+use kvs::{
+    Change, EncryptionKey, KvStore, KvStoreOptions, KvsEngine, KvsError, Lookup, LsmKvsEngine,
+    LsmOptions, Result,
+};
+use std::fs;
+use std::path::Path;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
 // Should get previously stored value
-#[test]
-fn get_stored_value() -> Result<()> {
+fn get_stored_value<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let mut store = open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -16,7 +21,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let mut store = open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -24,10 +29,9 @@ fn get_stored_value() -> Result<()> {
 }
 
 // Should overwrite existent value
-#[test]
-fn overwrite_value() -> Result<()> {
+fn overwrite_value<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let mut store = open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -36,7 +40,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let mut store = open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -45,34 +49,31 @@ fn overwrite_value() -> Result<()> {
 }
 
 // Should get `None` when getting a non-existent key
-#[test]
-fn get_non_existent_value() -> Result<()> {
+fn get_non_existent_value<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let mut store = open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let mut store = open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
 }
 
-#[test]
-fn remove_non_existent_key() -> Result<()> {
+fn remove_non_existent_key<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let mut store = open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
 
-#[test]
-fn remove_key() -> Result<()> {
+fn remove_key<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let mut store = open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
@@ -81,10 +82,9 @@ fn remove_key() -> Result<()> {
 
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
-#[test]
-fn compaction() -> Result<()> {
+fn compaction<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let mut store = open(temp_dir.path())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -114,7 +114,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content
-        let mut store = KvStore::open(temp_dir.path())?;
+        let mut store = open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -124,3 +124,846 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Should page through the keys of a prefix in key order
+fn scan_pages<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open(temp_dir.path())?;
+
+    for key_id in 0..300 {
+        store.set(format!("key{:03}", key_id), format!("value{}", key_id))?;
+        store.set(format!("other{:03}", key_id), "other".to_owned())?;
+    }
+    for key_id in (0..300).step_by(3) {
+        store.remove(format!("key{:03}", key_id))?;
+    }
+    store.set("key099".to_owned(), "again".to_owned())?;
+
+    let mut expected: Vec<(String, String)> = (0..300)
+        .filter(|key_id| key_id % 3 > 0)
+        .map(|key_id| (format!("key{:03}", key_id), format!("value{}", key_id)))
+        .collect();
+    expected.push(("key099".to_owned(), "again".to_owned()));
+    expected.sort();
+    assert_eq!(store.scan("key")?, expected);
+
+    let mut scanned = Vec::new();
+    let mut after = None;
+    loop {
+        let page = store.scan_page("key", after.as_deref(), 7)?;
+        assert!(page.len() <= 7);
+        match page.last() {
+            Some((key, _)) => after = Some(key.clone()),
+            None => break,
+        }
+        scanned.extend(page);
+    }
+    assert_eq!(scanned, expected);
+    assert_eq!(
+        store.scan_page("key", Some("key297"), 10)?,
+        vec![
+            ("key298".to_owned(), "value298".to_owned()),
+            ("key299".to_owned(), "value299".to_owned())
+        ]
+    );
+    assert!(store.scan("none")?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn kvs_get_stored_value() -> Result<()> {
+    get_stored_value(|path| KvStore::open(path))
+}
+
+#[test]
+fn lsm_get_stored_value() -> Result<()> {
+    get_stored_value(|path| LsmKvsEngine::open(path))
+}
+
+#[test]
+fn kvs_overwrite_value() -> Result<()> {
+    overwrite_value(|path| KvStore::open(path))
+}
+
+#[test]
+fn lsm_overwrite_value() -> Result<()> {
+    overwrite_value(|path| LsmKvsEngine::open(path))
+}
+
+#[test]
+fn kvs_get_non_existent_value() -> Result<()> {
+    get_non_existent_value(|path| KvStore::open(path))
+}
+
+#[test]
+fn lsm_get_non_existent_value() -> Result<()> {
+    get_non_existent_value(|path| LsmKvsEngine::open(path))
+}
+
+#[test]
+fn kvs_remove_non_existent_key() -> Result<()> {
+    remove_non_existent_key(|path| KvStore::open(path))
+}
+
+#[test]
+fn lsm_remove_non_existent_key() -> Result<()> {
+    remove_non_existent_key(|path| LsmKvsEngine::open(path))
+}
+
+#[test]
+fn kvs_remove_key() -> Result<()> {
+    remove_key(|path| KvStore::open(path))
+}
+
+#[test]
+fn lsm_remove_key() -> Result<()> {
+    remove_key(|path| LsmKvsEngine::open(path))
+}
+
+#[test]
+fn kvs_compaction() -> Result<()> {
+    compaction(|path| KvStore::open(path))
+}
+
+#[test]
+fn lsm_compaction() -> Result<()> {
+    compaction(|path| LsmKvsEngine::open(path))
+}
+
+#[test]
+fn kvs_scan_pages() -> Result<()> {
+    scan_pages(|path| KvStore::open(path))
+}
+
+#[test]
+fn lsm_scan_pages() -> Result<()> {
+    // Small tables, so that keys and their tombstones spread over levels.
+    let options = LsmOptions {
+        memtable_size: 1024,
+        block_size: 128,
+        table_size: 2 * 1024,
+        level0_tables: 2,
+        level1_size: 4 * 1024,
+    };
+    scan_pages(|path| LsmKvsEngine::open_with_options(path, options.clone()))
+}
+
+// Large values should be compressed on disk and read back transparently.
+#[test]
+fn compressed_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        compression_threshold: Some(64),
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    let large = r#"{"name":"kvs","tags":["a","b","c"]}"#.repeat(100);
+    store.set("large".to_owned(), large.clone())?;
+    store.set("small".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get("large".to_owned())?, Some(large.clone()));
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+
+    let stats = store.compression_stats();
+    assert_eq!(stats.compressed_values, 1);
+    assert!(stats.ratio() > 2.0);
+
+    let log_size: u64 = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .map(|entry| entry.unwrap().metadata().unwrap())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum();
+    assert!(log_size < large.len() as u64);
+
+    // Compressed records stay readable after compaction and without the option.
+    store.compact()?;
+    assert_eq!(store.get("large".to_owned())?, Some(large.clone()));
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("large".to_owned())?, Some(large));
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+fn encrypted_options(key: u8, previous: &[u8]) -> KvStoreOptions {
+    KvStoreOptions {
+        encryption_key: Some(EncryptionKey::new([key; 32])),
+        previous_keys: previous
+            .iter()
+            .map(|&key| EncryptionKey::new([key; 32]))
+            .collect(),
+        ..KvStoreOptions::default()
+    }
+}
+
+fn log_contents(dir: &TempDir) -> String {
+    WalkDir::new(dir.path())
+        .into_iter()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| fs::read_to_string(entry.path()).unwrap())
+        .collect()
+}
+
+// Encrypted records should not leak keys or values, and need the right key.
+#[test]
+fn encrypted_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_with_options(temp_dir.path(), encrypted_options(1, &[]))?;
+    store.set("secret-key".to_owned(), "secret-value".to_owned())?;
+    store.set("other".to_owned(), "value".to_owned())?;
+    store.remove("other".to_owned())?;
+    drop(store);
+
+    let contents = log_contents(&temp_dir);
+    assert!(!contents.contains("secret-key"));
+    assert!(!contents.contains("secret-value"));
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::KeyRequired) => {}
+        _ => panic!("opened an encrypted store without a key"),
+    }
+    match KvStore::open_with_options(temp_dir.path(), encrypted_options(2, &[])) {
+        Err(KvsError::Decryption) => {}
+        _ => panic!("opened an encrypted store with a wrong key"),
+    }
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), encrypted_options(1, &[]))?;
+    assert_eq!(
+        store.get("secret-key".to_owned())?,
+        Some("secret-value".to_owned())
+    );
+    assert_eq!(store.get("other".to_owned())?, None);
+    Ok(())
+}
+
+// A wrong key should be rejected even before any record is sealed.
+#[test]
+fn encrypted_store_checks_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    drop(KvStore::open_with_options(
+        temp_dir.path(),
+        encrypted_options(1, &[]),
+    )?);
+
+    match KvStore::open_with_options(temp_dir.path(), encrypted_options(2, &[])) {
+        Err(KvsError::Decryption) => {}
+        _ => panic!("opened an encrypted store with a wrong key"),
+    }
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::KeyRequired) => {}
+        _ => panic!("opened an encrypted store without a key"),
+    }
+    KvStore::open_with_options(temp_dir.path(), encrypted_options(1, &[]))?;
+
+    // Compacting with only a previous key decrypts the store for good.
+    let options = KvStoreOptions {
+        encryption_key: None,
+        ..encrypted_options(0, &[1])
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.compact()?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Compaction should re-encrypt the live records with the new key.
+#[test]
+fn rotate_encryption_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_with_options(temp_dir.path(), encrypted_options(1, &[]))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), encrypted_options(2, &[1]))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.compact()?;
+    drop(store);
+
+    assert!(KvStore::open_with_options(temp_dir.path(), encrypted_options(1, &[])).is_err());
+    let mut store = KvStore::open_with_options(temp_dir.path(), encrypted_options(2, &[]))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+fn blob_files(dir: &TempDir) -> Vec<String> {
+    let mut files: Vec<String> = fs::read_dir(dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.ends_with(".blob"))
+        .collect();
+    files.sort();
+    files
+}
+
+// Large values should live in blob files, and stale blob files be collected.
+#[test]
+fn blob_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        blob_threshold: Some(100),
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("{}", key_id).repeat(200))?;
+    }
+    store.set("small".to_owned(), "value".to_owned())?;
+    assert_eq!(blob_files(&temp_dir), vec!["1.blob"]);
+    store.compact()?;
+    assert_eq!(store.get("key3".to_owned())?, Some("3".repeat(200)));
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key3".to_owned())?, Some("3".repeat(200)));
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+    for key_id in 0..8 {
+        store.set(format!("key{}", key_id), "new".repeat(100))?;
+    }
+    store.remove("key8".to_owned())?;
+
+    // Only key9 is still live in the first blob file, so it gets moved,
+    // which is not a change of its value.
+    let last_seq = store.last_seq();
+    store.gc_blobs()?;
+    assert_eq!(blob_files(&temp_dir), vec!["2.blob"]);
+    assert_eq!(store.last_seq(), last_seq);
+    let key9_changes: Vec<Change> = store
+        .changes_since(0)?
+        .into_iter()
+        .filter(|change| change.key == "key9")
+        .collect();
+    assert_eq!(
+        key9_changes,
+        vec![change(10, "key9", Some(&"9".repeat(200)))]
+    );
+    // The acknowledged changes to values that are gone are skipped.
+    assert_eq!(store.get("key0".to_owned())?, Some("new".repeat(100)));
+    assert_eq!(store.get("key8".to_owned())?, None);
+    assert_eq!(store.get("key9".to_owned())?, Some("9".repeat(200)));
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key9".to_owned())?, Some("9".repeat(200)));
+    assert_eq!(store.get("key7".to_owned())?, Some("new".repeat(100)));
+    Ok(())
+}
+
+// Rotating keys should also re-encrypt values stored in blob files.
+#[test]
+fn rotate_encryption_key_with_blobs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = |key, previous| KvStoreOptions {
+        blob_threshold: Some(10),
+        ..encrypted_options(key, previous)
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options(1, &[]))?;
+    store.set("key1".to_owned(), "a large secret value".to_owned())?;
+    drop(store);
+    assert!(!log_contents(&temp_dir).contains("secret"));
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options(2, &[1]))?;
+    store.compact()?;
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options(2, &[]))?;
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some("a large secret value".to_owned())
+    );
+    Ok(())
+}
+
+// The on-disk index should behave like the in-memory one, even with a tiny cache.
+#[test]
+fn disk_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        disk_index_cache: Some(4),
+        blob_threshold: Some(50),
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.set("large".to_owned(), "x".repeat(100))?;
+    for key_id in 0..50 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    assert!(store.remove("key0".to_owned()).is_err());
+    store.compact()?;
+    assert_eq!(store.get("key10".to_owned())?, None);
+    assert_eq!(store.get("key60".to_owned())?, Some("value60".to_owned()));
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        let expected = if key_id < 50 {
+            None
+        } else {
+            Some(format!("value{}", key_id))
+        };
+        assert_eq!(store.get(format!("key{}", key_id))?, expected);
+    }
+    assert_eq!(store.get("large".to_owned())?, Some("x".repeat(100)));
+    assert_eq!(store.stats()?.keys, 51);
+    Ok(())
+}
+
+// The on-disk index should only replay the log written since its checkpoint.
+#[test]
+fn disk_index_checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        disk_index_cache: Some(4),
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("stale".to_owned(), "old".to_owned())?;
+    store.set("stale".to_owned(), "new".to_owned())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let stale_bytes = store.stats()?.stale_bytes;
+    drop(store);
+
+    // Written past the checkpoint, by a store without the on-disk index.
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // Replaying the whole log would trip over the damaged stale record.
+    let log = temp_dir.path().join("1.log");
+    let data = fs::read_to_string(&log)?;
+    fs::write(&log, data.replacen("\"old\"", "#old#", 1))?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("stale".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.stats()?.keys, 3);
+    assert_eq!(store.stats()?.stale_bytes, stale_bytes);
+    Ok(())
+}
+
+// Immutable generations should be readable through memory maps.
+#[test]
+fn mmap_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        mmap: true,
+        compression_threshold: Some(10),
+        ..encrypted_options(1, &[])
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set(
+        "key2".to_owned(),
+        "a value long enough to be compressed".to_owned(),
+    )?;
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    // Values of mapped generations are read without the store, even once
+    // compaction deleted their generation.
+    let mapped = match store.lookup("key2".to_owned())? {
+        Lookup::Mapped(value) => value,
+        Lookup::Value(_) => panic!("key2 should be read from its mapping"),
+    };
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert!(matches!(
+        store.lookup("key3".to_owned())?,
+        Lookup::Value(Some(ref value)) if value == "value3"
+    ));
+    store.compact()?;
+    assert_eq!(mapped.read()?, "a value long enough to be compressed");
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(
+        store.get("key2".to_owned())?,
+        Some("a value long enough to be compressed".to_owned())
+    );
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Repeated reads should hit the value cache, which must not serve stale values.
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        value_cache_size: Some(25),
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.cache_stats().hits, 1);
+    assert_eq!(store.cache_stats().misses, 1);
+
+    store.set("key1".to_owned(), "value4".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value4".to_owned()));
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    // Only two of these 10-byte entries fit in 25 bytes.
+    store.get("key2".to_owned())?;
+    store.get("key3".to_owned())?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    store.get("key4".to_owned())?;
+    let stats = store.cache_stats();
+    assert_eq!(stats.entries, 2);
+    assert_eq!(stats.size, 20);
+
+    store.compact()?;
+    assert_eq!(store.cache_stats().entries, 0);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Stats should count operations and reflect the log after compaction
+#[test]
+fn store_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    store.get("key1".to_owned())?;
+    store.remove("key2".to_owned())?;
+
+    let stats = store.stats()?;
+    assert_eq!(stats.keys, 1);
+    assert_eq!((stats.sets, stats.gets, stats.removes), (3, 1, 1));
+    assert_eq!(stats.compactions, 0);
+    assert!(stats.stale_bytes > 0);
+    assert!(stats.total_bytes > stats.stale_bytes);
+
+    let total_bytes = stats.total_bytes;
+    let report = store.compact()?;
+    let stats = store.stats()?;
+    assert_eq!(report.reclaimed_bytes, total_bytes - stats.total_bytes);
+    assert!(report.reclaimed_bytes > 0);
+    assert_eq!(stats.compactions, 1);
+    assert_eq!(stats.stale_bytes, 0);
+    assert!(stats.last_compaction_at.is_some());
+    assert!(stats.last_compaction_ms.is_some());
+    Ok(())
+}
+
+// Writes three keys, then corrupts the record of `key2` and appends a
+// truncated record. Returns the offset of the corrupt record.
+fn corrupt_log(dir: &Path) -> Result<usize> {
+    let mut store = KvStore::open(dir)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.set("key1".to_owned(), "value4".to_owned())?;
+    drop(store);
+
+    let path = dir.join("1.log");
+    let mut data = fs::read_to_string(&path)?;
+    let offset = data.find("{\"Sequenced\":[2,").unwrap();
+    data.replace_range(offset + 2..offset + 5, "###");
+    data.push_str("{\"Set\":[\"key5\",\"val");
+    fs::write(&path, data)?;
+    Ok(offset)
+}
+
+// Verify should report corrupt records and count live and stale keys
+#[test]
+fn verify_corrupt_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let offset = corrupt_log(temp_dir.path())?;
+
+    let report = admin::verify(temp_dir.path(), &KvStoreOptions::default())?;
+    assert!(!report.is_clean());
+    assert_eq!(report.bad_records.len(), 2);
+    assert_eq!(report.bad_records[0].gen, 1);
+    assert_eq!(report.bad_records[0].offset, offset as u64);
+    assert_eq!(report.records, 3);
+    assert_eq!(report.live_keys, 2);
+    assert_eq!(report.stale_records, 1);
+
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    Ok(())
+}
+
+// Repair should salvage every readable live record into a clean generation
+#[test]
+fn repair_corrupt_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    corrupt_log(temp_dir.path())?;
+
+    let report = admin::repair(temp_dir.path(), &KvStoreOptions::default())?;
+    assert_eq!(report.salvaged, 2);
+    assert_eq!(report.dropped.len(), 2);
+    assert!(admin::verify(temp_dir.path(), &KvStoreOptions::default())?.is_clean());
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key5".to_owned())?, None);
+    Ok(())
+}
+
+// Repair should keep encrypted records readable, and need the key
+#[test]
+fn repair_encrypted_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_with_options(temp_dir.path(), encrypted_options(7, &[]))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let path = temp_dir.path().join("1.log");
+    let mut data = fs::read_to_string(&path)?;
+    data.push_str("{\"Sealed\":[");
+    fs::write(&path, data)?;
+
+    match admin::repair(temp_dir.path(), &KvStoreOptions::default()) {
+        Err(KvsError::KeyRequired) => {}
+        other => panic!("expected KeyRequired, got {:?}", other),
+    }
+    let report = admin::repair(temp_dir.path(), &encrypted_options(7, &[]))?;
+    assert_eq!(report.salvaged, 1);
+    assert_eq!(report.dropped.len(), 1);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), encrypted_options(7, &[]))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Dump should list records in log order, filtered by prefix, generation and liveness
+#[test]
+fn dump_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("a1".to_owned(), "value1".to_owned())?;
+    store.set("b1".to_owned(), "value2".to_owned())?;
+    store.set("a1".to_owned(), "value3".to_owned())?;
+    store.remove("b1".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("a2".to_owned(), "value4".to_owned())?;
+    drop(store);
+
+    let summary = |filter: admin::DumpFilter| -> Result<Vec<(u64, &'static str, String)>> {
+        Ok(
+            admin::dump(temp_dir.path(), &KvStoreOptions::default(), &filter)?
+                .into_iter()
+                .map(|record| {
+                    let key = record.command.key().unwrap().to_owned();
+                    (record.gen, record.command.name(), key)
+                })
+                .collect(),
+        )
+    };
+
+    let all = summary(admin::DumpFilter::default())?;
+    assert_eq!(all.len(), 5);
+    assert_eq!(all[3], (1, "Remove", "b1".to_owned()));
+    assert_eq!(all[4], (2, "Set", "a2".to_owned()));
+
+    let prefixed = summary(admin::DumpFilter {
+        prefix: Some("a".to_owned()),
+        gen: Some(1),
+        ..admin::DumpFilter::default()
+    })?;
+    assert_eq!(prefixed.len(), 2);
+    assert!(prefixed
+        .iter()
+        .all(|(gen, _, key)| *gen == 1 && key == "a1"));
+
+    let live = summary(admin::DumpFilter {
+        live: true,
+        ..admin::DumpFilter::default()
+    })?;
+    assert_eq!(
+        live,
+        vec![(1, "Set", "a1".to_owned()), (2, "Set", "a2".to_owned())]
+    );
+    Ok(())
+}
+
+fn change(seq: u64, key: &str, value: Option<&str>) -> Change {
+    Change {
+        seq,
+        key: key.to_owned(),
+        value: value.map(str::to_owned),
+    }
+}
+
+// Every change should get the next sequence number, across reopens
+#[test]
+fn change_feed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert_eq!(
+        store.changes_since(0)?,
+        vec![
+            change(1, "key1", Some("value1")),
+            change(2, "key2", Some("value2")),
+            change(3, "key1", None),
+        ]
+    );
+    assert_eq!(store.changes_since(2)?, vec![change(3, "key1", None)]);
+
+    // Compaction drops the overwritten changes, not the sequence numbers.
+    store.compact()?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.last_seq(), 3);
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    assert_eq!(
+        store.changes_since(0)?,
+        vec![
+            change(2, "key2", Some("value2")),
+            change(4, "key2", Some("value3")),
+        ]
+    );
+    Ok(())
+}
+
+// Compaction should keep the changes that were not acknowledged
+#[test]
+fn change_retention() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        retain_changes: true,
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key1".to_owned(), "value4".to_owned())?;
+    store.acknowledge_changes(1)?;
+
+    store.compact()?;
+    let retained = vec![
+        change(2, "key1", Some("value2")),
+        change(3, "key2", Some("value3")),
+        change(4, "key2", None),
+        change(5, "key1", Some("value4")),
+    ];
+    assert_eq!(store.changes_since(0)?, retained);
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.changes_since(0)?, retained);
+
+    store.acknowledge_changes(4)?;
+    store.compact()?;
+    assert_eq!(
+        store.changes_since(0)?,
+        vec![change(5, "key1", Some("value4"))]
+    );
+    Ok(())
+}
+
+// Blob files should outlive the values that changes not acknowledged point to
+#[test]
+fn change_retention_with_blobs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        retain_changes: true,
+        blob_threshold: Some(10),
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "a".repeat(20))?;
+    store.set("key1".to_owned(), "b".repeat(20))?;
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "c".repeat(20))?;
+    store.gc_blobs()?;
+    store.compact()?;
+    assert_eq!(blob_files(&temp_dir), vec!["1.blob", "2.blob"]);
+    assert_eq!(
+        store.changes_since(0)?,
+        vec![
+            change(1, "key1", Some(&"a".repeat(20))),
+            change(2, "key1", Some(&"b".repeat(20))),
+            change(3, "key1", Some(&"c".repeat(20))),
+        ]
+    );
+    assert_eq!(
+        store.changes_since(2)?,
+        vec![change(3, "key1", Some(&"c".repeat(20)))]
+    );
+
+    store.acknowledge_changes(3)?;
+    store.gc_blobs()?;
+    assert_eq!(blob_files(&temp_dir), vec!["2.blob"]);
+    // The acknowledged changes to values that are gone are skipped.
+    assert_eq!(
+        store.changes_since(0)?,
+        vec![change(3, "key1", Some(&"c".repeat(20)))]
+    );
+    Ok(())
+}
+
+// Many flushes should go through leveled compaction without losing data.
+#[test]
+fn lsm_leveled_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = LsmOptions {
+        memtable_size: 2 * 1024,
+        block_size: 256,
+        table_size: 4 * 1024,
+        level0_tables: 2,
+        level1_size: 8 * 1024,
+    };
+    let mut engine = LsmKvsEngine::open_with_options(temp_dir.path(), options.clone())?;
+    for iter in 0..20 {
+        for key_id in 0..200 {
+            engine.set(
+                format!("key{}", key_id),
+                format!("value{}-{}", key_id, iter),
+            )?;
+        }
+    }
+    for key_id in (0..200).step_by(2) {
+        engine.remove(format!("key{}", key_id))?;
+    }
+    let tables = fs::read_dir(temp_dir.path())?
+        .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("sst".as_ref()))
+        .count();
+    assert!(tables > 1);
+    drop(engine);
+
+    let mut engine = LsmKvsEngine::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..200 {
+        let expected = if key_id % 2 == 0 {
+            None
+        } else {
+            Some(format!("value{}-19", key_id))
+        };
+        assert_eq!(engine.get(format!("key{}", key_id))?, expected);
+    }
+    assert!(engine.remove("key0".to_owned()).is_err());
+    Ok(())
+}
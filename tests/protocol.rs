@@ -0,0 +1,80 @@
+use kvs::common::ErrorCode;
+use kvs::KvsError;
+use std::io;
+
+#[test]
+fn error_codes_round_trip() {
+    let codes = vec![
+        ErrorCode::KeyNotFound,
+        ErrorCode::Io {
+            kind: "TimedOut".to_owned(),
+            message: "disk did not answer".to_owned(),
+        },
+        ErrorCode::Serde("expected value".to_owned()),
+        ErrorCode::Sled("tree is closed".to_owned()),
+        ErrorCode::Utf8Error(3),
+        ErrorCode::UnexpectedCommandType,
+        ErrorCode::UnexpectedResponseType,
+        ErrorCode::Corruption("bad record".to_owned()),
+        ErrorCode::Decompression("truncated block".to_owned()),
+        ErrorCode::KeyRequired,
+        ErrorCode::Decryption,
+        ErrorCode::InvalidKey("too short".to_owned()),
+        ErrorCode::WrongEngine("sled".to_owned()),
+        ErrorCode::ReadOnly("127.0.0.1:4000".to_owned()),
+        ErrorCode::Unauthorized("no token".to_owned()),
+        ErrorCode::Busy("too many connections".to_owned()),
+        ErrorCode::NotLeader(Some("127.0.0.1:4001".to_owned())),
+        ErrorCode::NotLeader(None),
+        ErrorCode::MembershipChangePending,
+        ErrorCode::Timeout,
+        ErrorCode::Unavailable("127.0.0.1:4002".to_owned()),
+        ErrorCode::Unsupported("snapshot".to_owned()),
+        ErrorCode::HistoryUnavailable(7),
+        ErrorCode::Protocol("bad magic".to_owned()),
+        ErrorCode::MalformedMessage("unexpected end".to_owned()),
+        ErrorCode::Other("something else".to_owned()),
+    ];
+    for code in codes {
+        let error = KvsError::from(code.clone());
+        assert_eq!(ErrorCode::from(error), code);
+    }
+}
+
+#[test]
+fn server_errors_round_trip() {
+    let error = KvsError::from(ErrorCode::from(KvsError::Io(io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        "read-only file system",
+    ))));
+    match error {
+        KvsError::Io(e) => {
+            assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+            assert_eq!(e.to_string(), "read-only file system");
+        }
+        other => panic!("unexpected error {:?}", other),
+    }
+
+    let errors = vec![
+        KvsError::KeyNotFound,
+        KvsError::KeyRequired,
+        KvsError::Decryption,
+        KvsError::InvalidKey("too short".to_owned()),
+        KvsError::WrongEngine("kvs".to_owned()),
+        KvsError::Decompression("truncated block".to_owned()),
+        KvsError::Utf8Error(3),
+        KvsError::UnexpectedResponseType,
+        KvsError::Protocol("bad magic".to_owned()),
+        KvsError::Serde(serde_json::from_str::<u64>("x").unwrap_err()),
+        KvsError::Sled(sled::Error::Unsupported("tree is closed".to_owned())),
+        KvsError::StringErr("something else".to_owned()),
+    ];
+    for error in errors {
+        let message = error.to_string();
+        let name = format!("{:?}", error);
+        let name = name.split('(').next().unwrap();
+        let returned = KvsError::from(ErrorCode::from(error));
+        assert_eq!(returned.to_string(), message);
+        assert!(format!("{:?}", returned).starts_with(name));
+    }
+}
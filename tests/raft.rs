@@ -0,0 +1,326 @@
+use kvs::raft::{LocalNetwork, Members, Raft, RaftConfig, Role};
+use kvs::watch::Watchers;
+use kvs::{KvStore, KvsEngine, KvsError, Result};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+struct Node {
+    raft: Arc<Raft>,
+    db: Arc<Mutex<Box<dyn KvsEngine + Send>>>,
+}
+
+impl Node {
+    fn value(&self, key: &str) -> Option<String> {
+        self.db.lock().unwrap().get(key.to_owned()).unwrap()
+    }
+}
+
+fn members(ids: &[u64]) -> Members {
+    ids.iter().map(|&id| (id, format!("node-{}", id))).collect()
+}
+
+fn start(
+    network: &Arc<LocalNetwork>,
+    dir: &Path,
+    id: u64,
+    members: Members,
+    snapshot_entries: u64,
+) -> Result<Node> {
+    let mut config = RaftConfig::new(id, format!("node-{}", id));
+    config.members = members;
+    config.dir = Some(dir.to_owned());
+    config.tick = Duration::from_millis(10);
+    config.snapshot_entries = snapshot_entries;
+    config.proposal_timeout = Duration::from_secs(1);
+    let db: Arc<Mutex<Box<dyn KvsEngine + Send>>> =
+        Arc::new(Mutex::new(Box::new(KvStore::open(dir)?)));
+    let log = slog::Logger::root(slog::Discard, slog::o!());
+    let raft = Raft::start(
+        config,
+        db.clone(),
+        Arc::new(Watchers::new()),
+        network.clone(),
+        log,
+    )?;
+    network.add(&raft);
+    Ok(Node { raft, db })
+}
+
+fn start_cluster(
+    network: &Arc<LocalNetwork>,
+    dirs: &[TempDir],
+    snapshot_entries: u64,
+) -> Result<Vec<Node>> {
+    let ids: Vec<u64> = (1..=dirs.len() as u64).collect();
+    ids.iter()
+        .zip(dirs)
+        .map(|(&id, dir)| start(network, dir.path(), id, members(&ids), snapshot_entries))
+        .collect()
+}
+
+fn temp_dirs(n: usize) -> Vec<TempDir> {
+    (0..n).map(|_| TempDir::new().unwrap()).collect()
+}
+
+/// Polls `f` until it returns `Some`, for at most ten seconds.
+fn wait_for<T>(what: &str, f: impl Fn() -> Option<T>) -> T {
+    let start = Instant::now();
+    loop {
+        if let Some(value) = f() {
+            return value;
+        }
+        assert!(start.elapsed() < Duration::from_secs(10), "{}", what);
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+/// Waits for one of `candidates` to lead the others, returning its id.
+fn wait_for_leader(nodes: &[Node], candidates: &[u64]) -> u64 {
+    wait_for("no leader elected", || {
+        let statuses: Vec<_> = candidates
+            .iter()
+            .map(|&id| nodes[id as usize - 1].raft.status())
+            .collect();
+        let leader = statuses.iter().find(|status| status.role == Role::Leader)?;
+        let agreed = statuses
+            .iter()
+            .all(|status| status.leader == Some(leader.id) && status.term == leader.term);
+        if agreed {
+            Some(leader.id)
+        } else {
+            None
+        }
+    })
+}
+
+fn wait_for_value(node: &Node, key: &str, value: Option<&str>) {
+    wait_for(&format!("{} did not become {:?}", key, value), || {
+        if node.value(key).as_deref() == value {
+            Some(())
+        } else {
+            None
+        }
+    })
+}
+
+#[test]
+fn raft_replicates_writes() -> Result<()> {
+    let network = LocalNetwork::new();
+    let dirs = temp_dirs(3);
+    let nodes = start_cluster(&network, &dirs, 1000)?;
+    let leader = wait_for_leader(&nodes, &[1, 2, 3]);
+    let follower = if leader == 1 { 2 } else { 1 };
+
+    nodes[leader as usize - 1]
+        .raft
+        .set("key1".to_owned(), "value1".to_owned())?;
+    for node in &nodes {
+        wait_for_value(node, "key1", Some("value1"));
+    }
+    assert_eq!(
+        nodes[leader as usize - 1].raft.get("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+
+    match nodes[follower as usize - 1]
+        .raft
+        .set("key2".to_owned(), "value2".to_owned())
+    {
+        Err(KvsError::NotLeader(addr)) => assert_eq!(addr, Some(format!("node-{}", leader))),
+        other => panic!("expected a redirect, got {:?}", other),
+    }
+    match nodes[follower as usize - 1].raft.get("key1".to_owned()) {
+        Err(KvsError::NotLeader(_)) => {}
+        other => panic!("expected a redirect, got {:?}", other),
+    }
+
+    nodes[leader as usize - 1].raft.remove("key1".to_owned())?;
+    match nodes[leader as usize - 1].raft.remove("key1".to_owned()) {
+        Err(KvsError::KeyNotFound) => {}
+        other => panic!("expected KeyNotFound, got {:?}", other),
+    }
+    for node in &nodes {
+        wait_for_value(node, "key1", None);
+    }
+    Ok(())
+}
+
+#[test]
+fn raft_survives_partition() -> Result<()> {
+    let network = LocalNetwork::new();
+    let dirs = temp_dirs(3);
+    let nodes = start_cluster(&network, &dirs, 1000)?;
+    let old = wait_for_leader(&nodes, &[1, 2, 3]);
+    let old_term = nodes[old as usize - 1].raft.status().term;
+
+    network.partition(&[old]);
+    // Reads need a quorum to confirm the leader, which it no longer has.
+    assert!(nodes[old as usize - 1].raft.get("key".to_owned()).is_err());
+    let rest: Vec<u64> = (1..=3).filter(|&id| id != old).collect();
+    let new = wait_for_leader(&nodes, &rest);
+    assert!(nodes[new as usize - 1].raft.status().term > old_term);
+    nodes[new as usize - 1]
+        .raft
+        .set("key".to_owned(), "majority".to_owned())?;
+
+    // The isolated leader cannot commit anything.
+    assert!(nodes[old as usize - 1]
+        .raft
+        .set("key".to_owned(), "minority".to_owned())
+        .is_err());
+
+    network.heal();
+    wait_for_leader(&nodes, &[1, 2, 3]);
+    for node in &nodes {
+        wait_for_value(node, "key", Some("majority"));
+    }
+    Ok(())
+}
+
+#[test]
+fn raft_snapshot_catches_up_follower() -> Result<()> {
+    let network = LocalNetwork::new();
+    let dirs = temp_dirs(3);
+    let nodes = start_cluster(&network, &dirs, 10)?;
+    let leader = wait_for_leader(&nodes, &[1, 2, 3]);
+    let lagging = if leader == 3 { 2 } else { 3 };
+
+    network.partition(&[lagging]);
+    for i in 0..50 {
+        nodes[leader as usize - 1]
+            .raft
+            .set(format!("key{}", i), format!("value{}", i))?;
+    }
+    nodes[leader as usize - 1].raft.remove("key0".to_owned())?;
+    assert!(nodes[leader as usize - 1].raft.status().snapshot_index >= 40);
+
+    network.heal();
+    let node = &nodes[lagging as usize - 1];
+    wait_for_value(node, "key49", Some("value49"));
+    wait_for_value(node, "key0", None);
+    assert_eq!(node.value("key1"), Some("value1".to_owned()));
+    assert!(node.raft.status().snapshot_index >= 40);
+    Ok(())
+}
+
+#[test]
+fn raft_membership_changes() -> Result<()> {
+    let network = LocalNetwork::new();
+    let dirs = temp_dirs(4);
+    let mut nodes = start_cluster(&network, &dirs[..3], 1000)?;
+    let leader = wait_for_leader(&nodes, &[1, 2, 3]);
+    nodes[leader as usize - 1]
+        .raft
+        .set("key".to_owned(), "value".to_owned())?;
+
+    // A joining node starts without members and waits to be added.
+    nodes.push(start(&network, dirs[3].path(), 4, Members::new(), 1000)?);
+    nodes[leader as usize - 1]
+        .raft
+        .add_member(4, "node-4".to_owned())?;
+    wait_for_value(&nodes[3], "key", Some("value"));
+    assert_eq!(nodes[3].raft.status().members, members(&[1, 2, 3, 4]));
+
+    // Removing the leader hands leadership to one of the others.
+    nodes[leader as usize - 1].raft.remove_member(leader)?;
+    let rest: Vec<u64> = (1..=4).filter(|&id| id != leader).collect();
+    let new = wait_for_leader(&nodes, &rest);
+    assert_eq!(
+        nodes[new as usize - 1].raft.status().members,
+        members(&rest)
+    );
+    nodes[new as usize - 1]
+        .raft
+        .set("key".to_owned(), "after".to_owned())?;
+    wait_for_value(&nodes[3], "key", Some("after"));
+    Ok(())
+}
+
+#[test]
+fn raft_restarts_from_disk() -> Result<()> {
+    let dir = TempDir::new()?;
+    {
+        let network = LocalNetwork::new();
+        let node = start(&network, dir.path(), 1, members(&[1]), 5)?;
+        wait_for_leader(std::slice::from_ref(&node), &[1]);
+        for i in 0..12 {
+            node.raft.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        node.raft.stop();
+    }
+    thread::sleep(Duration::from_millis(100));
+
+    let network = LocalNetwork::new();
+    let node = start(&network, dir.path(), 1, members(&[1]), 5)?;
+    wait_for_leader(std::slice::from_ref(&node), &[1]);
+    let status = node.raft.status();
+    assert!(status.term >= 2);
+    assert!(status.snapshot_index >= 10);
+    for i in 0..12 {
+        assert_eq!(
+            node.raft.get(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+    node.raft.set("key12".to_owned(), "value12".to_owned())?;
+    Ok(())
+}
+
+// The initial members and the applied index should be kept on disk before
+// any snapshot holds them.
+#[test]
+fn raft_restarts_without_snapshot() -> Result<()> {
+    let dir = TempDir::new()?;
+    {
+        let network = LocalNetwork::new();
+        let node = start(&network, dir.path(), 1, members(&[1]), 1000)?;
+        wait_for_leader(std::slice::from_ref(&node), &[1]);
+        node.raft.set("key1".to_owned(), "value1".to_owned())?;
+        node.raft.stop();
+    }
+    thread::sleep(Duration::from_millis(100));
+
+    // The store already holds the no-op and the write.
+    let network = LocalNetwork::new();
+    let node = start(&network, dir.path(), 1, Members::new(), 1000)?;
+    assert_eq!(node.raft.status().applied_index, 2);
+    wait_for_leader(std::slice::from_ref(&node), &[1]);
+    let status = node.raft.status();
+    assert_eq!(status.snapshot_index, 0);
+    assert_eq!(status.members, members(&[1]));
+    assert_eq!(node.raft.get("key1".to_owned())?, Some("value1".to_owned()));
+    node.raft.set("key2".to_owned(), "value2".to_owned())?;
+    Ok(())
+}
+
+// A torn record at the end of the log should be cut off, not buried under
+// the records written after it.
+#[test]
+fn raft_truncates_torn_log() -> Result<()> {
+    let dir = TempDir::new()?;
+    let run = |key: &str| -> Result<u64> {
+        let network = LocalNetwork::new();
+        let node = start(&network, dir.path(), 1, members(&[1]), 1000)?;
+        wait_for_leader(std::slice::from_ref(&node), &[1]);
+        node.raft.set(key.to_owned(), "value".to_owned())?;
+        let commit_index = node.raft.status().commit_index;
+        node.raft.stop();
+        thread::sleep(Duration::from_millis(100));
+        Ok(commit_index)
+    };
+    assert_eq!(run("key1")?, 2);
+    let mut log = OpenOptions::new()
+        .append(true)
+        .open(dir.path().join("raft-log"))?;
+    log.write_all(b"{\"first\":3,\"entr")?;
+    drop(log);
+
+    assert_eq!(run("key2")?, 4);
+    assert_eq!(run("key3")?, 6);
+    Ok(())
+}
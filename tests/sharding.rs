@@ -0,0 +1,61 @@
+use kvs::sharding::HashRing;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+fn server(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+fn ring(ports: &[u16]) -> HashRing {
+    let mut ring = HashRing::new(128);
+    for &port in ports {
+        ring.add(server(port));
+    }
+    ring
+}
+
+fn keys() -> Vec<String> {
+    (0..3000).map(|i| format!("key{}", i)).collect()
+}
+
+#[test]
+fn ring_spreads_keys() {
+    let ring = ring(&[5001, 5002, 5003]);
+    assert_eq!(
+        ring.servers(),
+        vec![server(5001), server(5002), server(5003)]
+    );
+    let mut counts: HashMap<SocketAddr, usize> = HashMap::new();
+    for key in keys() {
+        let owner = ring.server_for(&key).unwrap();
+        assert_eq!(ring.server_for(&key), Some(owner));
+        *counts.entry(owner).or_default() += 1;
+    }
+    for count in counts.values() {
+        assert!(*count > 600 && *count < 1400, "unbalanced: {:?}", counts);
+    }
+    assert!(HashRing::new(128).server_for("key").is_none());
+}
+
+#[test]
+fn ring_moves_few_keys_on_add() {
+    let before = ring(&[5001, 5002, 5003]);
+    let mut after = before.clone();
+    after.add(server(5004));
+
+    let mut moved = 0;
+    for key in keys() {
+        let (old, new) = (before.server_for(&key), after.server_for(&key));
+        if old != new {
+            // Keys only ever move to the new server.
+            assert_eq!(new, Some(server(5004)));
+            moved += 1;
+        }
+    }
+    assert!(moved > 450 && moved < 1050, "moved {} keys", moved);
+
+    after.remove(server(5004));
+    for key in keys() {
+        assert_eq!(before.server_for(&key), after.server_for(&key));
+    }
+}
@@ -0,0 +1,94 @@
+use kvs::watch::{Event, Watchers};
+use kvs::{KvStore, KvsEngine, KvsError, LsmKvsEngine, Result};
+use tempfile::TempDir;
+
+fn event(seq: u64, key: &str, value: Option<&str>) -> Event {
+    Event {
+        seq,
+        key: key.to_owned(),
+        value: value.map(str::to_owned),
+    }
+}
+
+fn set(store: &mut dyn KvsEngine, watchers: &Watchers, key: &str, value: &str) -> Result<()> {
+    store.set(key.to_owned(), value.to_owned())?;
+    watchers.publish(store, key.to_owned(), Some(value.to_owned()))
+}
+
+// Events should carry the store's sequence numbers, and a watcher resume
+// from the store's change feed after a restart.
+#[test]
+fn watchers_resume_from_feed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let watchers = Watchers::new();
+    set(&mut store, &watchers, "key1", "value1")?;
+    store.remove("key1".to_owned())?;
+    watchers.publish(&mut store, "key1".to_owned(), None)?;
+    assert_eq!(watchers.last_seq(), 2);
+    drop(watchers);
+
+    let watchers = Watchers::new();
+    let (backlog, _) = watchers.subscribe(&mut store, String::new(), Some(1))?;
+    assert_eq!(backlog, vec![event(2, "key1", None)]);
+    assert!(matches!(
+        watchers.subscribe(&mut store, String::new(), Some(3)),
+        Err(KvsError::HistoryUnavailable(3))
+    ));
+
+    set(&mut store, &watchers, "key2", "value2")?;
+    let (backlog, _) = watchers.subscribe(&mut store, "key2".to_owned(), Some(0))?;
+    assert_eq!(backlog, vec![event(3, "key2", Some("value2"))]);
+
+    // Compaction drops the changes of key1, which a watcher cannot skip.
+    store.compact()?;
+    let watchers = Watchers::new();
+    assert!(matches!(
+        watchers.subscribe(&mut store, String::new(), Some(0)),
+        Err(KvsError::HistoryUnavailable(0))
+    ));
+    let (backlog, _) = watchers.subscribe(&mut store, String::new(), Some(2))?;
+    assert_eq!(backlog, vec![event(3, "key2", Some("value2"))]);
+    Ok(())
+}
+
+// Without a change feed, event numbers should continue after a restart, and
+// older ones be refused.
+#[test]
+fn watchers_restart_without_feed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = LsmKvsEngine::open(temp_dir.path())?;
+    let watchers = Watchers::open(temp_dir.path())?;
+    set(&mut store, &watchers, "key1", "value1")?;
+    set(&mut store, &watchers, "key1", "value2")?;
+    assert_eq!(watchers.last_seq(), 2);
+    drop(watchers);
+
+    let watchers = Watchers::open(temp_dir.path())?;
+    assert!(watchers.last_seq() >= 2);
+    assert!(matches!(
+        watchers.subscribe(&mut store, String::new(), Some(1)),
+        Err(KvsError::HistoryUnavailable(1))
+    ));
+    set(&mut store, &watchers, "key2", "value2")?;
+    let since = watchers.last_seq() - 1;
+    let (backlog, _) = watchers.subscribe(&mut store, String::new(), Some(since))?;
+    assert_eq!(backlog, vec![event(since + 1, "key2", Some("value2"))]);
+    Ok(())
+}
+
+// A watcher that does not keep up should be dropped instead of buffering
+// every change.
+#[test]
+fn slow_watcher() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let watchers = Watchers::new();
+    let (_, events) = watchers.subscribe(&mut store, String::new(), None)?;
+    for i in 0..5_000 {
+        set(&mut store, &watchers, &format!("key{}", i), "value")?;
+    }
+    let received = events.iter().count();
+    assert!(received > 0 && received < 5_000);
+    Ok(())
+}